@@ -0,0 +1,22 @@
+// Engine constants written once per frame, see frame_constants.rs. The ray tracing shaders read
+// them at binding 13, other pipelines define FRAME_BINDING before the include.
+
+#ifndef FRAME_BINDING
+#define FRAME_BINDING 13
+#endif
+
+layout(binding = FRAME_BINDING, set = 0) uniform Frame {
+    // Sample index, it stays the same while the seed policy freezes the samples
+    uint index;
+    uint seed;
+    // Frames drawn since the renderer was created
+    uint frameIndex;
+    // Free for custom shaders, the engine does not read them
+    uint debugFlags;
+    // Seconds of the application clock
+    float time;
+    float deltaTime;
+    uvec2 resolution;
+    // Returned by the rays that hit nothing while the background is solid
+    vec4 clearColor;
+} frame;
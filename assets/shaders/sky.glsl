@@ -0,0 +1,48 @@
+// Daylight of Preetham et al. 1999, A Practical Analytic Model for Daylight. The sun itself is
+// not drawn, it lights the scene as a directional light instead.
+
+// Zenith luminances are in kcd/m2, this brings a clear noon sky close to the default sun light
+const float skyLuminanceScale = 0.04;
+
+// Relative luminance and chromaticity of the Perez sky model, one per component of xyY
+vec3 perez(float cosTheta, float gamma, vec3 a, vec3 b, vec3 c, vec3 d, vec3 e) {
+    float cosGamma = cos(gamma);
+    return (1.0 + a * exp(b / max(cosTheta, 0.01))) * (1.0 + c * exp(d * gamma) + e * cosGamma * cosGamma);
+}
+
+vec3 skyColor(vec3 direction, vec3 sunDirection, float turbidity) {
+    const float pi = 3.14159265359;
+    float t = turbidity;
+    // Below the horizon the sky is darkened, standing in for the ground
+    vec3 view = normalize(vec3(direction.x, max(direction.y, 0.0), direction.z));
+    float ground = direction.y < 0.0 ? 0.3 : 1.0;
+    // The model only holds while the sun is up, the sky then fades out through the dusk
+    float thetaSun = acos(clamp(sunDirection.y, 0.0, 1.0));
+    float dusk = smoothstep(-0.2, 0.05, sunDirection.y);
+
+    float chi = (4.0 / 9.0 - t / 120.0) * (pi - 2.0 * thetaSun);
+    float zenithLuminance = (4.0453 * t - 4.9710) * tan(chi) - 0.2155 * t + 2.4192;
+    vec4 angles = vec4(thetaSun * thetaSun * thetaSun, thetaSun * thetaSun, thetaSun, 1.0);
+    float zenithX = t * t * dot(vec4(0.00166, -0.00375, 0.00209, 0.0), angles)
+        + t * dot(vec4(-0.02903, 0.06377, -0.03202, 0.00394), angles)
+        + dot(vec4(0.11693, -0.21196, 0.06052, 0.25886), angles);
+    float zenithY = t * t * dot(vec4(0.00275, -0.00610, 0.00317, 0.0), angles)
+        + t * dot(vec4(-0.04214, 0.08970, -0.04153, 0.00516), angles)
+        + dot(vec4(0.15346, -0.26756, 0.06670, 0.26688), angles);
+
+    // Coefficients of the luminance and the x and y chromaticities
+    vec3 a = vec3(0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608);
+    vec3 b = vec3(-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092);
+    vec3 c = vec3(-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102);
+    vec3 d = vec3(0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537);
+    vec3 e = vec3(-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529);
+
+    float gamma = acos(clamp(dot(view, normalize(sunDirection)), -1.0, 1.0));
+    vec3 ratio = perez(view.y, gamma, a, b, c, d, e) / perez(1.0, thetaSun, a, b, c, d, e);
+    vec3 xyY = vec3(zenithX, zenithY, max(zenithLuminance, 0.0)) * ratio.yzx;
+    xyY.z *= skyLuminanceScale * ground * dusk;
+
+    vec3 xyz = vec3(xyY.x / xyY.y * xyY.z, xyY.z, (1.0 - xyY.x - xyY.y) / xyY.y * xyY.z);
+    vec3 rgb = mat3(3.2406, -0.9689, 0.0557, -1.5372, 1.8758, -0.2040, -0.4986, 0.0415, 1.0570) * xyz;
+    return max(rgb, vec3(0.0));
+}
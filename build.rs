@@ -1,23 +1,117 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::SystemTime;
+
+const SHADER_DIRECTORY: &str = "assets/shaders";
+// One permutation per line, the shader file followed by its defines, e.g.
+// closesthit.rchit NO_SHADOWS DEBUG_VIEW=2
+const PERMUTATIONS: &str = "assets/shaders/permutations.txt";
 
 fn main() {
-    let shader_files = std::fs::read_dir(Path::new("assets/shaders/")).unwrap();
+    println!("cargo:rerun-if-changed={}", SHADER_DIRECTORY);
+
+    let permutations = read_permutations(Path::new(PERMUTATIONS));
+    // The includes of a shader are not known, a change to any source recompiles every shader
+    let sources_modified = newest_source(Path::new(SHADER_DIRECTORY));
+
+    let shader_files = std::fs::read_dir(Path::new(SHADER_DIRECTORY)).unwrap();
 
     for shader_file in shader_files {
         let input = shader_file.unwrap().path();
         if let Some(extension) = input.extension() {
-            if extension.eq("rchit") || extension.eq("rmiss") || extension.eq("rgen") {
-                let output = input.with_extension("spv");
-                compile_shader(&input, &output);
+            // Other files, such as the .glsl includes, are not compiled on their own
+            if extension.eq("rchit")
+                || extension.eq("rmiss")
+                || extension.eq("rgen")
+                || extension.eq("comp")
+            {
+                let name = input.file_name().unwrap().to_str().unwrap();
+                let mut define_sets = vec![vec![]];
+                if let Some(permutations) = permutations.get(name) {
+                    define_sets.extend(permutations.iter().cloned());
+                }
+
+                for defines in define_sets.iter() {
+                    let output = permutation_output(&input, defines);
+                    if !is_up_to_date(&output, sources_modified) {
+                        compile_shader(&input, &output, defines);
+                    }
+                }
             }
         }
     }
 }
 
-fn compile_shader(input: &PathBuf, output: &PathBuf) {
-    let output = Command::new("glslc")
-        .args(&[input.to_str().unwrap(), "-o", output.to_str().unwrap()])
+fn read_permutations(path: &Path) -> HashMap<String, Vec<Vec<String>>> {
+    let mut permutations: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return permutations,
+    };
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let shader = words.next().unwrap().to_string();
+        let mut defines: Vec<String> = words.map(String::from).collect();
+        // Sorted by name like ShaderDefines, so the engine finds the file whatever the order
+        defines.sort_by(|a, b| define_name(a).cmp(define_name(b)));
+        if !defines.is_empty() {
+            permutations.entry(shader).or_default().push(defines);
+        }
+    }
+    permutations
+}
+
+fn define_name(define: &str) -> &str {
+    define.split('=').next().unwrap()
+}
+
+// closesthit.rchit with NO_SHADOWS and DEBUG_VIEW=2 goes to closesthit.DEBUG_VIEW=2.NO_SHADOWS.spv
+fn permutation_output(input: &Path, defines: &[String]) -> PathBuf {
+    let stem = input.file_stem().unwrap().to_str().unwrap();
+    let key: String = defines
+        .iter()
+        .map(|define| format!(".{}", define))
+        .collect();
+    input.with_file_name(format!("{}{}.spv", stem, key))
+}
+
+fn newest_source(directory: &Path) -> SystemTime {
+    std::fs::read_dir(directory)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() != Some(OsStr::new("spv")))
+        .filter_map(|path| {
+            path.metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .max()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+fn is_up_to_date(output: &Path, sources_modified: SystemTime) -> bool {
+    match output.metadata().and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified >= sources_modified,
+        Err(_) => false,
+    }
+}
+
+fn compile_shader(input: &Path, output: &Path, defines: &[String]) {
+    let mut command = Command::new("glslc");
+    command.args([input.to_str().unwrap(), "-I", SHADER_DIRECTORY]);
+    for define in defines.iter() {
+        command.arg(format!("-D{}", define));
+    }
+    let output = command
+        .args(["-o", output.to_str().unwrap()])
         .output()
         .expect("Failed to compile shader");
 
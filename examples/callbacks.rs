@@ -0,0 +1,31 @@
+use r2r2::application_manager::ApplicationManagerBuilder;
+use r2r2::input_manager::VirtualKeyCode;
+use r2r2::primitives;
+use vulkan_ray_tracing::glm;
+
+fn main() {
+    let mut app = ApplicationManagerBuilder::new()
+        .with_width(800)
+        .with_height(600)
+        .with_model(primitives::plane(20.0, 20.0))
+        .build();
+
+    app.on_init(|scene| {
+        scene.add_model(primitives::cube(2.0));
+    });
+
+    app.on_update(|time, input, scene| {
+        // Slow motion while the key is held
+        time.set_time_scale(if input.is_key_pressed(VirtualKeyCode::LShift) {
+            0.25
+        } else {
+            1.0
+        });
+        if input.is_key_pressed(VirtualKeyCode::C) {
+            let t = time.time().sin() as f32 * 0.5 + 0.5;
+            scene.set_clear_color(glm::vec4(t, 0.3, 1.0 - t, 1.0));
+        }
+    });
+
+    app.run();
+}
@@ -0,0 +1,12 @@
+use r2r2::application_manager::ApplicationManagerBuilder;
+use r2r2::primitives;
+
+fn main() {
+    let mut app = ApplicationManagerBuilder::new()
+        .with_width(800)
+        .with_height(600)
+        .with_model(primitives::sphere(2.0, 32, 16))
+        .build();
+
+    app.run();
+}
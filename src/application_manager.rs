@@ -1,46 +1,359 @@
 use simplelog::{Config, LevelFilter, SimpleLogger};
 
-use crate::camera_manager::{CameraManager, CameraProperties};
-use crate::input_manager::InputManager;
-use crate::render_manager::RenderManager;
+#[cfg(feature = "audio")]
+use crate::audio_manager::AudioManager;
+use crate::batch_render::{Accumulation, BatchCamera, BatchImage};
+use crate::camera_manager::{CameraManager, CameraProperties, ViewportCamera};
+use crate::event_bus::EventBus;
+use crate::frame_pacer::{FramePacer, FrameStats};
+use crate::input_manager::{InputManager, KeyBinding};
+use crate::light_manager::LightManager;
+use crate::model::{Model, ModelLoadOptions};
+#[cfg(feature = "physics")]
+use crate::physics_manager::PhysicsManager;
+use crate::render_manager::{RenderHandle, RenderManager, SwapchainInfo};
+use crate::scene::{InstanceHandle, Scene};
+use crate::screen_anchors::ScreenAnchors;
+use crate::time_manager::TimeManager;
 use crate::window_manager::WindowManager;
-use std::cell::RefCell;
-use std::path::Path;
-use std::rc::Rc;
-use std::time::Instant;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use vulkan_ray_tracing::ash::vk;
+use vulkan_ray_tracing::bytemuck::Pod;
+use vulkan_ray_tracing::frame_context::FrameContext;
 use vulkan_ray_tracing::glm;
+use vulkan_ray_tracing::instance_culling::CullingStats;
+use vulkan_ray_tracing::render_settings::{Aov, AovSettings, SeedPolicy};
+use vulkan_ray_tracing::renderer_stats::RendererStats;
+use vulkan_ray_tracing::text_overlay::TextLabel;
+use vulkan_ray_tracing::viewport::Viewport;
+use vulkan_ray_tracing::vulkan_bootstrap::errors::VulkanError;
+
+type InitCallback = Box<dyn FnOnce(&mut Scene)>;
+type UpdateCallback = Box<dyn FnMut(&mut TimeManager, &InputManager, &mut Scene)>;
+type FixedUpdateCallback = Box<dyn FnMut(&TimeManager, &InputManager, &mut Scene)>;
+
+// How often the stats HUD is refreshed
+const STATS_HUD_PERIOD: Duration = Duration::from_millis(500);
+// Top left corner of the stats, when they are drawn over the frames
+const STATS_HUD_POSITION: (i32, i32) = (8, 8);
 
 pub struct ApplicationManager {
     window_manager: Option<WindowManager>,
-    input_manager: Rc<RefCell<InputManager>>,
-    camera_manager: Rc<RefCell<CameraManager>>,
+    scene: Scene,
+    on_init: Option<InitCallback>,
+    on_update: Option<UpdateCallback>,
+    on_fixed_update: Option<FixedUpdateCallback>,
+    input_manager: Arc<Mutex<InputManager>>,
+    camera_manager: Arc<Mutex<CameraManager>>,
     render_manager: RenderManager,
-    target_framerate: u32,
+    screen_anchors: Arc<Mutex<ScreenAnchors>>,
+    event_bus: EventBus,
+    time_manager: TimeManager,
+    frame_pacer: FramePacer,
     begin_ticks: Instant,
-    delta_time: f32,
+    title: String,
+    stats_hud: bool,
+    stats_hud_ticks: Instant,
+    // Draws the stats over the frames instead of the title bar
+    text_hud: bool,
+    #[cfg(feature = "audio")]
+    audio_manager: Arc<Mutex<AudioManager>>,
+    #[cfg(feature = "physics")]
+    physics_manager: Arc<Mutex<PhysicsManager>>,
+    // The trace is written when the application is dropped
+    #[cfg(feature = "chrome-trace")]
+    _chrome_trace: Option<tracing_chrome::FlushGuard>,
 }
 
 impl ApplicationManager {
+    // Called once, right before the first frame
+    pub fn on_init<F>(&mut self, callback: F)
+    where
+        F: FnOnce(&mut Scene) + 'static,
+    {
+        self.on_init = Some(Box::new(callback));
+    }
+
+    // Called every frame with the clock, after the inputs are updated and before rendering. The
+    // clock can be paused or slowed down from the callback, the next frames then follow it.
+    pub fn on_update<F>(&mut self, callback: F)
+    where
+        F: FnMut(&mut TimeManager, &InputManager, &mut Scene) + 'static,
+    {
+        self.on_update = Some(Box::new(callback));
+    }
+
+    // Called fixed_steps times per frame after on_update, each simulating fixed_delta_time
+    // seconds of the scaled clock. The transforms set with Scene::set_fixed_transform are blended
+    // between the last two fixed updates, so the instances move smoothly at any framerate.
+    pub fn on_fixed_update<F>(&mut self, callback: F)
+    where
+        F: FnMut(&TimeManager, &InputManager, &mut Scene) + 'static,
+    {
+        self.on_fixed_update = Some(Box::new(callback));
+    }
+
+    // Called every frame to record extra Vulkan commands into the frame, once the traced image is
+    // in the back buffer. The frame context gives the back buffer, its view and the frame index.
+    pub fn with_frame_commands<F>(&mut self, callback: F)
+    where
+        F: FnMut(vk::CommandBuffer, &FrameContext) + 'static,
+    {
+        self.render_manager.with_frame_commands(callback);
+    }
+
     pub fn run(&mut self) {
         let window = self.window_manager.take();
+
+        if let Some(on_init) = self.on_init.take() {
+            on_init(&mut self.scene);
+        }
+
         window
             .expect("Window already running, call run only once!")
             .run(|window, mouse_position, events| {
-                self.input_manager.borrow_mut().update(events);
+                self.frame_pacer.begin_frame();
+                self.input_manager.lock().unwrap().update(
+                    events,
+                    *mouse_position,
+                    window.hidpi_factor(),
+                );
+                if let Some(on_update) = self.on_update.as_mut() {
+                    on_update(
+                        &mut self.time_manager,
+                        &self.input_manager.lock().unwrap(),
+                        &mut self.scene,
+                    );
+                }
+                for _ in 0..self.time_manager.fixed_steps() {
+                    self.scene.begin_fixed_update();
+                    if let Some(on_fixed_update) = self.on_fixed_update.as_mut() {
+                        on_fixed_update(
+                            &self.time_manager,
+                            &self.input_manager.lock().unwrap(),
+                            &mut self.scene,
+                        );
+                    }
+                    #[cfg(feature = "physics")]
+                    self.update_physics();
+                }
+                self.scene
+                    .interpolate_transforms(self.time_manager.render_alpha());
+                // The camera keeps moving in slow motion and while paused
                 self.camera_manager
-                    .borrow_mut()
-                    .update(window, mouse_position, self.delta_time);
+                    .lock()
+                    .unwrap()
+                    .update(window, self.time_manager.unscaled_delta_time());
+                #[cfg(feature = "audio")]
+                self.update_audio();
+                self.render_manager.set_frame_time(
+                    self.time_manager.time() as f32,
+                    self.time_manager.delta_time(),
+                );
                 self.render_manager.render_scene();
+                self.frame_pacer.end_frame();
+                self.render_manager
+                    .update_screen_anchors(&mut self.screen_anchors.lock().unwrap());
                 let end_ticks = Instant::now();
-                self.delta_time = end_ticks.duration_since(self.begin_ticks).as_secs_f32();
-                // If delta time is too big, it probably means that we hit a breakpoint
-                if self.delta_time > 1.0 {
-                    self.delta_time = 1.0 / self.target_framerate as f32;
+                if self.stats_hud
+                    && end_ticks.duration_since(self.stats_hud_ticks) > STATS_HUD_PERIOD
+                {
+                    self.stats_hud_ticks = end_ticks;
+                    if let Some(stats) = self.render_manager.stats() {
+                        let stats = format!("{}, {}", stats, self.frame_pacer.stats());
+                        if self.text_hud {
+                            self.render_manager
+                                .set_text_labels(vec![TextLabel::new(&stats, STATS_HUD_POSITION)]);
+                        } else {
+                            window.set_title(&format!("{} - {}", self.title, stats));
+                        }
+                    }
                 }
-
+                self.time_manager
+                    .advance(end_ticks.duration_since(self.begin_ticks));
                 self.begin_ticks = end_ticks;
             });
     }
+
+    // Renders every camera into output_directory/<name>.png instead of running the window, for
+    // offline previews. Each image averages sample_count frames, with SeedPolicy::PerFrame so that
+    // they differ. The checkerboard rendering is turned off meanwhile, both settings are restored
+    // at the end. The cameras have to fit in the window, see
+    // ApplicationManagerBuilder::with_hidden_window.
+    pub fn render_batch(
+        &mut self,
+        cameras: &[BatchCamera],
+        sample_count: u32,
+        output_directory: &Path,
+    ) -> io::Result<Vec<BatchImage>> {
+        if let Some(on_init) = self.on_init.take() {
+            on_init(&mut self.scene);
+        }
+        std::fs::create_dir_all(output_directory)?;
+
+        let render_error = |err: VulkanError| io::Error::other(format!("Cannot render: {:?}", err));
+        while !self.render_manager.is_ready() {
+            self.render_manager.render_scene();
+            if self.render_manager.is_device_lost() {
+                return Err(io::Error::other("The device was lost"));
+            }
+            if self.render_manager.load_progress() >= 1.0 && self.render_manager.stats().is_none() {
+                return Err(io::Error::other("The scene has no geometry"));
+            }
+        }
+
+        let render_settings = self.render_manager.render_settings();
+        self.render_manager.set_aovs(AovSettings {
+            color: true,
+            ..render_settings.aovs
+        });
+        self.render_manager.set_checkerboard(false);
+        self.render_manager
+            .set_random_seed(render_settings.random_seed, SeedPolicy::PerFrame);
+
+        let swapchain_info = self.render_manager.swapchain_info();
+        let mut images = Vec::with_capacity(cameras.len());
+        for camera in cameras.iter() {
+            if camera.width > swapchain_info.width || camera.height > swapchain_info.height {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} is {}x{}, larger than the window",
+                        camera.name, camera.width, camera.height
+                    ),
+                ));
+            }
+
+            let viewport = Viewport {
+                x: 0,
+                y: 0,
+                width: camera.width,
+                height: camera.height,
+            };
+            self.render_manager
+                .set_viewports(&[(viewport, ViewportCamera::Fixed(Box::new(camera.camera())))]);
+
+            let begin_ticks = Instant::now();
+            let mut accumulation = Accumulation::new(camera.width, camera.height);
+            for _ in 0..sample_count.max(1) {
+                self.render_manager.render_scene();
+                if self.render_manager.is_device_lost() {
+                    return Err(io::Error::other("The device was lost"));
+                }
+                let texels = self
+                    .render_manager
+                    .read_aov::<[u16; 4]>(Aov::Color, viewport)
+                    .map_err(render_error)?;
+                accumulation.add(&texels);
+            }
+            let render_time = begin_ticks.elapsed();
+
+            let path = output_directory.join(format!("{}.png", camera.name));
+            accumulation.save(&path)?;
+            log::info!(
+                "Rendered {} in {:.1} ms",
+                path.display(),
+                render_time.as_secs_f32() * 1000.0
+            );
+            images.push(BatchImage {
+                name: camera.name.clone(),
+                path,
+                render_time,
+            });
+        }
+
+        self.render_manager.set_viewports(&[]);
+        self.render_manager.set_aovs(render_settings.aovs);
+        self.render_manager
+            .set_checkerboard(render_settings.checkerboard);
+        self.render_manager
+            .set_random_seed(render_settings.random_seed, render_settings.seed_policy);
+        Ok(images)
+    }
+
+    // The listener is the interactive camera, the voices follow their instances
+    #[cfg(feature = "audio")]
+    fn update_audio(&self) {
+        let camera = *self.camera_manager.lock().unwrap().get_camera();
+        self.audio_manager
+            .lock()
+            .unwrap()
+            .update(&camera, |handle| {
+                self.scene
+                    .get_instance(handle)
+                    .map(|instance| instance.transform)
+            });
+    }
+
+    // One step of the simulation per fixed update, so pausing the clock pauses the bodies too.
+    // The bodies of the removed instances are removed with them. All the bodies are moved at once,
+    // the renderer then rebuilds its top level structure once.
+    #[cfg(feature = "physics")]
+    fn update_physics(&mut self) {
+        let mut physics_manager = self.physics_manager.lock().unwrap();
+        let transforms = physics_manager.step(self.time_manager.fixed_delta_time());
+        for handle in self.scene.set_fixed_transforms(&transforms) {
+            physics_manager.remove_body(handle);
+        }
+    }
+
+    pub fn load_progress(&self) -> f32 {
+        self.render_manager.load_progress()
+    }
+
+    pub fn swapchain_info(&self) -> SwapchainInfo {
+        self.render_manager.swapchain_info()
+    }
+
+    // Triangles, memory and GPU times of the renderer, None until a model is set
+    pub fn stats(&self) -> Option<RendererStats> {
+        self.render_manager.stats()
+    }
+
+    // Frame times and latency seen by the application loop
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_pacer.stats()
+    }
+
+    // Instances seen by the cameras, once the culling is enabled on the scene
+    pub fn culling_stats(&self) -> Option<CullingStats> {
+        self.render_manager.culling_stats()
+    }
+
+    pub fn render_handle(&self) -> RenderHandle {
+        self.render_manager.handle()
+    }
+
+    // Only available when the application is built with the ID buffer
+    pub fn read_id_buffer(
+        &self,
+        rect: Viewport,
+    ) -> Result<Vec<Option<(InstanceHandle, u32)>>, VulkanError> {
+        self.render_manager.read_id_buffer(rect)
+    }
+
+    // For external compositing, the AOVs other than the depth have to be enabled in the render
+    // settings first
+    pub fn read_aov<T: Pod>(&self, aov: Aov, rect: Viewport) -> Result<Vec<T>, VulkanError> {
+        self.render_manager.read_aov(aov, rect)
+    }
+
+    // Subscribe to learn about resizes, loaded scenes, reloaded assets and lost devices
+    pub fn event_bus(&self) -> EventBus {
+        self.event_bus.clone()
+    }
+
+    // Rebinds an action read with InputManager::is_action_pressed, including the movement of the
+    // interactive camera
+    pub fn bind_action(&mut self, action: &str, bindings: &[KeyBinding]) {
+        self.input_manager
+            .lock()
+            .unwrap()
+            .bind_action(action, bindings);
+    }
 }
 
 pub struct ApplicationManagerBuilder {
@@ -48,9 +361,20 @@ pub struct ApplicationManagerBuilder {
     width: u32,
     height: u32,
     scene: String,
+    load_options: ModelLoadOptions,
+    model: Option<Model>,
     clear_color: glm::Vec4,
     target_framerate: u32,
+    frame_limiter: bool,
     camera_properties: CameraProperties,
+    hot_reload: bool,
+    id_buffer: bool,
+    stats_hud: bool,
+    hud_font: Option<(PathBuf, u32, u32, char)>,
+    asset_cache: Option<PathBuf>,
+    hidden_window: bool,
+    #[cfg(feature = "chrome-trace")]
+    chrome_trace: Option<PathBuf>,
 }
 
 impl Default for ApplicationManagerBuilder {
@@ -60,9 +384,20 @@ impl Default for ApplicationManagerBuilder {
             width: 800,
             height: 600,
             scene: String::new(),
+            load_options: ModelLoadOptions::default(),
+            model: None,
             clear_color: glm::vec4(0.0, 0.0, 0.0, 1.0),
             target_framerate: 60,
+            frame_limiter: false,
             camera_properties: CameraProperties::default(),
+            hot_reload: false,
+            id_buffer: false,
+            stats_hud: false,
+            hud_font: None,
+            asset_cache: None,
+            hidden_window: false,
+            #[cfg(feature = "chrome-trace")]
+            chrome_trace: None,
         }
     }
 }
@@ -97,57 +432,196 @@ impl ApplicationManagerBuilder {
         self
     }
 
+    // Applied to the scene file
+    pub fn with_load_options(mut self, load_options: ModelLoadOptions) -> Self {
+        self.load_options = load_options;
+        self
+    }
+
+    pub fn with_model(mut self, model: Model) -> Self {
+        self.model = Some(model);
+        self
+    }
+
     pub fn with_target_framerate(mut self, target_framerate: u32) -> Self {
         self.target_framerate = target_framerate;
         self
     }
 
+    // Holds the frames back to the target framerate, on top of the vsync of the swapchain. The
+    // end of each wait is spun, so the frames start within a few microseconds of their due time.
+    pub fn with_frame_limiter(mut self, frame_limiter: bool) -> Self {
+        self.frame_limiter = frame_limiter;
+        self
+    }
+
     pub fn with_camera(mut self, camera_properties: CameraProperties) -> Self {
         self.camera_properties = camera_properties;
         self
     }
 
+    pub fn with_hot_reload(mut self, hot_reload: bool) -> Self {
+        self.hot_reload = hot_reload;
+        self
+    }
+
+    // Writes the instance and primitive of every pixel to a buffer that can be read back
+    pub fn with_id_buffer(mut self, id_buffer: bool) -> Self {
+        self.id_buffer = id_buffer;
+        self
+    }
+
+    // Shows the renderer stats in the title bar, or over the frames once a HUD font is given
+    pub fn with_stats_hud(mut self, stats_hud: bool) -> Self {
+        self.stats_hud = stats_hud;
+        self
+    }
+
+    // Bitmap font of the stats HUD, a sheet of columns by rows glyphs from first_char on. The
+    // stats then replace the text labels of the scene.
+    pub fn with_hud_font(mut self, path: &str, columns: u32, rows: u32, first_char: char) -> Self {
+        self.hud_font = Some((PathBuf::from(path), columns, rows, first_char));
+        self
+    }
+
+    // Processed models are stored in the directory, later runs reuse them while the sources and
+    // load options are unchanged
+    pub fn with_asset_cache(mut self, directory: &str) -> Self {
+        self.asset_cache = Some(PathBuf::from(directory));
+        self
+    }
+
+    // The window is never shown, for ApplicationManager::render_batch. It still needs a display,
+    // the swapchain is created for it.
+    pub fn with_hidden_window(mut self, hidden_window: bool) -> Self {
+        self.hidden_window = hidden_window;
+        self
+    }
+
+    // Records the tracing spans of the engine, loads, uploads, acceleration structure builds and
+    // frame phases, into a file that chrome://tracing or Perfetto can open
+    #[cfg(feature = "chrome-trace")]
+    pub fn with_chrome_trace(mut self, path: &str) -> Self {
+        self.chrome_trace = Some(PathBuf::from(path));
+        self
+    }
+
     pub fn build(self) -> ApplicationManager {
         SimpleLogger::init(LevelFilter::Trace, Config::default())
             .expect("Cannot create the logger!");
 
-        let window = WindowManager::new(&self.title, self.width, self.height)
-            .expect("Cannot create a window!");
+        #[cfg(feature = "chrome-trace")]
+        let chrome_trace = self.chrome_trace.as_ref().map(|path| {
+            use tracing_subscriber::prelude::*;
+            let (chrome_layer, guard) =
+                tracing_chrome::ChromeLayerBuilder::new().file(path).build();
+            tracing_subscriber::registry().with(chrome_layer).init();
+            guard
+        });
+
+        let event_bus = EventBus::new();
+
+        let window = WindowManager::new(
+            &self.title,
+            self.width,
+            self.height,
+            !self.hidden_window,
+            event_bus.clone(),
+        )
+        .expect("Cannot create a window!");
 
-        let input_manager = Rc::new(RefCell::new(InputManager::new()));
+        let input_manager = Arc::new(Mutex::new(InputManager::new()));
 
-        let camera_manager = Rc::new(RefCell::new(CameraManager::new(
-            Rc::clone(&input_manager),
+        let camera_manager = Arc::new(Mutex::new(CameraManager::new(
+            Arc::clone(&input_manager),
+            &event_bus,
             self.width as f32,
             self.height as f32,
             self.camera_properties,
         )));
 
+        let light_manager = Arc::new(Mutex::new(LightManager::default()));
+
         let size = window.size();
         let mut render_manager = RenderManager::new(
             true,
             window.hwnd(),
             size.width,
             size.height,
-            Rc::clone(&camera_manager),
+            Arc::clone(&camera_manager),
+            Arc::clone(&light_manager),
+            event_bus.clone(),
         );
 
         render_manager.set_clear_color(self.clear_color);
+        if self.hot_reload {
+            render_manager.enable_hot_reload(Duration::from_millis(500));
+        }
+        if self.id_buffer {
+            render_manager.enable_id_buffer();
+        }
+        if let Some(asset_cache) = self.asset_cache.as_ref() {
+            render_manager.enable_asset_cache(asset_cache);
+        }
+        if let Some((path, columns, rows, first_char)) = self.hud_font.as_ref() {
+            render_manager
+                .handle()
+                .load_font_atlas(path, *columns, *rows, *first_char);
+        }
 
-        let scene = Path::new(&self.scene);
-        if !scene.exists() {
-            panic!("No scene loaded");
+        if let Some(model) = self.model {
+            render_manager.set_model(model);
+        } else {
+            let scene = Path::new(&self.scene);
+            if !scene.exists() {
+                panic!("No scene loaded");
+            }
+            render_manager.load_model(scene, self.load_options);
         }
-        render_manager.load_model(scene);
+
+        let screen_anchors = Arc::new(Mutex::new(ScreenAnchors::new()));
+        #[cfg(feature = "audio")]
+        let audio_manager = Arc::new(Mutex::new(AudioManager::new()));
+        #[cfg(feature = "physics")]
+        let physics_manager = Arc::new(Mutex::new(PhysicsManager::new()));
 
         ApplicationManager {
             window_manager: Some(window),
+            scene: Scene::new(
+                render_manager.handle(),
+                Arc::clone(&camera_manager),
+                light_manager,
+                Arc::clone(&screen_anchors),
+                #[cfg(feature = "audio")]
+                Arc::clone(&audio_manager),
+                #[cfg(feature = "physics")]
+                Arc::clone(&physics_manager),
+            ),
+            on_init: None,
+            on_update: None,
+            on_fixed_update: None,
             input_manager,
             camera_manager,
             render_manager,
-            target_framerate: self.target_framerate,
+            screen_anchors,
+            event_bus,
+            time_manager: TimeManager::new(self.target_framerate),
+            frame_pacer: FramePacer::new(if self.frame_limiter {
+                Some(self.target_framerate)
+            } else {
+                None
+            }),
             begin_ticks: Instant::now(),
-            delta_time: 1.0 / self.target_framerate as f32,
+            title: self.title,
+            stats_hud: self.stats_hud,
+            stats_hud_ticks: Instant::now(),
+            text_hud: self.hud_font.is_some(),
+            #[cfg(feature = "audio")]
+            audio_manager,
+            #[cfg(feature = "physics")]
+            physics_manager,
+            #[cfg(feature = "chrome-trace")]
+            _chrome_trace: chrome_trace,
         }
     }
 }
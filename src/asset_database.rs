@@ -0,0 +1,496 @@
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use vulkan_ray_tracing::bytemuck::{self, Pod};
+use vulkan_ray_tracing::geometry_instance::ImageBuffer;
+use vulkan_ray_tracing::glm;
+use vulkan_ray_tracing::texture::TextureCompression;
+
+use crate::mesh_diagnostics::MeshDiagnostics;
+use crate::model::{Model, ModelLoadOptions, UpAxis};
+
+// Bumped whenever the processing of the models or the layout of the cache files changes
+const CACHE_VERSION: u32 = 2;
+const MAGIC: &[u8; 4] = b"R2AC";
+
+// FNV-1a, unlike the std hashers it gives the same value on every run and with every compiler
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Identifies a source file across runs, from its path as the scene refers to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AssetGuid(pub u64);
+
+impl AssetGuid {
+    pub fn from_path(path: &Path) -> Self {
+        let mut hasher = StableHasher::new();
+        hasher.write(path.to_string_lossy().replace('\\', "/").as_bytes());
+        AssetGuid(hasher.finish())
+    }
+}
+
+// What a previous run stored for a model whose sources and options did not change since
+pub struct CachedModel {
+    pub content_hash: u64,
+    pub group_count: usize,
+    pub dependencies: Vec<PathBuf>,
+}
+
+// Processed models kept in a local directory, in the layout of this machine. Every model has a
+// manifest with the hash of its sources, and one file per model the loader streams.
+#[derive(Clone)]
+pub struct AssetDatabase {
+    directory: PathBuf,
+}
+
+impl AssetDatabase {
+    pub fn new(directory: &Path) -> Self {
+        if let Err(err) = fs::create_dir_all(directory) {
+            log::warn!(
+                "Cannot create the asset cache {}: {}",
+                directory.display(),
+                err
+            );
+        }
+        AssetDatabase {
+            directory: directory.to_path_buf(),
+        }
+    }
+
+    // Hash of the cache version, the load options and the content of every dependency
+    pub fn content_hash(dependencies: &[PathBuf], options: &ModelLoadOptions) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write(&CACHE_VERSION.to_le_bytes());
+        hasher.write(&options.scale.to_bits().to_le_bytes());
+        hasher.write(&[
+            (options.up_axis == UpAxis::Z) as u8,
+            options.weld_vertices as u8,
+            options.optimize_mesh as u8,
+            options.texture_quality as u8,
+        ]);
+        hasher.write(&options.crease_angle.to_bits().to_le_bytes());
+
+        for dependency in dependencies.iter() {
+            hasher.write(dependency.to_string_lossy().as_bytes());
+            // A missing file hashes differently from an empty one
+            match fs::read(dependency) {
+                Ok(content) => {
+                    hasher.write(&(content.len() as u64).to_le_bytes());
+                    hasher.write(&content);
+                }
+                Err(_) => hasher.write(&[0xff]),
+            }
+        }
+
+        hasher.finish()
+    }
+
+    fn manifest_path(&self, guid: AssetGuid) -> PathBuf {
+        self.directory.join(format!("{:016x}.manifest", guid.0))
+    }
+
+    fn model_path(&self, guid: AssetGuid, group: usize) -> PathBuf {
+        self.directory
+            .join(format!("{:016x}_{}.model", guid.0, group))
+    }
+
+    // None when there is no manifest or a dependency changed since it was written
+    pub fn find_model(&self, guid: AssetGuid, options: &ModelLoadOptions) -> Option<CachedModel> {
+        let content = fs::read(self.manifest_path(guid)).ok()?;
+        let mut reader = CacheReader::new(&content)?;
+        let content_hash = reader.u64()?;
+        let group_count = reader.u64()? as usize;
+        let dependency_count = reader.u64()?;
+        let mut dependencies = vec![];
+        for _ in 0..dependency_count {
+            dependencies.push(PathBuf::from(reader.string()?));
+        }
+
+        if dependencies.is_empty() || Self::content_hash(&dependencies, options) != content_hash {
+            return None;
+        }
+
+        Some(CachedModel {
+            content_hash,
+            group_count,
+            dependencies,
+        })
+    }
+
+    // Written once every model is stored, so an interrupted load is processed again
+    pub fn write_manifest(
+        &self,
+        guid: AssetGuid,
+        content_hash: u64,
+        group_count: usize,
+        dependencies: &[PathBuf],
+    ) {
+        let mut writer = CacheWriter::new();
+        writer.u64(content_hash);
+        writer.u64(group_count as u64);
+        writer.u64(dependencies.len() as u64);
+        for dependency in dependencies.iter() {
+            writer.string(&dependency.to_string_lossy());
+        }
+        writer.save(&self.manifest_path(guid));
+    }
+
+    pub fn read_model(&self, guid: AssetGuid, content_hash: u64, group: usize) -> Option<Model> {
+        let content = fs::read(self.model_path(guid, group)).ok()?;
+        let mut reader = CacheReader::new(&content)?;
+        if reader.u64()? != content_hash {
+            return None;
+        }
+
+        let vertices = reader.pod_vec()?;
+        let indices = reader.pod_vec()?;
+        let materials = reader.pod_vec()?;
+
+        let texture_count = reader.u64()?;
+        let mut textures = vec![];
+        for _ in 0..texture_count {
+            textures.push(ImageBuffer {
+                tex_width: reader.u32()?,
+                tex_height: reader.u32()?,
+                tex_channels: reader.u32()?,
+                srgb: reader.u8()? != 0,
+                compression: compression_from_id(reader.u8()?)?,
+                pixels: reader.pod_vec()?,
+            });
+        }
+
+        let diagnostics_count = reader.u64()?;
+        let mut diagnostics = vec![];
+        for _ in 0..diagnostics_count {
+            diagnostics.push(MeshDiagnostics {
+                name: reader.string()?,
+                triangle_count: reader.u64()? as usize,
+                degenerate_triangles: reader.u64()? as usize,
+                duplicated_vertices: reader.u64()? as usize,
+                missing_normals: reader.u8()? != 0,
+                missing_tex_coords: reader.u8()? != 0,
+                boundary_edges: reader.u64()? as usize,
+                non_manifold_edges: reader.u64()? as usize,
+                aabb_min: reader.vec3()?,
+                aabb_max: reader.vec3()?,
+            });
+        }
+
+        Some(Model {
+            vertices,
+            indices,
+            materials,
+            textures,
+            diagnostics,
+        })
+    }
+
+    pub fn write_model(&self, guid: AssetGuid, content_hash: u64, group: usize, model: &Model) {
+        let mut writer = CacheWriter::new();
+        writer.u64(content_hash);
+        writer.pod_slice(&model.vertices);
+        writer.pod_slice(&model.indices);
+        writer.pod_slice(&model.materials);
+
+        writer.u64(model.textures.len() as u64);
+        for texture in model.textures.iter() {
+            writer.u32(texture.tex_width);
+            writer.u32(texture.tex_height);
+            writer.u32(texture.tex_channels);
+            writer.u8(texture.srgb as u8);
+            writer.u8(compression_id(texture.compression));
+            writer.pod_slice(&texture.pixels);
+        }
+
+        writer.u64(model.diagnostics.len() as u64);
+        for diagnostics in model.diagnostics.iter() {
+            writer.string(&diagnostics.name);
+            writer.u64(diagnostics.triangle_count as u64);
+            writer.u64(diagnostics.degenerate_triangles as u64);
+            writer.u64(diagnostics.duplicated_vertices as u64);
+            writer.u8(diagnostics.missing_normals as u8);
+            writer.u8(diagnostics.missing_tex_coords as u8);
+            writer.u64(diagnostics.boundary_edges as u64);
+            writer.u64(diagnostics.non_manifold_edges as u64);
+            writer.vec3(&diagnostics.aabb_min);
+            writer.vec3(&diagnostics.aabb_max);
+        }
+
+        writer.save(&self.model_path(guid, group));
+    }
+}
+
+fn compression_id(compression: Option<TextureCompression>) -> u8 {
+    match compression {
+        None => 0,
+        Some(TextureCompression::Bc1) => 1,
+        Some(TextureCompression::Bc4) => 2,
+        Some(TextureCompression::Bc7) => 3,
+    }
+}
+
+// None for an unknown id, Some(None) for uncompressed textures
+fn compression_from_id(id: u8) -> Option<Option<TextureCompression>> {
+    match id {
+        0 => Some(None),
+        1 => Some(Some(TextureCompression::Bc1)),
+        2 => Some(Some(TextureCompression::Bc4)),
+        3 => Some(Some(TextureCompression::Bc7)),
+        _ => None,
+    }
+}
+
+struct CacheWriter {
+    bytes: Vec<u8>,
+}
+
+impl CacheWriter {
+    fn new() -> Self {
+        let mut writer = CacheWriter { bytes: vec![] };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u32(CACHE_VERSION);
+        writer
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn vec3(&mut self, value: &glm::Vec3) {
+        for &component in value.iter() {
+            self.u32(component.to_bits());
+        }
+    }
+
+    fn string(&mut self, value: &str) {
+        self.pod_slice(value.as_bytes());
+    }
+
+    // Count followed by the raw values
+    fn pod_slice<T: Pod>(&mut self, values: &[T]) {
+        self.u64(values.len() as u64);
+        self.bytes.extend_from_slice(bytemuck::cast_slice(values));
+    }
+
+    // The cache only speeds up the next run, failing to write it is not an error
+    fn save(&self, path: &Path) {
+        if let Err(err) = fs::write(path, &self.bytes) {
+            log::warn!("Cannot write the asset cache {}: {}", path.display(), err);
+        }
+    }
+}
+
+// Every read returns None past the end, so a truncated file is a cache miss
+struct CacheReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> CacheReader<'a> {
+    // None for files of another format or version
+    fn new(bytes: &'a [u8]) -> Option<Self> {
+        let mut reader = CacheReader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC || reader.u32()? != CACHE_VERSION {
+            return None;
+        }
+        Some(reader)
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    fn vec3(&mut self) -> Option<glm::Vec3> {
+        Some(glm::vec3(
+            f32::from_bits(self.u32()?),
+            f32::from_bits(self.u32()?),
+            f32::from_bits(self.u32()?),
+        ))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.pod_vec()?).ok()
+    }
+
+    // Copied out, the file bytes are not aligned for the values
+    fn pod_vec<T: Pod>(&mut self) -> Option<Vec<T>> {
+        let count = self.u64()? as usize;
+        let bytes = self.take(count.checked_mul(mem::size_of::<T>())?)?;
+        let mut values = vec![T::zeroed(); count];
+        bytemuck::cast_slice_mut::<T, u8>(&mut values).copy_from_slice(bytes);
+        Some(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // A directory per test, the tests run in parallel
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("r2r2_{}_{}", name, process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn guid_is_stable_across_separators() {
+        // FNV-1a of "a"
+        assert_eq!(
+            AssetGuid::from_path(Path::new("a")).0,
+            0xaf63_dc4c_8601_ec8c
+        );
+        assert_eq!(
+            AssetGuid::from_path(Path::new("models\\sponza.obj")),
+            AssetGuid::from_path(Path::new("models/sponza.obj"))
+        );
+        assert_ne!(
+            AssetGuid::from_path(Path::new("models/sponza.obj")),
+            AssetGuid::from_path(Path::new("models/sponza.mtl"))
+        );
+    }
+
+    #[test]
+    fn content_hash_is_stable_and_follows_edits() {
+        let directory = temp_directory("content_hash");
+        let source = directory.join("model.obj");
+        fs::write(&source, "v 0 0 0\n").unwrap();
+        let dependencies = vec![source.clone()];
+        let options = ModelLoadOptions::default();
+
+        let hash = AssetDatabase::content_hash(&dependencies, &options);
+        assert_eq!(AssetDatabase::content_hash(&dependencies, &options), hash);
+
+        fs::write(&source, "v 0 0 1\n").unwrap();
+        let edited = AssetDatabase::content_hash(&dependencies, &options);
+        assert_ne!(edited, hash);
+
+        let scaled = ModelLoadOptions {
+            scale: 2.0,
+            ..options
+        };
+        assert_ne!(AssetDatabase::content_hash(&dependencies, &scaled), edited);
+
+        fs::write(&source, "").unwrap();
+        let empty = AssetDatabase::content_hash(&dependencies, &options);
+        fs::remove_dir_all(&directory).unwrap();
+        assert_ne!(AssetDatabase::content_hash(&dependencies, &options), empty);
+    }
+
+    #[test]
+    fn manifest_is_a_miss_once_a_dependency_changes() {
+        let directory = temp_directory("manifest");
+        let source = directory.join("model.obj");
+        fs::write(&source, "v 0 0 0\n").unwrap();
+        let dependencies = vec![source.clone()];
+        let options = ModelLoadOptions::default();
+        let database = AssetDatabase::new(&directory.join("cache"));
+        let guid = AssetGuid::from_path(&source);
+
+        assert!(database.find_model(guid, &options).is_none());
+        let hash = AssetDatabase::content_hash(&dependencies, &options);
+        database.write_manifest(guid, hash, 3, &dependencies);
+        let cached = database.find_model(guid, &options).unwrap();
+        assert_eq!(cached.content_hash, hash);
+        assert_eq!(cached.group_count, 3);
+        assert_eq!(cached.dependencies, dependencies);
+
+        fs::write(&source, "v 1 0 0\n").unwrap();
+        let found = database.find_model(guid, &options);
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(found.is_none());
+    }
+
+    #[test]
+    fn stored_model_reads_back() {
+        let directory = temp_directory("model");
+        let database = AssetDatabase::new(&directory);
+        let guid = AssetGuid::from_path(Path::new("model.obj"));
+        let model = Model {
+            vertices: vec![],
+            indices: vec![0, 1, 2],
+            materials: vec![],
+            textures: vec![ImageBuffer {
+                pixels: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                tex_width: 4,
+                tex_height: 4,
+                tex_channels: 4,
+                srgb: true,
+                compression: Some(TextureCompression::Bc1),
+            }],
+            diagnostics: vec![],
+        };
+
+        database.write_model(guid, 7, 0, &model);
+        let read = database.read_model(guid, 7, 0).unwrap();
+        let stale = database.read_model(guid, 8, 0);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(stale.is_none());
+        assert_eq!(read.indices, model.indices);
+        let texture = &read.textures[0];
+        assert_eq!(texture.pixels, model.textures[0].pixels);
+        assert_eq!((texture.tex_width, texture.tex_height), (4, 4));
+        assert!(texture.srgb);
+        assert_eq!(texture.compression, Some(TextureCompression::Bc1));
+    }
+
+    #[test]
+    fn truncated_file_is_a_miss() {
+        let mut writer = CacheWriter::new();
+        writer.u64(42);
+        let mut reader = CacheReader::new(&writer.bytes).unwrap();
+        assert_eq!(reader.u64(), Some(42));
+        assert_eq!(reader.u8(), None);
+
+        assert!(CacheReader::new(&writer.bytes[..6]).is_none());
+        assert!(CacheReader::new(b"XXXX\x02\x00\x00\x00").is_none());
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+struct WatchedFile {
+    modified: Option<SystemTime>,
+    source: PathBuf,
+}
+
+// Polls the modification time of the files a model was loaded from
+pub struct AssetWatcher {
+    files: HashMap<PathBuf, WatchedFile>,
+    interval: Duration,
+    last_poll: Instant,
+}
+
+impl AssetWatcher {
+    pub fn new(interval: Duration) -> Self {
+        AssetWatcher {
+            files: HashMap::new(),
+            interval,
+            last_poll: Instant::now(),
+        }
+    }
+
+    // A change to any of the dependencies reports the source file
+    pub fn watch(&mut self, source: &Path, dependencies: &[PathBuf]) {
+        for dependency in dependencies.iter() {
+            self.files.insert(
+                dependency.clone(),
+                WatchedFile {
+                    modified: modified_time(dependency),
+                    source: source.to_path_buf(),
+                },
+            );
+        }
+    }
+
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < self.interval {
+            return vec![];
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = vec![];
+        for (path, file) in self.files.iter_mut() {
+            let modified = modified_time(path);
+            if modified != file.modified {
+                file.modified = modified;
+                if !changed.contains(&file.source) {
+                    changed.push(file.source.clone());
+                }
+            }
+        }
+        changed
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
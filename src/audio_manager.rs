@@ -0,0 +1,232 @@
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Cursor, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use rodio::{Decoder, Device, Sample, Sink, Source, SpatialSink};
+use vulkan_ray_tracing::glm;
+
+use crate::camera_manager::Camera;
+use crate::handle::{Arena, Handle};
+use crate::scene::InstanceHandle;
+
+// Distance between the ears of the listener, in world units
+const EAR_DISTANCE: f32 = 0.2;
+
+// Encoded file kept in memory, every voice decodes its own copy
+pub struct Sound {
+    data: SoundData,
+}
+
+#[derive(Clone)]
+struct SoundData(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SoundData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// Where a voice is heard from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Emitter {
+    // Same in both ears wherever the camera is, for music and interface sounds
+    Ambient,
+    Position(glm::Vec3),
+    // Follows the translation of the instance, the voice stops once the instance is removed
+    Instance(InstanceHandle),
+}
+
+enum Output {
+    Ambient(Sink),
+    Spatial(SpatialSink),
+}
+
+pub struct Voice {
+    output: Output,
+    emitter: Emitter,
+}
+
+pub type SoundHandle = Handle<Sound>;
+pub type VoiceHandle = Handle<Voice>;
+
+// Sounds played from the scene, heard by a listener that follows the camera. The voices are
+// moved with their instances once per frame by the application. Without an output device the
+// sounds still load but nothing plays.
+pub struct AudioManager {
+    device: Option<Device>,
+    sounds: Arena<Sound>,
+    voices: Arena<Voice>,
+    left_ear: [f32; 3],
+    right_ear: [f32; 3],
+}
+
+impl Default for AudioManager {
+    fn default() -> Self {
+        let device = rodio::default_output_device();
+        if device.is_none() {
+            log::warn!("No audio output device, the sounds are muted");
+        }
+
+        AudioManager {
+            device,
+            sounds: Arena::new(),
+            voices: Arena::new(),
+            left_ear: [-EAR_DISTANCE * 0.5, 0.0, 0.0],
+            right_ear: [EAR_DISTANCE * 0.5, 0.0, 0.0],
+        }
+    }
+}
+
+impl AudioManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // WAV, Vorbis or FLAC, the format is checked now rather than when the sound is played
+    pub fn load_sound(&mut self, path: &Path) -> io::Result<SoundHandle> {
+        let data = SoundData(Arc::new(fs::read(path)?));
+        Decoder::new(Cursor::new(data.clone()))
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        Ok(self.sounds.insert(Sound { data }))
+    }
+
+    // Voices that are playing it keep their copy
+    pub fn remove_sound(&mut self, handle: SoundHandle) -> bool {
+        self.sounds.remove(handle).is_some()
+    }
+
+    // None if the sound was removed or there is no output device. Looping voices play until
+    // they are stopped.
+    pub fn play(
+        &mut self,
+        sound: SoundHandle,
+        emitter: Emitter,
+        looping: bool,
+    ) -> Option<VoiceHandle> {
+        let device = self.device.as_ref()?;
+        let data = self.sounds.get(sound)?.data.clone();
+        let source = match Decoder::new(Cursor::new(data)) {
+            Ok(source) => source,
+            Err(err) => {
+                log::error!("Cannot decode the sound: {}", err);
+                return None;
+            }
+        };
+
+        let output = match emitter {
+            Emitter::Ambient => Output::Ambient(Sink::new(device)),
+            _ => Output::Spatial(SpatialSink::new(
+                device,
+                [0.0; 3],
+                self.left_ear,
+                self.right_ear,
+            )),
+        };
+        if looping {
+            append(&output, source.repeat_infinite());
+        } else {
+            append(&output, source);
+        }
+
+        Some(self.voices.insert(Voice { output, emitter }))
+    }
+
+    // Returns false if the voice already ended
+    pub fn stop(&mut self, voice: VoiceHandle) -> bool {
+        match self.voices.remove(voice) {
+            Some(voice) => {
+                match voice.output {
+                    Output::Ambient(sink) => sink.stop(),
+                    Output::Spatial(sink) => sink.stop(),
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 1.0 plays the sound as it is
+    pub fn set_volume(&mut self, voice: VoiceHandle, volume: f32) -> bool {
+        match self.voices.get(voice).map(|voice| &voice.output) {
+            Some(Output::Ambient(sink)) => sink.set_volume(volume),
+            Some(Output::Spatial(sink)) => sink.set_volume(volume),
+            None => return false,
+        }
+        true
+    }
+
+    pub fn set_emitter(&mut self, voice: VoiceHandle, emitter: Emitter) -> bool {
+        match self.voices.get_mut(voice) {
+            // An ambient voice has no position to move
+            Some(voice) if matches!(voice.output, Output::Spatial(_)) => {
+                voice.emitter = emitter;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_playing(&self, voice: VoiceHandle) -> bool {
+        self.voices.contains(voice)
+    }
+
+    // Moves the listener to the camera and the voices to their emitters, the voices that ended
+    // or lost their instance are released
+    pub(crate) fn update<F>(&mut self, listener: &Camera, instance_transform: F)
+    where
+        F: Fn(InstanceHandle) -> Option<glm::Mat4>,
+    {
+        let view_inverse = listener.get_view_inverse();
+        let position = (view_inverse * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz();
+        let right = glm::normalize(&(view_inverse * glm::vec4(1.0, 0.0, 0.0, 0.0)).xyz())
+            * EAR_DISTANCE
+            * 0.5;
+        self.left_ear = (position - right).into();
+        self.right_ear = (position + right).into();
+
+        let mut ended = vec![];
+        for (handle, voice) in self.voices.iter_with_handles() {
+            let sink = match &voice.output {
+                Output::Ambient(sink) => {
+                    if sink.empty() {
+                        ended.push(handle);
+                    }
+                    continue;
+                }
+                Output::Spatial(sink) => sink,
+            };
+
+            let emitter_position = match voice.emitter {
+                Emitter::Position(position) => Some(position),
+                Emitter::Instance(instance) => instance_transform(instance)
+                    .map(|transform| (transform * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz()),
+                Emitter::Ambient => Some(position),
+            };
+            match emitter_position {
+                Some(emitter_position) if !sink.empty() => {
+                    sink.set_emitter_position(emitter_position.into());
+                    sink.set_left_ear_position(self.left_ear);
+                    sink.set_right_ear_position(self.right_ear);
+                }
+                _ => ended.push(handle),
+            }
+        }
+
+        for handle in ended {
+            self.stop(handle);
+        }
+    }
+}
+
+fn append<S>(output: &Output, source: S)
+where
+    S: Source + Send + 'static,
+    S::Item: Sample + Send + Debug,
+{
+    match output {
+        Output::Ambient(sink) => sink.append(source),
+        Output::Spatial(sink) => sink.append(source),
+    }
+}
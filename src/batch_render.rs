@@ -0,0 +1,190 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use image::ColorType;
+use vulkan_ray_tracing::glm;
+
+use crate::camera_manager::{Camera, CameraProperties, CameraType};
+
+// One image of a batch, seen from the position of the properties towards the target
+pub struct BatchCamera {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub target: glm::Vec3,
+    pub properties: CameraProperties,
+}
+
+impl BatchCamera {
+    pub fn new(name: &str) -> Self {
+        BatchCamera {
+            name: name.to_string(),
+            width: 800,
+            height: 600,
+            target: glm::vec3(0.0, 0.0, 0.0),
+            properties: CameraProperties::default(),
+        }
+    }
+
+    pub(crate) fn camera(&self) -> Camera {
+        Camera::look_at(
+            self.target,
+            &self.properties,
+            self.width as f32,
+            self.height as f32,
+        )
+    }
+}
+
+// Written by ApplicationManager::render_batch, one per camera in the same order
+#[derive(Clone, Debug)]
+pub struct BatchImage {
+    pub name: String,
+    pub path: PathBuf,
+    // From the first frame of the camera to the last readback, without writing the file
+    pub render_time: Duration,
+}
+
+// Text files with one section per camera, its name between brackets followed by its settings:
+//
+// [front]
+// width = 1280
+// height = 720
+// position = 0 2 10
+// target = 0 0 0
+//
+// The type is perspective or orthographic, ortho_size, near and far are the ones of
+// CameraProperties. Settings left out keep their default value, lines starting with # are
+// comments.
+pub fn load_batch_cameras(path: &Path) -> io::Result<Vec<BatchCamera>> {
+    let content = fs::read_to_string(path)?;
+    let mut cameras: Vec<BatchCamera> = vec![];
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), index + 1, message),
+            )
+        };
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            cameras.push(BatchCamera::new(name.trim()));
+            continue;
+        }
+
+        let camera = cameras
+            .last_mut()
+            .ok_or_else(|| invalid("setting outside of a camera"))?;
+        let (key, value) = match line.find('=') {
+            Some(position) => (line[..position].trim(), line[position + 1..].trim()),
+            None => return Err(invalid("expected a setting = value")),
+        };
+
+        let properties = &mut camera.properties;
+        match key {
+            "width" => camera.width = parse(value, &invalid)?,
+            "height" => camera.height = parse(value, &invalid)?,
+            "position" => properties.position = parse_vec3(value, &invalid)?,
+            "target" => camera.target = parse_vec3(value, &invalid)?,
+            "type" => {
+                properties.camera_type = match value {
+                    "perspective" => CameraType::Perspective,
+                    "orthographic" => CameraType::Orthographic,
+                    _ => return Err(invalid("unknown camera type")),
+                }
+            }
+            "ortho_size" => properties.ortho_size = parse(value, &invalid)?,
+            "near" => properties.near = parse(value, &invalid)?,
+            "far" => properties.far = parse(value, &invalid)?,
+            _ => return Err(invalid(&format!("unknown setting {}", key))),
+        }
+        if camera.width == 0 || camera.height == 0 {
+            return Err(invalid("empty image"));
+        }
+    }
+
+    Ok(cameras)
+}
+
+fn parse<T: FromStr>(value: &str, invalid: &dyn Fn(&str) -> io::Error) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(&format!("invalid value {}", value)))
+}
+
+fn parse_vec3(value: &str, invalid: &dyn Fn(&str) -> io::Error) -> io::Result<glm::Vec3> {
+    let components = value
+        .split_whitespace()
+        .map(|component| parse(component, invalid))
+        .collect::<io::Result<Vec<f32>>>()?;
+    match components.as_slice() {
+        [x, y, z] => Ok(glm::vec3(*x, *y, *z)),
+        _ => Err(invalid(&format!("expected three numbers, not {}", value))),
+    }
+}
+
+// Sum of the frames read from the color AOV, in half floats
+pub(crate) struct Accumulation {
+    width: u32,
+    height: u32,
+    sum: Vec<f32>,
+    sample_count: u32,
+}
+
+impl Accumulation {
+    pub fn new(width: u32, height: u32) -> Self {
+        Accumulation {
+            width,
+            height,
+            sum: vec![0.0; (width * height * 4) as usize],
+            sample_count: 0,
+        }
+    }
+
+    pub fn add(&mut self, texels: &[[u16; 4]]) {
+        for (sum, texel) in self.sum.chunks_mut(4).zip(texels.iter()) {
+            for (sum, &value) in sum.iter_mut().zip(texel.iter()) {
+                *sum += f16_to_f32(value);
+            }
+        }
+        self.sample_count += 1;
+    }
+
+    // The average clamped to 8 bits per channel, like the back buffer shows it
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let scale = 1.0 / self.sample_count.max(1) as f32;
+        let pixels: Vec<u8> = self
+            .sum
+            .iter()
+            .map(|&sum| ((sum * scale).clamp(0.0, 1.0) * 255.0).round() as u8)
+            .collect();
+        image::save_buffer(path, &pixels, self.width, self.height, ColorType::RGBA(8))
+    }
+}
+
+// Infinity and NaN are kept, the accumulation of a pixel then shows the error
+fn f16_to_f32(value: u16) -> f32 {
+    let sign = u32::from(value & 0x8000) << 16;
+    let exponent = u32::from((value >> 10) & 0x1f);
+    let mantissa = u32::from(value & 0x3ff);
+
+    if exponent == 0 {
+        // Zero or subnormal, without the implicit leading bit
+        let magnitude = mantissa as f32 * 2.0f32.powi(-24);
+        return if sign != 0 { -magnitude } else { magnitude };
+    }
+    let bits = if exponent == 0x1f {
+        sign | 0x7f80_0000 | (mantissa << 13)
+    } else {
+        sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)
+    };
+    f32::from_bits(bits)
+}
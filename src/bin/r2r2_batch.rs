@@ -0,0 +1,117 @@
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use r2r2::application_manager::ApplicationManagerBuilder;
+use r2r2::batch_render::load_batch_cameras;
+
+const USAGE: &str =
+    "usage: r2r2_batch <scene> <cameras> [--samples <count>] [--output <directory>]";
+
+struct Arguments {
+    scene: String,
+    cameras: PathBuf,
+    sample_count: u32,
+    output_directory: PathBuf,
+}
+
+fn parse_arguments() -> Result<Arguments, String> {
+    let mut positional = vec![];
+    let mut sample_count = 16;
+    let mut output_directory = PathBuf::from(".");
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--samples" => {
+                sample_count = args
+                    .next()
+                    .and_then(|value| value.parse().ok())
+                    .filter(|&count| count > 0)
+                    .ok_or("--samples expects a count above 0")?;
+            }
+            "--output" => {
+                output_directory = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or("--output expects a directory")?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    match positional.as_slice() {
+        [scene, cameras] => Ok(Arguments {
+            scene: scene.clone(),
+            cameras: PathBuf::from(cameras),
+            sample_count,
+            output_directory,
+        }),
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+// Renders the scene from each camera of the list with a hidden window, see
+// batch_render::load_batch_cameras for the format of the list
+fn main() {
+    let arguments = parse_arguments().unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        process::exit(2);
+    });
+    if !Path::new(&arguments.scene).exists() {
+        eprintln!("{} does not exist", arguments.scene);
+        process::exit(1);
+    }
+    let cameras = load_batch_cameras(&arguments.cameras).unwrap_or_else(|err| {
+        eprintln!("Cannot read the cameras: {}", err);
+        process::exit(1);
+    });
+    if cameras.is_empty() {
+        eprintln!("{} has no camera", arguments.cameras.display());
+        process::exit(1);
+    }
+
+    // The swapchain of the window holds the largest image, the others are traced in its corner
+    let width = cameras.iter().map(|camera| camera.width).max().unwrap_or(1);
+    let height = cameras
+        .iter()
+        .map(|camera| camera.height)
+        .max()
+        .unwrap_or(1);
+    let mut app = ApplicationManagerBuilder::new()
+        .with_title("R2R2 batch")
+        .with_width(width)
+        .with_height(height)
+        .with_hidden_window(true)
+        .with_scene(&arguments.scene)
+        .build();
+
+    let images = app
+        .render_batch(
+            &cameras,
+            arguments.sample_count,
+            &arguments.output_directory,
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Cannot render the batch: {}", err);
+            process::exit(1);
+        });
+
+    let mut total_time = Duration::default();
+    for image in images.iter() {
+        println!(
+            "{}: {} in {:.1} ms",
+            image.name,
+            image.path.display(),
+            image.render_time.as_secs_f32() * 1000.0
+        );
+        total_time += image.render_time;
+    }
+    println!(
+        "{} images of {} samples in {:.1} s",
+        images.len(),
+        arguments.sample_count,
+        total_time.as_secs_f32()
+    );
+}
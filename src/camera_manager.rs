@@ -1,29 +1,125 @@
-use crate::input_manager::InputManager;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::event_bus::{EngineEvent, EventBus};
+use crate::input_manager::{
+    InputManager, ACTION_MOVE_BACKWARD, ACTION_MOVE_FORWARD, ACTION_MOVE_LEFT, ACTION_MOVE_RIGHT,
+};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use vulkan_ray_tracing::bytemuck::{Pod, Zeroable};
 use vulkan_ray_tracing::glm;
 use winit::dpi::LogicalPosition;
-use winit::event::VirtualKeyCode;
 use winit::window::Window;
 
 type Transform = glm::Mat4;
 
+// Vertical, in degrees, of the perspective projections
+pub(crate) const FIELD_OF_VIEW: f32 = 65.0;
+
 #[repr(C)]
-struct Camera {
+#[derive(Clone, Copy)]
+pub struct Camera {
     view: Transform,
     proj: Transform,
     view_inverse: Transform,
     proj_inverse: Transform,
 }
 
+unsafe impl Zeroable for Camera {}
+unsafe impl Pod for Camera {}
+
+impl Camera {
+    // Fixed camera, for views that are not driven by the input such as a top down editor view
+    pub fn look_at(
+        target: glm::Vec3,
+        camera_properties: &CameraProperties,
+        width: f32,
+        height: f32,
+    ) -> Self {
+        let direction = target - camera_properties.position;
+        // Looking straight up or down, the usual up vector is aligned with the view
+        let up = if direction.normalize().y.abs() > 0.999 {
+            glm::vec3(0.0, 0.0, -1.0)
+        } else {
+            glm::vec3(0.0, 1.0, 0.0)
+        };
+        let view = glm::look_at(&camera_properties.position, &target, &up);
+        let proj = CameraManager::projection(
+            camera_properties.camera_type,
+            camera_properties.depth_mode,
+            camera_properties.ortho_size,
+            camera_properties.near,
+            camera_properties.far,
+            width,
+            height,
+        );
+
+        Camera {
+            view,
+            proj,
+            view_inverse: glm::inverse(&view),
+            proj_inverse: glm::inverse(&proj),
+        }
+    }
+
+    // Camera to world, its translation is the position of the camera
+    pub fn get_view_inverse(&self) -> glm::Mat4 {
+        self.view_inverse
+    }
+
+    // Pixel position in xy and view depth in z of a world space point, None behind the camera
+    pub fn project(&self, point: glm::Vec3, width: f32, height: f32) -> Option<glm::Vec3> {
+        let view_point = self.view * glm::vec4(point.x, point.y, point.z, 1.0);
+        let depth = -view_point.z;
+        if depth <= 0.0 {
+            return None;
+        }
+
+        // The projection is already flipped for Vulkan, y goes down like the pixels
+        let clip = self.proj * view_point;
+        let x = (clip.x / clip.w + 1.0) * 0.5 * width;
+        let y = (clip.y / clip.w + 1.0) * 0.5 * height;
+        Some(glm::vec3(x, y, depth))
+    }
+
+    // Pixels from the top left corner of the window to clip space, for overlays drawn in screen
+    // space. Depth 0 maps to the middle of the depth range.
+    pub fn screen_projection(width: f32, height: f32) -> glm::Mat4 {
+        // Vulkan clip space already has y going down
+        glm::ortho_rh_zo(0.0, width, 0.0, height, -1.0, 1.0)
+    }
+}
+
+// Camera traced in a viewport
+#[derive(Clone)]
+pub enum ViewportCamera {
+    // Follows the camera manager, with the aspect ratio of the viewport
+    Interactive,
+    Fixed(Box<Camera>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CameraType {
     Orthographic,
     Perspective,
 }
 
+// Depth range the projection maps the view depth to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthMode {
+    // Near plane at -1 and far plane at 1
+    Standard,
+    // Near plane at 1 and far plane at 0, compared with GREATER and cleared to 0. The floating
+    // point precision then follows the perspective divide. Perspective cameras have no far
+    // plane, orthographic ones keep theirs.
+    Reversed,
+}
+
 pub struct CameraProperties {
     pub position: glm::Vec3,
     pub camera_type: CameraType,
+    pub depth_mode: DepthMode,
+    // Height of the view of orthographic cameras in world units, the width follows the aspect
+    // ratio. Smaller sizes zoom in.
+    pub ortho_size: f32,
     pub near: f32,
     pub far: f32,
 }
@@ -33,6 +129,8 @@ impl Default for CameraProperties {
         CameraProperties {
             position: glm::vec3(0.0, 0.0, 10.0),
             camera_type: CameraType::Perspective,
+            depth_mode: DepthMode::Standard,
+            ortho_size: 10.0,
             near: 0.1,
             far: 1000.0,
         }
@@ -40,11 +138,21 @@ impl Default for CameraProperties {
 }
 
 pub struct CameraManager {
-    input_manager: Rc<RefCell<InputManager>>,
+    input_manager: Arc<Mutex<InputManager>>,
+    events: Receiver<EngineEvent>,
     camera: Camera,
+    camera_type: CameraType,
+    depth_mode: DepthMode,
+    ortho_size: f32,
+    near: f32,
+    far: f32,
+    // Of the window, for the aspect ratio of the projection
+    width: f32,
+    height: f32,
     position: glm::Vec3,
     movement_speed: f32,
-    rotation_speed: f32,
+    // Degrees per raw count of the mouse
+    mouse_sensitivity: f32,
     yaw: f32,
     pitch: f32,
     mouse_grabbed: bool,
@@ -53,7 +161,8 @@ pub struct CameraManager {
 
 impl CameraManager {
     pub fn new(
-        input_manager: Rc<RefCell<InputManager>>,
+        input_manager: Arc<Mutex<InputManager>>,
+        event_bus: &EventBus,
         width: f32,
         height: f32,
         camera_properties: CameraProperties,
@@ -66,39 +175,37 @@ impl CameraManager {
             &up,
         );
 
-        let aspect_ratio = width / height;
-        let mut proj = match camera_properties.camera_type {
-            CameraType::Perspective => glm::perspective(
-                f32::to_radians(65.0),
-                aspect_ratio,
-                camera_properties.near,
-                camera_properties.far,
-            ),
-            CameraType::Orthographic => glm::ortho(
-                0.0,
-                width,
-                0.0,
-                height,
-                camera_properties.near,
-                camera_properties.far,
-            ),
-        };
-
-        proj[(1, 1)] = -proj[(1, 1)];
+        let proj = Self::projection(
+            camera_properties.camera_type,
+            camera_properties.depth_mode,
+            camera_properties.ortho_size,
+            camera_properties.near,
+            camera_properties.far,
+            width,
+            height,
+        );
         let view_inverse = glm::inverse(&view);
         let proj_inverse = glm::inverse(&proj);
 
         Self {
             input_manager,
+            events: event_bus.subscribe(),
             camera: Camera {
                 view,
                 proj,
                 view_inverse,
                 proj_inverse,
             },
+            camera_type: camera_properties.camera_type,
+            depth_mode: camera_properties.depth_mode,
+            ortho_size: camera_properties.ortho_size,
+            near: camera_properties.near,
+            far: camera_properties.far,
+            width,
+            height,
             position: camera_properties.position,
             movement_speed: 2.0,
-            rotation_speed: 50.0,
+            mouse_sensitivity: 0.8,
             yaw: -90.0,
             pitch: 0.0,
             mouse_grabbed: false,
@@ -106,18 +213,143 @@ impl CameraManager {
         }
     }
 
-    pub fn get_camera_buffer(&self) -> &[u8] {
-        let data = &self.camera as *const Camera as *const u8;
-        unsafe { std::slice::from_raw_parts(data, std::mem::size_of::<Camera>()) }
+    fn projection(
+        camera_type: CameraType,
+        depth_mode: DepthMode,
+        ortho_size: f32,
+        near: f32,
+        far: f32,
+        width: f32,
+        height: f32,
+    ) -> Transform {
+        let aspect_ratio = width / height;
+        // Centered on the view direction like the perspective projection
+        let top = ortho_size * 0.5;
+        let right = top * aspect_ratio;
+        let mut proj = match (camera_type, depth_mode) {
+            (CameraType::Perspective, DepthMode::Standard) => {
+                glm::perspective(f32::to_radians(FIELD_OF_VIEW), aspect_ratio, near, far)
+            }
+            (CameraType::Perspective, DepthMode::Reversed) => {
+                let proj =
+                    glm::perspective(f32::to_radians(FIELD_OF_VIEW), aspect_ratio, near, far);
+                Self::reversed_infinite(&proj, near)
+            }
+            (CameraType::Orthographic, DepthMode::Standard) => {
+                glm::ortho(-right, right, -top, top, near, far)
+            }
+            (CameraType::Orthographic, DepthMode::Reversed) => {
+                glm::ortho_rh_zo(-right, right, -top, top, far, near)
+            }
+        };
+
+        proj[(1, 1)] = -proj[(1, 1)];
+        proj
+    }
+
+    // Same frustum as the perspective projection, with a depth of near / distance: 1 on the near
+    // plane and 0 at infinity
+    pub fn reversed_infinite(proj: &Transform, near: f32) -> Transform {
+        let mut proj = *proj;
+        proj[(2, 2)] = 0.0;
+        proj[(2, 3)] = near;
+        proj[(3, 2)] = -1.0;
+        proj[(3, 3)] = 0.0;
+        proj
+    }
+
+    fn handle_events(&mut self) {
+        let mut resized = false;
+        for event in self.events.try_iter() {
+            if let EngineEvent::WindowResized { width, height } = event {
+                // Minimized windows have no size
+                if width == 0 || height == 0 {
+                    continue;
+                }
+                self.width = width as f32;
+                self.height = height as f32;
+                resized = true;
+            }
+        }
+        if resized {
+            self.update_projection();
+        }
+    }
+
+    fn update_projection(&mut self) {
+        self.camera.proj = Self::projection(
+            self.camera_type,
+            self.depth_mode,
+            self.ortho_size,
+            self.near,
+            self.far,
+            self.width,
+            self.height,
+        );
+        self.camera.proj_inverse = glm::inverse(&self.camera.proj);
+    }
+
+    pub fn set_camera_type(&mut self, camera_type: CameraType) {
+        self.camera_type = camera_type;
+        self.update_projection();
+    }
+
+    pub fn get_camera_type(&self) -> CameraType {
+        self.camera_type
+    }
+
+    // Only changes the view of orthographic cameras
+    pub fn set_ortho_size(&mut self, ortho_size: f32) {
+        self.ortho_size = ortho_size.max(f32::EPSILON);
+        self.update_projection();
+    }
+
+    pub fn get_ortho_size(&self) -> f32 {
+        self.ortho_size
+    }
+
+    pub fn get_camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn get_properties(&self) -> CameraProperties {
+        CameraProperties {
+            position: self.position,
+            camera_type: self.camera_type,
+            depth_mode: self.depth_mode,
+            ortho_size: self.ortho_size,
+            near: self.near,
+            far: self.far,
+        }
+    }
+
+    // Same view with a projection for another aspect ratio
+    pub fn get_camera_with_extent(&self, width: f32, height: f32) -> Camera {
+        let proj = Self::projection(
+            self.camera_type,
+            self.depth_mode,
+            self.ortho_size,
+            self.near,
+            self.far,
+            width,
+            height,
+        );
+        Camera {
+            proj,
+            proj_inverse: glm::inverse(&proj),
+            ..self.camera
+        }
     }
 
     pub fn get_camera_buffer_size(&self) -> usize {
         std::mem::size_of::<Camera>()
     }
 
-    pub fn update(&mut self, window: &Window, mouse_position: &LogicalPosition, delta_time: f32) {
+    pub fn update(&mut self, window: &Window, delta_time: f32) {
+        self.handle_events();
+
         // Hide the mouse when controlling the camera
-        if !self.input_manager.borrow().is_right_button_down() {
+        if !self.input_manager.lock().unwrap().is_right_button_down() {
             if self.mouse_grabbed {
                 self.mouse_grabbed = false;
                 window.set_cursor_grab(false).unwrap();
@@ -131,15 +363,16 @@ impl CameraManager {
 
         if !self.mouse_grabbed {
             self.mouse_grabbed = true;
-            self.last_mouse_position = *mouse_position;
+            let (x, y) = self.input_manager.lock().unwrap().cursor_position();
+            self.last_mouse_position = LogicalPosition::new(x, y);
             window.set_cursor_grab(true).unwrap();
             window.set_cursor_visible(false);
         }
 
-        // mouse movement
-        let mouse_movement = self.input_manager.borrow().mouse_movement();
-        self.yaw += mouse_movement.0 as f32 * delta_time * self.rotation_speed;
-        self.pitch += mouse_movement.1 as f32 * delta_time * self.rotation_speed;
+        // Raw mouse movement, already a distance for the frame so it is not scaled by delta_time
+        let mouse_movement = self.input_manager.lock().unwrap().mouse_movement();
+        self.yaw += mouse_movement.0 as f32 * self.mouse_sensitivity;
+        self.pitch += mouse_movement.1 as f32 * self.mouse_sensitivity;
 
         self.pitch = self.pitch.min(89.0).max(-89.0);
 
@@ -154,29 +387,33 @@ impl CameraManager {
         let up = glm::vec3(0.0, 1.0, 0.0);
         if self
             .input_manager
-            .borrow()
-            .is_key_pressed(VirtualKeyCode::S)
+            .lock()
+            .unwrap()
+            .is_action_pressed(ACTION_MOVE_BACKWARD)
         {
             self.position -= front * delta_time * self.movement_speed;
         }
         if self
             .input_manager
-            .borrow()
-            .is_key_pressed(VirtualKeyCode::W)
+            .lock()
+            .unwrap()
+            .is_action_pressed(ACTION_MOVE_FORWARD)
         {
             self.position += front * delta_time * self.movement_speed;
         }
         if self
             .input_manager
-            .borrow()
-            .is_key_pressed(VirtualKeyCode::A)
+            .lock()
+            .unwrap()
+            .is_action_pressed(ACTION_MOVE_LEFT)
         {
             self.position -= front.cross(&up).normalize() * delta_time * self.movement_speed;
         }
         if self
             .input_manager
-            .borrow()
-            .is_key_pressed(VirtualKeyCode::D)
+            .lock()
+            .unwrap()
+            .is_action_pressed(ACTION_MOVE_RIGHT)
         {
             self.position += front.cross(&up).normalize() * delta_time * self.movement_speed;
         }
@@ -0,0 +1,26 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::hdr::HDRDecoder;
+use image::ImageResult;
+use vulkan_ray_tracing::background::EnvironmentMap;
+
+// Radiance HDR file in the equirectangular layout, as most HDRI environments are shared
+pub(crate) fn load_environment_map(path: &Path) -> ImageResult<EnvironmentMap> {
+    let decoder = HDRDecoder::new(BufReader::new(File::open(path)?))?;
+    let metadata = decoder.metadata();
+    let texels = decoder.read_image_hdr()?;
+
+    let mut pixels = Vec::with_capacity(texels.len() * 4);
+    for texel in texels.iter() {
+        pixels.extend_from_slice(&texel.0);
+        pixels.push(1.0);
+    }
+
+    Ok(EnvironmentMap {
+        width: metadata.width,
+        height: metadata.height,
+        pixels,
+    })
+}
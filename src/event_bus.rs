@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::scene::InstanceHandle;
+use vulkan_ray_tracing::render_settings::RenderSettings;
+
+#[derive(Clone, Debug)]
+pub enum EngineEvent {
+    // Physical size of the window
+    WindowResized {
+        width: u32,
+        height: u32,
+    },
+    // Every part of the scene file was uploaded
+    SceneLoaded,
+    AssetReloaded(PathBuf),
+    // Frames cannot be rendered anymore, nothing is drawn after this
+    DeviceLost,
+    RenderSettingsChanged(RenderSettings),
+    // Saved to the path, the volume is shown from then on
+    IrradianceVolumeBaked(PathBuf),
+    // Recorded for upload, the model is drawn once all of it was submitted
+    ModelUploadProgress {
+        handle: InstanceHandle,
+        uploaded_bytes: u64,
+        total_bytes: u64,
+    },
+    // The turntable went around or was stopped, its exported frames are all written
+    TurntableFinished,
+}
+
+// Every subscriber gets its own copy of the events published after it subscribed.
+// Can be shared with other threads, the events are read whenever the subscriber polls them.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<EngineEvent>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Subscribers that were dropped are forgotten
+    pub fn publish(&self, event: EngineEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    pub fn subscribe(&self) -> Receiver<EngineEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
@@ -0,0 +1,32 @@
+use std::path::Path;
+
+use image::ImageResult;
+use vulkan_ray_tracing::text_overlay::FontAtlas;
+
+// Bitmap font sheet of columns by rows glyphs, white or light glyphs over a transparent or black
+// background, e.g. the usual 16x6 ASCII sheets starting at ' '
+pub(crate) fn load_font_atlas(
+    path: &Path,
+    columns: u32,
+    rows: u32,
+    first_char: char,
+) -> ImageResult<FontAtlas> {
+    let image = image::open(path)?.to_rgba();
+    let (width, height) = image.dimensions();
+    let pixels = image
+        .pixels()
+        .map(|pixel| {
+            let luma = (pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3;
+            (luma * pixel[3] as u32 / 255) as u8
+        })
+        .collect();
+
+    Ok(FontAtlas {
+        width,
+        height,
+        pixels,
+        columns,
+        rows,
+        first_char,
+    })
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The sleeps of the OS can overshoot by a scheduler tick, the end of the wait is spun instead
+const SPIN_MARGIN: Duration = Duration::from_micros(1500);
+// Weight of the last frame in the averages
+const SMOOTHING: f32 = 0.1;
+
+// Timing of the frames as the application loop sees them, in milliseconds averaged over the last
+// frames
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    // Between the starts of two frames
+    pub frame_time: f32,
+    // Mean deviation of the frame time, how unevenly the frames are spaced
+    pub frame_time_jitter: f32,
+    // From reading the inputs to the return of the present, the part of the input latency the
+    // CPU sees
+    pub latency: f32,
+    // Slept or spun by the frame limiter, 0 without it
+    pub limiter_wait: f32,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {:.2} ms (jitter {:.2} ms), latency {:.2} ms",
+            self.frame_time, self.frame_time_jitter, self.latency
+        )
+    }
+}
+
+// Starts the frames at the target framerate when it is limited. Each frame is due one period
+// after the previous one was due rather than after it started, so the waits do not drift.
+pub(crate) struct FramePacer {
+    period: Option<Duration>,
+    next_frame: Option<Instant>,
+    frame_begin: Option<Instant>,
+    stats: FrameStats,
+}
+
+impl FramePacer {
+    // Without a framerate the frames start as soon as the previous one was presented
+    pub fn new(target_framerate: Option<u32>) -> Self {
+        FramePacer {
+            period: target_framerate
+                .map(|framerate| Duration::from_secs_f64(1.0 / f64::from(framerate.max(1)))),
+            next_frame: None,
+            frame_begin: None,
+            stats: FrameStats::default(),
+        }
+    }
+
+    // Waits for the frame to be due, then starts it, the inputs are read right after
+    pub fn begin_frame(&mut self) {
+        let mut wait = Duration::from_secs(0);
+        if let (Some(period), Some(next_frame)) = (self.period, self.next_frame) {
+            let now = Instant::now();
+            if next_frame > now {
+                wait = next_frame - now;
+                if wait > SPIN_MARGIN {
+                    thread::sleep(wait - SPIN_MARGIN);
+                }
+                while Instant::now() < next_frame {
+                    std::hint::spin_loop();
+                }
+                self.next_frame = Some(next_frame + period);
+            } else {
+                // Too late to catch up, the frames are paced from now on
+                self.next_frame = Some(now + period);
+            }
+        }
+
+        let now = Instant::now();
+        if self.next_frame.is_none() {
+            self.next_frame = self.period.map(|period| now + period);
+        }
+        if let Some(frame_begin) = self.frame_begin {
+            let frame_time = milliseconds(now - frame_begin);
+            let deviation = (frame_time - self.stats.frame_time).abs();
+            smooth(&mut self.stats.frame_time, frame_time);
+            smooth(&mut self.stats.frame_time_jitter, deviation);
+        }
+        smooth(&mut self.stats.limiter_wait, milliseconds(wait));
+        self.frame_begin = Some(now);
+    }
+
+    // Once the frame was presented
+    pub fn end_frame(&mut self) {
+        if let Some(frame_begin) = self.frame_begin {
+            smooth(&mut self.stats.latency, milliseconds(frame_begin.elapsed()));
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+}
+
+fn milliseconds(duration: Duration) -> f32 {
+    duration.as_secs_f32() * 1000.0
+}
+
+fn smooth(average: &mut f32, value: f32) {
+    *average += (value - *average) * SMOOTHING;
+}
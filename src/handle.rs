@@ -0,0 +1,230 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+// Index into an Arena, the generation tells apart slots that were freed and reused
+pub struct Handle<T> {
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    pub fn index(self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+}
+
+// Implemented by hand so that T does not need to implement them
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handle({}v{})", self.index, self.generation)
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+pub struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Arena {
+            slots: vec![],
+            free: vec![],
+        }
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle<T> {
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize].value = Some(value);
+                index
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                });
+                self.slots.len() as u32 - 1
+            }
+        };
+
+        Handle {
+            index,
+            generation: self.slots[index as usize].generation,
+            _marker: PhantomData,
+        }
+    }
+
+    // The slot generation is bumped, so every copy of the handle becomes stale
+    pub fn remove(&mut self, handle: Handle<T>) -> Option<T> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation || slot.value.is_none() {
+            return None;
+        }
+
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        slot.value.take()
+    }
+
+    pub fn get(&self, handle: Handle<T>) -> Option<&T> {
+        self.slots
+            .get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, handle: Handle<T>) -> Option<&mut T> {
+        self.slots
+            .get_mut(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+
+    pub fn contains(&self, handle: Handle<T>) -> bool {
+        self.get(handle).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().filter_map(|slot| slot.value.as_mut())
+    }
+
+    pub fn iter_with_handles(&self) -> impl Iterator<Item = (Handle<T>, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let handle = Handle {
+                index: index as u32,
+                generation: slot.generation,
+                _marker: PhantomData,
+            };
+            slot.value.as_ref().map(|value| (handle, value))
+        })
+    }
+
+    pub fn iter_mut_with_handles(&mut self) -> impl Iterator<Item = (Handle<T>, &mut T)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                let handle = Handle {
+                    index: index as u32,
+                    generation: slot.generation,
+                    _marker: PhantomData,
+                };
+                slot.value.as_mut().map(|value| (handle, value))
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_handle_is_rejected_after_remove() {
+        let mut arena = Arena::new();
+        let handle = arena.insert("first");
+        assert_eq!(arena.remove(handle), Some("first"));
+
+        assert!(!arena.contains(handle));
+        assert_eq!(arena.get(handle), None);
+        assert_eq!(arena.remove(handle), None);
+        assert!(arena.is_empty());
+    }
+
+    #[test]
+    fn freed_slot_is_reused_with_the_next_generation() {
+        let mut arena = Arena::new();
+        let first = arena.insert(1);
+        let kept = arena.insert(2);
+        arena.remove(first);
+
+        let second = arena.insert(3);
+        assert_eq!(second.index(), first.index());
+        assert_eq!(second.generation(), first.generation() + 1);
+        assert_ne!(second, first);
+
+        assert_eq!(arena.get(first), None);
+        assert_eq!(arena.get(second), Some(&3));
+        assert_eq!(arena.get(kept), Some(&2));
+        assert_eq!(arena.len(), 2);
+    }
+
+    #[test]
+    fn get_mut_returns_none_for_a_removed_handle() {
+        let mut arena = Arena::new();
+        let handle = arena.insert(String::from("value"));
+        arena.get_mut(handle).unwrap().push_str(" edited");
+        assert_eq!(arena.remove(handle).as_deref(), Some("value edited"));
+
+        assert!(arena.get_mut(handle).is_none());
+        arena.insert(String::from("other"));
+        assert!(arena.get_mut(handle).is_none());
+    }
+
+    #[test]
+    fn iteration_skips_removed_values() {
+        let mut arena = Arena::new();
+        let handles: Vec<_> = (0..4).map(|value| arena.insert(value)).collect();
+        arena.remove(handles[1]);
+        arena.remove(handles[3]);
+
+        assert_eq!(arena.iter().copied().collect::<Vec<_>>(), vec![0, 2]);
+        let remaining: Vec<_> = arena
+            .iter_with_handles()
+            .map(|(handle, _)| handle)
+            .collect();
+        assert_eq!(remaining, vec![handles[0], handles[2]]);
+    }
+}
@@ -1,37 +1,130 @@
-use std::collections::HashSet;
-use winit::event::{DeviceEvent, ElementState, VirtualKeyCode};
+use std::collections::{HashMap, HashSet};
+use winit::dpi::LogicalPosition;
+use winit::event::{DeviceEvent, ElementState};
+
+pub use winit::event::VirtualKeyCode;
+
+// Actions of the interactive camera, bound by default to the keys where WASD is on a QWERTY
+// keyboard, whatever the layout
+pub const ACTION_MOVE_FORWARD: &str = "move_forward";
+pub const ACTION_MOVE_BACKWARD: &str = "move_backward";
+pub const ACTION_MOVE_LEFT: &str = "move_left";
+pub const ACTION_MOVE_RIGHT: &str = "move_right";
+
+// Physical key, the same on every keyboard layout. The values are the set 1 scancodes that
+// Windows reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ScanCode(pub u32);
+
+impl ScanCode {
+    pub const Q: ScanCode = ScanCode(0x10);
+    pub const W: ScanCode = ScanCode(0x11);
+    pub const E: ScanCode = ScanCode(0x12);
+    pub const A: ScanCode = ScanCode(0x1e);
+    pub const S: ScanCode = ScanCode(0x1f);
+    pub const D: ScanCode = ScanCode(0x20);
+    pub const LEFT_SHIFT: ScanCode = ScanCode(0x2a);
+    pub const SPACE: ScanCode = ScanCode(0x39);
+
+    // Label of the key on a US QWERTY keyboard
+    fn qwerty_name(self) -> Option<&'static str> {
+        match self {
+            ScanCode::Q => Some("Q"),
+            ScanCode::W => Some("W"),
+            ScanCode::E => Some("E"),
+            ScanCode::A => Some("A"),
+            ScanCode::S => Some("S"),
+            ScanCode::D => Some("D"),
+            ScanCode::LEFT_SHIFT => Some("LShift"),
+            ScanCode::SPACE => Some("Space"),
+            _ => None,
+        }
+    }
+}
+
+// Key that triggers an action, by the character of the current layout or by its position
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyBinding {
+    Key(VirtualKeyCode),
+    ScanCode(ScanCode),
+}
 
 pub struct InputManager {
     key_inputs: HashSet<VirtualKeyCode>,
+    scancode_inputs: HashSet<ScanCode>,
+    // Key of the current layout at each scancode, learnt from the key events, to name the
+    // scancode bindings
+    layout: HashMap<ScanCode, VirtualKeyCode>,
+    actions: HashMap<String, Vec<KeyBinding>>,
+    // Raw counts of the mouse since the last frame, before any acceleration or DPI scaling
     mouse_delta: (f64, f64),
+    // In logical pixels of the window, the hidpi factor turns them into pixels of the frames
+    cursor_position: LogicalPosition,
+    hidpi_factor: f64,
     left_button_down: bool,
     right_button_down: bool,
 }
 
-impl InputManager {
-    pub fn new() -> Self {
+impl Default for InputManager {
+    fn default() -> Self {
         InputManager {
             key_inputs: HashSet::new(),
+            scancode_inputs: HashSet::new(),
+            layout: HashMap::new(),
+            actions: [
+                (ACTION_MOVE_FORWARD, ScanCode::W),
+                (ACTION_MOVE_BACKWARD, ScanCode::S),
+                (ACTION_MOVE_LEFT, ScanCode::A),
+                (ACTION_MOVE_RIGHT, ScanCode::D),
+            ]
+            .iter()
+            .map(|&(action, scancode)| (action.to_string(), vec![KeyBinding::ScanCode(scancode)]))
+            .collect(),
             mouse_delta: (0.0, 0.0),
+            cursor_position: LogicalPosition::new(0.0, 0.0),
+            hidpi_factor: 1.0,
             left_button_down: false,
             right_button_down: false,
         }
     }
+}
+
+impl InputManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    pub fn update(&mut self, events: &[DeviceEvent]) {
+    pub fn update(
+        &mut self,
+        events: &[DeviceEvent],
+        cursor_position: LogicalPosition,
+        hidpi_factor: f64,
+    ) {
         self.mouse_delta = (0.0, 0.0);
+        self.cursor_position = cursor_position;
+        self.hidpi_factor = hidpi_factor;
 
         for event in events {
             match *event {
                 DeviceEvent::Key(input) => {
+                    let scancode = ScanCode(input.scancode);
+                    match input.state {
+                        ElementState::Pressed => self.scancode_inputs.insert(scancode),
+                        ElementState::Released => self.scancode_inputs.remove(&scancode),
+                    };
                     if let Some(keycode) = input.virtual_keycode {
+                        self.layout.insert(scancode, keycode);
                         match input.state {
                             ElementState::Pressed => self.key_inputs.insert(keycode),
                             ElementState::Released => self.key_inputs.remove(&keycode),
                         };
                     }
                 }
-                DeviceEvent::MouseMotion { delta } => self.mouse_delta = delta,
+                // Several motions can come in one frame
+                DeviceEvent::MouseMotion { delta } => {
+                    self.mouse_delta.0 += delta.0;
+                    self.mouse_delta.1 += delta.1;
+                }
                 DeviceEvent::Button { button, state } => {
                     if button == 1 {
                         self.left_button_down = state == ElementState::Pressed;
@@ -44,14 +137,76 @@ impl InputManager {
         }
     }
 
+    // By the character of the current layout, for shortcuts named after a letter
     pub fn is_key_pressed(&self, keycode: VirtualKeyCode) -> bool {
         self.key_inputs.contains(&keycode)
     }
 
+    // By the position of the key, for movement
+    pub fn is_scancode_pressed(&self, scancode: ScanCode) -> bool {
+        self.scancode_inputs.contains(&scancode)
+    }
+
+    // Replaces the bindings of the action, any of them triggers it
+    pub fn bind_action(&mut self, action: &str, bindings: &[KeyBinding]) {
+        self.actions.insert(action.to_string(), bindings.to_vec());
+    }
+
+    pub fn get_action_bindings(&self, action: &str) -> &[KeyBinding] {
+        self.actions.get(action).map_or(&[], |bindings| bindings)
+    }
+
+    // False for an action without any binding
+    pub fn is_action_pressed(&self, action: &str) -> bool {
+        self.get_action_bindings(action)
+            .iter()
+            .any(|binding| match *binding {
+                KeyBinding::Key(keycode) => self.is_key_pressed(keycode),
+                KeyBinding::ScanCode(scancode) => self.is_scancode_pressed(scancode),
+            })
+    }
+
+    // Labels of the keys bound to the action, e.g. "Z" for moving forward on an AZERTY keyboard.
+    // A scancode is named after the key of the current layout once it was pressed, after the US
+    // QWERTY key before that.
+    pub fn get_action_display_name(&self, action: &str) -> String {
+        self.get_action_bindings(action)
+            .iter()
+            .map(|binding| match *binding {
+                KeyBinding::Key(keycode) => format!("{:?}", keycode),
+                KeyBinding::ScanCode(scancode) => match self.layout.get(&scancode) {
+                    Some(keycode) => format!("{:?}", keycode),
+                    None => scancode
+                        .qwerty_name()
+                        .map_or_else(|| format!("Scancode {:#x}", scancode.0), str::to_string),
+                },
+            })
+            .collect::<Vec<_>>()
+            .join(" / ")
+    }
+
+    // For mouse look, the same motion turns the camera by the same angle on every monitor and at
+    // any framerate
     pub fn mouse_movement(&self) -> (f64, f64) {
         self.mouse_delta
     }
 
+    // In logical pixels from the top left corner of the window, for UI laid out in points
+    pub fn cursor_position(&self) -> (f64, f64) {
+        (self.cursor_position.x, self.cursor_position.y)
+    }
+
+    // In pixels of the frames, for picking and for the screen anchors
+    pub fn cursor_pixel_position(&self) -> (f64, f64) {
+        let position = self.cursor_position.to_physical(self.hidpi_factor);
+        (position.x, position.y)
+    }
+
+    // Pixels of the frames per logical pixel of the monitor the window is on
+    pub fn hidpi_factor(&self) -> f64 {
+        self.hidpi_factor
+    }
+
     pub fn is_right_button_down(&self) -> bool {
         self.right_button_down
     }
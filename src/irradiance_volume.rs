@@ -0,0 +1,72 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use vulkan_ray_tracing::bytemuck;
+use vulkan_ray_tracing::glm;
+use vulkan_ray_tracing::irradiance_volume::{IrradianceVolume, ProbeGrid, SH_COEFFICIENT_COUNT};
+
+// Bumped whenever the layout of the files changes
+const VOLUME_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"R2IV";
+
+// Little endian, the magic and version, the grid and then the coefficients of every probe
+pub(crate) fn save_irradiance_volume(path: &Path, volume: &IrradianceVolume) -> io::Result<()> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VOLUME_VERSION.to_le_bytes());
+    for value in volume.grid.origin.iter().chain(volume.grid.spacing.iter()) {
+        bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+    for count in volume.grid.counts.iter() {
+        bytes.extend_from_slice(&count.to_le_bytes());
+    }
+    for value in volume.coefficients.iter().flatten() {
+        bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+    fs::write(path, bytes)
+}
+
+pub(crate) fn load_irradiance_volume(path: &Path) -> io::Result<IrradianceVolume> {
+    let bytes = fs::read(path)?;
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    if bytes.len() < MAGIC.len() + 4 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not an irradiance volume"));
+    }
+
+    let mut words = bytes[MAGIC.len()..].chunks_exact(4).map(|word| {
+        let mut le_bytes = [0; 4];
+        le_bytes.copy_from_slice(word);
+        u32::from_le_bytes(le_bytes)
+    });
+    if words.next() != Some(VOLUME_VERSION) {
+        return Err(invalid("unsupported irradiance volume version"));
+    }
+
+    let header: Vec<u32> = words.by_ref().take(9).collect();
+    if header.len() < 9 {
+        return Err(invalid("truncated irradiance volume"));
+    }
+    let grid = ProbeGrid {
+        origin: glm::vec3(
+            f32::from_bits(header[0]),
+            f32::from_bits(header[1]),
+            f32::from_bits(header[2]),
+        ),
+        spacing: glm::vec3(
+            f32::from_bits(header[3]),
+            f32::from_bits(header[4]),
+            f32::from_bits(header[5]),
+        ),
+        counts: [header[6], header[7], header[8]],
+    };
+
+    let values: Vec<f32> = words.map(f32::from_bits).collect();
+    let expected = grid.probe_count() * SH_COEFFICIENT_COUNT;
+    if values.len() != expected * 4 {
+        return Err(invalid("the coefficients do not match the probe grid"));
+    }
+    let mut coefficients = vec![[0.0f32; 4]; expected];
+    bytemuck::cast_slice_mut::<[f32; 4], f32>(&mut coefficients).copy_from_slice(&values);
+
+    Ok(IrradianceVolume { grid, coefficients })
+}
@@ -1,7 +1,37 @@
 pub mod application_manager;
+#[cfg(feature = "audio")]
+pub mod audio_manager;
+pub mod batch_render;
+pub mod event_bus;
+pub mod frame_pacer;
+pub mod handle;
+pub mod input_manager;
+pub mod light_manager;
+pub mod mesh_diagnostics;
+pub mod model;
+#[cfg(feature = "physics")]
+pub mod physics_manager;
+pub mod prelude;
+pub mod primitives;
+pub mod render_presets;
+pub mod scene;
+pub mod screen_anchors;
+pub mod time_manager;
 
+mod asset_database;
+mod asset_watcher;
 mod camera_manager;
-mod input_manager;
-mod model;
+mod environment;
+mod font_atlas;
+mod irradiance_volume;
+mod mesh_normals;
+mod mesh_optimizer;
+mod mesh_tangents;
 mod render_manager;
+mod texture_compression;
+mod transform_interpolation;
+mod turntable;
 mod window_manager;
+
+pub use camera_manager::{Camera, CameraProperties, CameraType, DepthMode, ViewportCamera};
+pub use render_manager::{RenderHandle, SwapchainInfo};
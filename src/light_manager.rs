@@ -0,0 +1,86 @@
+use vulkan_ray_tracing::glm;
+pub use vulkan_ray_tracing::light::{Light, LightSamplingStrategy, LightType};
+
+use crate::handle::{Arena, Handle};
+
+pub type LightHandle = Handle<Light>;
+
+// The lights of the scene, the render manager uploads them again when they change
+pub struct LightManager {
+    lights: Arena<Light>,
+    sampling_strategy: LightSamplingStrategy,
+    changed: bool,
+}
+
+impl Default for LightManager {
+    // A single directional light, for scenes that do not set up their own
+    fn default() -> Self {
+        let mut light_manager = LightManager::new();
+        light_manager.add_light(Light {
+            light_type: LightType::Directional,
+            position: glm::vec3(5.0, 4.0, 3.0),
+            ..Light::default()
+        });
+        light_manager
+    }
+}
+
+impl LightManager {
+    pub fn new() -> Self {
+        LightManager {
+            lights: Arena::new(),
+            sampling_strategy: LightSamplingStrategy::Power,
+            changed: true,
+        }
+    }
+
+    pub fn add_light(&mut self, light: Light) -> LightHandle {
+        self.changed = true;
+        self.lights.insert(light)
+    }
+
+    pub fn remove_light(&mut self, handle: LightHandle) -> Option<Light> {
+        let light = self.lights.remove(handle);
+        self.changed |= light.is_some();
+        light
+    }
+
+    pub fn get_light(&self, handle: LightHandle) -> Option<&Light> {
+        self.lights.get(handle)
+    }
+
+    // Returns false if the handle is stale
+    pub fn set_light(&mut self, handle: LightHandle, light: Light) -> bool {
+        match self.lights.get_mut(handle) {
+            Some(current) => {
+                *current = light;
+                self.changed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn light_count(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn sampling_strategy(&self) -> LightSamplingStrategy {
+        self.sampling_strategy
+    }
+
+    // Uniform is cheaper to build, Power reduces noise when the light intensities differ a lot
+    pub fn set_sampling_strategy(&mut self, sampling_strategy: LightSamplingStrategy) {
+        self.changed |= self.sampling_strategy != sampling_strategy;
+        self.sampling_strategy = sampling_strategy;
+    }
+
+    pub(crate) fn lights(&self) -> Vec<Light> {
+        self.lights.iter().cloned().collect()
+    }
+
+    // Returns whether the lights changed since the last call
+    pub(crate) fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+
+use vulkan_ray_tracing::bytemuck;
+use vulkan_ray_tracing::geometry_instance::Vertex;
+use vulkan_ray_tracing::glm;
+
+// What was found in a mesh of an asset when it was loaded, to understand why it renders wrong
+#[derive(Clone, Debug)]
+pub struct MeshDiagnostics {
+    pub name: String,
+    pub triangle_count: usize,
+    // Triangles with a repeated corner or no area, they are never hit
+    pub degenerate_triangles: usize,
+    // Vertices identical to another one, welding removes them
+    pub duplicated_vertices: usize,
+    pub missing_normals: bool,
+    pub missing_tex_coords: bool,
+    // Edges used by a single triangle, holes let rays leak into closed meshes
+    pub boundary_edges: usize,
+    // Edges shared by more than two triangles
+    pub non_manifold_edges: usize,
+    pub aabb_min: glm::Vec3,
+    pub aabb_max: glm::Vec3,
+}
+
+impl MeshDiagnostics {
+    pub fn is_watertight(&self) -> bool {
+        self.boundary_edges == 0 && self.non_manifold_edges == 0
+    }
+
+    pub(crate) fn transform(&mut self, matrix: &glm::Mat4) {
+        let min =
+            (matrix * glm::vec4(self.aabb_min.x, self.aabb_min.y, self.aabb_min.z, 1.0)).xyz();
+        let max =
+            (matrix * glm::vec4(self.aabb_max.x, self.aabb_max.y, self.aabb_max.z, 1.0)).xyz();
+        self.aabb_min = glm::min2(&min, &max);
+        self.aabb_max = glm::max2(&min, &max);
+    }
+
+    pub(crate) fn log(&self) {
+        log::info!(
+            "Mesh {:?}: {} triangles, bounds {:?} to {:?}",
+            self.name,
+            self.triangle_count,
+            self.aabb_min.as_slice(),
+            self.aabb_max.as_slice(),
+        );
+        if self.degenerate_triangles > 0 {
+            log::warn!(
+                "Mesh {:?}: {} degenerate triangles",
+                self.name,
+                self.degenerate_triangles
+            );
+        }
+        if self.duplicated_vertices > 0 {
+            log::info!(
+                "Mesh {:?}: {} duplicated vertices",
+                self.name,
+                self.duplicated_vertices
+            );
+        }
+        if self.missing_normals {
+            log::warn!("Mesh {:?}: no normals, they are generated", self.name);
+        }
+        if self.missing_tex_coords {
+            log::warn!("Mesh {:?}: no texture coordinates", self.name);
+        }
+        if self.non_manifold_edges > 0 {
+            log::warn!(
+                "Mesh {:?}: {} non manifold edges",
+                self.name,
+                self.non_manifold_edges
+            );
+        }
+        if self.boundary_edges > 0 {
+            log::info!(
+                "Mesh {:?}: not watertight, {} boundary edges",
+                self.name,
+                self.boundary_edges
+            );
+        }
+    }
+}
+
+// Runs on the vertices as they are in the file, before normals are generated or vertices welded
+pub(crate) fn diagnose(
+    name: &str,
+    vertices: &[Vertex],
+    indices: &[u32],
+    missing_normals: bool,
+    missing_tex_coords: bool,
+) -> MeshDiagnostics {
+    let mut aabb_min = glm::vec3(0.0, 0.0, 0.0);
+    let mut aabb_max = glm::vec3(0.0, 0.0, 0.0);
+    if let Some(first) = vertices.first() {
+        aabb_min = first.pos;
+        aabb_max = first.pos;
+        for vertex in vertices.iter() {
+            aabb_min = glm::min2(&aabb_min, &vertex.pos);
+            aabb_max = glm::max2(&aabb_max, &vertex.pos);
+        }
+    }
+
+    let mut unique: HashSet<[u32; 16]> = HashSet::with_capacity(vertices.len());
+    let mut duplicated_vertices = 0;
+    for vertex in vertices.iter() {
+        let key: [u32; 16] = bytemuck::cast(*vertex);
+        if !unique.insert(key) {
+            duplicated_vertices += 1;
+        }
+    }
+
+    // Edges are matched on positions, OBJ files split the vertices of faces with other attributes
+    let mut positions: HashMap<[u32; 3], u32> = HashMap::with_capacity(vertices.len());
+    let position_ids: Vec<u32> = vertices
+        .iter()
+        .map(|vertex| {
+            let key = [
+                vertex.pos.x.to_bits(),
+                vertex.pos.y.to_bits(),
+                vertex.pos.z.to_bits(),
+            ];
+            let count = positions.len() as u32;
+            *positions.entry(key).or_insert(count)
+        })
+        .collect();
+
+    let mut degenerate_triangles = 0;
+    let mut edges: HashMap<(u32, u32), u32> = HashMap::with_capacity(indices.len());
+    for corners in indices.chunks_exact(3) {
+        let ids = [
+            position_ids[corners[0] as usize],
+            position_ids[corners[1] as usize],
+            position_ids[corners[2] as usize],
+        ];
+        let a = vertices[corners[0] as usize].pos;
+        let b = vertices[corners[1] as usize].pos;
+        let c = vertices[corners[2] as usize].pos;
+        let area = (b - a).cross(&(c - a)).norm();
+        if ids[0] == ids[1] || ids[1] == ids[2] || ids[2] == ids[0] || area == 0.0 {
+            degenerate_triangles += 1;
+            continue;
+        }
+
+        for corner in 0..3 {
+            let start = ids[corner];
+            let end = ids[(corner + 1) % 3];
+            *edges.entry((start.min(end), start.max(end))).or_insert(0) += 1;
+        }
+    }
+
+    let boundary_edges = edges.values().filter(|&&count| count == 1).count();
+    let non_manifold_edges = edges.values().filter(|&&count| count > 2).count();
+
+    MeshDiagnostics {
+        name: name.to_string(),
+        triangle_count: indices.len() / 3,
+        degenerate_triangles,
+        duplicated_vertices,
+        missing_normals,
+        missing_tex_coords,
+        boundary_edges,
+        non_manifold_edges,
+        aabb_min,
+        aabb_max,
+    }
+}
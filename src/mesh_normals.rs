@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use vulkan_ray_tracing::geometry_instance::Vertex;
+use vulkan_ray_tracing::glm;
+
+// Smooth normals for meshes that come without them. Faces meeting at a corner are averaged,
+// weighted by their angle at the corner, unless their normals differ by more than the crease
+// angle. Vertices on a crease are split so that each side keeps its own normal.
+pub(crate) fn generate_normals(vertices: &mut Vec<Vertex>, indices: &mut [u32], crease_angle: f32) {
+    let triangle_count = indices.len() / 3;
+    let mut face_normals = Vec::with_capacity(triangle_count);
+    let mut corner_angles = Vec::with_capacity(triangle_count * 3);
+    for corners in indices.chunks_exact(3) {
+        let positions = [
+            vertices[corners[0] as usize].pos,
+            vertices[corners[1] as usize].pos,
+            vertices[corners[2] as usize].pos,
+        ];
+        let normal = (positions[1] - positions[0]).cross(&(positions[2] - positions[0]));
+        let length = glm::length(&normal);
+        face_normals.push(if length > 0.0 {
+            normal / length
+        } else {
+            glm::zero()
+        });
+
+        for corner in 0..3 {
+            let edge_a = positions[(corner + 1) % 3] - positions[corner];
+            let edge_b = positions[(corner + 2) % 3] - positions[corner];
+            let lengths = glm::length(&edge_a) * glm::length(&edge_b);
+            corner_angles.push(if lengths > 0.0 {
+                (glm::dot(&edge_a, &edge_b) / lengths)
+                    .clamp(-1.0, 1.0)
+                    .acos()
+            } else {
+                0.0
+            });
+        }
+    }
+
+    // Corners sharing a position, whatever their other attributes
+    let mut corners_at: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+    for (corner, &index) in indices[..triangle_count * 3].iter().enumerate() {
+        let pos = vertices[index as usize].pos;
+        corners_at
+            .entry([pos.x.to_bits(), pos.y.to_bits(), pos.z.to_bits()])
+            .or_default()
+            .push(corner);
+    }
+
+    let min_cos = crease_angle.cos();
+    let mut split: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+    let mut assigned = vec![false; vertices.len()];
+    for corner in 0..triangle_count * 3 {
+        let face_normal = face_normals[corner / 3];
+        let index = indices[corner];
+        let pos = vertices[index as usize].pos;
+
+        let mut normal: glm::Vec3 = glm::zero();
+        for &other in corners_at[&[pos.x.to_bits(), pos.y.to_bits(), pos.z.to_bits()]].iter() {
+            let other_normal = face_normals[other / 3];
+            if glm::dot(&face_normal, &other_normal) >= min_cos {
+                normal += other_normal * corner_angles[other];
+            }
+        }
+        let length = glm::length(&normal);
+        normal = if length > 0.0 {
+            normal / length
+        } else if glm::length(&face_normal) > 0.0 {
+            face_normal
+        } else {
+            glm::vec3(0.0, 1.0, 0.0)
+        };
+
+        // The first corner using a vertex sets its normal, the others reuse it or get a copy
+        let key = (
+            index,
+            [normal.x.to_bits(), normal.y.to_bits(), normal.z.to_bits()],
+        );
+        indices[corner] = *split.entry(key).or_insert_with(|| {
+            if !assigned[index as usize] {
+                assigned[index as usize] = true;
+                vertices[index as usize].nrm = normal;
+                index
+            } else {
+                let mut vertex = vertices[index as usize];
+                vertex.nrm = normal;
+                vertices.push(vertex);
+                vertices.len() as u32 - 1
+            }
+        });
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use vulkan_ray_tracing::bytemuck;
+use vulkan_ray_tracing::geometry_instance::Vertex;
+
+// Tuning of the vertex cache optimization (Forsyth 2006)
+const CACHE_SIZE: usize = 32;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const CACHE_DECAY_POWER: f32 = 1.5;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+// Merges the vertices that are identical bit for bit and points the indices to the ones kept
+pub(crate) fn weld_vertices(vertices: &mut Vec<Vertex>, indices: &mut [u32]) {
+    let mut unique: HashMap<[u32; 16], u32> = HashMap::with_capacity(vertices.len());
+    let mut welded = Vec::with_capacity(vertices.len());
+    let remap: Vec<u32> = vertices
+        .iter()
+        .map(|vertex| {
+            let key: [u32; 16] = bytemuck::cast(*vertex);
+            *unique.entry(key).or_insert_with(|| {
+                welded.push(*vertex);
+                welded.len() as u32 - 1
+            })
+        })
+        .collect();
+
+    for index in indices.iter_mut() {
+        *index = remap[*index as usize];
+    }
+    *vertices = welded;
+}
+
+// Reorders the triangles so that the vertices they share are still in the post transform cache
+pub(crate) fn optimize_vertex_cache(indices: &mut [u32], vertex_count: usize) {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return;
+    }
+
+    // Triangles using each vertex, packed one vertex after the other
+    let mut valence = vec![0; vertex_count];
+    for &index in indices[..triangle_count * 3].iter() {
+        valence[index as usize] += 1;
+    }
+    let mut offsets = vec![0; vertex_count + 1];
+    for vertex in 0..vertex_count {
+        offsets[vertex + 1] = offsets[vertex] + valence[vertex];
+    }
+    let mut adjacency = vec![0; triangle_count * 3];
+    let mut cursor = offsets.clone();
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for &vertex in corners.iter() {
+            adjacency[cursor[vertex as usize]] = triangle;
+            cursor[vertex as usize] += 1;
+        }
+    }
+
+    let mut cache_position: Vec<Option<usize>> = vec![None; vertex_count];
+    let mut vertex_scores: Vec<f32> = valence
+        .iter()
+        .map(|&remaining| vertex_score(None, remaining))
+        .collect();
+    let triangle_score = |corners: &[u32], scores: &[f32]| -> f32 {
+        corners.iter().map(|&vertex| scores[vertex as usize]).sum()
+    };
+
+    let mut emitted = vec![false; triangle_count];
+    let mut output = Vec::with_capacity(triangle_count * 3);
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut next_unemitted = 0;
+
+    let mut best = (0..triangle_count).max_by(|&a, &b| {
+        let score_a = triangle_score(&indices[3 * a..3 * a + 3], &vertex_scores);
+        let score_b = triangle_score(&indices[3 * b..3 * b + 3], &vertex_scores);
+        score_a.partial_cmp(&score_b).unwrap()
+    });
+
+    while let Some(triangle) = best {
+        emitted[triangle] = true;
+        let corners = [
+            indices[3 * triangle],
+            indices[3 * triangle + 1],
+            indices[3 * triangle + 2],
+        ];
+        output.extend_from_slice(&corners);
+
+        // The corners go to the front of the cache, the vertices pushed past its end are evicted
+        let mut new_cache = corners.to_vec();
+        for &vertex in corners.iter() {
+            valence[vertex as usize] -= 1;
+        }
+        for &vertex in cache.iter() {
+            if !corners.contains(&vertex) {
+                new_cache.push(vertex);
+            }
+        }
+        for (position, &vertex) in new_cache.iter().enumerate() {
+            cache_position[vertex as usize] = if position < CACHE_SIZE {
+                Some(position)
+            } else {
+                None
+            };
+            vertex_scores[vertex as usize] =
+                vertex_score(cache_position[vertex as usize], valence[vertex as usize]);
+        }
+
+        // Only the triangles touching the cache changed their score
+        best = None;
+        let mut best_score = -1.0;
+        for &vertex in new_cache.iter() {
+            let vertex = vertex as usize;
+            for &candidate in adjacency[offsets[vertex]..offsets[vertex + 1]].iter() {
+                if emitted[candidate] {
+                    continue;
+                }
+                let score =
+                    triangle_score(&indices[3 * candidate..3 * candidate + 3], &vertex_scores);
+                if score > best_score {
+                    best = Some(candidate);
+                    best_score = score;
+                }
+            }
+        }
+
+        new_cache.truncate(CACHE_SIZE);
+        cache = new_cache;
+
+        // Nothing left around the cache, the next triangle starts a new strip
+        if best.is_none() {
+            while next_unemitted < triangle_count && emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+            if next_unemitted < triangle_count {
+                best = Some(next_unemitted);
+            }
+        }
+    }
+
+    indices[..output.len()].copy_from_slice(&output);
+}
+
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+
+    let cache_score = match cache_position {
+        // The last triangle corners get a fixed score, so that strips are not favored too much
+        Some(position) if position < 3 => LAST_TRIANGLE_SCORE,
+        Some(position) => {
+            let scale = 1.0 / (CACHE_SIZE - 3) as f32;
+            (1.0 - (position - 3) as f32 * scale).powf(CACHE_DECAY_POWER)
+        }
+        None => 0.0,
+    };
+
+    // Vertices with few triangles left are finished first, to free the cache of them
+    cache_score + VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+// Stores the vertices in the order the indices use them, dropping the ones no triangle uses
+pub(crate) fn optimize_vertex_fetch(vertices: &mut Vec<Vertex>, indices: &mut [u32]) {
+    let mut remap = vec![u32::MAX; vertices.len()];
+    let mut reordered = Vec::with_capacity(vertices.len());
+    for index in indices.iter_mut() {
+        let vertex = *index as usize;
+        if remap[vertex] == u32::MAX {
+            remap[vertex] = reordered.len() as u32;
+            reordered.push(vertices[vertex]);
+        }
+        *index = remap[vertex];
+    }
+    *vertices = reordered;
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use vulkan_ray_tracing::geometry_instance::Vertex;
+use vulkan_ray_tracing::glm;
+
+// Tangents following the MikkTSpace conventions: the face tangent comes from the texture
+// coordinate derivatives, is projected on the plane of the vertex normal and averaged with the
+// corner angles as weights. The bitangent is sign * cross(normal, tangent), the sign being in w.
+// Vertices shared by faces with mirrored texture coordinates are split, one copy per sign.
+pub(crate) fn generate_tangents(vertices: &mut Vec<Vertex>, indices: &mut [u32]) {
+    let triangle_count = indices.len() / 3;
+    let mut sums: HashMap<(u32, bool), glm::Vec3> = HashMap::new();
+    let mut corner_keys = Vec::with_capacity(triangle_count * 3);
+
+    for corners in indices.chunks_exact(3) {
+        let v = [
+            vertices[corners[0] as usize],
+            vertices[corners[1] as usize],
+            vertices[corners[2] as usize],
+        ];
+        let d1 = v[1].pos - v[0].pos;
+        let d2 = v[2].pos - v[0].pos;
+        let st1 = v[1].tex_coord - v[0].tex_coord;
+        let st2 = v[2].tex_coord - v[0].tex_coord;
+        let signed_area = st1.x * st2.y - st1.y * st2.x;
+        let orientation_preserving = signed_area > 0.0;
+        // Mirrored texture coordinates flip the tangent, the sign restores the bitangent
+        let face_tangent = if signed_area != 0.0 {
+            (d1 * st2.y - d2 * st1.y) * signed_area.signum()
+        } else {
+            glm::zero()
+        };
+
+        for corner in 0..3 {
+            let key = (corners[corner], orientation_preserving);
+            corner_keys.push(key);
+
+            let normal = v[corner].nrm;
+            let tangent = project(&face_tangent, &normal);
+            let edge_a = project(&(v[(corner + 1) % 3].pos - v[corner].pos), &normal);
+            let edge_b = project(&(v[(corner + 2) % 3].pos - v[corner].pos), &normal);
+            let angle = match (tangent, edge_a, edge_b) {
+                (Some(_), Some(a), Some(b)) => glm::dot(&a, &b).clamp(-1.0, 1.0).acos(),
+                _ => 0.0,
+            };
+
+            let sum = sums.entry(key).or_insert_with(glm::zero);
+            if let Some(tangent) = tangent {
+                *sum += tangent * angle;
+            }
+        }
+    }
+
+    // The first sign met keeps the vertex, the other one gets a copy
+    let mut remap: HashMap<(u32, bool), u32> = HashMap::new();
+    let mut assigned = vec![false; vertices.len()];
+    for (corner, &key) in corner_keys.iter().enumerate() {
+        let (index, orientation_preserving) = key;
+        indices[corner] = *remap.entry(key).or_insert_with(|| {
+            let mut vertex = vertices[index as usize];
+            let tangent =
+                project(&sums[&key], &vertex.nrm).unwrap_or_else(|| any_perpendicular(&vertex.nrm));
+            let sign = if orientation_preserving { 1.0 } else { -1.0 };
+            vertex.tangent = glm::vec4(tangent.x, tangent.y, tangent.z, sign);
+
+            if !assigned[index as usize] {
+                assigned[index as usize] = true;
+                vertices[index as usize] = vertex;
+                index
+            } else {
+                vertices.push(vertex);
+                vertices.len() as u32 - 1
+            }
+        });
+    }
+
+    // Vertices no triangle uses still get a valid frame
+    for (vertex, assigned) in vertices.iter_mut().zip(assigned) {
+        if !assigned {
+            let tangent = any_perpendicular(&vertex.nrm);
+            vertex.tangent = glm::vec4(tangent.x, tangent.y, tangent.z, 1.0);
+        }
+    }
+}
+
+// Normalized component of the vector orthogonal to the normal, none when it is degenerate
+fn project(vector: &glm::Vec3, normal: &glm::Vec3) -> Option<glm::Vec3> {
+    let projected = vector - normal * glm::dot(normal, vector);
+    let length = glm::length(&projected);
+    if length > 1e-12 {
+        Some(projected / length)
+    } else {
+        None
+    }
+}
+
+fn any_perpendicular(normal: &glm::Vec3) -> glm::Vec3 {
+    let axis = if normal.x.abs() > 0.9 {
+        glm::vec3(0.0, 1.0, 0.0)
+    } else {
+        glm::vec3(1.0, 0.0, 0.0)
+    };
+    project(&axis, normal).unwrap_or(axis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(x: f32, y: f32, u: f32, v: f32) -> Vertex {
+        Vertex {
+            pos: glm::vec3(x, y, 0.0),
+            nrm: glm::vec3(0.0, 0.0, 1.0),
+            color: glm::vec3(1.0, 1.0, 1.0),
+            tex_coord: glm::vec2(u, v),
+            mat_id: 0,
+            tangent: glm::zero(),
+        }
+    }
+
+    fn assert_tangent(vertex: &Vertex, expected: glm::Vec4) {
+        assert!(
+            glm::distance(&vertex.tangent, &expected) < 1e-5,
+            "tangent {:?}, expected {:?}",
+            vertex.tangent,
+            expected
+        );
+    }
+
+    #[test]
+    fn quad_tangent_follows_u() {
+        let mut vertices = vec![
+            vertex(0.0, 0.0, 0.0, 0.0),
+            vertex(1.0, 0.0, 1.0, 0.0),
+            vertex(1.0, 1.0, 1.0, 1.0),
+            vertex(0.0, 1.0, 0.0, 1.0),
+        ];
+        let mut indices = vec![0, 1, 2, 0, 2, 3];
+
+        generate_tangents(&mut vertices, &mut indices);
+
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices, vec![0, 1, 2, 0, 2, 3]);
+        for vertex in vertices.iter() {
+            assert_tangent(vertex, glm::vec4(1.0, 0.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn mirrored_uvs_split_the_shared_vertices() {
+        // The second quad mirrors the first one along their shared edge, from vertex 1 to 2
+        let mut vertices = vec![
+            vertex(0.0, 0.0, 0.0, 0.0),
+            vertex(1.0, 0.0, 1.0, 0.0),
+            vertex(1.0, 1.0, 1.0, 1.0),
+            vertex(0.0, 1.0, 0.0, 1.0),
+            vertex(2.0, 0.0, 0.0, 0.0),
+            vertex(2.0, 1.0, 0.0, 1.0),
+        ];
+        let mut indices = vec![0, 1, 2, 0, 2, 3, 1, 4, 5, 1, 5, 2];
+
+        generate_tangents(&mut vertices, &mut indices);
+
+        assert_eq!(vertices.len(), 8);
+        assert_eq!(&indices[..6], &[0, 1, 2, 0, 2, 3]);
+        for &index in indices[..6].iter() {
+            assert_tangent(&vertices[index as usize], glm::vec4(1.0, 0.0, 0.0, 1.0));
+        }
+        for &index in indices[6..].iter() {
+            assert_tangent(&vertices[index as usize], glm::vec4(-1.0, 0.0, 0.0, -1.0));
+        }
+        assert!(indices[6] >= 6 && indices[11] >= 6);
+        assert_eq!(vertices[indices[6] as usize].pos, vertices[1].pos);
+        assert_eq!(vertices[indices[11] as usize].pos, vertices[2].pos);
+    }
+}
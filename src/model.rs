@@ -1,43 +1,113 @@
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use vulkan_ray_tracing::geometry_instance::{ImageBuffer, Material, Vertex};
+use vulkan_ray_tracing::geometry_instance::{
+    ImageBuffer, Material, Vertex, BRDF_GGX_METAL_ROUGH, BRDF_GLASS, BRDF_PHONG,
+};
 use vulkan_ray_tracing::glm;
 
+use crate::asset_database::{AssetDatabase, AssetGuid};
+use crate::mesh_diagnostics::{self, MeshDiagnostics};
+use crate::mesh_normals;
+use crate::mesh_optimizer;
+use crate::mesh_tangents;
+use crate::texture_compression;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+// Block compression of the textures as they are loaded, for assets that are not shipped
+// compressed. Gray data textures get BC4 at any quality below Full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureQuality {
+    // Uncompressed RGBA
+    Full,
+    // BC7, a quarter of the memory
+    High,
+    // BC1 for opaque textures, an eighth of the memory
+    Low,
+}
+
+// Conversion of an asset to the Y up convention and units of the scene
+#[derive(Clone, Copy, Debug)]
+pub struct ModelLoadOptions {
+    pub scale: f32,
+    pub up_axis: UpAxis,
+    // OBJ files duplicate the vertices of every face, welding shrinks the buffers and the BLAS
+    pub weld_vertices: bool,
+    // Reorders triangles and vertices for the vertex cache and memory fetches
+    pub optimize_mesh: bool,
+    // Faces further apart than this angle, in radians, keep hard edges when normals are generated
+    pub crease_angle: f32,
+    // Ignored on devices that cannot sample BC textures
+    pub texture_quality: TextureQuality,
+}
+
+impl Default for ModelLoadOptions {
+    fn default() -> Self {
+        ModelLoadOptions {
+            scale: 1.0,
+            up_axis: UpAxis::Y,
+            weld_vertices: false,
+            optimize_mesh: false,
+            crease_angle: std::f32::consts::FRAC_PI_3,
+            texture_quality: TextureQuality::Full,
+        }
+    }
+}
+
+impl ModelLoadOptions {
+    // Rotation part of the conversion, Z up assets are rotated so that +Z becomes +Y
+    fn axis_conversion(&self) -> glm::Mat4 {
+        match self.up_axis {
+            UpAxis::Y => glm::identity(),
+            UpAxis::Z => glm::rotation(-std::f32::consts::FRAC_PI_2, &glm::vec3(1.0, 0.0, 0.0)),
+        }
+    }
+
+    pub fn matrix(&self) -> glm::Mat4 {
+        glm::scale(
+            &self.axis_conversion(),
+            &glm::vec3(self.scale, self.scale, self.scale),
+        )
+    }
+
+    // For transforms authored in the asset space, applied to vertices that were already converted
+    pub fn convert_transform(&self, transform: &glm::Mat4) -> glm::Mat4 {
+        let matrix = self.matrix();
+        matrix * transform * glm::inverse(&matrix)
+    }
+}
+
 pub struct Model {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
     pub materials: Vec<Material>,
     pub textures: Vec<ImageBuffer>,
+    // One entry per mesh of the file, empty for models built in code
+    pub diagnostics: Vec<MeshDiagnostics>,
 }
 
 impl Model {
     pub fn new(filename: &Path) -> Model {
+        Self::new_with_options(filename, &ModelLoadOptions::default())
+    }
+
+    pub fn new_with_options(filename: &Path, options: &ModelLoadOptions) -> Model {
+        let _span = tracing::info_span!("load_obj", file = %filename.display()).entered();
         let (models, mats) = tobj::load_obj(filename).expect("Cannot load model");
 
         let mut indices = vec![];
         let mut vertices = vec![];
         let mut materials = vec![];
         let mut textures = vec![];
+        let mut diagnostics = vec![];
 
         for mat in mats.iter() {
-            let mut texture_id = -1;
-            if !mat.diffuse_texture.is_empty() {
-                let texture = Self::load_texture(&mat.diffuse_texture);
-                textures.push(texture);
-                texture_id = textures.len() as i32 - 1;
-            }
-
-            let material = Material {
-                ambient: glm::make_vec3(&mat.ambient),
-                diffuse: glm::make_vec3(&mat.diffuse),
-                specular: glm::make_vec3(&mat.specular),
-                dissolve: mat.dissolve,
-                ior: mat.optical_density,
-                illum: mat.illumination_model.unwrap_or(0) as i32,
-                texture_id,
-                ..Material::default()
-            };
-            materials.push(material);
+            materials.push(Self::load_material(mat, &mut textures));
         }
 
         if materials.is_empty() {
@@ -45,64 +115,421 @@ impl Model {
         }
 
         for model in models.iter() {
-            let current_indices: Vec<u32> = model
-                .mesh
-                .indices
-                .iter()
-                .map(|x| x + vertices.len() as u32)
-                .collect();
-            indices.extend_from_slice(&current_indices);
-
-            vertices.reserve(model.mesh.positions.len() / 3);
-            for v in 0..model.mesh.positions.len() / 3 {
-                let tex_coord = if model.mesh.texcoords.is_empty() {
-                    glm::vec2(0.0, 1.0)
-                } else {
-                    glm::vec2(
-                        model.mesh.texcoords[2 * v],
-                        1.0 - model.mesh.texcoords[2 * v + 1],
-                    )
-                };
-
-                let vertex = Vertex {
-                    pos: glm::vec3(
-                        model.mesh.positions[3 * v],
-                        model.mesh.positions[3 * v + 1],
-                        model.mesh.positions[3 * v + 2],
-                    ),
-                    nrm: glm::vec3(
-                        model.mesh.normals[3 * v],
-                        model.mesh.normals[3 * v + 1],
-                        model.mesh.normals[3 * v + 2],
-                    ),
-                    color: glm::vec3(1.0, 1.0, 1.0),
-                    tex_coord,
-                    mat_id: model.mesh.material_id.unwrap_or(0) as i32,
-                };
-
-                vertices.push(vertex);
-            }
+            diagnostics.push(Self::append_mesh(
+                &model.name,
+                &model.mesh,
+                model.mesh.material_id.unwrap_or(0) as i32,
+                options.crease_angle,
+                &mut vertices,
+                &mut indices,
+            ));
         }
 
-        Model {
+        let mut model = Model {
             vertices,
             indices,
             materials,
             textures,
+            diagnostics,
+        };
+        model.apply_load_options(options);
+        model.log_diagnostics();
+        model
+    }
+
+    pub fn apply_load_options(&mut self, options: &ModelLoadOptions) {
+        if options.scale != 1.0 || options.up_axis != UpAxis::Y {
+            self.convert_axis(options);
+        }
+        // Generated after the conversion, a mirroring scale changes the tangent sign
+        mesh_tangents::generate_tangents(&mut self.vertices, &mut self.indices);
+
+        if options.weld_vertices {
+            mesh_optimizer::weld_vertices(&mut self.vertices, &mut self.indices);
+        }
+        if options.optimize_mesh {
+            mesh_optimizer::optimize_vertex_cache(&mut self.indices, self.vertices.len());
+            mesh_optimizer::optimize_vertex_fetch(&mut self.vertices, &mut self.indices);
+        }
+        for texture in self.textures.iter_mut() {
+            texture_compression::compress_image(texture, options.texture_quality);
         }
     }
 
-    fn load_texture(filename: &str) -> ImageBuffer {
-        let path = Path::new("assets/textures/").join(filename);
-        let image = image::open(path).unwrap().to_rgba();
+    // Normals are only rotated, the scale is the same on every axis
+    fn convert_axis(&mut self, options: &ModelLoadOptions) {
+        let matrix = options.matrix();
+        let rotation = options.axis_conversion() * options.scale.signum();
+        for vertex in self.vertices.iter_mut() {
+            vertex.pos = (matrix * glm::vec4(vertex.pos.x, vertex.pos.y, vertex.pos.z, 1.0)).xyz();
+            vertex.nrm =
+                (rotation * glm::vec4(vertex.nrm.x, vertex.nrm.y, vertex.nrm.z, 0.0)).xyz();
+        }
+        for diagnostics in self.diagnostics.iter_mut() {
+            diagnostics.transform(&matrix);
+        }
+
+        // A negative scale mirrors the mesh, the winding has to follow
+        if options.scale < 0.0 {
+            for triangle in self.indices.chunks_mut(3) {
+                triangle.swap(1, 2);
+            }
+        }
+    }
+
+    fn log_diagnostics(&self) {
+        for diagnostics in self.diagnostics.iter() {
+            diagnostics.log();
+        }
+    }
+
+    fn load_material(mat: &tobj::Material, textures: &mut Vec<ImageBuffer>) -> Material {
+        let mut texture_id = -1;
+        if !mat.diffuse_texture.is_empty() {
+            if let Some(texture) = Self::load_texture(&mat.diffuse_texture) {
+                textures.push(texture);
+                texture_id = textures.len() as i32 - 1;
+            }
+        }
+
+        let pbr_param = |name: &str| {
+            mat.unknown_param
+                .get(name)
+                .and_then(|value| value.trim().parse::<f32>().ok())
+        };
+        let (roughness, metallic) = (pbr_param("Pr"), pbr_param("Pm"));
+        // The refraction illumination models, then the PBR extension of the MTL format
+        let brdf = match (mat.illumination_model, roughness, metallic) {
+            (Some(6), _, _) | (Some(7), _, _) => BRDF_GLASS,
+            (_, None, None) => BRDF_PHONG,
+            _ => BRDF_GGX_METAL_ROUGH,
+        };
+        let default = Material::default();
+
+        Material {
+            ambient: glm::make_vec3(&mat.ambient),
+            diffuse: glm::make_vec3(&mat.diffuse),
+            specular: glm::make_vec3(&mat.specular),
+            dissolve: mat.dissolve,
+            ior: mat.optical_density,
+            illum: mat.illumination_model.unwrap_or(0) as i32,
+            texture_id,
+            brdf,
+            roughness: roughness.unwrap_or(default.roughness),
+            metallic: metallic.unwrap_or(default.metallic),
+            ..default
+        }
+    }
+
+    fn append_mesh(
+        name: &str,
+        mesh: &tobj::Mesh,
+        mat_id: i32,
+        crease_angle: f32,
+        vertices: &mut Vec<Vertex>,
+        indices: &mut Vec<u32>,
+    ) -> MeshDiagnostics {
+        let has_normals = mesh.normals.len() == mesh.positions.len();
+
+        let mut mesh_vertices = Vec::with_capacity(mesh.positions.len() / 3);
+        for v in 0..mesh.positions.len() / 3 {
+            let tex_coord = if mesh.texcoords.is_empty() {
+                glm::vec2(0.0, 1.0)
+            } else {
+                glm::vec2(mesh.texcoords[2 * v], 1.0 - mesh.texcoords[2 * v + 1])
+            };
+
+            let nrm = if has_normals {
+                glm::vec3(
+                    mesh.normals[3 * v],
+                    mesh.normals[3 * v + 1],
+                    mesh.normals[3 * v + 2],
+                )
+            } else {
+                glm::zero()
+            };
+
+            let vertex = Vertex {
+                pos: glm::vec3(
+                    mesh.positions[3 * v],
+                    mesh.positions[3 * v + 1],
+                    mesh.positions[3 * v + 2],
+                ),
+                nrm,
+                color: glm::vec3(1.0, 1.0, 1.0),
+                tex_coord,
+                mat_id,
+                tangent: glm::zero(),
+            };
+
+            mesh_vertices.push(vertex);
+        }
+
+        let diagnostics = mesh_diagnostics::diagnose(
+            name,
+            &mesh_vertices,
+            &mesh.indices,
+            !has_normals,
+            mesh.texcoords.is_empty(),
+        );
+
+        let mut mesh_indices = mesh.indices.clone();
+        if !has_normals {
+            mesh_normals::generate_normals(&mut mesh_vertices, &mut mesh_indices, crease_angle);
+        }
+
+        let offset = vertices.len() as u32;
+        indices.extend(mesh_indices.iter().map(|x| x + offset));
+        vertices.extend(mesh_vertices);
+        diagnostics
+    }
+
+    fn texture_path(filename: &str) -> PathBuf {
+        Path::new("assets/textures/").join(filename)
+    }
+
+    // Only diffuse textures are loaded, which hold colors. A texture that cannot be read, like one
+    // being saved while a model reloads, leaves the material untextured.
+    fn load_texture(filename: &str) -> Option<ImageBuffer> {
+        let path = Self::texture_path(filename);
+        let image = match image::open(&path) {
+            Ok(image) => image.to_rgba(),
+            Err(err) => {
+                log::error!("Cannot load texture {}: {}", path.display(), err);
+                return None;
+            }
+        };
         let width = image.width();
         let height = image.height();
 
-        ImageBuffer {
+        Some(ImageBuffer {
             pixels: image.into_raw(),
             tex_width: width,
             tex_height: height,
             tex_channels: 1,
+            srgb: true,
+            compression: None,
+        })
+    }
+}
+
+// Splits an OBJ file into one model per material, so big scenes can be uploaded piece by piece
+pub struct ModelLoader {
+    models: Vec<tobj::Model>,
+    materials: Vec<tobj::Material>,
+    groups: Vec<Vec<usize>>,
+    next_group: usize,
+    dependencies: Vec<PathBuf>,
+    options: ModelLoadOptions,
+    cache: Option<ModelCache>,
+}
+
+struct ModelCache {
+    database: AssetDatabase,
+    guid: AssetGuid,
+    content_hash: u64,
+    // The models are read back instead of processing the source
+    hit: bool,
+}
+
+impl ModelLoader {
+    pub fn new(filename: &Path) -> Result<ModelLoader, tobj::LoadError> {
+        Self::new_with_options(filename, ModelLoadOptions::default())
+    }
+
+    // Fails on a file that cannot be parsed, like one that is still being saved
+    pub fn new_with_options(
+        filename: &Path,
+        options: ModelLoadOptions,
+    ) -> Result<ModelLoader, tobj::LoadError> {
+        let _span = tracing::info_span!("parse_obj", file = %filename.display()).entered();
+        let (models, materials) = tobj::load_obj(filename)?;
+
+        let mut groups: Vec<Vec<usize>> = vec![vec![]; materials.len().max(1)];
+        for (index, model) in models.iter().enumerate() {
+            let material_id = model.mesh.material_id.unwrap_or(0).min(groups.len() - 1);
+            groups[material_id].push(index);
+        }
+        groups.retain(|group| !group.is_empty());
+
+        let dependencies = Self::find_dependencies(filename, &materials);
+
+        Ok(ModelLoader {
+            models,
+            materials,
+            groups,
+            next_group: 0,
+            dependencies,
+            options,
+            cache: None,
+        })
+    }
+
+    // Reuses the models a previous run processed, as long as the sources and options are the same
+    pub fn new_with_cache(
+        filename: &Path,
+        options: ModelLoadOptions,
+        database: &AssetDatabase,
+    ) -> Result<ModelLoader, tobj::LoadError> {
+        let guid = AssetGuid::from_path(filename);
+        if let Some(cached_model) = database.find_model(guid, &options) {
+            return Ok(ModelLoader {
+                models: vec![],
+                materials: vec![],
+                groups: vec![vec![]; cached_model.group_count],
+                next_group: 0,
+                dependencies: cached_model.dependencies,
+                options,
+                cache: Some(ModelCache {
+                    database: database.clone(),
+                    guid,
+                    content_hash: cached_model.content_hash,
+                    hit: true,
+                }),
+            });
+        }
+
+        let mut model_loader = Self::new_with_options(filename, options)?;
+        model_loader.cache = Some(ModelCache {
+            database: database.clone(),
+            guid,
+            content_hash: AssetDatabase::content_hash(&model_loader.dependencies, &options),
+            hit: false,
+        });
+        Ok(model_loader)
+    }
+
+    pub fn options(&self) -> &ModelLoadOptions {
+        &self.options
+    }
+
+    // The OBJ file, its material libraries and the textures they reference
+    pub fn dependencies(&self) -> &[PathBuf] {
+        &self.dependencies
+    }
+
+    fn find_dependencies(filename: &Path, materials: &[tobj::Material]) -> Vec<PathBuf> {
+        let mut dependencies = vec![filename.to_path_buf()];
+
+        let directory = filename.parent().unwrap_or_else(|| Path::new(""));
+        if let Ok(content) = fs::read_to_string(filename) {
+            for line in content.lines() {
+                if let Some(library) = line.trim().strip_prefix("mtllib ") {
+                    dependencies.push(directory.join(library.trim()));
+                }
+            }
+        }
+
+        for material in materials.iter() {
+            if !material.diffuse_texture.is_empty() {
+                let texture = Model::texture_path(&material.diffuse_texture);
+                if !dependencies.contains(&texture) {
+                    dependencies.push(texture);
+                }
+            }
+        }
+
+        dependencies
+    }
+
+    fn load_group(&self, group_index: usize) -> Model {
+        let group = &self.groups[group_index];
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        let mut textures = vec![];
+        let mut diagnostics = vec![];
+
+        let material = match self.models[group[0]].mesh.material_id {
+            Some(id) if id < self.materials.len() => {
+                Model::load_material(&self.materials[id], &mut textures)
+            }
+            _ => Material::default(),
+        };
+
+        for &index in group.iter() {
+            diagnostics.push(Model::append_mesh(
+                &self.models[index].name,
+                &self.models[index].mesh,
+                0,
+                self.options.crease_angle,
+                &mut vertices,
+                &mut indices,
+            ));
+        }
+
+        let mut model = Model {
+            vertices,
+            indices,
+            materials: vec![material],
+            textures,
+            diagnostics,
+        };
+        model.apply_load_options(&self.options);
+        model.log_diagnostics();
+        model
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.groups.is_empty() {
+            1.0
+        } else {
+            self.next_group as f32 / self.groups.len() as f32
+        }
+    }
+}
+
+impl Iterator for ModelLoader {
+    type Item = Model;
+
+    fn next(&mut self) -> Option<Model> {
+        let group_index = self.next_group;
+        let _span = tracing::info_span!("load_model_group", group = group_index).entered();
+        if group_index >= self.groups.len() {
+            return None;
+        }
+        self.next_group += 1;
+
+        if let Some(cache) = self.cache.as_mut().filter(|cache| cache.hit) {
+            if let Some(model) =
+                cache
+                    .database
+                    .read_model(cache.guid, cache.content_hash, group_index)
+            {
+                model.log_diagnostics();
+                return Some(model);
+            }
+
+            // A cache file is missing or damaged, the rest comes from the source
+            cache.hit = false;
+            let source = match Self::new_with_options(&self.dependencies[0], self.options) {
+                Ok(source) => source,
+                Err(err) => {
+                    log::error!("Cannot load {}: {:?}", self.dependencies[0].display(), err);
+                    return None;
+                }
+            };
+            self.models = source.models;
+            self.materials = source.materials;
+            self.groups = source.groups;
+            if group_index >= self.groups.len() {
+                return None;
+            }
+        }
+
+        let model = self.load_group(group_index);
+        if let Some(cache) = self.cache.as_ref() {
+            cache
+                .database
+                .write_model(cache.guid, cache.content_hash, group_index, &model);
+            if self.next_group == self.groups.len() {
+                cache.database.write_manifest(
+                    cache.guid,
+                    cache.content_hash,
+                    self.groups.len(),
+                    &self.dependencies,
+                );
+            }
         }
+        Some(model)
     }
 }
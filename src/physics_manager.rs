@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use rapier3d::na;
+use rapier3d::prelude::*;
+use vulkan_ray_tracing::glm;
+
+use crate::model::Model;
+use crate::scene::InstanceHandle;
+
+// Shape of the collider of a body, in the space of the model. The scale of the instance
+// transform is applied to it when the body is added.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColliderShape {
+    Cuboid {
+        half_extents: glm::Vec3,
+    },
+    Ball {
+        radius: f32,
+    },
+    ConvexHull {
+        points: Vec<glm::Vec3>,
+    },
+    TriMesh {
+        vertices: Vec<glm::Vec3>,
+        indices: Vec<[u32; 3]>,
+    },
+}
+
+impl ColliderShape {
+    // Every triangle of the model, for fixed bodies such as the ground or the level
+    pub fn trimesh(model: &Model) -> Self {
+        ColliderShape::TriMesh {
+            vertices: model.vertices.iter().map(|vertex| vertex.pos).collect(),
+            indices: model
+                .indices
+                .chunks_exact(3)
+                .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+                .collect(),
+        }
+    }
+
+    // Convex hull of the vertices of the model, for dynamic bodies
+    pub fn convex_hull(model: &Model) -> Self {
+        ColliderShape::ConvexHull {
+            points: model.vertices.iter().map(|vertex| vertex.pos).collect(),
+        }
+    }
+
+    fn build(&self, scale: &glm::Vec3) -> Option<ColliderBuilder> {
+        let point = |p: &glm::Vec3| point![p.x * scale.x, p.y * scale.y, p.z * scale.z];
+        match self {
+            ColliderShape::Cuboid { half_extents } => Some(ColliderBuilder::cuboid(
+                half_extents.x * scale.x,
+                half_extents.y * scale.y,
+                half_extents.z * scale.z,
+            )),
+            // Stays a ball, scaled by the largest axis
+            ColliderShape::Ball { radius } => {
+                Some(ColliderBuilder::ball(radius * glm::comp_max(scale)))
+            }
+            ColliderShape::ConvexHull { points } => {
+                let points: Vec<Point<Real>> = points.iter().map(point).collect();
+                ColliderBuilder::convex_hull(&points)
+            }
+            ColliderShape::TriMesh { vertices, indices } => Some(ColliderBuilder::trimesh(
+                vertices.iter().map(point).collect(),
+                indices.clone(),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyType {
+    // Moved by the simulation, its instance follows it
+    Dynamic,
+    // Never moves, other bodies collide with it
+    Fixed,
+    // Moved by the application with set_kinematic_target, pushes the dynamic bodies
+    Kinematic,
+}
+
+struct Body {
+    handle: RigidBodyHandle,
+    // Applied to the instance on top of the position of the body, which has no scale
+    scale: glm::Vec3,
+}
+
+// Rigid bodies attached to instances of the scene. The application steps the simulation at every
+// fixed update and the instances follow the moving bodies, blended between the fixed updates.
+pub struct PhysicsManager {
+    gravity: Vector<Real>,
+    integration_parameters: IntegrationParameters,
+    pipeline: PhysicsPipeline,
+    islands: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    rigid_bodies: RigidBodySet,
+    colliders: ColliderSet,
+    impulse_joints: ImpulseJointSet,
+    multibody_joints: MultibodyJointSet,
+    ccd_solver: CCDSolver,
+    bodies: HashMap<InstanceHandle, Body>,
+}
+
+impl Default for PhysicsManager {
+    fn default() -> Self {
+        PhysicsManager {
+            gravity: vector![0.0, -9.81, 0.0],
+            integration_parameters: IntegrationParameters::default(),
+            pipeline: PhysicsPipeline::new(),
+            islands: IslandManager::new(),
+            broad_phase: BroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            rigid_bodies: RigidBodySet::new(),
+            colliders: ColliderSet::new(),
+            impulse_joints: ImpulseJointSet::new(),
+            multibody_joints: MultibodyJointSet::new(),
+            ccd_solver: CCDSolver::new(),
+            bodies: HashMap::new(),
+        }
+    }
+}
+
+impl PhysicsManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_gravity(&mut self, gravity: glm::Vec3) {
+        self.gravity = vector![gravity.x, gravity.y, gravity.z];
+    }
+
+    // Attaches a body to the instance, starting at its transform. Replaces the previous body of
+    // the instance, returns false if the collider cannot be built, e.g. the convex hull of flat
+    // geometry.
+    pub fn add_body(
+        &mut self,
+        instance: InstanceHandle,
+        transform: &glm::Mat4,
+        shape: &ColliderShape,
+        body_type: BodyType,
+    ) -> bool {
+        let (position, scale) = decompose(transform);
+        let collider = match shape.build(&scale) {
+            Some(collider) => collider.build(),
+            None => {
+                log::error!("Cannot build the collider of {:?}", instance);
+                return false;
+            }
+        };
+
+        self.remove_body(instance);
+        let rigid_body = match body_type {
+            BodyType::Dynamic => RigidBodyBuilder::dynamic(),
+            BodyType::Fixed => RigidBodyBuilder::fixed(),
+            BodyType::Kinematic => RigidBodyBuilder::kinematic_position_based(),
+        }
+        .position(position)
+        .build();
+        let handle = self.rigid_bodies.insert(rigid_body);
+        self.colliders
+            .insert_with_parent(collider, handle, &mut self.rigid_bodies);
+        self.bodies.insert(instance, Body { handle, scale });
+        true
+    }
+
+    // Returns false if the instance has no body
+    pub fn remove_body(&mut self, instance: InstanceHandle) -> bool {
+        match self.bodies.remove(&instance) {
+            Some(body) => {
+                self.rigid_bodies.remove(
+                    body.handle,
+                    &mut self.islands,
+                    &mut self.colliders,
+                    &mut self.impulse_joints,
+                    &mut self.multibody_joints,
+                    true,
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn has_body(&self, instance: InstanceHandle) -> bool {
+        self.bodies.contains_key(&instance)
+    }
+
+    pub fn set_linear_velocity(&mut self, instance: InstanceHandle, velocity: glm::Vec3) -> bool {
+        self.with_rigid_body(instance, |rigid_body| {
+            rigid_body.set_linvel(vector![velocity.x, velocity.y, velocity.z], true)
+        })
+    }
+
+    pub fn apply_impulse(&mut self, instance: InstanceHandle, impulse: glm::Vec3) -> bool {
+        self.with_rigid_body(instance, |rigid_body| {
+            rigid_body.apply_impulse(vector![impulse.x, impulse.y, impulse.z], true)
+        })
+    }
+
+    // Where a kinematic body is at the end of the next step, the scale of the transform is
+    // ignored
+    pub fn set_kinematic_target(
+        &mut self,
+        instance: InstanceHandle,
+        transform: &glm::Mat4,
+    ) -> bool {
+        let (position, _) = decompose(transform);
+        self.with_rigid_body(instance, |rigid_body| {
+            rigid_body.set_next_kinematic_position(position)
+        })
+    }
+
+    fn with_rigid_body<F>(&mut self, instance: InstanceHandle, update: F) -> bool
+    where
+        F: FnOnce(&mut RigidBody),
+    {
+        let rigid_bodies = &mut self.rigid_bodies;
+        let rigid_body = self
+            .bodies
+            .get(&instance)
+            .and_then(|body| rigid_bodies.get_mut(body.handle));
+        match rigid_body {
+            Some(rigid_body) => {
+                update(rigid_body);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // One step of the simulation, returns the new transforms of the instances whose bodies are
+    // moving
+    pub(crate) fn step(&mut self, time_step: f32) -> Vec<(InstanceHandle, glm::Mat4)> {
+        let _span = tracing::info_span!("physics_step").entered();
+        self.integration_parameters.dt = time_step;
+        self.pipeline.step(
+            &self.gravity,
+            &self.integration_parameters,
+            &mut self.islands,
+            &mut self.broad_phase,
+            &mut self.narrow_phase,
+            &mut self.rigid_bodies,
+            &mut self.colliders,
+            &mut self.impulse_joints,
+            &mut self.multibody_joints,
+            &mut self.ccd_solver,
+            None,
+            &(),
+            &(),
+        );
+
+        self.bodies
+            .iter()
+            .filter_map(|(&instance, body)| {
+                let rigid_body = self.rigid_bodies.get(body.handle)?;
+                if rigid_body.is_fixed() || rigid_body.is_sleeping() {
+                    return None;
+                }
+                Some((instance, compose(rigid_body.position(), &body.scale)))
+            })
+            .collect()
+    }
+}
+
+// Position without scale and the scale of the axes, the transform should not be skewed
+fn decompose(transform: &glm::Mat4) -> (Isometry<Real>, glm::Vec3) {
+    let axis = |column: usize| {
+        glm::vec3(
+            transform[(0, column)],
+            transform[(1, column)],
+            transform[(2, column)],
+        )
+    };
+    let axes = [axis(0), axis(1), axis(2)];
+    let scale = glm::vec3(
+        glm::length(&axes[0]).max(1e-6),
+        glm::length(&axes[1]).max(1e-6),
+        glm::length(&axes[2]).max(1e-6),
+    );
+
+    let mut rotation = na::Matrix3::zeros();
+    for (column, axis) in axes.iter().enumerate() {
+        for row in 0..3 {
+            rotation[(row, column)] = axis[row] / scale[column];
+        }
+    }
+    let rotation =
+        na::UnitQuaternion::from_rotation_matrix(&na::Rotation3::from_matrix_unchecked(rotation));
+    let translation =
+        na::Translation3::new(transform[(0, 3)], transform[(1, 3)], transform[(2, 3)]);
+
+    (Isometry::from_parts(translation, rotation), scale)
+}
+
+fn compose(position: &Isometry<Real>, scale: &glm::Vec3) -> glm::Mat4 {
+    // Both are column major
+    glm::make_mat4(position.to_homogeneous().as_slice()) * glm::scaling(scale)
+}
@@ -0,0 +1,46 @@
+// The types an application is written against, `use r2r2::prelude::*` is enough for most of
+// them. What is not exported here, the Vulkan objects of the renderer in particular, can change
+// from one version to the next.
+
+pub use crate::application_manager::{ApplicationManager, ApplicationManagerBuilder};
+pub use crate::batch_render::{load_batch_cameras, BatchCamera, BatchImage};
+pub use crate::camera_manager::{Camera, CameraProperties, CameraType, DepthMode, ViewportCamera};
+pub use crate::event_bus::{EngineEvent, EventBus};
+pub use crate::frame_pacer::FrameStats;
+pub use crate::handle::Handle;
+pub use crate::input_manager::{InputManager, KeyBinding, ScanCode, VirtualKeyCode};
+pub use crate::light_manager::{LightHandle, LightManager};
+pub use crate::model::{Model, ModelLoadOptions, TextureQuality, UpAxis};
+pub use crate::primitives;
+pub use crate::render_manager::{RenderHandle, SwapchainInfo};
+pub use crate::render_presets::{load_render_presets, save_render_presets};
+pub use crate::scene::{Instance, InstanceHandle, Scene};
+pub use crate::screen_anchors::{ScreenAnchor, ScreenAnchorHandle, ScreenPosition};
+pub use crate::time_manager::TimeManager;
+
+#[cfg(feature = "audio")]
+pub use crate::audio_manager::{AudioManager, Emitter, SoundHandle, VoiceHandle};
+#[cfg(feature = "physics")]
+pub use crate::physics_manager::{BodyType, ColliderShape, PhysicsManager};
+
+pub use vulkan_ray_tracing::background::{Background, EnvironmentMap};
+pub use vulkan_ray_tracing::geometry_instance::{ImageBuffer, Material, Vertex};
+pub use vulkan_ray_tracing::glm;
+pub use vulkan_ray_tracing::instance_culling::CullingStats;
+pub use vulkan_ray_tracing::instance_data::InstanceUserData;
+pub use vulkan_ray_tracing::irradiance_volume::ProbeGrid;
+pub use vulkan_ray_tracing::light::{Light, LightSamplingStrategy, LightType};
+pub use vulkan_ray_tracing::render_settings::{
+    Aov, AovSettings, ClipPlane, FogSettings, LatencyMode, MotionBlurSettings, PathTracingSettings,
+    ReflectionSettings, RenderPreset, RenderSettings, SeedPolicy, WatchdogSettings,
+};
+pub use vulkan_ray_tracing::renderer_stats::{GeometryMemory, RendererStats};
+pub use vulkan_ray_tracing::shader_permutation::ShaderDefines;
+pub use vulkan_ray_tracing::sky::Sky;
+pub use vulkan_ray_tracing::text_overlay::TextLabel;
+pub use vulkan_ray_tracing::viewport::{TraceDispatch, Viewport};
+pub use vulkan_ray_tracing::vulkan_bootstrap::errors::VulkanError;
+
+// For ApplicationManager::with_frame_commands, which records into the command buffer of the frame
+pub use vulkan_ray_tracing::ash::vk;
+pub use vulkan_ray_tracing::frame_context::FrameContext;
@@ -0,0 +1,204 @@
+use std::f32::consts::PI;
+
+use vulkan_ray_tracing::geometry_instance::{Material, Vertex};
+use vulkan_ray_tracing::glm;
+
+use crate::mesh_tangents;
+use crate::model::Model;
+
+pub fn plane(width: f32, depth: f32) -> Model {
+    let half_width = width / 2.0;
+    let half_depth = depth / 2.0;
+    let normal = glm::vec3(0.0, 1.0, 0.0);
+
+    let vertices = vec![
+        vertex(
+            glm::vec3(-half_width, 0.0, half_depth),
+            normal,
+            glm::vec2(0.0, 1.0),
+        ),
+        vertex(
+            glm::vec3(half_width, 0.0, half_depth),
+            normal,
+            glm::vec2(1.0, 1.0),
+        ),
+        vertex(
+            glm::vec3(half_width, 0.0, -half_depth),
+            normal,
+            glm::vec2(1.0, 0.0),
+        ),
+        vertex(
+            glm::vec3(-half_width, 0.0, -half_depth),
+            normal,
+            glm::vec2(0.0, 0.0),
+        ),
+    ];
+    let indices = vec![0, 1, 2, 0, 2, 3];
+
+    from_mesh(vertices, indices)
+}
+
+pub fn cube(size: f32) -> Model {
+    let half = size / 2.0;
+    // One face per axis direction, each with its own vertices so normals stay flat
+    let faces = [
+        (glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, 0.0, -1.0)),
+        (glm::vec3(-1.0, 0.0, 0.0), glm::vec3(0.0, 0.0, 1.0)),
+        (glm::vec3(0.0, 1.0, 0.0), glm::vec3(1.0, 0.0, 0.0)),
+        (glm::vec3(0.0, -1.0, 0.0), glm::vec3(1.0, 0.0, 0.0)),
+        (glm::vec3(0.0, 0.0, 1.0), glm::vec3(1.0, 0.0, 0.0)),
+        (glm::vec3(0.0, 0.0, -1.0), glm::vec3(-1.0, 0.0, 0.0)),
+    ];
+
+    let mut vertices = Vec::with_capacity(24);
+    let mut indices = Vec::with_capacity(36);
+
+    for (normal, right) in faces.iter() {
+        let up = normal.cross(right);
+        let center = normal * half;
+        let base = vertices.len() as u32;
+
+        vertices.push(vertex(
+            center - right * half - up * half,
+            *normal,
+            glm::vec2(0.0, 1.0),
+        ));
+        vertices.push(vertex(
+            center + right * half - up * half,
+            *normal,
+            glm::vec2(1.0, 1.0),
+        ));
+        vertices.push(vertex(
+            center + right * half + up * half,
+            *normal,
+            glm::vec2(1.0, 0.0),
+        ));
+        vertices.push(vertex(
+            center - right * half + up * half,
+            *normal,
+            glm::vec2(0.0, 0.0),
+        ));
+
+        indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+
+    from_mesh(vertices, indices)
+}
+
+pub fn sphere(radius: f32, sectors: u32, stacks: u32) -> Model {
+    let sectors = sectors.max(3);
+    let stacks = stacks.max(2);
+
+    let mut vertices = Vec::with_capacity(((sectors + 1) * (stacks + 1)) as usize);
+    let mut indices = Vec::with_capacity((sectors * stacks * 6) as usize);
+
+    for stack in 0..=stacks {
+        let v = stack as f32 / stacks as f32;
+        let phi = v * PI;
+
+        for sector in 0..=sectors {
+            let u = sector as f32 / sectors as f32;
+            let theta = u * 2.0 * PI;
+
+            let normal = glm::vec3(phi.sin() * theta.cos(), phi.cos(), -phi.sin() * theta.sin());
+            vertices.push(vertex(normal * radius, normal, glm::vec2(u, v)));
+        }
+    }
+
+    for stack in 0..stacks {
+        for sector in 0..sectors {
+            let top = stack * (sectors + 1) + sector;
+            let bottom = top + sectors + 1;
+
+            if stack != 0 {
+                indices.extend_from_slice(&[top, bottom, top + 1]);
+            }
+            if stack != stacks - 1 {
+                indices.extend_from_slice(&[top + 1, bottom, bottom + 1]);
+            }
+        }
+    }
+
+    from_mesh(vertices, indices)
+}
+
+pub fn cylinder(radius: f32, height: f32, sectors: u32) -> Model {
+    let sectors = sectors.max(3);
+    let half_height = height / 2.0;
+
+    let mut vertices = vec![];
+    let mut indices = vec![];
+
+    // Side
+    for sector in 0..=sectors {
+        let u = sector as f32 / sectors as f32;
+        let theta = u * 2.0 * PI;
+        let normal = glm::vec3(theta.cos(), 0.0, -theta.sin());
+
+        vertices.push(vertex(
+            normal * radius + glm::vec3(0.0, half_height, 0.0),
+            normal,
+            glm::vec2(u, 0.0),
+        ));
+        vertices.push(vertex(
+            normal * radius - glm::vec3(0.0, half_height, 0.0),
+            normal,
+            glm::vec2(u, 1.0),
+        ));
+    }
+
+    for sector in 0..sectors {
+        let top = sector * 2;
+        indices.extend_from_slice(&[top, top + 1, top + 2, top + 2, top + 1, top + 3]);
+    }
+
+    // Caps
+    for &(y, sign) in [(half_height, 1.0), (-half_height, -1.0)].iter() {
+        let normal = glm::vec3(0.0, sign, 0.0);
+        let center = vertices.len() as u32;
+        vertices.push(vertex(glm::vec3(0.0, y, 0.0), normal, glm::vec2(0.5, 0.5)));
+
+        for sector in 0..=sectors {
+            let theta = sector as f32 / sectors as f32 * 2.0 * PI;
+            let (sin, cos) = theta.sin_cos();
+            vertices.push(vertex(
+                glm::vec3(cos * radius, y, -sin * radius),
+                normal,
+                glm::vec2(0.5 + cos * 0.5, 0.5 - sin * 0.5),
+            ));
+        }
+
+        for sector in 0..sectors {
+            let current = center + 1 + sector;
+            if sign > 0.0 {
+                indices.extend_from_slice(&[center, current, current + 1]);
+            } else {
+                indices.extend_from_slice(&[center, current + 1, current]);
+            }
+        }
+    }
+
+    from_mesh(vertices, indices)
+}
+
+fn vertex(pos: glm::Vec3, nrm: glm::Vec3, tex_coord: glm::Vec2) -> Vertex {
+    Vertex {
+        pos,
+        nrm,
+        color: glm::vec3(1.0, 1.0, 1.0),
+        tex_coord,
+        mat_id: 0,
+        tangent: glm::zero(),
+    }
+}
+
+fn from_mesh(mut vertices: Vec<Vertex>, mut indices: Vec<u32>) -> Model {
+    mesh_tangents::generate_tangents(&mut vertices, &mut indices);
+    Model {
+        vertices,
+        indices,
+        materials: vec![Material::default()],
+        textures: vec![],
+        diagnostics: vec![],
+    }
+}
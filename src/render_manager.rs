@@ -1,35 +1,635 @@
+use std::fs;
+use std::io;
 use std::os::raw::c_void;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr::null;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use vulkan_bootstrap::debug::{DebugOptions, DebugSeverity, DebugType};
-use vulkan_bootstrap::extensions::DeviceExtensions;
-use vulkan_bootstrap::features::Features;
-use vulkan_bootstrap::vulkan_context::{VulkanContext, VulkanContextBuilder};
-use vulkan_bootstrap::windows::Win32Window;
+use vulkan_ray_tracing::vulkan_bootstrap::debug::{DebugOptions, DebugSeverity, DebugType};
+use vulkan_ray_tracing::vulkan_bootstrap::errors::VulkanError;
+use vulkan_ray_tracing::vulkan_bootstrap::extensions::DeviceExtensions;
+use vulkan_ray_tracing::vulkan_bootstrap::features::Features;
+use vulkan_ray_tracing::vulkan_bootstrap::vulkan_context::{VulkanContext, VulkanContextBuilder};
+use vulkan_ray_tracing::vulkan_bootstrap::windows::Win32Window;
 
-use vulkan_ray_tracing::geometry_instance::GeometryInstanceBuilder;
+use vulkan_ray_tracing::ash::vk;
+use vulkan_ray_tracing::background::{Background, EnvironmentMap};
+use vulkan_ray_tracing::bytemuck::Pod;
+use vulkan_ray_tracing::frame_constants::FrameParameters;
+use vulkan_ray_tracing::frame_context::FrameContext;
+use vulkan_ray_tracing::geometry_instance::{GeometryInstance, GeometryInstanceBuilder};
 use vulkan_ray_tracing::glm;
+use vulkan_ray_tracing::instance_culling::CullingStats;
+use vulkan_ray_tracing::instance_data::{InstanceUserData, ObjectId, INSTANCE_FLAG_HIGHLIGHTED};
+use vulkan_ray_tracing::irradiance_volume::{IrradianceVolume, ProbeGrid};
 use vulkan_ray_tracing::ray_tracing_pipeline::{RayTracingPipeline, RayTracingPipelineBuilder};
+use vulkan_ray_tracing::render_settings::{
+    Aov, AovSettings, ClipPlane, FogSettings, LatencyMode, MotionBlurSettings, PathTracingSettings,
+    ReflectionSettings, RenderPreset, RenderSettings, SeedPolicy, WatchdogSettings,
+    MAX_CLIP_PLANES,
+};
+use vulkan_ray_tracing::renderer_stats::{GeometryMemory, RendererStats};
+use vulkan_ray_tracing::shader_permutation::ShaderDefines;
+use vulkan_ray_tracing::text_overlay::{FontAtlas, TextLabel};
+use vulkan_ray_tracing::texture::TextureCompression;
+use vulkan_ray_tracing::viewport::{TraceDispatch, Viewport};
 
-use crate::camera_manager::CameraManager;
-use crate::model::Model;
+use crate::asset_database::AssetDatabase;
+use crate::asset_watcher::AssetWatcher;
+use crate::batch_render::Accumulation;
+use crate::camera_manager::{Camera, CameraManager, ViewportCamera};
+use crate::environment::load_environment_map;
+use crate::event_bus::{EngineEvent, EventBus};
+use crate::font_atlas::load_font_atlas;
+use crate::handle::Arena;
+use crate::irradiance_volume::{load_irradiance_volume, save_irradiance_volume};
+use crate::light_manager::{LightHandle, LightManager};
+use crate::mesh_diagnostics::MeshDiagnostics;
+use crate::model::{Model, ModelLoadOptions, ModelLoader, TextureQuality};
+use crate::scene::{Instance, InstanceHandle};
+use crate::screen_anchors::{ScreenAnchors, ScreenPosition};
+use crate::transform_interpolation::TransformHistory;
+use crate::turntable::{scene_bounds, Turntable, TurntableExport};
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
+pub enum RenderCommand {
+    AddModel {
+        model: Model,
+        handle: InstanceHandle,
+        source: Option<PathBuf>,
+        progress: Option<f32>,
+    },
+    ReloadModel {
+        source: PathBuf,
+        models: Vec<Model>,
+    },
+    WatchModel {
+        source: PathBuf,
+        dependencies: Vec<PathBuf>,
+        options: ModelLoadOptions,
+    },
+    RemoveInstance(InstanceHandle),
+    // Applied together, with a single rebuild of the top level structure
+    SetTransforms(Vec<(InstanceHandle, glm::Mat4)>),
+    // The data itself is read from the instances when the buffer is rebuilt
+    SetUserData(InstanceHandle),
+    SetSelected(Vec<InstanceHandle>),
+    SetClearColor(glm::Vec4),
+    SetBackground(Background),
+    SetEnvironmentMap(EnvironmentMap),
+    SetReflectionSettings(ReflectionSettings),
+    SetPathTracingSettings(PathTracingSettings),
+    SetFogSettings(FogSettings),
+    SetWatchdogSettings(WatchdogSettings),
+    SetRandomSeed(u32, SeedPolicy),
+    SetLatencyMode(LatencyMode),
+    SetAovs(AovSettings),
+    SetCheckerboard(bool),
+    SetMotionBlur(MotionBlurSettings),
+    SetRenderPreset(RenderPreset),
+    SetViewports(Vec<(Viewport, ViewportCamera)>),
+    SetTraceDispatch(TraceDispatch),
+    SetInstanceCulling(bool),
+    SetClipPlanes(Vec<ClipPlane>),
+    SetShaderDefines(ShaderDefines),
+    BakeIrradianceVolume {
+        grid: ProbeGrid,
+        sample_count: u32,
+        path: PathBuf,
+    },
+    SetIrradianceVolume(Option<IrradianceVolume>),
+    SetFontAtlas(FontAtlas),
+    SetTextLabels(Vec<TextLabel>),
+    SetDebugFlags(u32),
+    StartTurntable {
+        duration: Duration,
+        revolutions: f32,
+        // Directory and frame rate
+        export: Option<(PathBuf, u32)>,
+    },
+    StopTurntable,
+}
+
+// Can be sent to other threads, commands are applied by the render thread at the next frame
+#[derive(Clone)]
+pub struct RenderHandle {
+    sender: Sender<RenderCommand>,
+    instances: Arc<Mutex<Arena<Instance>>>,
+    asset_database: Option<AssetDatabase>,
+    texture_compression: bool,
+}
+
+impl RenderHandle {
+    pub fn load_model(&self, filename: &Path) -> JoinHandle<()> {
+        self.load_model_with_options(filename, ModelLoadOptions::default())
+    }
+
+    pub fn load_model_with_options(
+        &self,
+        filename: &Path,
+        options: ModelLoadOptions,
+    ) -> JoinHandle<()> {
+        let sender = self.sender.clone();
+        let instances = Arc::clone(&self.instances);
+        let filename = filename.to_path_buf();
+        let asset_database = self.asset_database.clone();
+        let options = supported_load_options(options, self.texture_compression);
+        thread::spawn(move || {
+            let model_loader =
+                match create_model_loader(&filename, options, asset_database.as_ref()) {
+                    Ok(model_loader) => model_loader,
+                    Err(err) => {
+                        log::error!("Cannot load {}: {:?}", filename.display(), err);
+                        return;
+                    }
+                };
+            let _ = sender.send(RenderCommand::WatchModel {
+                source: filename.clone(),
+                dependencies: model_loader.dependencies().to_vec(),
+                options,
+            });
+            stream_models(model_loader, filename, instances, sender)
+        })
+    }
+
+    pub fn add_model(&self, model: Model) -> InstanceHandle {
+        let handle = self.instances.lock().unwrap().insert(Instance::new(&model));
+        let _ = self.sender.send(RenderCommand::AddModel {
+            model,
+            handle,
+            source: None,
+            progress: None,
+        });
+        handle
+    }
+
+    // Returns false if the handle is stale
+    pub fn remove_instance(&self, handle: InstanceHandle) -> bool {
+        if self.instances.lock().unwrap().remove(handle).is_none() {
+            return false;
+        }
+        let _ = self.sender.send(RenderCommand::RemoveInstance(handle));
+        true
+    }
+
+    pub fn set_transform(&self, handle: InstanceHandle, transform: glm::Mat4) -> bool {
+        match self.instances.lock().unwrap().get_mut(handle) {
+            Some(instance) => {
+                instance.transform = transform;
+                instance.history = None;
+            }
+            None => return false,
+        }
+        let _ = self
+            .sender
+            .send(RenderCommand::SetTransforms(vec![(handle, transform)]));
+        true
+    }
+
+    // The transform is only sent by interpolate_transforms
+    pub(crate) fn set_fixed_transform(
+        &self,
+        handle: InstanceHandle,
+        transform: glm::Mat4,
+        step: u64,
+    ) -> bool {
+        self.set_fixed_transforms(&[(handle, transform)], step)
+            .is_empty()
+    }
+
+    // Under a single lock, returns the handles of the instances that no longer exist
+    pub(crate) fn set_fixed_transforms(
+        &self,
+        transforms: &[(InstanceHandle, glm::Mat4)],
+        step: u64,
+    ) -> Vec<InstanceHandle> {
+        let mut instances = self.instances.lock().unwrap();
+        let mut removed = vec![];
+        for &(handle, transform) in transforms.iter() {
+            match instances.get_mut(handle) {
+                Some(instance) => match instance.history.as_mut() {
+                    Some(history) => history.push(transform, step),
+                    None => {
+                        instance.history =
+                            Some(TransformHistory::new(instance.transform, transform, step))
+                    }
+                },
+                None => removed.push(handle),
+            }
+        }
+        removed
+    }
+
+    // One command for all the instances that moved this frame
+    pub(crate) fn interpolate_transforms(&self, render_alpha: f32, step: u64) {
+        let mut instances = self.instances.lock().unwrap();
+        let mut transforms = vec![];
+        for (handle, instance) in instances.iter_mut_with_handles() {
+            let transform = match instance.history.as_mut() {
+                Some(history) => history.sample(render_alpha, step),
+                None => None,
+            };
+            if let Some(transform) = transform {
+                instance.transform = transform;
+                transforms.push((handle, transform));
+            }
+        }
+        if !transforms.is_empty() {
+            let _ = self.sender.send(RenderCommand::SetTransforms(transforms));
+        }
+    }
+
+    pub fn set_user_data(&self, handle: InstanceHandle, user_data: InstanceUserData) -> bool {
+        match self.instances.lock().unwrap().get_mut(handle) {
+            Some(instance) => instance.user_data = user_data,
+            None => return false,
+        }
+        let _ = self.sender.send(RenderCommand::SetUserData(handle));
+        true
+    }
+
+    pub fn set_selected(&self, handles: &[InstanceHandle]) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetSelected(handles.to_vec()));
+    }
+
+    pub fn get_instance(&self, handle: InstanceHandle) -> Option<Instance> {
+        self.instances.lock().unwrap().get(handle).cloned()
+    }
+
+    pub fn diagnostics(&self) -> Vec<MeshDiagnostics> {
+        self.instances
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|instance| instance.diagnostics.iter().cloned())
+            .collect()
+    }
+
+    pub fn memory_breakdown(&self) -> Vec<(InstanceHandle, GeometryMemory)> {
+        self.instances
+            .lock()
+            .unwrap()
+            .iter_with_handles()
+            .filter_map(|(handle, instance)| {
+                instance
+                    .memory
+                    .as_ref()
+                    .map(|memory| (handle, memory.clone()))
+            })
+            .collect()
+    }
+
+    pub fn set_clear_color(&self, clear_color: glm::Vec4) {
+        let _ = self.sender.send(RenderCommand::SetClearColor(clear_color));
+    }
+
+    pub fn set_background(&self, background: Background) {
+        let _ = self.sender.send(RenderCommand::SetBackground(background));
+    }
+
+    // Decoded on a worker thread, shown once the background is set to the environment
+    pub fn load_environment_map(&self, filename: &Path) -> JoinHandle<()> {
+        let sender = self.sender.clone();
+        let filename = filename.to_path_buf();
+        thread::spawn(move || match load_environment_map(&filename) {
+            Ok(environment_map) => {
+                let _ = sender.send(RenderCommand::SetEnvironmentMap(environment_map));
+            }
+            Err(err) => log::error!("Cannot load the environment map {:?}: {}", filename, err),
+        })
+    }
+
+    pub fn set_reflection_settings(&self, reflection_settings: ReflectionSettings) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetReflectionSettings(reflection_settings));
+    }
+
+    pub fn set_path_tracing_settings(&self, path_tracing_settings: PathTracingSettings) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetPathTracingSettings(path_tracing_settings));
+    }
+
+    pub fn set_fog_settings(&self, fog_settings: FogSettings) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetFogSettings(fog_settings));
+    }
+
+    pub fn set_watchdog_settings(&self, watchdog_settings: WatchdogSettings) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetWatchdogSettings(watchdog_settings));
+    }
+
+    pub fn set_random_seed(&self, random_seed: u32, seed_policy: SeedPolicy) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetRandomSeed(random_seed, seed_policy));
+    }
+
+    pub fn set_latency_mode(&self, latency_mode: LatencyMode) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetLatencyMode(latency_mode));
+    }
+
+    pub fn set_aovs(&self, aovs: AovSettings) {
+        let _ = self.sender.send(RenderCommand::SetAovs(aovs));
+    }
+
+    pub fn start_turntable(&self, duration: Duration, revolutions: f32) {
+        let _ = self.sender.send(RenderCommand::StartTurntable {
+            duration,
+            revolutions,
+            export: None,
+        });
+    }
+
+    pub fn start_turntable_export(
+        &self,
+        duration: Duration,
+        revolutions: f32,
+        directory: &Path,
+        frame_rate: u32,
+    ) {
+        let _ = self.sender.send(RenderCommand::StartTurntable {
+            duration,
+            revolutions,
+            export: Some((directory.to_path_buf(), frame_rate)),
+        });
+    }
+
+    pub fn stop_turntable(&self) {
+        let _ = self.sender.send(RenderCommand::StopTurntable);
+    }
+
+    pub fn set_checkerboard(&self, checkerboard: bool) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetCheckerboard(checkerboard));
+    }
+
+    pub fn set_motion_blur(&self, motion_blur: MotionBlurSettings) {
+        let _ = self.sender.send(RenderCommand::SetMotionBlur(motion_blur));
+    }
+
+    pub fn set_render_preset(&self, render_preset: &RenderPreset) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetRenderPreset(render_preset.clone()));
+    }
+
+    pub fn set_viewports(&self, viewports: &[(Viewport, ViewportCamera)]) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetViewports(viewports.to_vec()));
+    }
+
+    pub fn set_trace_dispatch(&self, trace_dispatch: TraceDispatch) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetTraceDispatch(trace_dispatch));
+    }
+
+    pub fn set_instance_culling(&self, enabled: bool) {
+        let _ = self.sender.send(RenderCommand::SetInstanceCulling(enabled));
+    }
+
+    // Section view, cuts away what is on the side the normal points to
+    pub fn set_clip_plane(&self, normal: glm::Vec3, d: f32) {
+        self.set_clip_planes(&[ClipPlane { normal, d }]);
+    }
+
+    pub fn set_clip_planes(&self, clip_planes: &[ClipPlane]) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetClipPlanes(clip_planes.to_vec()));
+    }
+
+    pub fn set_shader_defines(&self, shader_defines: ShaderDefines) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetShaderDefines(shader_defines));
+    }
+
+    pub fn bake_irradiance_volume(&self, grid: ProbeGrid, sample_count: u32, path: &Path) {
+        let _ = self.sender.send(RenderCommand::BakeIrradianceVolume {
+            grid,
+            sample_count,
+            path: path.to_path_buf(),
+        });
+    }
+
+    // Read on a worker thread, the volume replaces the current one once it is loaded
+    pub fn load_irradiance_volume(&self, filename: &Path) -> JoinHandle<()> {
+        let sender = self.sender.clone();
+        let filename = filename.to_path_buf();
+        thread::spawn(move || match load_irradiance_volume(&filename) {
+            Ok(irradiance_volume) => {
+                let _ = sender.send(RenderCommand::SetIrradianceVolume(Some(irradiance_volume)));
+            }
+            Err(err) => log::error!("Cannot load the irradiance volume {:?}: {}", filename, err),
+        })
+    }
+
+    pub fn clear_irradiance_volume(&self) {
+        let _ = self.sender.send(RenderCommand::SetIrradianceVolume(None));
+    }
+
+    // Bitmap font of the text labels, read on a worker thread
+    pub fn load_font_atlas(
+        &self,
+        filename: &Path,
+        columns: u32,
+        rows: u32,
+        first_char: char,
+    ) -> JoinHandle<()> {
+        let sender = self.sender.clone();
+        let filename = filename.to_path_buf();
+        thread::spawn(
+            move || match load_font_atlas(&filename, columns, rows, first_char) {
+                Ok(font_atlas) => {
+                    let _ = sender.send(RenderCommand::SetFontAtlas(font_atlas));
+                }
+                Err(err) => log::error!("Cannot load the font atlas {:?}: {}", filename, err),
+            },
+        )
+    }
+
+    // Bits of the frame constants left to custom shaders
+    pub fn set_debug_flags(&self, debug_flags: u32) {
+        let _ = self.sender.send(RenderCommand::SetDebugFlags(debug_flags));
+    }
+
+    // Replaces the labels drawn over the frames, an empty list clears them
+    pub fn set_text_labels(&self, text_labels: &[TextLabel]) {
+        let _ = self
+            .sender
+            .send(RenderCommand::SetTextLabels(text_labels.to_vec()));
+    }
+}
+
+fn stream_models(
+    mut model_loader: ModelLoader,
+    source: PathBuf,
+    instances: Arc<Mutex<Arena<Instance>>>,
+    sender: Sender<RenderCommand>,
+) {
+    while let Some(model) = model_loader.next() {
+        let progress = Some(model_loader.progress());
+        let handle = instances.lock().unwrap().insert(Instance::new(&model));
+        if sender
+            .send(RenderCommand::AddModel {
+                model,
+                handle,
+                source: Some(source.clone()),
+                progress,
+            })
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn create_model_loader(
+    filename: &Path,
+    options: ModelLoadOptions,
+    asset_database: Option<&AssetDatabase>,
+) -> Result<ModelLoader, tobj::LoadError> {
+    match asset_database {
+        Some(asset_database) => ModelLoader::new_with_cache(filename, options, asset_database),
+        None => ModelLoader::new_with_options(filename, options),
+    }
+}
+
+// The textures stay uncompressed on devices that cannot sample BC textures
+fn supported_load_options(
+    mut options: ModelLoadOptions,
+    texture_compression: bool,
+) -> ModelLoadOptions {
+    if !texture_compression && options.texture_quality != TextureQuality::Full {
+        log::warn!("BC textures are not supported by the device, they are loaded uncompressed");
+        options.texture_quality = TextureQuality::Full;
+    }
+    options
+}
+
+fn reload_models(
+    source: PathBuf,
+    options: ModelLoadOptions,
+    asset_database: Option<AssetDatabase>,
+    sender: Sender<RenderCommand>,
+) {
+    // A file caught in the middle of a save is read again once the save is done
+    match create_model_loader(&source, options, asset_database.as_ref()) {
+        Ok(model_loader) => {
+            let models = model_loader.collect();
+            let _ = sender.send(RenderCommand::ReloadModel { source, models });
+        }
+        Err(err) => log::error!("Cannot reload {}: {:?}", source.display(), err),
+    }
+}
+
+// The errors only carry a message, which names the vk::Result the driver returned or gives its
+// description
+fn is_device_lost(err: &VulkanError) -> bool {
+    let message = format!("{:?}", err);
+    message.contains("ERROR_DEVICE_LOST")
+        || message.contains(&vk::Result::ERROR_DEVICE_LOST.to_string())
+}
+
+const FRAMES_COUNT: usize = 2;
+// Models with more textures than this get them packed into texture arrays and atlases
+const TEXTURE_PACKING_THRESHOLD: usize = 16;
+
+// What the swapchain was actually created with, which can differ from the requested window size
+#[derive(Clone, Copy, Debug)]
+pub struct SwapchainInfo {
+    pub width: u32,
+    pub height: u32,
+    pub frames_in_flight: u32,
+}
+
+struct Geometry {
+    handle: InstanceHandle,
+    source: Option<PathBuf>,
+}
+
+type FrameCommandsCallback = Box<dyn FnMut(vk::CommandBuffer, &FrameContext)>;
+
+// The Vulkan context is not thread safe, the RenderManager has to stay on the thread that created it
 pub struct RenderManager {
     context: Rc<RefCell<VulkanContext>>,
-    camera_manager: Rc<RefCell<CameraManager>>,
+    camera_manager: Arc<Mutex<CameraManager>>,
+    light_manager: Arc<Mutex<LightManager>>,
+    // Added with a sky background, and removed with it
+    sun_light: Option<LightHandle>,
     pipeline: Option<RayTracingPipeline>,
+    sender: Sender<RenderCommand>,
+    receiver: Receiver<RenderCommand>,
+    load_progress: f32,
+    instances: Arc<Mutex<Arena<Instance>>>,
+    // Handle and source file of each geometry instance of the pipeline, in the same order
+    geometries: Vec<Geometry>,
+    asset_watcher: Option<AssetWatcher>,
+    asset_database: Option<AssetDatabase>,
+    // Import options of the loaded files, reused when they are reloaded
+    load_options: HashMap<PathBuf, ModelLoadOptions>,
+    // Kept here so a new pipeline starts with the current settings
+    render_settings: RenderSettings,
+    // The user data buffer has one entry per instance slot, indexed by the handle index
+    user_data_changed: bool,
+    // Highlighted by the hit shader, on top of the flags of their user data
+    selected: HashSet<InstanceHandle>,
+    event_bus: EventBus,
+    background: Background,
+    // Kept so a new pipeline starts with it
+    environment_map: Option<EnvironmentMap>,
+    // Empty to render the interactive camera over the whole window
+    viewports: Vec<(Viewport, ViewportCamera)>,
+    trace_dispatch: TraceDispatch,
+    instance_culling: bool,
+    shader_defines: ShaderDefines,
+    irradiance_volume: Option<IrradianceVolume>,
+    font_atlas: Option<FontAtlas>,
+    text_labels: Vec<TextLabel>,
+    // Written to the frame constants with the clock of the application
+    time: f32,
+    delta_time: f32,
+    debug_flags: u32,
+    // Set once a frame failed, nothing is rendered after that
+    device_lost: bool,
+    // Replaces the interactive camera while it runs
+    turntable: Option<Turntable>,
+    id_buffer: bool,
+    frame_commands: Option<FrameCommandsCallback>,
+    // The device samples BC textures, the texture quality of the load options is ignored otherwise
+    texture_compression: bool,
 }
 
 impl RenderManager {
+    // The debug messenger and the validation layer are only enabled with debug. The extra checks
+    // of the layer, like GPU-assisted or synchronization validation, are read from the
+    // VK_LAYER_ENABLES environment variable when the instance is created, for instance
+    // VK_LAYER_ENABLES=VK_VALIDATION_FEATURE_ENABLE_SYNCHRONIZATION_VALIDATION_EXT.
     pub fn new(
         debug: bool,
         hwnd: *const c_void,
         width: u32,
         height: u32,
-        camera_manager: Rc<RefCell<CameraManager>>,
+        camera_manager: Arc<Mutex<CameraManager>>,
+        light_manager: Arc<Mutex<LightManager>>,
+        event_bus: EventBus,
     ) -> Self {
         let extensions = vec![
             DeviceExtensions::ExtDescriptorIndexing,
@@ -67,52 +667,1207 @@ impl RenderManager {
                 .with_window(window)
                 .with_extensions(extensions)
                 .with_features(Features::all())
-                .with_frames_count(2)
+                .with_frames_count(FRAMES_COUNT)
                 .build()
                 .unwrap(),
         ));
 
+        let texture_compression = {
+            let context = context.borrow();
+            [
+                TextureCompression::Bc1,
+                TextureCompression::Bc4,
+                TextureCompression::Bc7,
+            ]
+            .iter()
+            .all(|compression| compression.is_supported(&context))
+        };
+
+        let (sender, receiver) = mpsc::channel();
+
         Self {
             context,
             camera_manager,
+            light_manager,
+            sun_light: None,
             pipeline: None,
+            sender,
+            receiver,
+            load_progress: 1.0,
+            instances: Arc::new(Mutex::new(Arena::new())),
+            geometries: vec![],
+            asset_watcher: None,
+            asset_database: None,
+            load_options: HashMap::new(),
+            render_settings: RenderSettings::default(),
+            user_data_changed: false,
+            selected: HashSet::new(),
+            event_bus,
+            background: Background::default(),
+            environment_map: None,
+            viewports: vec![],
+            trace_dispatch: TraceDispatch::default(),
+            instance_culling: false,
+            shader_defines: ShaderDefines::default(),
+            irradiance_volume: None,
+            font_atlas: None,
+            text_labels: vec![],
+            time: 0.0,
+            delta_time: 0.0,
+            debug_flags: 0,
+            device_lost: false,
+            turntable: None,
+            id_buffer: false,
+            frame_commands: None,
+            texture_compression,
         }
     }
 
-    pub fn set_clear_color(&self, clear_color: glm::Vec4) {
+    // Reloads models loaded from files when the files or their materials and textures change
+    pub fn enable_hot_reload(&mut self, interval: Duration) {
+        self.asset_watcher = Some(AssetWatcher::new(interval));
+    }
+
+    // Keeps the processed models in the directory, so the next runs skip the processing. Has to be
+    // enabled before the first model is loaded and the render handles are created.
+    pub fn enable_asset_cache(&mut self, directory: &Path) {
+        self.asset_database = Some(AssetDatabase::new(directory));
+    }
+
+    // Has to be enabled before the first model is set
+    pub fn enable_id_buffer(&mut self) {
+        self.id_buffer = true;
+    }
+
+    // Instance and primitive under every pixel of the rect, row by row, None where nothing was hit
+    pub fn read_id_buffer(
+        &self,
+        rect: Viewport,
+    ) -> Result<Vec<Option<(InstanceHandle, u32)>>, VulkanError> {
+        let pipeline = match self.pipeline.as_ref() {
+            Some(pipeline) => pipeline,
+            None => return Ok(vec![None; (rect.width * rect.height) as usize]),
+        };
+
+        let ids = pipeline.read_id_buffer(rect)?;
+        Ok(ids
+            .iter()
+            .map(|id: &ObjectId| {
+                let geometry = self.geometries.get(id.custom_index()? as usize)?;
+                Some((geometry.handle, id.primitive))
+            })
+            .collect())
+    }
+
+    // Texels of an AOV of the last frame, see Aov for the type T has to match. Empty until the
+    // first model is set.
+    pub fn read_aov<T: Pod>(&self, aov: Aov, rect: Viewport) -> Result<Vec<T>, VulkanError> {
+        match self.pipeline.as_ref() {
+            Some(pipeline) => pipeline.read_aov(aov, rect),
+            None => Ok(vec![]),
+        }
+    }
+
+    // With the interactive camera over the whole window. Anchors on an instance read the ID buffer
+    // under them, one pixel each, to know whether another instance covers them.
+    pub fn update_screen_anchors(&self, screen_anchors: &mut ScreenAnchors) {
+        if screen_anchors.is_empty() {
+            return;
+        }
+
+        let extent = self.context.borrow().get_swapchain().get_extent();
+        let (width, height) = (extent.width as f32, extent.height as f32);
+        let camera = *self.camera_manager.lock().unwrap().get_camera();
+        let instances = self.instances.lock().unwrap();
+        screen_anchors.update(|anchor| {
+            let point = match anchor.instance {
+                Some(handle) => {
+                    let transform = instances.get(handle)?.transform;
+                    (transform * glm::vec4(anchor.point.x, anchor.point.y, anchor.point.z, 1.0))
+                        .xyz()
+                }
+                None => anchor.point,
+            };
+            let projected = camera.project(point, width, height)?;
+            let on_screen = projected.x >= 0.0
+                && projected.x < width
+                && projected.y >= 0.0
+                && projected.y < height;
+
+            Some(ScreenPosition {
+                x: projected.x,
+                y: projected.y,
+                depth: projected.z,
+                on_screen,
+                occluded: None,
+            })
+        });
+        drop(instances);
+
+        if !self.id_buffer {
+            return;
+        }
+
+        // A single readback of the rectangle around the anchors to test, each one would wait for
+        // the queue otherwise
+        let pixels: Vec<(u32, u32)> = screen_anchors
+            .anchors()
+            .filter(|(_, anchor)| anchor.instance.is_some())
+            .filter_map(|(_, anchor)| anchor.position())
+            .filter(|position| position.on_screen)
+            .map(|position| (position.x as u32, position.y as u32))
+            .collect();
+        let (min_x, min_y, max_x, max_y) = match pixels.first() {
+            Some(&(x, y)) => pixels.iter().fold((x, y, x, y), |bounds, &(x, y)| {
+                (
+                    bounds.0.min(x),
+                    bounds.1.min(y),
+                    bounds.2.max(x),
+                    bounds.3.max(y),
+                )
+            }),
+            None => return,
+        };
+        let rect = Viewport {
+            x: min_x,
+            y: min_y,
+            width: max_x - min_x + 1,
+            height: max_y - min_y + 1,
+        };
+        let ids = match self.read_id_buffer(rect) {
+            Ok(ids) => ids,
+            Err(_) => return,
+        };
+
+        screen_anchors.update(|anchor| {
+            let position = anchor.position()?;
+            let occluded = match anchor.instance {
+                Some(handle) if position.on_screen => {
+                    let x = position.x as u32 - rect.x;
+                    let y = position.y as u32 - rect.y;
+                    // Nothing hit means the anchor floats next to its instance
+                    ids.get((y * rect.width + x) as usize)
+                        .map(|&id| matches!(id, Some((hit, _)) if hit != handle))
+                }
+                _ => None,
+            };
+            Some(ScreenPosition {
+                occluded,
+                ..position
+            })
+        });
+    }
+
+    // Called every frame to record extra commands, like raster or compute passes drawn over the
+    // traced image. Replaces the previous callback.
+    pub fn with_frame_commands<F>(&mut self, callback: F)
+    where
+        F: FnMut(vk::CommandBuffer, &FrameContext) + 'static,
+    {
+        self.frame_commands = Some(Box::new(callback));
+    }
+
+    pub fn handle(&self) -> RenderHandle {
+        RenderHandle {
+            sender: self.sender.clone(),
+            instances: Arc::clone(&self.instances),
+            asset_database: self.asset_database.clone(),
+            texture_compression: self.texture_compression,
+        }
+    }
+
+    // Also what rays that hit nothing return
+    pub fn set_clear_color(&mut self, clear_color: glm::Vec4) {
         self.context
             .borrow_mut()
             .set_clear_value(clear_color.into());
+        self.set_background(Background::Solid(clear_color));
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_background(background) {
+                log::error!("Cannot update the background: {:?}", err);
+            }
+        }
+        self.update_sun_light(background);
+    }
+
+    // The sun of the sky follows it, the lights are uploaded again at the next frame
+    fn update_sun_light(&mut self, background: Background) {
+        let mut light_manager = self.light_manager.lock().unwrap();
+        match (background, self.sun_light) {
+            (Background::Sky(sky), Some(handle))
+                if light_manager.set_light(handle, sky.sun_light()) => {}
+            (Background::Sky(sky), _) => {
+                self.sun_light = Some(light_manager.add_light(sky.sun_light()));
+            }
+            (_, Some(handle)) => {
+                light_manager.remove_light(handle);
+                self.sun_light = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_environment_map(&mut self, environment_map: EnvironmentMap) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_environment_map(&environment_map) {
+                log::error!("Cannot upload the environment map: {:?}", err);
+                return;
+            }
+        }
+        self.environment_map = Some(environment_map);
     }
 
-    pub fn load_model(&mut self, filename: &Path) {
-        let mut model = Model::new(filename);
+    pub fn set_reflection_settings(&mut self, reflection_settings: ReflectionSettings) {
+        self.render_settings.reflections = reflection_settings;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_reflection_settings(reflection_settings) {
+                log::error!("Cannot update the reflection settings: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    pub fn set_path_tracing_settings(&mut self, path_tracing_settings: PathTracingSettings) {
+        self.render_settings.path_tracing = path_tracing_settings;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_path_tracing_settings(path_tracing_settings) {
+                log::error!("Cannot update the path tracing settings: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    pub fn set_fog_settings(&mut self, fog_settings: FogSettings) {
+        self.render_settings.fog = fog_settings;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_fog_settings(fog_settings) {
+                log::error!("Cannot update the fog settings: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
 
-        let geom = GeometryInstanceBuilder::new(&self.context.borrow())
+    pub fn set_watchdog_settings(&mut self, watchdog_settings: WatchdogSettings) {
+        self.render_settings.watchdog = watchdog_settings;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_watchdog_settings(watchdog_settings) {
+                log::error!("Cannot update the watchdog settings: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    pub fn set_random_seed(&mut self, random_seed: u32, seed_policy: SeedPolicy) {
+        self.render_settings.random_seed = random_seed;
+        self.render_settings.seed_policy = seed_policy;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_random_seed(random_seed, seed_policy) {
+                log::error!("Cannot update the random seed: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    pub fn set_latency_mode(&mut self, latency_mode: LatencyMode) {
+        self.render_settings.latency_mode = latency_mode;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_latency_mode(latency_mode) {
+                log::error!("Cannot update the latency mode: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    pub fn set_aovs(&mut self, aovs: AovSettings) {
+        self.render_settings.aovs = aovs;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_aovs(aovs) {
+                log::error!("Cannot update the AOVs: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    // Orbits a camera around the instances for the duration, in place of the interactive camera.
+    // An export writes each frame to the directory and steps by the frame rate, however long the
+    // frames take.
+    pub fn start_turntable(
+        &mut self,
+        duration: Duration,
+        revolutions: f32,
+        export: Option<(PathBuf, u32)>,
+    ) {
+        self.stop_turntable();
+        let export = match export {
+            Some((directory, frame_rate)) => {
+                if let Err(err) = fs::create_dir_all(&directory) {
+                    log::error!("Cannot create {}: {:?}", directory.display(), err);
+                    return;
+                }
+                let aovs = self.render_settings.aovs;
+                self.set_aovs(AovSettings {
+                    color: true,
+                    ..aovs
+                });
+                Some(TurntableExport {
+                    directory,
+                    frame_rate,
+                    aovs,
+                })
+            }
+            None => None,
+        };
+        self.turntable = Some(Turntable::new(duration, revolutions, export));
+    }
+
+    pub fn stop_turntable(&mut self) {
+        if let Some(turntable) = self.turntable.take() {
+            if let Some(export) = turntable.export {
+                self.set_aovs(export.aovs);
+            }
+            self.event_bus.publish(EngineEvent::TurntableFinished);
+        }
+    }
+
+    // Exports the frame that was just rendered, then moves the camera for the next one
+    fn update_turntable(&mut self) {
+        let frame_path = match self.turntable.as_ref() {
+            Some(turntable) => turntable.frame_path(),
+            None => return,
+        };
+        if let Some(path) = frame_path {
+            if let Err(err) = self.export_frame(&path) {
+                log::error!("Cannot export {}: {:?}", path.display(), err);
+            }
+        }
+
+        let delta_time = self.delta_time;
+        if let Some(turntable) = self.turntable.as_mut() {
+            turntable.advance(delta_time);
+            if turntable.is_finished() {
+                self.stop_turntable();
+            }
+        }
+    }
+
+    fn export_frame(&self, path: &Path) -> io::Result<()> {
+        let extent = self.context.borrow().get_swapchain().get_extent();
+        let texels = self
+            .read_aov::<[u16; 4]>(Aov::Color, Viewport::full(extent))
+            .map_err(|err| io::Error::other(format!("{:?}", err)))?;
+        let mut accumulation = Accumulation::new(extent.width, extent.height);
+        accumulation.add(&texels);
+        accumulation.save(path)
+    }
+
+    pub fn set_checkerboard(&mut self, checkerboard: bool) {
+        self.render_settings.checkerboard = checkerboard;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_checkerboard(checkerboard) {
+                log::error!("Cannot update the checkerboard rendering: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    pub fn set_motion_blur(&mut self, motion_blur: MotionBlurSettings) {
+        self.render_settings.motion_blur = motion_blur;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_motion_blur(motion_blur) {
+                log::error!("Cannot update the motion blur: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    // A single change of the settings, whatever the preset replaces
+    pub fn set_render_preset(&mut self, render_preset: &RenderPreset) {
+        render_preset.apply(&mut self.render_settings);
+        let random_seed = self.render_settings.random_seed;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            let result = pipeline
+                .set_reflection_settings(render_preset.reflections)
+                .and_then(|_| pipeline.set_path_tracing_settings(render_preset.path_tracing))
+                .and_then(|_| pipeline.set_random_seed(random_seed, render_preset.seed_policy));
+            if let Err(err) = result {
+                log::error!(
+                    "Cannot apply the render preset {}: {:?}",
+                    render_preset.name,
+                    err
+                );
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    pub fn set_clip_planes(&mut self, clip_planes: &[ClipPlane]) {
+        if clip_planes.len() > MAX_CLIP_PLANES {
+            log::error!(
+                "Cannot set {} clip planes, at most {} are supported",
+                clip_planes.len(),
+                MAX_CLIP_PLANES
+            );
+            return;
+        }
+
+        self.render_settings.set_clip_planes(clip_planes);
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_render_settings(self.render_settings) {
+                log::error!("Cannot update the clip planes: {:?}", err);
+            }
+        }
+        self.event_bus
+            .publish(EngineEvent::RenderSettingsChanged(self.render_settings));
+    }
+
+    // Replaces the whole selection, stale handles are ignored
+    pub fn set_selected(&mut self, handles: &[InstanceHandle]) {
+        self.selected = handles.iter().copied().collect();
+        self.user_data_changed = true;
+    }
+
+    // One viewport per camera, at most MAX_VIEWPORTS of them
+    pub fn set_viewports(&mut self, viewports: &[(Viewport, ViewportCamera)]) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            let regions: Vec<Viewport> = viewports.iter().map(|(viewport, _)| *viewport).collect();
+            if let Err(err) = pipeline.set_viewports(&regions) {
+                log::error!("Cannot set the viewports: {:?}", err);
+                return;
+            }
+        }
+        self.viewports = viewports.to_vec();
+    }
+
+    pub fn set_trace_dispatch(&mut self, trace_dispatch: TraceDispatch) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.set_trace_dispatch(trace_dispatch);
+        }
+        self.trace_dispatch = trace_dispatch;
+    }
+
+    // Camera rays skip the instances outside of every camera frustum
+    pub fn set_instance_culling(&mut self, enabled: bool) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_instance_culling(enabled) {
+                log::error!("Cannot set the instance culling: {:?}", err);
+                return;
+            }
+        }
+        self.instance_culling = enabled;
+    }
+
+    // The current permutation is rendered until the new one is compiled
+    pub fn set_shader_defines(&mut self, shader_defines: ShaderDefines) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_shader_defines(shader_defines.clone()) {
+                log::error!("Cannot change the shader permutation: {:?}", err);
+                return;
+            }
+        }
+        self.shader_defines = shader_defines;
+    }
+
+    // The frames stop while the probes are traced, the volume is saved and then shown
+    pub fn bake_irradiance_volume(&mut self, grid: &ProbeGrid, sample_count: u32, path: &Path) {
+        let pipeline = match self.pipeline.as_mut() {
+            Some(pipeline) => pipeline,
+            None => {
+                log::error!("Cannot bake an irradiance volume before a model is set");
+                return;
+            }
+        };
+        let irradiance_volume = match pipeline.bake_irradiance_volume(grid, sample_count) {
+            Ok(irradiance_volume) => irradiance_volume,
+            Err(err) => {
+                log::error!("Cannot bake the irradiance volume: {:?}", err);
+                return;
+            }
+        };
+
+        match save_irradiance_volume(path, &irradiance_volume) {
+            Ok(()) => self
+                .event_bus
+                .publish(EngineEvent::IrradianceVolumeBaked(path.to_path_buf())),
+            Err(err) => log::error!("Cannot save the irradiance volume {:?}: {}", path, err),
+        }
+        self.set_irradiance_volume(Some(irradiance_volume));
+    }
+
+    pub fn set_irradiance_volume(&mut self, irradiance_volume: Option<IrradianceVolume>) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_irradiance_volume(irradiance_volume.as_ref()) {
+                log::error!("Cannot upload the irradiance volume: {:?}", err);
+                return;
+            }
+        }
+        self.irradiance_volume = irradiance_volume;
+    }
+
+    pub fn set_font_atlas(&mut self, font_atlas: FontAtlas) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_font_atlas(&font_atlas) {
+                log::error!("Cannot upload the font atlas: {:?}", err);
+                return;
+            }
+        }
+        self.font_atlas = Some(font_atlas);
+    }
+
+    // Drawn once a font atlas is set
+    pub fn set_text_labels(&mut self, text_labels: Vec<TextLabel>) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.set_text_labels(&text_labels);
+        }
+        self.text_labels = text_labels;
+    }
+
+    // Clock of the frame about to be rendered, for the shaders that animate with it
+    pub fn set_frame_time(&mut self, time: f32, delta_time: f32) {
+        self.time = time;
+        self.delta_time = delta_time;
+    }
+
+    pub fn set_debug_flags(&mut self, debug_flags: u32) {
+        self.debug_flags = debug_flags;
+    }
+
+    // None until the first model is set
+    pub fn stats(&self) -> Option<RendererStats> {
+        self.pipeline.as_ref().map(|pipeline| pipeline.get_stats())
+    }
+
+    // Counts of the last frame that is done, None while the culling is disabled
+    pub fn culling_stats(&self) -> Option<CullingStats> {
+        self.pipeline
+            .as_ref()
+            .and_then(|pipeline| pipeline.get_culling_stats())
+    }
+
+    // Upload the first part synchronously, the rest is loaded on a worker thread. The loading is
+    // over without any pipeline when the file cannot be read or has no geometry.
+    pub fn load_model(&mut self, filename: &Path, options: ModelLoadOptions) {
+        let _span = tracing::info_span!("load_model", file = %filename.display()).entered();
+        self.load_progress = 0.0;
+        let options = supported_load_options(options, self.texture_compression);
+        let mut model_loader =
+            match create_model_loader(filename, options, self.asset_database.as_ref()) {
+                Ok(model_loader) => model_loader,
+                Err(err) => {
+                    log::error!("Cannot load {}: {:?}", filename.display(), err);
+                    self.set_load_progress(1.0);
+                    return;
+                }
+            };
+        self.load_options.insert(filename.to_path_buf(), options);
+        if let Some(asset_watcher) = self.asset_watcher.as_mut() {
+            asset_watcher.watch(filename, model_loader.dependencies());
+        }
+        match model_loader.next() {
+            Some(model) => {
+                self.set_model(model);
+                self.geometries[0].source = Some(filename.to_path_buf());
+            }
+            None => {
+                log::error!("{} has no geometry", filename.display());
+                self.set_load_progress(1.0);
+                return;
+            }
+        }
+        self.set_load_progress(model_loader.progress());
+
+        let sender = self.sender.clone();
+        let instances = Arc::clone(&self.instances);
+        let source = filename.to_path_buf();
+        thread::spawn(move || stream_models(model_loader, source, instances, sender));
+    }
+
+    pub fn load_progress(&self) -> f32 {
+        self.load_progress
+    }
+
+    // Once the scene is loaded and its pipeline compiled, the frames until then are cleared
+    pub fn is_ready(&self) -> bool {
+        self.load_progress >= 1.0
+            && self
+                .pipeline
+                .as_ref()
+                .is_some_and(|pipeline| pipeline.is_ready())
+    }
+
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost
+    }
+
+    pub fn render_settings(&self) -> RenderSettings {
+        self.render_settings
+    }
+
+    fn set_load_progress(&mut self, load_progress: f32) {
+        if self.load_progress < 1.0 && load_progress >= 1.0 {
+            self.event_bus.publish(EngineEvent::SceneLoaded);
+        }
+        self.load_progress = load_progress;
+    }
+
+    pub fn swapchain_info(&self) -> SwapchainInfo {
+        let extent = self.context.borrow().get_swapchain().get_extent();
+        SwapchainInfo {
+            width: extent.width,
+            height: extent.height,
+            frames_in_flight: FRAMES_COUNT as u32,
+        }
+    }
+
+    pub fn set_model(&mut self, model: Model) -> InstanceHandle {
+        let handle = self.instances.lock().unwrap().insert(Instance::new(&model));
+        self.set_model_with_handle(model, handle);
+        handle
+    }
+
+    fn set_model_with_handle(&mut self, model: Model, handle: InstanceHandle) {
+        // Instances that were part of the previous pipeline are gone
+        let mut instances = self.instances.lock().unwrap();
+        for geometry in self.geometries.drain(..) {
+            instances.remove(geometry.handle);
+        }
+        drop(instances);
+
+        let geom = self.create_geometry_instance(model, handle);
+
+        let mut light_manager = self.light_manager.lock().unwrap();
+        light_manager.take_changed();
+        let lights = light_manager.lights();
+        let light_sampling_strategy = light_manager.sampling_strategy();
+        drop(light_manager);
+
+        let mut builder = RayTracingPipelineBuilder::new(Rc::clone(&self.context));
+        if let Some(environment_map) = self.environment_map.as_ref() {
+            builder = builder.with_environment_map(environment_map.clone());
+        }
+        if let Some(irradiance_volume) = self.irradiance_volume.as_ref() {
+            builder = builder.with_irradiance_volume(irradiance_volume.clone());
+        }
+        if let Some(font_atlas) = self.font_atlas.as_ref() {
+            builder = builder.with_font_atlas(font_atlas.clone());
+        }
+        let ray_tracing_pipeline = builder
+            .with_geometry_instance(geom)
+            .with_frames_in_flight(FRAMES_COUNT as u64)
+            .with_async_compilation(true)
+            .with_id_buffer(self.id_buffer)
+            .with_render_settings(self.render_settings)
+            .with_background(self.background)
+            .with_shader_defines(self.shader_defines.clone())
+            .with_lights(&lights, light_sampling_strategy)
+            .with_camera_buffer_size(
+                self.camera_manager.lock().unwrap().get_camera_buffer_size() as u64
+            )
+            .build()
+            .unwrap();
+
+        self.pipeline = Some(ray_tracing_pipeline);
+        let text_labels = self.text_labels.clone();
+        self.set_text_labels(text_labels);
+        let viewports = self.viewports.clone();
+        self.set_viewports(&viewports);
+        self.set_trace_dispatch(self.trace_dispatch);
+        self.set_instance_culling(self.instance_culling);
+        self.geometries = vec![Geometry {
+            handle,
+            source: None,
+        }];
+        self.user_data_changed = true;
+        self.update_memory_breakdown();
+    }
+
+    // InstanceHandle -> custom index (position in the pipeline) -> user data entry (handle index)
+    fn create_geometry_instance(
+        &self,
+        mut model: Model,
+        handle: InstanceHandle,
+    ) -> GeometryInstance {
+        let texture_packing = model.textures.len() > TEXTURE_PACKING_THRESHOLD;
+        let context = self.context.borrow();
+        let event_bus = self.event_bus.clone();
+        let mut builder = GeometryInstanceBuilder::new(&context)
             .with_vertices(&mut model.vertices)
             .with_indices(&mut model.indices)
             .with_materials(&mut model.materials)
             .with_textures(&mut model.textures)
-            .build()
-            .unwrap();
+            .with_texture_packing(texture_packing)
+            .with_upload_progress(move |progress| {
+                event_bus.publish(EngineEvent::ModelUploadProgress {
+                    handle,
+                    uploaded_bytes: progress.uploaded_bytes,
+                    total_bytes: progress.total_bytes,
+                })
+            });
+        // Before the first pipeline, the uploads wait on the queue once per model
+        if let Some(pipeline) = self.pipeline.as_ref() {
+            builder = builder.with_transient_commands(pipeline.get_transient_commands());
+        }
+        let mut geom = builder.build().unwrap();
+        geom.user_data_index = handle.index();
+        geom
+    }
 
-        let ray_tracing_pipeline = RayTracingPipelineBuilder::new(Rc::clone(&self.context))
-            .with_geometry_instance(geom)
-            .with_camera_buffer_size(self.camera_manager.borrow().get_camera_buffer_size() as u64)
-            .build()
+    fn add_model(&mut self, model: Model, handle: InstanceHandle, source: Option<PathBuf>) {
+        // Removed before the model made it to the render thread
+        let transform = match self.instances.lock().unwrap().get(handle) {
+            Some(instance) => instance.transform,
+            None => return,
+        };
+
+        if self.pipeline.is_none() {
+            self.set_model_with_handle(model, handle);
+            self.geometries[0].source = source;
+            self.set_transforms(&[(handle, transform)]);
+            return;
+        }
+
+        let mut geom = self.create_geometry_instance(model, handle);
+        geom.transform = transform;
+        self.pipeline
+            .as_mut()
+            .unwrap()
+            .add_geometry_instance(geom)
             .unwrap();
+        self.geometries.push(Geometry { handle, source });
+        self.user_data_changed = true;
+        self.update_memory_breakdown();
+    }
 
-        self.pipeline = Some(ray_tracing_pipeline);
+    // Published in the instances, so the scene can read it from the application thread
+    fn update_memory_breakdown(&self) {
+        let memory_breakdown = match self.pipeline.as_ref() {
+            Some(pipeline) => pipeline.get_memory_breakdown(),
+            None => return,
+        };
+        let mut instances = self.instances.lock().unwrap();
+        for (geometry, memory) in self.geometries.iter().zip(memory_breakdown) {
+            if let Some(instance) = instances.get_mut(geometry.handle) {
+                instance.memory = Some(memory);
+            }
+        }
+    }
+
+    fn geometry_index(&self, handle: InstanceHandle) -> Option<usize> {
+        self.geometries
+            .iter()
+            .position(|geometry| geometry.handle == handle)
+    }
+
+    fn remove_instance(&mut self, handle: InstanceHandle) {
+        self.selected.remove(&handle);
+        let index = match self.geometry_index(handle) {
+            Some(index) => index,
+            None => return,
+        };
+
+        match self
+            .pipeline
+            .as_mut()
+            .unwrap()
+            .replace_geometry_instances(&[index], vec![])
+        {
+            Ok(()) => {
+                self.geometries.remove(index);
+            }
+            Err(err) => log::error!("Cannot remove instance {:?}: {:?}", handle, err),
+        }
+    }
+
+    // The instances removed meanwhile are skipped
+    fn set_transforms(&mut self, transforms: &[(InstanceHandle, glm::Mat4)]) {
+        let indexed: Vec<(usize, glm::Mat4)> = transforms
+            .iter()
+            .filter_map(|&(handle, transform)| {
+                self.geometry_index(handle).map(|index| (index, transform))
+            })
+            .collect();
+        if indexed.is_empty() {
+            return;
+        }
+
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            if let Err(err) = pipeline.set_transforms(&indexed) {
+                log::error!("Cannot move {} instances: {:?}", indexed.len(), err);
+            }
+        }
+    }
+
+    // Existing handles are kept in order, extra parts get new handles and missing parts are removed
+    fn reload_model(&mut self, source: PathBuf, models: Vec<Model>) {
+        if self.pipeline.is_none() || models.is_empty() {
+            return;
+        }
+
+        // The instances removed by the handle are already out of the arena while their
+        // RemoveInstance command waits in the queue, those are left to it
+        let mut instances = self.instances.lock().unwrap();
+        let indices: Vec<usize> = self
+            .geometries
+            .iter()
+            .enumerate()
+            .filter(|(_, geometry)| {
+                geometry.source.as_ref() == Some(&source) && instances.contains(geometry.handle)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        let mut handles: Vec<InstanceHandle> = indices
+            .iter()
+            .map(|&index| self.geometries[index].handle)
+            .collect();
+        let first_instance = match handles.first().and_then(|&handle| instances.get(handle)) {
+            Some(instance) => instance.clone(),
+            None => return,
+        };
+
+        // The instances only go away once the pipeline took the new geometry
+        let reused_count = handles.len();
+        while handles.len() < models.len() {
+            handles.push(instances.insert(first_instance.clone()));
+        }
+        let removed: Vec<InstanceHandle> = handles.drain(models.len()..).collect();
+        let mut transforms = Vec::with_capacity(handles.len());
+        for (&handle, model) in handles.iter().zip(models.iter()) {
+            match instances.get_mut(handle) {
+                Some(instance) => {
+                    instance.diagnostics = model.diagnostics.clone();
+                    transforms.push(instance.transform);
+                }
+                None => transforms.push(first_instance.transform),
+            }
+        }
+        drop(instances);
+
+        let geometry_instances: Vec<GeometryInstance> = models
+            .into_iter()
+            .zip(handles.iter().zip(transforms))
+            .map(|(model, (&handle, transform))| {
+                let mut geometry_instance = self.create_geometry_instance(model, handle);
+                geometry_instance.transform = transform;
+                geometry_instance
+            })
+            .collect();
+
+        match self
+            .pipeline
+            .as_mut()
+            .unwrap()
+            .replace_geometry_instances(&indices, geometry_instances)
+        {
+            Ok(()) => {
+                let mut instances = self.instances.lock().unwrap();
+                for handle in removed {
+                    instances.remove(handle);
+                }
+                drop(instances);
+
+                let mut index = 0;
+                self.geometries.retain(|_| {
+                    index += 1;
+                    !indices.contains(&(index - 1))
+                });
+                for handle in handles {
+                    self.geometries.push(Geometry {
+                        handle,
+                        source: Some(source.clone()),
+                    });
+                }
+                self.user_data_changed = true;
+                self.update_memory_breakdown();
+                self.event_bus.publish(EngineEvent::AssetReloaded(source));
+            }
+            Err(err) => {
+                let mut instances = self.instances.lock().unwrap();
+                for &handle in handles.iter().skip(reused_count) {
+                    instances.remove(handle);
+                }
+                log::error!("Cannot reload {}: {:?}", source.display(), err)
+            }
+        }
+    }
+
+    fn poll_assets(&mut self) {
+        let changed = match self.asset_watcher.as_mut() {
+            Some(asset_watcher) => asset_watcher.poll(),
+            None => return,
+        };
+
+        for source in changed {
+            let options = self.load_options.get(&source).copied().unwrap_or_default();
+            let sender = self.sender.clone();
+            let asset_database = self.asset_database.clone();
+            thread::spawn(move || reload_models(source, options, asset_database, sender));
+        }
+    }
+
+    // The pending commands are applied up to the first upload, so that uploads are spread over
+    // several frames. The transforms are gathered until another command or the end, the top level
+    // structure is then rebuilt once for all of them.
+    fn process_commands(&mut self) {
+        let _span = tracing::info_span!("process_commands").entered();
+        let mut transforms = vec![];
+        while let Ok(command) = self.receiver.try_recv() {
+            if let RenderCommand::SetTransforms(mut moved) = command {
+                transforms.append(&mut moved);
+                continue;
+            }
+
+            // Before the instances are added or removed, which changes their indices
+            self.set_transforms(&transforms);
+            transforms.clear();
+
+            let upload = matches!(
+                command,
+                RenderCommand::AddModel { .. }
+                    | RenderCommand::ReloadModel { .. }
+                    | RenderCommand::SetEnvironmentMap(_)
+                    | RenderCommand::BakeIrradianceVolume { .. }
+                    | RenderCommand::SetIrradianceVolume(_)
+                    | RenderCommand::SetFontAtlas(_)
+            );
+            self.apply_command(command);
+            if upload {
+                break;
+            }
+        }
+        self.set_transforms(&transforms);
+    }
+
+    fn apply_command(&mut self, command: RenderCommand) {
+        match command {
+            RenderCommand::AddModel {
+                model,
+                handle,
+                source,
+                progress,
+            } => {
+                self.add_model(model, handle, source);
+                if let Some(progress) = progress {
+                    self.set_load_progress(progress);
+                }
+            }
+            RenderCommand::ReloadModel { source, models } => self.reload_model(source, models),
+            RenderCommand::WatchModel {
+                source,
+                dependencies,
+                options,
+            } => {
+                self.load_options.insert(source.clone(), options);
+                if let Some(asset_watcher) = self.asset_watcher.as_mut() {
+                    asset_watcher.watch(&source, &dependencies);
+                }
+            }
+            RenderCommand::RemoveInstance(handle) => self.remove_instance(handle),
+            RenderCommand::SetUserData(handle) => {
+                if self.geometry_index(handle).is_some() {
+                    self.user_data_changed = true;
+                }
+            }
+            RenderCommand::SetSelected(handles) => self.set_selected(&handles),
+            RenderCommand::SetClearColor(clear_color) => self.set_clear_color(clear_color),
+            RenderCommand::SetBackground(background) => self.set_background(background),
+            RenderCommand::SetEnvironmentMap(environment_map) => {
+                self.set_environment_map(environment_map)
+            }
+            RenderCommand::SetReflectionSettings(reflection_settings) => {
+                self.set_reflection_settings(reflection_settings)
+            }
+            RenderCommand::SetPathTracingSettings(path_tracing_settings) => {
+                self.set_path_tracing_settings(path_tracing_settings)
+            }
+            RenderCommand::SetFogSettings(fog_settings) => self.set_fog_settings(fog_settings),
+            RenderCommand::SetViewports(viewports) => self.set_viewports(&viewports),
+            RenderCommand::SetWatchdogSettings(watchdog_settings) => {
+                self.set_watchdog_settings(watchdog_settings)
+            }
+            RenderCommand::SetRandomSeed(random_seed, seed_policy) => {
+                self.set_random_seed(random_seed, seed_policy)
+            }
+            RenderCommand::SetLatencyMode(latency_mode) => self.set_latency_mode(latency_mode),
+            RenderCommand::SetAovs(aovs) => self.set_aovs(aovs),
+            RenderCommand::SetCheckerboard(checkerboard) => self.set_checkerboard(checkerboard),
+            RenderCommand::StartTurntable {
+                duration,
+                revolutions,
+                export,
+            } => self.start_turntable(duration, revolutions, export),
+            RenderCommand::StopTurntable => self.stop_turntable(),
+            RenderCommand::SetMotionBlur(motion_blur) => self.set_motion_blur(motion_blur),
+            RenderCommand::SetRenderPreset(render_preset) => self.set_render_preset(&render_preset),
+            RenderCommand::SetTraceDispatch(trace_dispatch) => {
+                self.set_trace_dispatch(trace_dispatch)
+            }
+            RenderCommand::SetInstanceCulling(enabled) => self.set_instance_culling(enabled),
+            RenderCommand::SetClipPlanes(clip_planes) => self.set_clip_planes(&clip_planes),
+            RenderCommand::SetShaderDefines(shader_defines) => {
+                self.set_shader_defines(shader_defines)
+            }
+            RenderCommand::BakeIrradianceVolume {
+                grid,
+                sample_count,
+                path,
+            } => self.bake_irradiance_volume(&grid, sample_count, &path),
+            RenderCommand::SetIrradianceVolume(irradiance_volume) => {
+                self.set_irradiance_volume(irradiance_volume)
+            }
+            RenderCommand::SetFontAtlas(font_atlas) => self.set_font_atlas(font_atlas),
+            RenderCommand::SetTextLabels(text_labels) => self.set_text_labels(text_labels),
+            RenderCommand::SetDebugFlags(debug_flags) => self.set_debug_flags(debug_flags),
+            // Gathered by process_commands
+            RenderCommand::SetTransforms(transforms) => self.set_transforms(&transforms),
+        }
+    }
+
+    fn collect_user_data(&self) -> Vec<InstanceUserData> {
+        let instances = self.instances.lock().unwrap();
+        let entry_count = self
+            .geometries
+            .iter()
+            .map(|geometry| geometry.handle.index() as usize + 1)
+            .max()
+            .unwrap_or(0);
+        let mut user_data = vec![InstanceUserData::default(); entry_count];
+        for geometry in self.geometries.iter() {
+            if let Some(instance) = instances.get(geometry.handle) {
+                let entry = &mut user_data[geometry.handle.index() as usize];
+                *entry = instance.user_data;
+                if self.selected.contains(&geometry.handle) {
+                    entry.flags |= INSTANCE_FLAG_HIGHLIGHTED;
+                }
+            }
+        }
+        user_data
     }
 
     pub fn render_scene(&mut self) {
+        let _span = tracing::info_span!("render_scene").entered();
+        if self.device_lost {
+            return;
+        }
+
+        self.poll_assets();
+        self.process_commands();
+
+        // Nothing to trace until a model with some geometry is added
+        let pipeline = match self.pipeline.as_mut() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+
+        let mut light_manager = self.light_manager.lock().unwrap();
+        if light_manager.take_changed() {
+            if let Err(err) =
+                pipeline.set_lights(&light_manager.lights(), light_manager.sampling_strategy())
+            {
+                log::error!("Cannot upload the lights: {:?}", err);
+            }
+        }
+        drop(light_manager);
+
+        if self.user_data_changed {
+            self.user_data_changed = false;
+            let user_data = self.collect_user_data();
+            let pipeline = self.pipeline.as_mut().unwrap();
+            if let Err(err) = pipeline.set_user_data(&user_data) {
+                log::error!("Cannot upload the instance user data: {:?}", err);
+            }
+        }
+
         let pipeline = self.pipeline.as_mut().unwrap();
-        pipeline
-            .update_camera_buffer(self.camera_manager.borrow().get_camera_buffer())
-            .unwrap();
+        // The clear color stays the one of the background
+        pipeline.set_frame_parameters(FrameParameters {
+            time: self.time,
+            delta_time: self.delta_time,
+            debug_flags: self.debug_flags,
+            ..*pipeline.get_frame_parameters()
+        });
+        let camera_manager = self.camera_manager.lock().unwrap();
+        // The turntable stands in for the interactive camera
+        let geometry_count = self.geometries.len();
+        let turntable = self.turntable.as_ref().and_then(|turntable| {
+            let geometry_instances =
+                (0..geometry_count).filter_map(|index| pipeline.get_geometry_instance(index));
+            scene_bounds(geometry_instances).map(|bounds| (turntable, bounds))
+        });
+        let interactive_camera = |width: f32, height: f32| match turntable {
+            Some((turntable, bounds)) => {
+                turntable.camera(bounds, camera_manager.get_properties(), width, height)
+            }
+            None => camera_manager.get_camera_with_extent(width, height),
+        };
+        let cameras: Vec<Camera> = if self.viewports.is_empty() {
+            match turntable {
+                Some(_) => {
+                    let extent = self.context.borrow().get_swapchain().get_extent();
+                    vec![interactive_camera(
+                        extent.width as f32,
+                        extent.height as f32,
+                    )]
+                }
+                None => vec![*camera_manager.get_camera()],
+            }
+        } else {
+            self.viewports
+                .iter()
+                .map(|(viewport, camera)| match camera {
+                    ViewportCamera::Interactive => {
+                        interactive_camera(viewport.width as f32, viewport.height as f32)
+                    }
+                    ViewportCamera::Fixed(camera) => **camera,
+                })
+                .collect()
+        };
+        let frame_commands = &mut self.frame_commands;
+        let result = pipeline
+            .update_camera_buffers(&cameras)
+            .and_then(|_| pipeline.begin_draw())
+            .and_then(|_| pipeline.draw())
+            .and_then(|_| match frame_commands.as_mut() {
+                Some(frame_commands) => pipeline.end_draw_with(|command_buffer, frame_context| {
+                    frame_commands(command_buffer, frame_context)
+                }),
+                None => pipeline.end_draw(),
+            });
+        drop(camera_manager);
+
+        // The watchdog split the frame, kept here so a new pipeline starts with the same tiles
+        let trace_dispatch = pipeline.get_trace_dispatch();
+        if trace_dispatch != self.trace_dispatch {
+            log::warn!(
+                "A launch took {:.1} ms, tracing in tiles of {:?} pixels",
+                pipeline.get_longest_dispatch_time(),
+                trace_dispatch.tile_size
+            );
+            self.trace_dispatch = trace_dispatch;
+        }
+
+        // Only a lost device stops the rendering, the next frame tries again after other errors
+        if let Err(err) = result {
+            log::error!("Cannot render the frame: {:?}", err);
+            if is_device_lost(&err) {
+                self.device_lost = true;
+                self.event_bus.publish(EngineEvent::DeviceLost);
+            }
+            return;
+        }
 
-        pipeline.begin_draw().unwrap();
-        pipeline.draw().unwrap();
-        pipeline.end_draw().unwrap();
+        self.update_turntable();
     }
 }
@@ -0,0 +1,244 @@
+use std::fmt::Write;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+
+use vulkan_ray_tracing::render_settings::{RenderPreset, SeedPolicy};
+
+// Text files with one section per preset, the name between brackets followed by its settings:
+//
+// [Preview]
+// reflections.max_roughness = 0.1
+// path_tracing.enabled = false
+//
+// Settings left out keep their default value, lines starting with # are comments.
+pub fn save_render_presets(path: &Path, presets: &[RenderPreset]) -> io::Result<()> {
+    let mut content = String::new();
+    for preset in presets.iter() {
+        let reflections = &preset.reflections;
+        let path_tracing = &preset.path_tracing;
+        // Writing to a String cannot fail
+        let _ = writeln!(content, "[{}]", preset.name);
+        let _ = writeln!(content, "reflections.enabled = {}", reflections.enabled);
+        let _ = writeln!(
+            content,
+            "reflections.max_roughness = {}",
+            reflections.max_roughness
+        );
+        let _ = writeln!(
+            content,
+            "reflections.max_distance = {}",
+            reflections.max_distance
+        );
+        let _ = writeln!(
+            content,
+            "reflections.environment_fallback = {}",
+            reflections.environment_fallback
+        );
+        let _ = writeln!(content, "path_tracing.enabled = {}", path_tracing.enabled);
+        let _ = writeln!(
+            content,
+            "path_tracing.min_bounces = {}",
+            path_tracing.min_bounces
+        );
+        let _ = writeln!(
+            content,
+            "path_tracing.max_bounces = {}",
+            path_tracing.max_bounces
+        );
+        let _ = writeln!(
+            content,
+            "path_tracing.max_direct_radiance = {}",
+            path_tracing.max_direct_radiance
+        );
+        let _ = writeln!(
+            content,
+            "path_tracing.max_indirect_radiance = {}",
+            path_tracing.max_indirect_radiance
+        );
+        let _ = writeln!(
+            content,
+            "seed_policy = {}",
+            seed_policy_name(preset.seed_policy)
+        );
+        content.push('\n');
+    }
+    fs::write(path, content)
+}
+
+pub fn load_render_presets(path: &Path) -> io::Result<Vec<RenderPreset>> {
+    let content = fs::read_to_string(path)?;
+    let mut presets: Vec<RenderPreset> = vec![];
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), index + 1, message),
+            )
+        };
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            presets.push(RenderPreset::new(name.trim()));
+            continue;
+        }
+
+        let preset = presets
+            .last_mut()
+            .ok_or_else(|| invalid("setting outside of a preset"))?;
+        let (key, value) = match line.find('=') {
+            Some(position) => (line[..position].trim(), line[position + 1..].trim()),
+            None => return Err(invalid("expected a setting = value")),
+        };
+
+        let reflections = &mut preset.reflections;
+        let path_tracing = &mut preset.path_tracing;
+        match key {
+            "reflections.enabled" => reflections.enabled = parse(value, &invalid)?,
+            "reflections.max_roughness" => reflections.max_roughness = parse(value, &invalid)?,
+            "reflections.max_distance" => reflections.max_distance = parse(value, &invalid)?,
+            "reflections.environment_fallback" => {
+                reflections.environment_fallback = parse(value, &invalid)?
+            }
+            "path_tracing.enabled" => path_tracing.enabled = parse(value, &invalid)?,
+            "path_tracing.min_bounces" => path_tracing.min_bounces = parse(value, &invalid)?,
+            "path_tracing.max_bounces" => path_tracing.max_bounces = parse(value, &invalid)?,
+            "path_tracing.max_direct_radiance" => {
+                path_tracing.max_direct_radiance = parse(value, &invalid)?
+            }
+            "path_tracing.max_indirect_radiance" => {
+                path_tracing.max_indirect_radiance = parse(value, &invalid)?
+            }
+            "seed_policy" => {
+                preset.seed_policy =
+                    seed_policy_from_name(value).ok_or_else(|| invalid("unknown seed policy"))?
+            }
+            _ => return Err(invalid(&format!("unknown setting {}", key))),
+        }
+    }
+
+    Ok(presets)
+}
+
+fn parse<T: FromStr>(value: &str, invalid: &dyn Fn(&str) -> io::Error) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(&format!("invalid value {}", value)))
+}
+
+fn seed_policy_name(seed_policy: SeedPolicy) -> &'static str {
+    match seed_policy {
+        SeedPolicy::PerFrame => "per_frame",
+        SeedPolicy::Fixed => "fixed",
+        SeedPolicy::FrozenWhileStatic => "frozen_while_static",
+    }
+}
+
+fn seed_policy_from_name(name: &str) -> Option<SeedPolicy> {
+    match name {
+        "per_frame" => Some(SeedPolicy::PerFrame),
+        "fixed" => Some(SeedPolicy::Fixed),
+        "frozen_while_static" => Some(SeedPolicy::FrozenWhileStatic),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("r2r2_{}_{}.presets", name, process::id()))
+    }
+
+    fn load_text(name: &str, content: &str) -> io::Result<Vec<RenderPreset>> {
+        let path = temp_path(name);
+        fs::write(&path, content).unwrap();
+        let presets = load_render_presets(&path);
+        fs::remove_file(&path).unwrap();
+        presets
+    }
+
+    #[test]
+    fn saved_presets_load_back() {
+        let mut custom = RenderPreset::new("Custom");
+        custom.reflections.enabled = false;
+        custom.reflections.max_distance = 42.5;
+        custom.path_tracing.min_bounces = 1;
+        custom.path_tracing.max_direct_radiance = 3.25;
+        custom.seed_policy = SeedPolicy::FrozenWhileStatic;
+        let presets = vec![RenderPreset::preview(), RenderPreset::quality(), custom];
+
+        let path = temp_path("round_trip");
+        save_render_presets(&path, &presets).unwrap();
+        let loaded = load_render_presets(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), presets.len());
+        for (loaded, preset) in loaded.iter().zip(&presets) {
+            assert_eq!(format!("{:?}", loaded), format!("{:?}", preset));
+        }
+    }
+
+    #[test]
+    fn missing_settings_keep_their_default() {
+        let presets = load_text(
+            "defaults",
+            "# Comment\n\n[ Fast ]\nreflections.max_roughness = 0.05\nseed_policy = fixed\n",
+        )
+        .unwrap();
+
+        assert_eq!(presets.len(), 1);
+        let preset = &presets[0];
+        assert_eq!(preset.name, "Fast");
+        assert_eq!(preset.reflections.max_roughness, 0.05);
+        assert_eq!(preset.seed_policy, SeedPolicy::Fixed);
+        assert_eq!(preset.path_tracing.max_bounces, 8);
+        assert!(preset.reflections.enabled);
+    }
+
+    #[test]
+    fn invalid_presets_are_rejected_with_their_line() {
+        let cases = [
+            ("outside", "reflections.enabled = true\n", "setting outside"),
+            (
+                "no_value",
+                "[A]\nreflections.enabled\n",
+                "expected a setting",
+            ),
+            (
+                "unknown",
+                "[A]\nreflections.bounces = 2\n",
+                "unknown setting",
+            ),
+            (
+                "value",
+                "[A]\n\npath_tracing.max_bounces = many\n",
+                "invalid value",
+            ),
+            (
+                "policy",
+                "[A]\nseed_policy = random\n",
+                "unknown seed policy",
+            ),
+        ];
+        for (name, content, message) in cases.iter() {
+            let err = load_text(name, content).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            let line = content.lines().count();
+            assert!(
+                err.to_string().contains(&format!(":{}: {}", line, message)),
+                "{}",
+                err
+            );
+        }
+    }
+}
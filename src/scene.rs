@@ -0,0 +1,414 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use vulkan_ray_tracing::background::Background;
+use vulkan_ray_tracing::glm;
+use vulkan_ray_tracing::instance_data::{InstanceUserData, INSTANCE_FLAG_SHADOW_CATCHER};
+use vulkan_ray_tracing::irradiance_volume::ProbeGrid;
+use vulkan_ray_tracing::render_settings::{
+    AovSettings, ClipPlane, FogSettings, LatencyMode, MotionBlurSettings, PathTracingSettings,
+    ReflectionSettings, RenderPreset, SeedPolicy, WatchdogSettings,
+};
+use vulkan_ray_tracing::renderer_stats::GeometryMemory;
+use vulkan_ray_tracing::shader_permutation::ShaderDefines;
+use vulkan_ray_tracing::sky::Sky;
+use vulkan_ray_tracing::text_overlay::TextLabel;
+use vulkan_ray_tracing::viewport::{TraceDispatch, Viewport};
+
+#[cfg(feature = "audio")]
+use crate::audio_manager::AudioManager;
+use crate::camera_manager::{CameraManager, CameraType, ViewportCamera};
+use crate::handle::Handle;
+use crate::light_manager::LightManager;
+use crate::mesh_diagnostics::MeshDiagnostics;
+use crate::model::{Model, ModelLoadOptions};
+#[cfg(feature = "physics")]
+use crate::physics_manager::PhysicsManager;
+use crate::primitives;
+use crate::render_manager::RenderHandle;
+use crate::screen_anchors::ScreenAnchors;
+use crate::transform_interpolation::TransformHistory;
+
+#[derive(Clone)]
+pub struct Instance {
+    // As rendered, blended between the fixed updates for the instances moved by them
+    pub transform: glm::Mat4,
+    pub user_data: InstanceUserData,
+    // Of the meshes of the model, when it was loaded from a file
+    pub diagnostics: Vec<MeshDiagnostics>,
+    // None until the render thread uploaded the geometry
+    pub memory: Option<GeometryMemory>,
+    // Set by the fixed updates, cleared by set_transform
+    pub(crate) history: Option<TransformHistory>,
+}
+
+impl Instance {
+    pub(crate) fn new(model: &Model) -> Self {
+        Instance {
+            diagnostics: model.diagnostics.clone(),
+            ..Instance::default()
+        }
+    }
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Instance {
+            transform: glm::identity(),
+            user_data: InstanceUserData::default(),
+            diagnostics: vec![],
+            memory: None,
+            history: None,
+        }
+    }
+}
+
+pub type InstanceHandle = Handle<Instance>;
+
+// What the application callbacks can change, the changes are applied by the render manager
+pub struct Scene {
+    render_handle: RenderHandle,
+    camera_manager: Arc<Mutex<CameraManager>>,
+    light_manager: Arc<Mutex<LightManager>>,
+    screen_anchors: Arc<Mutex<ScreenAnchors>>,
+    #[cfg(feature = "audio")]
+    audio_manager: Arc<Mutex<AudioManager>>,
+    #[cfg(feature = "physics")]
+    physics_manager: Arc<Mutex<PhysicsManager>>,
+    // Fixed updates run so far
+    fixed_step: u64,
+}
+
+impl Scene {
+    pub(crate) fn new(
+        render_handle: RenderHandle,
+        camera_manager: Arc<Mutex<CameraManager>>,
+        light_manager: Arc<Mutex<LightManager>>,
+        screen_anchors: Arc<Mutex<ScreenAnchors>>,
+        #[cfg(feature = "audio")] audio_manager: Arc<Mutex<AudioManager>>,
+        #[cfg(feature = "physics")] physics_manager: Arc<Mutex<PhysicsManager>>,
+    ) -> Self {
+        Scene {
+            render_handle,
+            camera_manager,
+            light_manager,
+            screen_anchors,
+            #[cfg(feature = "audio")]
+            audio_manager,
+            #[cfg(feature = "physics")]
+            physics_manager,
+            fixed_step: 0,
+        }
+    }
+
+    pub fn add_model(&mut self, model: Model) -> InstanceHandle {
+        self.render_handle.add_model(model)
+    }
+
+    // Square of the given size at the given height, large enough it looks like an infinite ground.
+    // A shadow catcher keeps the background visible with the shadows of the models on it.
+    pub fn add_ground_plane(
+        &mut self,
+        size: f32,
+        height: f32,
+        shadow_catcher: bool,
+    ) -> InstanceHandle {
+        let handle = self.render_handle.add_model(primitives::plane(size, size));
+        self.render_handle
+            .set_transform(handle, glm::translation(&glm::vec3(0.0, height, 0.0)));
+        if shadow_catcher {
+            let user_data = InstanceUserData {
+                flags: INSTANCE_FLAG_SHADOW_CATCHER,
+                ..InstanceUserData::default()
+            };
+            self.render_handle.set_user_data(handle, user_data);
+        }
+        handle
+    }
+
+    pub fn load_model(&mut self, filename: &Path) -> JoinHandle<()> {
+        self.render_handle.load_model(filename)
+    }
+
+    pub fn load_model_with_options(
+        &mut self,
+        filename: &Path,
+        options: ModelLoadOptions,
+    ) -> JoinHandle<()> {
+        self.render_handle
+            .load_model_with_options(filename, options)
+    }
+
+    // Returns false if the instance was already removed
+    pub fn remove_instance(&mut self, handle: InstanceHandle) -> bool {
+        self.render_handle.remove_instance(handle)
+    }
+
+    // Moves the instance right away, it stops following its fixed updates if it had any
+    pub fn set_transform(&mut self, handle: InstanceHandle, transform: glm::Mat4) -> bool {
+        self.render_handle.set_transform(handle, transform)
+    }
+
+    // From the fixed update callback, the frames rendered until the next fixed update blend the
+    // previous and this transform with the render alpha of the clock
+    pub fn set_fixed_transform(&mut self, handle: InstanceHandle, transform: glm::Mat4) -> bool {
+        self.render_handle
+            .set_fixed_transform(handle, transform, self.fixed_step)
+    }
+
+    // Same as set_fixed_transform for several instances, returns the ones that were removed
+    pub fn set_fixed_transforms(
+        &mut self,
+        transforms: &[(InstanceHandle, glm::Mat4)],
+    ) -> Vec<InstanceHandle> {
+        self.render_handle
+            .set_fixed_transforms(transforms, self.fixed_step)
+    }
+
+    pub(crate) fn begin_fixed_update(&mut self) {
+        self.fixed_step += 1;
+    }
+
+    // Moves the instances set by the fixed updates to where they are at this frame
+    pub(crate) fn interpolate_transforms(&mut self, render_alpha: f32) {
+        self.render_handle
+            .interpolate_transforms(render_alpha, self.fixed_step);
+    }
+
+    // Read by the hit shader, for object ids, tints or highlights
+    pub fn set_user_data(&mut self, handle: InstanceHandle, user_data: InstanceUserData) -> bool {
+        self.render_handle.set_user_data(handle, user_data)
+    }
+
+    // The selected instances are highlighted, an empty slice clears the selection
+    pub fn set_selected(&mut self, handles: &[InstanceHandle]) {
+        self.render_handle.set_selected(handles);
+    }
+
+    pub fn get_instance(&self, handle: InstanceHandle) -> Option<Instance> {
+        self.render_handle.get_instance(handle)
+    }
+
+    // Meshes of every loaded instance, the same report is logged when they are loaded
+    pub fn diagnostics(&self) -> Vec<MeshDiagnostics> {
+        self.render_handle.diagnostics()
+    }
+
+    // GPU memory of every uploaded instance, the textures are also split per material
+    pub fn memory_breakdown(&self) -> Vec<(InstanceHandle, GeometryMemory)> {
+        self.render_handle.memory_breakdown()
+    }
+
+    pub fn light_manager(&self) -> MutexGuard<'_, LightManager> {
+        self.light_manager.lock().unwrap()
+    }
+
+    // Screen positions of world points, to place labels over the render
+    pub fn screen_anchors(&self) -> MutexGuard<'_, ScreenAnchors> {
+        self.screen_anchors.lock().unwrap()
+    }
+
+    // Sounds of the scene, the listener follows the interactive camera
+    #[cfg(feature = "audio")]
+    pub fn audio_manager(&self) -> MutexGuard<'_, AudioManager> {
+        self.audio_manager.lock().unwrap()
+    }
+
+    // Rigid bodies of the instances, the application copies their positions to the transforms
+    // after every update
+    #[cfg(feature = "physics")]
+    pub fn physics_manager(&self) -> MutexGuard<'_, PhysicsManager> {
+        self.physics_manager.lock().unwrap()
+    }
+
+    // Switches the interactive camera between the projections, it keeps its position and direction
+    pub fn set_camera_type(&mut self, camera_type: CameraType) {
+        self.camera_manager
+            .lock()
+            .unwrap()
+            .set_camera_type(camera_type);
+    }
+
+    pub fn camera_type(&self) -> CameraType {
+        self.camera_manager.lock().unwrap().get_camera_type()
+    }
+
+    // Height of the orthographic view in world units
+    pub fn set_ortho_size(&mut self, ortho_size: f32) {
+        self.camera_manager
+            .lock()
+            .unwrap()
+            .set_ortho_size(ortho_size);
+    }
+
+    pub fn ortho_size(&self) -> f32 {
+        self.camera_manager.lock().unwrap().get_ortho_size()
+    }
+
+    pub fn set_clear_color(&mut self, clear_color: glm::Vec4) {
+        self.render_handle.set_clear_color(clear_color);
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.render_handle.set_background(background);
+    }
+
+    // Background::Sky, with its sun added as a directional light. Called every frame with
+    // Sky::with_time_of_day for a day-night cycle.
+    pub fn set_sky(&mut self, sky: Sky) {
+        self.render_handle.set_background(Background::Sky(sky));
+    }
+
+    // Radiance HDR file, used by Background::Environment
+    pub fn load_environment_map(&mut self, filename: &Path) -> JoinHandle<()> {
+        self.render_handle.load_environment_map(filename)
+    }
+
+    pub fn set_reflection_settings(&mut self, reflection_settings: ReflectionSettings) {
+        self.render_handle
+            .set_reflection_settings(reflection_settings);
+    }
+
+    pub fn set_path_tracing_settings(&mut self, path_tracing_settings: PathTracingSettings) {
+        self.render_handle
+            .set_path_tracing_settings(path_tracing_settings);
+    }
+
+    // Height fog over the traced image, lit by the directional lights
+    pub fn set_fog_settings(&mut self, fog_settings: FogSettings) {
+        self.render_handle.set_fog_settings(fog_settings);
+    }
+
+    pub fn set_watchdog_settings(&mut self, watchdog_settings: WatchdogSettings) {
+        self.render_handle.set_watchdog_settings(watchdog_settings);
+    }
+
+    // Reproducible renders, e.g. a fixed seed and SeedPolicy::Fixed for golden images. Changing
+    // the seed restarts the sequence of samples.
+    pub fn set_random_seed(&mut self, random_seed: u32, seed_policy: SeedPolicy) {
+        self.render_handle.set_random_seed(random_seed, seed_policy);
+    }
+
+    // LatencyMode::LowLatency keeps a single frame in flight, for interactive editing
+    pub fn set_latency_mode(&mut self, latency_mode: LatencyMode) {
+        self.render_handle.set_latency_mode(latency_mode);
+    }
+
+    // Albedo, normal and motion images written next to the color, read them back with
+    // ApplicationManager::read_aov
+    pub fn set_aovs(&mut self, aovs: AovSettings) {
+        self.render_handle.set_aovs(aovs);
+    }
+
+    // Traces half of the pixels each frame and reuses the previous frame for the others, for
+    // slower GPUs. Camera cuts fall back to the traced pixels alone for a frame.
+    pub fn set_checkerboard(&mut self, checkerboard: bool) {
+        self.render_handle.set_checkerboard(checkerboard);
+    }
+
+    // Blurs the instances along their motion since the previous frame, the cameras are not
+    // blurred
+    pub fn set_motion_blur(&mut self, motion_blur: MotionBlurSettings) {
+        self.render_handle.set_motion_blur(motion_blur);
+    }
+
+    // Orbits a camera around the whole scene in place of the interactive one, for previews.
+    // EngineEvent::TurntableFinished is published once it went around.
+    pub fn start_turntable(&mut self, duration: Duration, revolutions: f32) {
+        self.render_handle.start_turntable(duration, revolutions);
+    }
+
+    // Same orbit, with every frame written to directory/frame_0000.png onwards. The frames are
+    // frame_rate per second of the duration, however long they take to render.
+    pub fn start_turntable_export(
+        &mut self,
+        duration: Duration,
+        revolutions: f32,
+        directory: &Path,
+        frame_rate: u32,
+    ) {
+        self.render_handle
+            .start_turntable_export(duration, revolutions, directory, frame_rate);
+    }
+
+    pub fn stop_turntable(&mut self) {
+        self.render_handle.stop_turntable();
+    }
+
+    // RenderPreset::preview, quality and benchmark, or the presets of a file, see
+    // render_presets::load_render_presets
+    pub fn set_render_preset(&mut self, render_preset: &RenderPreset) {
+        self.render_handle.set_render_preset(render_preset);
+    }
+
+    pub fn set_clip_plane(&mut self, normal: glm::Vec3, d: f32) {
+        self.render_handle.set_clip_plane(normal, d);
+    }
+
+    // Cutaway views, at most MAX_CLIP_PLANES planes
+    pub fn set_clip_planes(&mut self, clip_planes: &[ClipPlane]) {
+        self.render_handle.set_clip_planes(clip_planes);
+    }
+
+    // For split screen, e.g. a top down editor view next to the interactive camera
+    pub fn set_viewports(&mut self, viewports: &[(Viewport, ViewportCamera)]) {
+        self.render_handle.set_viewports(viewports);
+    }
+
+    // Tiles for heavy scenes on slow GPUs, or a region to trace only part of the frame while debugging
+    pub fn set_trace_dispatch(&mut self, trace_dispatch: TraceDispatch) {
+        self.render_handle.set_trace_dispatch(trace_dispatch);
+    }
+
+    // Frustum culling of the instances on the GPU, for scenes with many objects out of view
+    pub fn set_instance_culling(&mut self, enabled: bool) {
+        self.render_handle.set_instance_culling(enabled);
+    }
+
+    // Debug views and feature toggles compiled into the shaders, e.g. NO_SHADOWS. The permutation
+    // has to be listed in assets/shaders/permutations.txt.
+    pub fn set_shader_defines(&mut self, shader_defines: ShaderDefines) {
+        self.render_handle.set_shader_defines(shader_defines);
+    }
+
+    // Offline pass, path traces the diffuse light at every probe of the grid and saves it to the
+    // path. Once baked, or loaded, the frames that are not path traced light their surfaces with
+    // it instead of the constant ambient term.
+    pub fn bake_irradiance_volume(&mut self, grid: ProbeGrid, sample_count: u32, path: &Path) {
+        self.render_handle
+            .bake_irradiance_volume(grid, sample_count, path);
+    }
+
+    // A volume saved by bake_irradiance_volume
+    pub fn load_irradiance_volume(&mut self, filename: &Path) -> JoinHandle<()> {
+        self.render_handle.load_irradiance_volume(filename)
+    }
+
+    pub fn clear_irradiance_volume(&mut self) {
+        self.render_handle.clear_irradiance_volume();
+    }
+
+    // Bits of the frame constants left to custom shaders, the engine does not read them
+    pub fn set_debug_flags(&mut self, debug_flags: u32) {
+        self.render_handle.set_debug_flags(debug_flags);
+    }
+
+    // Sheet of columns by rows glyphs holding the characters from first_char on
+    pub fn load_font_atlas(
+        &mut self,
+        filename: &Path,
+        columns: u32,
+        rows: u32,
+        first_char: char,
+    ) -> JoinHandle<()> {
+        self.render_handle
+            .load_font_atlas(filename, columns, rows, first_char)
+    }
+
+    // Screen space text drawn over the frames, such as FPS counters
+    pub fn set_text_labels(&mut self, text_labels: &[TextLabel]) {
+        self.render_handle.set_text_labels(text_labels);
+    }
+}
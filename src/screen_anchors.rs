@@ -0,0 +1,92 @@
+use vulkan_ray_tracing::glm;
+
+use crate::handle::{Arena, Handle};
+use crate::scene::InstanceHandle;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScreenPosition {
+    // In pixels, from the top left corner of the window
+    pub x: f32,
+    pub y: f32,
+    // Along the view direction
+    pub depth: f32,
+    pub on_screen: bool,
+    // Whether another instance covers the anchor, None without the ID buffer or an instance to
+    // compare the hit with
+    pub occluded: Option<bool>,
+}
+
+// Point followed on screen, for labels and annotations drawn over the render
+#[derive(Clone, Debug)]
+pub struct ScreenAnchor {
+    // In the space of the instance when there is one, so the anchor moves with it
+    pub point: glm::Vec3,
+    pub instance: Option<InstanceHandle>,
+    position: Option<ScreenPosition>,
+}
+
+impl ScreenAnchor {
+    pub fn world(point: glm::Vec3) -> Self {
+        ScreenAnchor {
+            point,
+            instance: None,
+            position: None,
+        }
+    }
+
+    pub fn on_instance(instance: InstanceHandle, point: glm::Vec3) -> Self {
+        ScreenAnchor {
+            point,
+            instance: Some(instance),
+            position: None,
+        }
+    }
+
+    // Of the last rendered frame, None behind the camera or once the instance is removed
+    pub fn position(&self) -> Option<ScreenPosition> {
+        self.position
+    }
+}
+
+pub type ScreenAnchorHandle = Handle<ScreenAnchor>;
+
+// The anchors are projected with the interactive camera after every frame
+#[derive(Default)]
+pub struct ScreenAnchors {
+    anchors: Arena<ScreenAnchor>,
+}
+
+impl ScreenAnchors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_anchor(&mut self, anchor: ScreenAnchor) -> ScreenAnchorHandle {
+        self.anchors.insert(anchor)
+    }
+
+    pub fn remove_anchor(&mut self, handle: ScreenAnchorHandle) -> Option<ScreenAnchor> {
+        self.anchors.remove(handle)
+    }
+
+    pub fn get_anchor(&self, handle: ScreenAnchorHandle) -> Option<&ScreenAnchor> {
+        self.anchors.get(handle)
+    }
+
+    pub fn anchors(&self) -> impl Iterator<Item = (ScreenAnchorHandle, &ScreenAnchor)> {
+        self.anchors.iter_with_handles()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.anchors.is_empty()
+    }
+
+    pub(crate) fn update<F>(&mut self, mut project: F)
+    where
+        F: FnMut(&ScreenAnchor) -> Option<ScreenPosition>,
+    {
+        for anchor in self.anchors.iter_mut() {
+            anchor.position = project(anchor);
+        }
+    }
+}
@@ -0,0 +1,603 @@
+use vulkan_ray_tracing::geometry_instance::ImageBuffer;
+use vulkan_ray_tracing::texture::{full_mip_levels, TextureCompression};
+
+use crate::model::TextureQuality;
+
+type Block = [[u8; 4]; 16];
+
+// Interpolation weights of the 4 bit indices of BC7, out of 64
+const BC7_WEIGHTS: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+// Iterations to find the main axis of the colors of a block
+const POWER_ITERATIONS: usize = 8;
+
+// Replaces the RGBA pixels of the image with the blocks of its whole mip chain. The levels are
+// filtered in linear space for color images, like the blits of uncompressed textures.
+pub fn compress_image(image: &mut ImageBuffer, quality: TextureQuality) {
+    if image.compression.is_some() || quality == TextureQuality::Full {
+        return;
+    }
+    let _span = tracing::info_span!(
+        "compress_texture",
+        width = image.tex_width,
+        height = image.tex_height
+    )
+    .entered();
+
+    let compression = choose_compression(image, quality);
+    let mip_levels = full_mip_levels(image.tex_width, image.tex_height);
+    let (mut width, mut height) = (image.tex_width, image.tex_height);
+    let mut level = std::mem::take(&mut image.pixels);
+    let mut blocks = vec![];
+    for mip_level in 0..mip_levels {
+        if mip_level > 0 {
+            level = downsample(&level, width, height, image.srgb);
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+        }
+        compress_level(&level, width, height, compression, &mut blocks);
+    }
+
+    image.pixels = blocks;
+    image.compression = Some(compression);
+}
+
+// Gray data maps only need one channel, BC1 has no alpha
+fn choose_compression(image: &ImageBuffer, quality: TextureQuality) -> TextureCompression {
+    let opaque = image.pixels.chunks(4).all(|pixel| pixel[3] == 255);
+    let gray = image
+        .pixels
+        .chunks(4)
+        .all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+
+    if gray && opaque && !image.srgb {
+        TextureCompression::Bc4
+    } else if quality == TextureQuality::Low && opaque {
+        TextureCompression::Bc1
+    } else {
+        TextureCompression::Bc7
+    }
+}
+
+fn compress_level(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    compression: TextureCompression,
+    blocks: &mut Vec<u8>,
+) {
+    for block_y in 0..height.div_ceil(4) {
+        for block_x in 0..width.div_ceil(4) {
+            let block = fetch_block(pixels, width, height, block_x, block_y);
+            match compression {
+                TextureCompression::Bc1 => encode_bc1(&block, blocks),
+                TextureCompression::Bc4 => encode_bc4(&block, blocks),
+                TextureCompression::Bc7 => encode_bc7(&block, blocks),
+            }
+        }
+    }
+}
+
+// Blocks over the edge of the image repeat its last row and column
+fn fetch_block(pixels: &[u8], width: u32, height: u32, block_x: u32, block_y: u32) -> Block {
+    let mut block = [[0; 4]; 16];
+    for (texel, color) in block.iter_mut().enumerate() {
+        let x = (block_x * 4 + texel as u32 % 4).min(width - 1);
+        let y = (block_y * 4 + texel as u32 / 4).min(height - 1);
+        let offset = (y * width + x) as usize * 4;
+        color.copy_from_slice(&pixels[offset..offset + 4]);
+    }
+    block
+}
+
+// Box filter, an odd last row or column is folded into the previous texels
+fn downsample(pixels: &[u8], width: u32, height: u32, srgb: bool) -> Vec<u8> {
+    let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut result = Vec::with_capacity((half_width * half_height) as usize * 4);
+    for y in 0..half_height {
+        for x in 0..half_width {
+            let mut sum = [0.0; 4];
+            for (dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let source_x = (x * 2 + dx).min(width - 1);
+                let source_y = (y * 2 + dy).min(height - 1);
+                let offset = (source_y * width + source_x) as usize * 4;
+                for channel in 0..4 {
+                    let value = f32::from(pixels[offset + channel]) / 255.0;
+                    sum[channel] += if srgb && channel < 3 {
+                        srgb_to_linear(value)
+                    } else {
+                        value
+                    };
+                }
+            }
+            for (channel, value) in sum.iter().enumerate() {
+                let value = value / 4.0;
+                let value = if srgb && channel < 3 {
+                    linear_to_srgb(value)
+                } else {
+                    value
+                };
+                result.push((value * 255.0).round().clamp(0.0, 255.0) as u8);
+            }
+        }
+    }
+    result
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Ends of the segment the colors of the block spread along, over the first channels of the
+// texels. Flat blocks get the same color at both ends.
+fn principal_endpoints(block: &Block, channels: usize) -> ([f32; 4], [f32; 4]) {
+    let mut mean = [0.0; 4];
+    for color in block.iter() {
+        for channel in 0..channels {
+            mean[channel] += f32::from(color[channel]) / 16.0;
+        }
+    }
+
+    let mut covariance = [[0.0; 4]; 4];
+    for color in block.iter() {
+        for i in 0..channels {
+            for j in 0..channels {
+                covariance[i][j] +=
+                    (f32::from(color[i]) - mean[i]) * (f32::from(color[j]) - mean[j]);
+            }
+        }
+    }
+
+    let mut axis = [1.0; 4];
+    for _ in 0..POWER_ITERATIONS {
+        let mut next = [0.0; 4];
+        for i in 0..channels {
+            for j in 0..channels {
+                next[i] += covariance[i][j] * axis[j];
+            }
+        }
+        let length = next.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if length < 1e-6 {
+            return (mean, mean);
+        }
+        for i in 0..4 {
+            axis[i] = next[i] / length;
+        }
+    }
+
+    let (mut min, mut max) = (f32::MAX, f32::MIN);
+    for color in block.iter() {
+        let projection: f32 = (0..channels)
+            .map(|channel| (f32::from(color[channel]) - mean[channel]) * axis[channel])
+            .sum();
+        min = min.min(projection);
+        max = max.max(projection);
+    }
+
+    let mut start = [0.0; 4];
+    let mut end = [0.0; 4];
+    for channel in 0..channels {
+        start[channel] = (mean[channel] + axis[channel] * min).clamp(0.0, 255.0);
+        end[channel] = (mean[channel] + axis[channel] * max).clamp(0.0, 255.0);
+    }
+    (start, end)
+}
+
+// Index of the palette entry closest to the color, over the first channels
+fn nearest(palette: &[[f32; 4]], color: &[u8; 4], channels: usize) -> usize {
+    let distance = |entry: &[f32; 4]| -> f32 {
+        (0..channels)
+            .map(|channel| {
+                let difference = entry[channel] - f32::from(color[channel]);
+                difference * difference
+            })
+            .sum()
+    };
+    let mut best = 0;
+    for (index, entry) in palette.iter().enumerate().skip(1) {
+        if distance(entry) < distance(&palette[best]) {
+            best = index;
+        }
+    }
+    best
+}
+
+fn pack_565(color: &[f32; 4]) -> u16 {
+    let r = (color[0] * 31.0 / 255.0).round() as u16;
+    let g = (color[1] * 63.0 / 255.0).round() as u16;
+    let b = (color[2] * 31.0 / 255.0).round() as u16;
+    (r << 11) | (g << 5) | b
+}
+
+fn unpack_565(color: u16) -> [f32; 4] {
+    let r = (color >> 11) & 31;
+    let g = (color >> 5) & 63;
+    let b = color & 31;
+    [
+        f32::from((r << 3) | (r >> 2)),
+        f32::from((g << 2) | (g >> 4)),
+        f32::from((b << 3) | (b >> 2)),
+        255.0,
+    ]
+}
+
+// Two 565 endpoints and 2 bit indices. The first endpoint has to be the greater one for the four
+// color mode, equal endpoints decode to the first one in the three color mode.
+fn encode_bc1(block: &Block, out: &mut Vec<u8>) {
+    let (start, end) = principal_endpoints(block, 3);
+    let (mut color0, mut color1) = (pack_565(&end), pack_565(&start));
+    if color0 < color1 {
+        std::mem::swap(&mut color0, &mut color1);
+    }
+
+    let mut indices = 0u32;
+    if color0 != color1 {
+        let (c0, c1) = (unpack_565(color0), unpack_565(color1));
+        let mut palette = [c0, c1, [0.0; 4], [0.0; 4]];
+        for channel in 0..3 {
+            palette[2][channel] = (2.0 * c0[channel] + c1[channel]) / 3.0;
+            palette[3][channel] = (c0[channel] + 2.0 * c1[channel]) / 3.0;
+        }
+        for (texel, color) in block.iter().enumerate() {
+            indices |= (nearest(&palette, color, 3) as u32) << (texel * 2);
+        }
+    }
+
+    out.extend_from_slice(&color0.to_le_bytes());
+    out.extend_from_slice(&color1.to_le_bytes());
+    out.extend_from_slice(&indices.to_le_bytes());
+}
+
+// Two 8 bit endpoints of the red channel and 3 bit indices, in the eight value mode
+fn encode_bc4(block: &Block, out: &mut Vec<u8>) {
+    let red0 = block.iter().map(|color| color[0]).max().unwrap();
+    let red1 = block.iter().map(|color| color[0]).min().unwrap();
+
+    let mut indices = 0u64;
+    if red0 != red1 {
+        let mut palette = [[0.0; 4]; 8];
+        palette[0][0] = f32::from(red0);
+        palette[1][0] = f32::from(red1);
+        for step in 1..7 {
+            palette[step + 1][0] =
+                (f32::from(red0) * (7 - step) as f32 + f32::from(red1) * step as f32) / 7.0;
+        }
+        for (texel, color) in block.iter().enumerate() {
+            indices |= (nearest(&palette, color, 1) as u64) << (texel * 3);
+        }
+    }
+
+    out.push(red0);
+    out.push(red1);
+    out.extend_from_slice(&indices.to_le_bytes()[..6]);
+}
+
+// Mode 6 only: a single subset with two RGBA endpoints of 7 bits plus a p-bit, and 4 bit indices
+fn encode_bc7(block: &Block, out: &mut Vec<u8>) {
+    let (start, end) = principal_endpoints(block, 4);
+    let (mut endpoint0, mut p0) = quantize_bc7_endpoint(&start);
+    let (mut endpoint1, mut p1) = quantize_bc7_endpoint(&end);
+
+    let decoded0 = decode_bc7_endpoint(&endpoint0, p0);
+    let decoded1 = decode_bc7_endpoint(&endpoint1, p1);
+    let mut palette = [[0.0; 4]; 16];
+    for (entry, &weight) in palette.iter_mut().zip(BC7_WEIGHTS.iter()) {
+        for channel in 0..4 {
+            entry[channel] = ((u32::from(decoded0[channel]) * (64 - weight)
+                + u32::from(decoded1[channel]) * weight
+                + 32)
+                >> 6) as f32;
+        }
+    }
+    let mut indices = [0; 16];
+    for (index, color) in indices.iter_mut().zip(block.iter()) {
+        *index = nearest(&palette, color, 4) as u32;
+    }
+
+    // The index of the first texel is stored without its high bit
+    if indices[0] >= 8 {
+        std::mem::swap(&mut endpoint0, &mut endpoint1);
+        std::mem::swap(&mut p0, &mut p1);
+        for index in indices.iter_mut() {
+            *index = 15 - *index;
+        }
+    }
+
+    let mut bits = 0u128;
+    let mut position = 0;
+    let mut write = |value: u32, count: u32| {
+        bits |= u128::from(value) << position;
+        position += count;
+    };
+    write(1 << 6, 7);
+    for channel in 0..4 {
+        write(u32::from(endpoint0[channel]), 7);
+        write(u32::from(endpoint1[channel]), 7);
+    }
+    write(p0, 1);
+    write(p1, 1);
+    for (texel, &index) in indices.iter().enumerate() {
+        write(index, if texel == 0 { 3 } else { 4 });
+    }
+
+    out.extend_from_slice(&bits.to_le_bytes());
+}
+
+// The 7 bits of every channel and the p-bit they share, whichever p-bit is closer
+fn quantize_bc7_endpoint(color: &[f32; 4]) -> ([u8; 4], u32) {
+    let quantize = |p: u32| {
+        let mut endpoint = [0; 4];
+        let mut error = 0.0;
+        for channel in 0..4 {
+            let value = ((color[channel] - p as f32) / 2.0)
+                .round()
+                .clamp(0.0, 127.0) as u8;
+            let decoded = f32::from(value) * 2.0 + p as f32;
+            error += (decoded - color[channel]) * (decoded - color[channel]);
+            endpoint[channel] = value;
+        }
+        (endpoint, error)
+    };
+
+    let (endpoint0, error0) = quantize(0);
+    let (endpoint1, error1) = quantize(1);
+    if error0 <= error1 {
+        (endpoint0, 0)
+    } else {
+        (endpoint1, 1)
+    }
+}
+
+fn decode_bc7_endpoint(endpoint: &[u8; 4], p: u32) -> [u8; 4] {
+    let mut decoded = [0; 4];
+    for channel in 0..4 {
+        decoded[channel] = (endpoint[channel] << 1) | p as u8;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vulkan_ray_tracing::texture::compressed_level_size;
+
+    fn image(
+        width: u32,
+        height: u32,
+        srgb: bool,
+        pixel: impl Fn(u32, u32) -> [u8; 4],
+    ) -> ImageBuffer {
+        let mut pixels = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(&pixel(x, y));
+            }
+        }
+        ImageBuffer {
+            pixels,
+            tex_width: width,
+            tex_height: height,
+            tex_channels: 4,
+            srgb,
+            compression: None,
+        }
+    }
+
+    fn decode_bc1(bytes: &[u8]) -> Block {
+        let color0 = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let color1 = u16::from_le_bytes([bytes[2], bytes[3]]);
+        let indices = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let (c0, c1) = (unpack_565(color0), unpack_565(color1));
+        let mut palette = [c0, c1, [0.0, 0.0, 0.0, 255.0], [0.0, 0.0, 0.0, 255.0]];
+        for channel in 0..3 {
+            if color0 > color1 {
+                palette[2][channel] = (2.0 * c0[channel] + c1[channel]) / 3.0;
+                palette[3][channel] = (c0[channel] + 2.0 * c1[channel]) / 3.0;
+            } else {
+                palette[2][channel] = (c0[channel] + c1[channel]) / 2.0;
+            }
+        }
+
+        let mut block = [[0; 4]; 16];
+        for (texel, color) in block.iter_mut().enumerate() {
+            let entry = palette[(indices >> (texel * 2)) as usize & 3];
+            for channel in 0..4 {
+                color[channel] = entry[channel].round() as u8;
+            }
+        }
+        block
+    }
+
+    fn decode_bc4(bytes: &[u8]) -> Block {
+        let (red0, red1) = (f32::from(bytes[0]), f32::from(bytes[1]));
+        let mut index_bytes = [0; 8];
+        index_bytes[..6].copy_from_slice(&bytes[2..8]);
+        let indices = u64::from_le_bytes(index_bytes);
+        let mut palette = [red0, red1, 0.0, 0.0, 0.0, 0.0, 0.0, 255.0];
+        for step in 1..7 {
+            palette[step + 1] = if red0 > red1 {
+                (red0 * (7 - step) as f32 + red1 * step as f32) / 7.0
+            } else if step < 5 {
+                (red0 * (5 - step) as f32 + red1 * step as f32) / 5.0
+            } else {
+                palette[step + 1]
+            };
+        }
+
+        let mut block = [[0; 4]; 16];
+        for (texel, color) in block.iter_mut().enumerate() {
+            let red = palette[(indices >> (texel * 3)) as usize & 7].round() as u8;
+            *color = [red, red, red, 255];
+        }
+        block
+    }
+
+    fn decode_bc7(bytes: &[u8]) -> Block {
+        let mut block_bytes = [0; 16];
+        block_bytes.copy_from_slice(bytes);
+        let bits = u128::from_le_bytes(block_bytes);
+        let mut position = 0;
+        let mut read = |count: u32| {
+            let value = (bits >> position) as u32 & ((1 << count) - 1);
+            position += count;
+            value
+        };
+        assert_eq!(read(7), 1 << 6, "not a mode 6 block");
+        let (mut endpoint0, mut endpoint1) = ([0; 4], [0; 4]);
+        for channel in 0..4 {
+            endpoint0[channel] = read(7) as u8;
+            endpoint1[channel] = read(7) as u8;
+        }
+        let decoded0 = decode_bc7_endpoint(&endpoint0, read(1));
+        let decoded1 = decode_bc7_endpoint(&endpoint1, read(1));
+
+        let mut block = [[0; 4]; 16];
+        for (texel, color) in block.iter_mut().enumerate() {
+            let weight = BC7_WEIGHTS[read(if texel == 0 { 3 } else { 4 }) as usize];
+            for channel in 0..4 {
+                color[channel] = ((u32::from(decoded0[channel]) * (64 - weight)
+                    + u32::from(decoded1[channel]) * weight
+                    + 32)
+                    >> 6) as u8;
+            }
+        }
+        block
+    }
+
+    // The RGBA pixels of the first level of a compressed image
+    fn decode_level(image: &ImageBuffer) -> Vec<u8> {
+        let compression = image.compression.unwrap();
+        let (width, height) = (image.tex_width, image.tex_height);
+        let blocks_x = width.div_ceil(4);
+        let mut pixels = vec![0; (width * height) as usize * 4];
+        for (index, bytes) in image.pixels[..compressed_level_size(compression, width, height, 0)]
+            .chunks(compression.block_size())
+            .enumerate()
+        {
+            let block = match compression {
+                TextureCompression::Bc1 => decode_bc1(bytes),
+                TextureCompression::Bc4 => decode_bc4(bytes),
+                TextureCompression::Bc7 => decode_bc7(bytes),
+            };
+            let (block_x, block_y) = (index as u32 % blocks_x, index as u32 / blocks_x);
+            for (texel, color) in block.iter().enumerate() {
+                let x = block_x * 4 + texel as u32 % 4;
+                let y = block_y * 4 + texel as u32 / 4;
+                if x < width && y < height {
+                    let offset = (y * width + x) as usize * 4;
+                    pixels[offset..offset + 4].copy_from_slice(color);
+                }
+            }
+        }
+        pixels
+    }
+
+    fn assert_close(decoded: &[u8], expected: &[u8], tolerance: u8) {
+        assert_eq!(decoded.len(), expected.len());
+        for (index, (&decoded, &expected)) in decoded.iter().zip(expected).enumerate() {
+            assert!(
+                decoded.abs_diff(expected) <= tolerance,
+                "byte {}: decoded {}, expected {}",
+                index,
+                decoded,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn solid_color_round_trips() {
+        let color = [200, 100, 50, 255];
+        let mut high = image(8, 8, true, |_, _| color);
+        let expected = high.pixels.clone();
+        compress_image(&mut high, TextureQuality::High);
+        assert_eq!(high.compression, Some(TextureCompression::Bc7));
+        assert_close(&decode_level(&high), &expected, 1);
+
+        let mut low = image(8, 8, true, |_, _| color);
+        compress_image(&mut low, TextureQuality::Low);
+        assert_eq!(low.compression, Some(TextureCompression::Bc1));
+        assert_close(&decode_level(&low), &expected, 4);
+
+        let mut gray = image(8, 8, false, |_, _| [90, 90, 90, 255]);
+        let expected = gray.pixels.clone();
+        compress_image(&mut gray, TextureQuality::High);
+        assert_eq!(gray.compression, Some(TextureCompression::Bc4));
+        assert_close(&decode_level(&gray), &expected, 0);
+    }
+
+    // The columns step from one 565 color to the other through the two BC1 interpolated colors
+    #[test]
+    fn gradient_565_round_trips() {
+        let start = unpack_565(pack_565(&[16.0, 200.0, 64.0, 255.0]));
+        let end = unpack_565(pack_565(&[240.0, 40.0, 160.0, 255.0]));
+        let mut gradient = image(4, 4, false, |x, _| {
+            let mut color = [255; 4];
+            for channel in 0..3 {
+                let value = (start[channel] * (3 - x) as f32 + end[channel] * x as f32) / 3.0;
+                color[channel] = value.round() as u8;
+            }
+            color
+        });
+        let expected = gradient.pixels.clone();
+        compress_image(&mut gradient, TextureQuality::Low);
+        assert_eq!(gradient.compression, Some(TextureCompression::Bc1));
+        assert_close(&decode_level(&gradient), &expected, 1);
+    }
+
+    #[test]
+    fn edge_blocks_repeat_the_last_row_and_column() {
+        let pixels = image(6, 5, false, |x, y| [x as u8, y as u8, 0, 255]).pixels;
+        let block = fetch_block(&pixels, 6, 5, 1, 1);
+        for (texel, color) in block.iter().enumerate() {
+            let x = (4 + texel % 4).min(5) as u8;
+            let y = (4 + texel / 4).min(4) as u8;
+            assert_eq!(color, &[x, y, 0, 255]);
+        }
+
+        let color = [30, 60, 90, 255];
+        let mut odd = image(6, 5, true, |_, _| color);
+        let expected = odd.pixels.clone();
+        compress_image(&mut odd, TextureQuality::High);
+        assert_close(&decode_level(&odd), &expected, 1);
+    }
+
+    #[test]
+    fn mip_chain_goes_down_to_one_texel() {
+        let mut chain = image(13, 7, true, |x, y| {
+            [(x * 19) as u8, (y * 36) as u8, 128, 255]
+        });
+        compress_image(&mut chain, TextureQuality::High);
+
+        let compression = chain.compression.unwrap();
+        let mip_levels = full_mip_levels(13, 7);
+        assert_eq!(mip_levels, 4);
+        let size: usize = (0..mip_levels)
+            .map(|mip_level| compressed_level_size(compression, 13, 7, mip_level))
+            .sum();
+        assert_eq!(chain.pixels.len(), size);
+    }
+
+    #[test]
+    fn downsample_folds_the_odd_column() {
+        let pixels = image(3, 2, false, |x, _| [[0, 100, 200][x as usize], 0, 0, 255]).pixels;
+        let half = downsample(&pixels, 3, 2, false);
+        assert_eq!(half, vec![50, 0, 0, 255]);
+
+        let solid = image(5, 3, true, |_, _| [180, 20, 90, 255]).pixels;
+        let half = downsample(&solid, 5, 3, true);
+        assert_eq!(half.len(), 2 * 4);
+        assert_close(&half, &[180, 20, 90, 255, 180, 20, 90, 255], 1);
+    }
+}
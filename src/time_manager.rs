@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+// Longer frames are most likely a breakpoint or a stalled window, they count as a normal frame
+const MAX_DELTA_TIME: f32 = 1.0;
+// Fixed updates run at most in one frame, a slow frame then slows the simulation down instead of
+// making the next frames slower
+const MAX_FIXED_STEPS: u32 = 8;
+
+// Clock of the application, advanced once per frame. The scaled time drives the simulation and
+// stops while paused, the unscaled time follows the wall clock for the camera and the UI.
+pub struct TimeManager {
+    time_scale: f32,
+    paused: bool,
+    // Frames still advanced while paused, one per call to step
+    pending_steps: u32,
+    // Used instead of the measured delta of frames that took too long
+    fallback_delta_time: f32,
+    delta_time: f32,
+    unscaled_delta_time: f32,
+    time: f64,
+    unscaled_time: f64,
+    frame_count: u64,
+    fixed_delta_time: f32,
+    // Scaled time not simulated by the fixed updates yet, less than one fixed step
+    fixed_accumulator: f32,
+    fixed_steps: u32,
+}
+
+impl TimeManager {
+    pub fn new(target_framerate: u32) -> Self {
+        let fallback_delta_time = 1.0 / target_framerate.max(1) as f32;
+        TimeManager {
+            time_scale: 1.0,
+            paused: false,
+            pending_steps: 0,
+            fallback_delta_time,
+            delta_time: fallback_delta_time,
+            unscaled_delta_time: fallback_delta_time,
+            time: 0.0,
+            unscaled_time: 0.0,
+            frame_count: 0,
+            fixed_delta_time: 1.0 / 60.0,
+            fixed_accumulator: 0.0,
+            fixed_steps: 0,
+        }
+    }
+
+    // 0.5 runs at half speed and 2 at double speed, negative scales are clamped to 0
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.max(0.0);
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.pending_steps = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Advances the next frame by the fallback delta while paused, for stepping frame by frame
+    pub fn step(&mut self) {
+        if self.paused {
+            self.pending_steps += 1;
+        }
+    }
+
+    // Scaled, 0 while paused
+    pub fn delta_time(&self) -> f32 {
+        self.delta_time
+    }
+
+    pub fn unscaled_delta_time(&self) -> f32 {
+        self.unscaled_delta_time
+    }
+
+    // Scaled seconds since the start, it does not advance while paused
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn unscaled_time(&self) -> f64 {
+        self.unscaled_time
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Scaled seconds simulated by each fixed update, 1/60 by default
+    pub fn set_fixed_delta_time(&mut self, fixed_delta_time: f32) {
+        self.fixed_delta_time = fixed_delta_time.max(1e-4);
+        self.fixed_accumulator = self.fixed_accumulator.min(self.fixed_delta_time);
+    }
+
+    pub fn fixed_delta_time(&self) -> f32 {
+        self.fixed_delta_time
+    }
+
+    // Fixed updates run this frame, 0 while paused
+    pub fn fixed_steps(&self) -> u32 {
+        self.fixed_steps
+    }
+
+    // How far the frame is between the last two fixed updates, from 0 to 1, to blend their states
+    pub fn render_alpha(&self) -> f32 {
+        self.fixed_accumulator / self.fixed_delta_time
+    }
+
+    pub(crate) fn advance(&mut self, elapsed: Duration) {
+        let mut unscaled_delta_time = elapsed.as_secs_f32();
+        if unscaled_delta_time > MAX_DELTA_TIME {
+            unscaled_delta_time = self.fallback_delta_time;
+        }
+        self.unscaled_delta_time = unscaled_delta_time;
+        self.unscaled_time += f64::from(unscaled_delta_time);
+
+        self.delta_time = if !self.paused {
+            unscaled_delta_time * self.time_scale
+        } else if self.pending_steps > 0 {
+            self.pending_steps -= 1;
+            self.fallback_delta_time * self.time_scale
+        } else {
+            0.0
+        };
+        self.time += f64::from(self.delta_time);
+        self.frame_count += 1;
+
+        self.fixed_accumulator += self.delta_time;
+        self.fixed_steps = (self.fixed_accumulator / self.fixed_delta_time) as u32;
+        self.fixed_accumulator =
+            (self.fixed_accumulator - self.fixed_steps as f32 * self.fixed_delta_time).max(0.0);
+        if self.fixed_steps > MAX_FIXED_STEPS {
+            self.fixed_steps = MAX_FIXED_STEPS;
+            self.fixed_accumulator = 0.0;
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use vulkan_ray_tracing::glm;
+
+// Transforms of an instance at the last two fixed updates. The frames rendered in between show
+// a blend of both, so the motion stays smooth whatever the framerate.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TransformHistory {
+    previous: glm::Mat4,
+    current: glm::Mat4,
+    // Fixed update that set the current transform
+    step: u64,
+    // The current transform was rendered after the instance stopped moving
+    settled: bool,
+}
+
+impl TransformHistory {
+    pub fn new(previous: glm::Mat4, current: glm::Mat4, step: u64) -> Self {
+        TransformHistory {
+            previous,
+            current,
+            step,
+            settled: false,
+        }
+    }
+
+    // Setting the transform twice in the same fixed update keeps the first previous transform
+    pub fn push(&mut self, transform: glm::Mat4, step: u64) {
+        if self.step != step {
+            self.previous = self.current;
+        }
+        self.current = transform;
+        self.step = step;
+        self.settled = false;
+    }
+
+    // The transform to render, render_alpha goes from 0 at the previous fixed update to 1 at
+    // the current one. None once the instance rests at its current transform.
+    pub fn sample(&mut self, render_alpha: f32, step: u64) -> Option<glm::Mat4> {
+        if self.step == step {
+            return Some(interpolate(&self.previous, &self.current, render_alpha));
+        }
+
+        // Not moved at the last fixed update
+        if self.settled {
+            None
+        } else {
+            self.settled = true;
+            Some(self.current)
+        }
+    }
+}
+
+// Blends the translations, rotations and scales apart, a blend of the matrices themselves would
+// shrink the rotating instances
+fn interpolate(from: &glm::Mat4, to: &glm::Mat4, alpha: f32) -> glm::Mat4 {
+    let alpha = alpha.clamp(0.0, 1.0);
+    let (from_translation, from_rotation, from_scale) = decompose(from);
+    let (to_translation, mut to_rotation, to_scale) = decompose(to);
+
+    // Along the shortest arc
+    if glm::quat_dot(&from_rotation, &to_rotation) < 0.0 {
+        to_rotation = -to_rotation;
+    }
+    let rotation = glm::quat_normalize(&glm::quat_lerp(&from_rotation, &to_rotation, alpha));
+
+    glm::translation(&glm::lerp(&from_translation, &to_translation, alpha))
+        * glm::quat_to_mat4(&rotation)
+        * glm::scaling(&glm::lerp(&from_scale, &to_scale, alpha))
+}
+
+fn decompose(transform: &glm::Mat4) -> (glm::Vec3, glm::Qua<f32>, glm::Vec3) {
+    let translation = (transform * glm::vec4(0.0, 0.0, 0.0, 1.0)).xyz();
+    let mut rotation = glm::mat4_to_mat3(transform);
+    let mut scale = glm::vec3(1.0, 1.0, 1.0);
+    for axis in 0..3 {
+        let length = glm::length(&rotation.column(axis).into_owned()).max(1e-6);
+        scale[axis] = length;
+        for row in 0..3 {
+            rotation[(row, axis)] /= length;
+        }
+    }
+    (translation, glm::mat3_to_quat(&rotation), scale)
+}
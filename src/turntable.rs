@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use vulkan_ray_tracing::geometry_instance::GeometryInstance;
+use vulkan_ray_tracing::glm;
+use vulkan_ray_tracing::render_settings::AovSettings;
+
+use crate::camera_manager::{Camera, CameraProperties, CameraType, FIELD_OF_VIEW};
+
+// Angle of the orbit above the horizon
+const ELEVATION: f32 = 20.0;
+// Room left around the bounds of the scene
+const MARGIN: f32 = 1.1;
+
+pub(crate) struct TurntableExport {
+    pub directory: PathBuf,
+    pub frame_rate: u32,
+    // Restored once the turntable is done, the export reads the color AOV
+    pub aovs: AovSettings,
+}
+
+// Orbits a camera around the scene, starting in front of it on the +Z side
+pub(crate) struct Turntable {
+    duration: f32,
+    revolutions: f32,
+    elapsed: f32,
+    frame_index: u32,
+    pub export: Option<TurntableExport>,
+}
+
+impl Turntable {
+    pub fn new(duration: Duration, revolutions: f32, export: Option<TurntableExport>) -> Self {
+        Turntable {
+            duration: duration.as_secs_f32(),
+            revolutions,
+            elapsed: 0.0,
+            frame_index: 0,
+            export,
+        }
+    }
+
+    // Exports step by their frame rate whatever the frames took, for a steady animation. The
+    // last frame comes before the end, a whole number of revolutions then loops.
+    pub fn advance(&mut self, delta_time: f32) {
+        self.elapsed += match self.export.as_ref() {
+            Some(export) => 1.0 / export.frame_rate.max(1) as f32,
+            None => delta_time,
+        };
+        self.frame_index += 1;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+
+    // Numbered from 0, for tools that assemble the frames
+    pub fn frame_path(&self) -> Option<PathBuf> {
+        self.export.as_ref().map(|export| {
+            export
+                .directory
+                .join(format!("frame_{:04}.png", self.frame_index))
+        })
+    }
+
+    // Far enough to see the whole sphere with the field of view, the properties give the
+    // projection
+    pub fn camera(
+        &self,
+        bounds: (glm::Vec3, f32),
+        properties: CameraProperties,
+        width: f32,
+        height: f32,
+    ) -> Camera {
+        let (center, radius) = bounds;
+        let progress = if self.duration > 0.0 {
+            self.elapsed / self.duration
+        } else {
+            1.0
+        };
+        let angle = progress * self.revolutions * std::f32::consts::PI * 2.0;
+        let elevation = ELEVATION.to_radians();
+        let direction = glm::vec3(
+            angle.sin() * elevation.cos(),
+            elevation.sin(),
+            angle.cos() * elevation.cos(),
+        );
+
+        let radius = radius * MARGIN;
+        let half_fov = (FIELD_OF_VIEW * 0.5).to_radians();
+        let fit_width = width.min(height) / height;
+        let distance = radius / (half_fov.tan() * fit_width).atan().sin();
+        let properties = CameraProperties {
+            position: center + direction * distance,
+            ortho_size: match properties.camera_type {
+                CameraType::Orthographic => radius * 2.0 / fit_width,
+                CameraType::Perspective => properties.ortho_size,
+            },
+            far: properties.far.max(distance + radius),
+            ..properties
+        };
+        Camera::look_at(center, &properties, width, height)
+    }
+}
+
+// Center and radius of a sphere around the instances, None without any
+pub(crate) fn scene_bounds<'a, I>(geometry_instances: I) -> Option<(glm::Vec3, f32)>
+where
+    I: Iterator<Item = &'a GeometryInstance>,
+{
+    let mut bounds: Option<(glm::Vec3, glm::Vec3)> = None;
+    for geometry_instance in geometry_instances {
+        let transform = &geometry_instance.transform;
+        let sphere = geometry_instance.bounding_sphere;
+        let center = (transform * glm::vec4(sphere.x, sphere.y, sphere.z, 1.0)).xyz();
+        // The largest scale of the transform
+        let scale = (0..3)
+            .map(|column| {
+                glm::length(&glm::vec3(
+                    transform[(0, column)],
+                    transform[(1, column)],
+                    transform[(2, column)],
+                ))
+            })
+            .fold(0.0, f32::max);
+        let extent = glm::vec3(1.0, 1.0, 1.0) * sphere.w * scale;
+        let (min, max) = (center - extent, center + extent);
+        bounds = Some(match bounds {
+            Some((bounds_min, bounds_max)) => {
+                (glm::min2(&bounds_min, &min), glm::max2(&bounds_max, &max))
+            }
+            None => (min, max),
+        });
+    }
+
+    bounds.map(|(min, max)| ((min + max) * 0.5, glm::distance(&min, &max) * 0.5))
+}
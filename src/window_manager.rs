@@ -8,9 +8,12 @@ use winit::platform::desktop::EventLoopExtDesktop;
 use winit::platform::windows::WindowExtWindows;
 use winit::window::{Window, WindowBuilder};
 
+use crate::event_bus::{EngineEvent, EventBus};
+
 pub struct WindowManager {
     event_loop: EventLoop<()>,
     window: Window,
+    event_bus: EventBus,
 }
 
 pub struct Size {
@@ -19,16 +22,27 @@ pub struct Size {
 }
 
 impl WindowManager {
-    pub fn new(title: &str, width: u32, height: u32) -> Result<WindowManager, OsError> {
+    pub fn new(
+        title: &str,
+        width: u32,
+        height: u32,
+        visible: bool,
+        event_bus: EventBus,
+    ) -> Result<WindowManager, OsError> {
         let event_loop = EventLoop::new();
 
         let window = WindowBuilder::new()
             .with_title(title)
             .with_inner_size((width, height).into())
             .with_resizable(false)
+            .with_visible(visible)
             .build(&event_loop)?;
 
-        Ok(WindowManager { event_loop, window })
+        Ok(WindowManager {
+            event_loop,
+            window,
+            event_bus,
+        })
     }
 
     pub fn hwnd(&self) -> *mut c_void {
@@ -50,6 +64,7 @@ impl WindowManager {
     {
         let mut event_loop = self.event_loop;
         let window = self.window;
+        let event_bus = self.event_bus;
 
         let mut events = vec![];
         let mut mouse_position = LogicalPosition::new(0.0, 0.0);
@@ -74,6 +89,16 @@ impl WindowManager {
                 } => {
                     mouse_position = position;
                 }
+                Event::WindowEvent {
+                    event: WindowEvent::Resized(size),
+                    ..
+                } => {
+                    let physical_size = size.to_physical(window.hidpi_factor());
+                    event_bus.publish(EngineEvent::WindowResized {
+                        width: physical_size.width as u32,
+                        height: physical_size.height as u32,
+                    });
+                }
                 Event::DeviceEvent { event, .. } => {
                     events.push(event);
                 }
@@ -1,25 +1,26 @@
-use std::mem;
-use std::os::raw::c_void;
 use std::rc::Rc;
 
 use ash::vk;
+use bytemuck::{Pod, Zeroable};
 use nalgebra_glm as glm;
-use vulkan_bootstrap::buffer::{Buffer, BufferBuilder, BufferType};
 use vulkan_bootstrap::errors::VulkanError;
 use vulkan_bootstrap::vulkan_context::VulkanContext;
 
+use crate::barrier_commands::{BarrierCommands, DependencyInfo, MemoryBarrier2};
 use crate::bottom_level_acceleration_structure::BottomLevelAccelerationStructure;
+use crate::buffer::{align_up, DataBuffer, DataBufferBuilder, MemoryLocation};
 use crate::ray_tracing::RayTracing;
-use std::convert::TryInto;
 
 pub struct Instance {
     pub bottom_level_as: vk::AccelerationStructureNV,
     pub transform: glm::Mat4,
     pub instance_id: u32,
     pub hit_group_index: u32,
+    pub flags: vk::GeometryInstanceFlagsNV,
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct VulkanGeometryInstance {
     transform: [f32; 12],
     instance_id_and_mask: u32,
@@ -27,6 +28,10 @@ struct VulkanGeometryInstance {
     acceleration_handle: u64,
 }
 
+// 64 bytes, the handle is 8 bytes aligned so there is no padding
+unsafe impl Zeroable for VulkanGeometryInstance {}
+unsafe impl Pod for VulkanGeometryInstance {}
+
 impl VulkanGeometryInstance {
     pub fn new(
         transform: [f32; 12],
@@ -72,9 +77,11 @@ impl VulkanGeometryInstance {
 
 pub struct AccelerationStructure {
     ray_tracing: Rc<RayTracing>,
-    _scratch_buffer: Buffer,
-    _result_buffer: Buffer,
-    _instances_buffer: Option<Buffer>,
+    scratch_buffer: DataBuffer,
+    result_buffer: DataBuffer,
+    // Top level only, also read by the instance culling pass
+    instances_buffer: Option<DataBuffer>,
+    instance_count: u32,
     acc_structure: vk::AccelerationStructureNV,
 }
 
@@ -89,6 +96,41 @@ impl AccelerationStructure {
     pub fn get(&self) -> vk::AccelerationStructureNV {
         self.acc_structure
     }
+
+    pub fn get_instances_buffer(&self) -> Option<&DataBuffer> {
+        self.instances_buffer.as_ref()
+    }
+
+    pub fn instance_count(&self) -> u32 {
+        self.instance_count
+    }
+
+    pub fn memory_size(&self) -> vk::DeviceSize {
+        let instances_size = self
+            .instances_buffer
+            .as_ref()
+            .map_or(0, |instances_buffer| instances_buffer.size());
+        self.result_buffer.size() + self.scratch_buffer.size() + instances_size
+    }
+
+    // Builds the top level structure again from other instances of the same count, e.g. with
+    // different masks. The instances are read by the build, which has to be synchronized.
+    pub fn cmd_rebuild_top_level(&self, command_buffer: vk::CommandBuffer, instances: vk::Buffer) {
+        let build_info = vk::AccelerationStructureInfoNV::builder()
+            .flags(vk::BuildAccelerationStructureFlagsNV::empty())
+            .ty(vk::AccelerationStructureTypeNV::TOP_LEVEL)
+            .instance_count(self.instance_count)
+            .build();
+
+        self.ray_tracing.cmd_build_acceleration_structure(
+            command_buffer,
+            &build_info,
+            instances,
+            self.acc_structure,
+            self.scratch_buffer.get(),
+            0,
+        );
+    }
 }
 
 pub struct AccelerationStructureBuilder<'a> {
@@ -129,6 +171,11 @@ impl<'a> AccelerationStructureBuilder<'a> {
     }
 
     pub fn build(self) -> Result<AccelerationStructure, VulkanError> {
+        let _span = tracing::info_span!(
+            "build_acceleration_structure",
+            top_level = self.top_level_as.is_some()
+        )
+        .entered();
         let as_info = if self.bottom_level_as.is_some() {
             vk::AccelerationStructureInfoNV::builder()
                 .ty(vk::AccelerationStructureTypeNV::BOTTOM_LEVEL)
@@ -154,34 +201,39 @@ impl<'a> AccelerationStructureBuilder<'a> {
             .ray_tracing
             .create_acceleration_structure(&as_create_info)?;
 
-        let (scratch_size, result_size) = self.compute_as_buffer_sizes(acc_structure);
-
-        let instances_size = if self.top_level_as.is_some() {
-            (self.top_level_as.unwrap().len() * mem::size_of::<VulkanGeometryInstance>())
-                as vk::DeviceSize
-        } else {
-            0
-        };
+        let (scratch_requirements, result_requirements) =
+            self.compute_as_memory_requirements(acc_structure);
 
-        let scratch_buffer = BufferBuilder::new(self.context)
-            .with_type(BufferType::RayTracing)
-            .with_size(scratch_size)
+        let scratch_buffer = DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::RAY_TRACING_NV | vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_size(scratch_requirements.size)
+            .with_memory_requirements(scratch_requirements)
             .build()?;
 
-        let result_buffer = BufferBuilder::new(self.context)
-            .with_type(BufferType::RayTracing)
-            .with_size(result_size)
+        // The acceleration structure is bound to this memory, so it must satisfy its requirements
+        let result_buffer = DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::RAY_TRACING_NV)
+            .with_location(MemoryLocation::Device)
+            .with_size(result_requirements.size)
+            .with_memory_requirements(result_requirements)
             .build()?;
 
-        let instances_buffer = if self.bottom_level_as.is_some() {
-            None
-        } else {
-            Some(
-                BufferBuilder::new(self.context)
-                    .with_type(BufferType::RayTracingInstance)
-                    .with_size(instances_size)
-                    .build()?,
-            )
+        let instances_buffer = match self.top_level_as {
+            Some(top_level_as) => {
+                let geometry_instances = self.create_geometry_instances(top_level_as)?;
+                Some(
+                    DataBufferBuilder::new(self.context)
+                        .with_usage(
+                            vk::BufferUsageFlags::RAY_TRACING_NV
+                                | vk::BufferUsageFlags::STORAGE_BUFFER,
+                        )
+                        .with_location(MemoryLocation::Host)
+                        .with_data(&geometry_instances)
+                        .build()?,
+                )
+            }
+            None => None,
         };
 
         self.generate(
@@ -194,35 +246,48 @@ impl<'a> AccelerationStructureBuilder<'a> {
         Ok(AccelerationStructure {
             ray_tracing: self.ray_tracing,
             acc_structure,
-            _scratch_buffer: scratch_buffer,
-            _result_buffer: result_buffer,
-            _instances_buffer: instances_buffer,
+            scratch_buffer,
+            result_buffer,
+            instances_buffer,
+            instance_count: self
+                .top_level_as
+                .map_or(0, |instances| instances.len() as u32),
         })
     }
 
-    fn compute_as_buffer_sizes(
+    fn compute_as_memory_requirements(
         &self,
         acc_structure: vk::AccelerationStructureNV,
-    ) -> (vk::DeviceSize, vk::DeviceSize) {
-        let mem_requirements = self.get_memory_requirements(
-            acc_structure,
-            vk::AccelerationStructureMemoryRequirementsTypeNV::OBJECT,
-        );
-        let result_size = mem_requirements.memory_requirements.size;
+    ) -> (vk::MemoryRequirements, vk::MemoryRequirements) {
+        let result_requirements = self
+            .get_memory_requirements(
+                acc_structure,
+                vk::AccelerationStructureMemoryRequirementsTypeNV::OBJECT,
+            )
+            .memory_requirements;
 
-        let mem_requirements = self.get_memory_requirements(
-            acc_structure,
-            vk::AccelerationStructureMemoryRequirementsTypeNV::BUILD_SCRATCH,
-        );
-        let scratch_size = mem_requirements.memory_requirements.size;
+        let build_scratch = self
+            .get_memory_requirements(
+                acc_structure,
+                vk::AccelerationStructureMemoryRequirementsTypeNV::BUILD_SCRATCH,
+            )
+            .memory_requirements;
 
-        let mem_requirements = self.get_memory_requirements(
-            acc_structure,
-            vk::AccelerationStructureMemoryRequirementsTypeNV::UPDATE_SCRATCH,
-        );
-        let scratch_size = scratch_size.max(mem_requirements.memory_requirements.size);
+        let update_scratch = self
+            .get_memory_requirements(
+                acc_structure,
+                vk::AccelerationStructureMemoryRequirementsTypeNV::UPDATE_SCRATCH,
+            )
+            .memory_requirements;
+
+        let alignment = build_scratch.alignment.max(update_scratch.alignment);
+        let scratch_requirements = vk::MemoryRequirements {
+            size: align_up(build_scratch.size.max(update_scratch.size), alignment),
+            alignment,
+            memory_type_bits: build_scratch.memory_type_bits & update_scratch.memory_type_bits,
+        };
 
-        (scratch_size, result_size)
+        (scratch_requirements, result_requirements)
     }
 
     fn get_memory_requirements(
@@ -238,38 +303,45 @@ impl<'a> AccelerationStructureBuilder<'a> {
             .get_acceleration_structure_memory_requirements(&mem_requirements_info)
     }
 
-    fn generate(
+    fn create_geometry_instances(
         &self,
-        acc_structure: vk::AccelerationStructureNV,
-        scratch_buffer: &Buffer,
-        result_buffer: &Buffer,
-        instances_buffer: Option<&Buffer>,
-    ) -> Result<(), VulkanError> {
-        if let Some(top_level_as) = self.top_level_as {
-            let mut geometry_instances = Vec::with_capacity(top_level_as.len());
-            for tlas in top_level_as.iter() {
-                let handle = self
-                    .ray_tracing
-                    .get_acceleration_structure_handle(tlas.bottom_level_as)?;
-
-                let transform = &tlas.transform.as_slice()[0..12];
-                let g_inst = VulkanGeometryInstance::new(
-                    transform.try_into().unwrap(),
-                    tlas.instance_id,
-                    std::u8::MAX,
-                    tlas.hit_group_index,
-                    vk::GeometryInstanceFlagsNV::TRIANGLE_CULL_DISABLE,
-                    handle,
-                );
-
-                geometry_instances.push(g_inst);
+        top_level_as: &[Instance],
+    ) -> Result<Vec<VulkanGeometryInstance>, VulkanError> {
+        let mut geometry_instances = Vec::with_capacity(top_level_as.len());
+        for tlas in top_level_as.iter() {
+            let handle = self
+                .ray_tracing
+                .get_acceleration_structure_handle(tlas.bottom_level_as)?;
+
+            // Vulkan expects the first three rows, glm stores the matrix column major
+            let mut transform = [0.0; 12];
+            for row in 0..3 {
+                for column in 0..4 {
+                    transform[row * 4 + column] = tlas.transform[(row, column)];
+                }
             }
-
-            instances_buffer
-                .unwrap()
-                .copy_data(geometry_instances.as_ptr() as *const c_void)?;
+            let g_inst = VulkanGeometryInstance::new(
+                transform,
+                tlas.instance_id,
+                u8::MAX,
+                tlas.hit_group_index,
+                tlas.flags,
+                handle,
+            );
+
+            geometry_instances.push(g_inst);
         }
 
+        Ok(geometry_instances)
+    }
+
+    fn generate(
+        &self,
+        acc_structure: vk::AccelerationStructureNV,
+        scratch_buffer: &DataBuffer,
+        result_buffer: &DataBuffer,
+        instances_buffer: Option<&DataBuffer>,
+    ) -> Result<(), VulkanError> {
         let bind_info = vk::BindAccelerationStructureMemoryInfoNV::builder()
             .acceleration_structure(acc_structure)
             .memory(result_buffer.get_memory())
@@ -308,25 +380,19 @@ impl<'a> AccelerationStructureBuilder<'a> {
             0,
         );
 
-        let memory_barrier = vk::MemoryBarrier::builder()
-            .src_access_mask(
-                vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_NV
-                    | vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV,
-            )
-            .dst_access_mask(
-                vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_NV
-                    | vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV,
-            )
-            .build();
-
-        self.context.get_device().cmd_pipeline_barrier(
+        self.context.get_device().cmd_pipeline_barrier2(
             self.command_buffer.unwrap(),
-            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV,
-            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV,
-            vk::DependencyFlags::empty(),
-            &[memory_barrier],
-            &[],
-            &[],
+            &DependencyInfo {
+                memory_barriers: &[MemoryBarrier2 {
+                    src_stage_mask: vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV,
+                    src_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_NV
+                        | vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV,
+                    dst_stage_mask: vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV,
+                    dst_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_NV
+                        | vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV,
+                }],
+                ..Default::default()
+            },
         );
 
         Ok(())
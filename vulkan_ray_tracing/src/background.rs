@@ -0,0 +1,141 @@
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+
+use crate::sky::Sky;
+
+// What rays that hit nothing return
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Background {
+    Solid(glm::Vec4),
+    // Blended on the height of the ray direction, from the bottom color straight down to the top
+    // color straight up
+    Gradient { top: glm::Vec3, bottom: glm::Vec3 },
+    // The environment map, scaled by the intensity and turned around the Y axis by the rotation
+    // in radians. Black until a map is set.
+    Environment { intensity: f32, rotation: f32 },
+    // Zero color and alpha, so the output is premultiplied and can be composited over anything
+    Transparent,
+    // Procedural daylight, an alternative to environment maps that follows the sun
+    Sky(Sky),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Solid(glm::vec4(0.0, 0.0, 0.0, 1.0))
+    }
+}
+
+// Equirectangular image in linear RGBA, the top row looks straight up
+#[derive(Clone)]
+pub struct EnvironmentMap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<f32>,
+}
+
+impl EnvironmentMap {
+    // Bound when no map is set, the shaders always need a texture
+    pub(crate) fn black() -> Self {
+        EnvironmentMap {
+            width: 1,
+            height: 1,
+            pixels: vec![0.0, 0.0, 0.0, 1.0],
+        }
+    }
+
+    // Half floats, linear filtering of 32 bit float textures is optional
+    pub(crate) fn half_pixels(&self) -> Vec<u16> {
+        self.pixels.iter().map(|&value| f32_to_f16(value)).collect()
+    }
+}
+
+// Rounded toward zero, values out of range become infinity
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // NaN keeps a mantissa bit
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // Subnormal, the implicit leading bit becomes part of the mantissa
+        let mantissa = mantissa | 0x80_0000;
+        return sign | (mantissa >> (14 - exponent)) as u16;
+    }
+
+    sign | ((exponent as u16) << 10) | (mantissa >> 13) as u16
+}
+
+// std140 layout of the Background uniform block of the shaders
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct BackgroundUniform {
+    // Top of the gradient or the sun direction and turbidity of the sky, solid colors are in the
+    // frame constants
+    color: [f32; 4],
+    bottom: [f32; 4],
+    mode: u32,
+    intensity: f32,
+    rotation: f32,
+    padding: u32,
+}
+
+unsafe impl Zeroable for BackgroundUniform {}
+unsafe impl Pod for BackgroundUniform {}
+
+impl From<&Background> for BackgroundUniform {
+    fn from(background: &Background) -> Self {
+        let mut uniform = BackgroundUniform {
+            color: [0.0; 4],
+            bottom: [0.0; 4],
+            mode: 0,
+            intensity: 1.0,
+            rotation: 0.0,
+            padding: 0,
+        };
+
+        match *background {
+            // The color is read from the frame constants
+            Background::Solid(_) => {}
+            Background::Gradient { top, bottom } => {
+                uniform.mode = 1;
+                uniform.color = [top.x, top.y, top.z, 1.0];
+                uniform.bottom = [bottom.x, bottom.y, bottom.z, 1.0];
+            }
+            Background::Environment {
+                intensity,
+                rotation,
+            } => {
+                uniform.mode = 2;
+                uniform.intensity = intensity;
+                uniform.rotation = rotation;
+            }
+            Background::Transparent => uniform.mode = 3,
+            Background::Sky(sky) => {
+                let sun_direction = glm::normalize(&sky.sun_direction);
+                uniform.mode = 4;
+                uniform.color = [
+                    sun_direction.x,
+                    sun_direction.y,
+                    sun_direction.z,
+                    sky.turbidity,
+                ];
+                uniform.intensity = sky.intensity;
+            }
+        }
+
+        uniform
+    }
+}
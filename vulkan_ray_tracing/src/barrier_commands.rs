@@ -0,0 +1,151 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+
+// Barriers in the style of VK_KHR_synchronization2, every barrier carries its own stages next to
+// its accesses. Only vkCmdPipelineBarrier is available, so they are recorded with one call per
+// pair of stages.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryBarrier2 {
+    pub src_stage_mask: vk::PipelineStageFlags,
+    pub src_access_mask: vk::AccessFlags,
+    pub dst_stage_mask: vk::PipelineStageFlags,
+    pub dst_access_mask: vk::AccessFlags,
+}
+
+// Covers the whole buffer
+#[derive(Clone, Copy, Debug)]
+pub struct BufferMemoryBarrier2 {
+    pub src_stage_mask: vk::PipelineStageFlags,
+    pub src_access_mask: vk::AccessFlags,
+    pub dst_stage_mask: vk::PipelineStageFlags,
+    pub dst_access_mask: vk::AccessFlags,
+    pub buffer: vk::Buffer,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImageMemoryBarrier2 {
+    pub src_stage_mask: vk::PipelineStageFlags,
+    pub src_access_mask: vk::AccessFlags,
+    pub dst_stage_mask: vk::PipelineStageFlags,
+    pub dst_access_mask: vk::AccessFlags,
+    pub old_layout: vk::ImageLayout,
+    pub new_layout: vk::ImageLayout,
+    pub image: vk::Image,
+    pub subresource_range: vk::ImageSubresourceRange,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DependencyInfo<'a> {
+    pub memory_barriers: &'a [MemoryBarrier2],
+    pub buffer_memory_barriers: &'a [BufferMemoryBarrier2],
+    pub image_memory_barriers: &'a [ImageMemoryBarrier2],
+}
+
+pub trait BarrierCommands {
+    fn cmd_pipeline_barrier2(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        dependency_info: &DependencyInfo,
+    );
+}
+
+// The barriers of one vkCmdPipelineBarrier call
+struct BarrierBatch {
+    src_stage_mask: vk::PipelineStageFlags,
+    dst_stage_mask: vk::PipelineStageFlags,
+    memory_barriers: Vec<vk::MemoryBarrier>,
+    buffer_memory_barriers: Vec<vk::BufferMemoryBarrier>,
+    image_memory_barriers: Vec<vk::ImageMemoryBarrier>,
+}
+
+fn batch_for(
+    batches: &mut Vec<BarrierBatch>,
+    src_stage_mask: vk::PipelineStageFlags,
+    dst_stage_mask: vk::PipelineStageFlags,
+) -> &mut BarrierBatch {
+    let index = match batches.iter().position(|batch| {
+        batch.src_stage_mask == src_stage_mask && batch.dst_stage_mask == dst_stage_mask
+    }) {
+        Some(index) => index,
+        None => {
+            batches.push(BarrierBatch {
+                src_stage_mask,
+                dst_stage_mask,
+                memory_barriers: vec![],
+                buffer_memory_barriers: vec![],
+                image_memory_barriers: vec![],
+            });
+            batches.len() - 1
+        }
+    };
+    &mut batches[index]
+}
+
+impl BarrierCommands for VulkanDevice {
+    fn cmd_pipeline_barrier2(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        dependency_info: &DependencyInfo,
+    ) {
+        let mut batches: Vec<BarrierBatch> = vec![];
+
+        for barrier in dependency_info.memory_barriers {
+            batch_for(&mut batches, barrier.src_stage_mask, barrier.dst_stage_mask)
+                .memory_barriers
+                .push(
+                    vk::MemoryBarrier::builder()
+                        .src_access_mask(barrier.src_access_mask)
+                        .dst_access_mask(barrier.dst_access_mask)
+                        .build(),
+                );
+        }
+
+        for barrier in dependency_info.buffer_memory_barriers {
+            batch_for(&mut batches, barrier.src_stage_mask, barrier.dst_stage_mask)
+                .buffer_memory_barriers
+                .push(
+                    vk::BufferMemoryBarrier::builder()
+                        .src_access_mask(barrier.src_access_mask)
+                        .dst_access_mask(barrier.dst_access_mask)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .buffer(barrier.buffer)
+                        .offset(0)
+                        .size(vk::WHOLE_SIZE)
+                        .build(),
+                );
+        }
+
+        for barrier in dependency_info.image_memory_barriers {
+            batch_for(&mut batches, barrier.src_stage_mask, barrier.dst_stage_mask)
+                .image_memory_barriers
+                .push(
+                    vk::ImageMemoryBarrier::builder()
+                        .src_access_mask(barrier.src_access_mask)
+                        .dst_access_mask(barrier.dst_access_mask)
+                        .old_layout(barrier.old_layout)
+                        .new_layout(barrier.new_layout)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(barrier.image)
+                        .subresource_range(barrier.subresource_range)
+                        .build(),
+                );
+        }
+
+        for batch in batches.iter() {
+            unsafe {
+                self.get().cmd_pipeline_barrier(
+                    command_buffer,
+                    batch.src_stage_mask,
+                    batch.dst_stage_mask,
+                    vk::DependencyFlags::empty(),
+                    &batch.memory_barriers,
+                    &batch.buffer_memory_barriers,
+                    &batch.image_memory_barriers,
+                );
+            }
+        }
+    }
+}
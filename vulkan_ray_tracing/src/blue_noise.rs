@@ -0,0 +1,149 @@
+// Blue noise made with the void and cluster method (Ulichney 1993).
+// Every texel gets a unique rank, so thresholding the texture at any level gives evenly spread points.
+
+const SIGMA: f32 = 1.5;
+
+// Tightly packed RGBA8 texels of a size x size tileable texture. The channels hold the same
+// pattern shifted by half the size in x, y and both, so they are decorrelated from each other.
+pub fn generate_blue_noise(size: usize, seed: u32) -> Vec<u8> {
+    let ranks = void_and_cluster(size, seed);
+    let texel_count = (size * size) as f32;
+    let value = |x: usize, y: usize| {
+        let rank = ranks[(y % size) * size + x % size];
+        ((rank as f32 + 0.5) / texel_count * 256.0) as u8
+    };
+
+    let half = size / 2;
+    let mut pixels = Vec::with_capacity(size * size * 4);
+    for y in 0..size {
+        for x in 0..size {
+            pixels.push(value(x, y));
+            pixels.push(value(x + half, y));
+            pixels.push(value(x, y + half));
+            pixels.push(value(x + half, y + half));
+        }
+    }
+    pixels
+}
+
+// Gaussian energy of every texel against the set ones, wrapping around the edges
+struct EnergyField {
+    size: usize,
+    kernel: Vec<f32>,
+    energy: Vec<f32>,
+}
+
+impl EnergyField {
+    fn new(size: usize) -> Self {
+        let mut kernel = vec![0.0; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let dx = x.min(size - x) as f32;
+                let dy = y.min(size - y) as f32;
+                kernel[y * size + x] = (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+
+        EnergyField {
+            size,
+            kernel,
+            energy: vec![0.0; size * size],
+        }
+    }
+
+    fn splat(&mut self, index: usize, sign: f32) {
+        let (px, py) = (index % self.size, index / self.size);
+        for y in 0..self.size {
+            let ky = (y + self.size - py) % self.size;
+            for x in 0..self.size {
+                let kx = (x + self.size - px) % self.size;
+                self.energy[y * self.size + x] += sign * self.kernel[ky * self.size + kx];
+            }
+        }
+    }
+
+    // Set texel with the most energy around it
+    fn tightest_cluster(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, true, |a, b| a > b)
+    }
+
+    // Unset texel with the least energy around it
+    fn largest_void(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, false, |a, b| a < b)
+    }
+
+    fn extreme(&self, pattern: &[bool], set: bool, better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best = None;
+        for (index, &energy) in self.energy.iter().enumerate() {
+            if pattern[index] != set {
+                continue;
+            }
+            match best {
+                Some((_, best_energy)) if !better(energy, best_energy) => {}
+                _ => best = Some((index, energy)),
+            }
+        }
+        best.map(|(index, _)| index).unwrap_or(0)
+    }
+}
+
+fn void_and_cluster(size: usize, seed: u32) -> Vec<u32> {
+    let texel_count = size * size;
+    let mut field = EnergyField::new(size);
+
+    // Initial random pattern with a tenth of the texels set
+    let mut state = seed.max(1);
+    let mut pattern = vec![false; texel_count];
+    let initial_count = (texel_count / 10).max(1);
+    let mut set_count = 0;
+    while set_count < initial_count {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let index = state as usize % texel_count;
+        if !pattern[index] {
+            pattern[index] = true;
+            field.splat(index, 1.0);
+            set_count += 1;
+        }
+    }
+
+    // Moves texels from clusters to voids until the pattern is evenly spread
+    for _ in 0..texel_count {
+        let cluster = field.tightest_cluster(&pattern);
+        pattern[cluster] = false;
+        field.splat(cluster, -1.0);
+
+        let void = field.largest_void(&pattern);
+        pattern[void] = true;
+        field.splat(void, 1.0);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; texel_count];
+
+    // Ranks the initial texels by removing them from the tightest cluster first
+    let initial_pattern = pattern.clone();
+    let initial_energy = field.energy.clone();
+    for rank in (0..set_count).rev() {
+        let cluster = field.tightest_cluster(&pattern);
+        pattern[cluster] = false;
+        field.splat(cluster, -1.0);
+        ranks[cluster] = rank as u32;
+    }
+
+    // Ranks the others by filling the largest void first
+    pattern = initial_pattern;
+    field.energy = initial_energy;
+    for rank in set_count..texel_count {
+        let void = field.largest_void(&pattern);
+        pattern[void] = true;
+        field.splat(void, 1.0);
+        ranks[void] = rank as u32;
+    }
+
+    ranks
+}
@@ -0,0 +1,397 @@
+use std::rc::Rc;
+
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+use bytemuck::Pod;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::transient_commands::UploadBatch;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemoryLocation {
+    // Only reachable from the GPU, filled with staging buffers or command buffer updates
+    Device,
+    // Host visible and coherent, can be mapped
+    Host,
+}
+
+// Buffer that remembers its size, so typed uploads can be validated before touching the memory
+pub struct DataBuffer {
+    device: Rc<VulkanDevice>,
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    non_coherent_atom_size: vk::DeviceSize,
+}
+
+impl Drop for DataBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.get().destroy_buffer(self.buffer, None);
+            self.device.get().free_memory(self.memory, None);
+        }
+    }
+}
+
+impl DataBuffer {
+    pub fn get(&self) -> vk::Buffer {
+        self.buffer
+    }
+
+    pub fn get_memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    // Writes into host visible memory, the data has to cover the whole buffer
+    pub fn upload<T: Pod>(&self, data: &[T]) -> Result<(), VulkanError> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        self.check_size(bytes.len() as vk::DeviceSize, true)?;
+        self.copy_data_slice(bytes)
+    }
+
+    pub fn copy_data_slice(&self, data: &[u8]) -> Result<(), VulkanError> {
+        self.copy_data_at(0, data)
+    }
+
+    pub fn copy_data_at(&self, offset: vk::DeviceSize, data: &[u8]) -> Result<(), VulkanError> {
+        self.check_range(offset, data.len() as vk::DeviceSize)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let (mapped, map_offset) = self.map(offset)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                mapped.add((offset - map_offset) as usize),
+                data.len(),
+            );
+        }
+
+        // Needed when the memory is not host coherent, harmless otherwise
+        let range = self.mapped_range(map_offset);
+        let result = unsafe { self.device.get().flush_mapped_memory_ranges(&[range]) };
+        self.device.unmap_memory(self.get_memory());
+        result.map_err(|err| VulkanError::PipelineError(err.to_string()))
+    }
+
+    pub fn read_data_at(&self, offset: vk::DeviceSize, data: &mut [u8]) -> Result<(), VulkanError> {
+        self.check_range(offset, data.len() as vk::DeviceSize)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let (mapped, map_offset) = self.map(offset)?;
+
+        let range = self.mapped_range(map_offset);
+        let result = unsafe { self.device.get().invalidate_mapped_memory_ranges(&[range]) };
+        if result.is_ok() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    mapped.add((offset - map_offset) as usize),
+                    data.as_mut_ptr(),
+                    data.len(),
+                );
+            }
+        }
+
+        self.device.unmap_memory(self.get_memory());
+        result.map_err(|err| VulkanError::PipelineError(err.to_string()))
+    }
+
+    // Maps from the closest non coherent atom boundary up to the end of the memory
+    fn map(&self, offset: vk::DeviceSize) -> Result<(*mut u8, vk::DeviceSize), VulkanError> {
+        let map_offset = offset - offset % self.non_coherent_atom_size;
+        let mapped = unsafe {
+            self.device.get().map_memory(
+                self.get_memory(),
+                map_offset,
+                vk::WHOLE_SIZE,
+                vk::MemoryMapFlags::empty(),
+            )
+        }
+        .map_err(|err| VulkanError::PipelineError(err.to_string()))?;
+
+        Ok((mapped as *mut u8, map_offset))
+    }
+
+    fn mapped_range(&self, map_offset: vk::DeviceSize) -> vk::MappedMemoryRange {
+        vk::MappedMemoryRange::builder()
+            .memory(self.get_memory())
+            .offset(map_offset)
+            .size(vk::WHOLE_SIZE)
+            .build()
+    }
+
+    // Records a vkCmdUpdateBuffer, used for small uniform data updated every frame
+    pub fn update<T: Pod>(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        data: &[T],
+    ) -> Result<(), VulkanError> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        self.check_size(bytes.len() as vk::DeviceSize, false)?;
+        if bytes.len() % 4 != 0 {
+            return Err(VulkanError::PipelineError(format!(
+                "Buffer update size {} is not a multiple of 4",
+                bytes.len()
+            )));
+        }
+        unsafe {
+            self.device
+                .get()
+                .cmd_update_buffer(command_buffer, self.buffer, 0, bytes);
+        }
+        Ok(())
+    }
+
+    // Used for device local buffers, the data goes through a temporary host visible buffer
+    pub fn update_via_staging<T: Pod>(
+        &self,
+        context: &VulkanContext,
+        data: &[T],
+    ) -> Result<(), VulkanError> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        self.check_size(bytes.len() as vk::DeviceSize, false)?;
+        upload_staged(context, self, bytes, None)
+    }
+
+    fn check_range(&self, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<(), VulkanError> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(VulkanError::PipelineError(format!(
+                "Range of {} bytes at offset {} is out of a buffer of {} bytes",
+                size, offset, self.size
+            ))),
+        }
+    }
+
+    fn check_size(&self, size: vk::DeviceSize, exact: bool) -> Result<(), VulkanError> {
+        if size > self.size || (exact && size != self.size) {
+            return Err(VulkanError::PipelineError(format!(
+                "Cannot write {} bytes into a buffer of {} bytes",
+                size, self.size
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub struct DataBufferBuilder<'a> {
+    context: &'a VulkanContext,
+    usage: vk::BufferUsageFlags,
+    location: MemoryLocation,
+    size: vk::DeviceSize,
+    memory_requirements: Option<vk::MemoryRequirements>,
+    data: Option<&'a [u8]>,
+    upload_batch: Option<&'a UploadBatch<'a>>,
+}
+
+impl<'a> DataBufferBuilder<'a> {
+    pub fn new(context: &'a VulkanContext) -> Self {
+        DataBufferBuilder {
+            context,
+            usage: vk::BufferUsageFlags::empty(),
+            location: MemoryLocation::Host,
+            size: 0,
+            memory_requirements: None,
+            data: None,
+            upload_batch: None,
+        }
+    }
+
+    pub fn with_usage(mut self, usage: vk::BufferUsageFlags) -> Self {
+        self.usage |= usage;
+        self
+    }
+
+    pub fn with_location(mut self, location: MemoryLocation) -> Self {
+        self.location = location;
+        self
+    }
+
+    // For memory that is bound to something else than the buffer, like acceleration structures
+    pub fn with_memory_requirements(mut self, memory_requirements: vk::MemoryRequirements) -> Self {
+        self.memory_requirements = Some(memory_requirements);
+        self
+    }
+
+    pub fn with_size(mut self, size: vk::DeviceSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn with_data<T: Pod>(mut self, data: &'a [T]) -> Self {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        self.size = bytes.len() as vk::DeviceSize;
+        self.data = Some(bytes);
+        self
+    }
+
+    // The copy of device local data is recorded into the batch instead of waiting on its own
+    pub fn with_upload_batch(mut self, upload_batch: &'a UploadBatch<'a>) -> Self {
+        self.upload_batch = Some(upload_batch);
+        self
+    }
+
+    pub fn build(self) -> Result<DataBuffer, VulkanError> {
+        let device = Rc::clone(self.context.get_device());
+        let device_local = self.location == MemoryLocation::Device;
+
+        // Device local buffers can only be written through transfer commands
+        let mut usage = self.usage;
+        if device_local {
+            usage |= vk::BufferUsageFlags::TRANSFER_DST;
+        }
+
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(self.size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .build();
+        let buffer = unsafe { device.get().create_buffer(&buffer_info, None) }
+            .map_err(|err| VulkanError::PipelineError(err.to_string()))?;
+
+        let memory = match self.allocate_memory(&device, buffer, device_local) {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { device.get().destroy_buffer(buffer, None) };
+                return Err(err);
+            }
+        };
+
+        let buffer = DataBuffer {
+            device,
+            buffer,
+            memory,
+            size: self.size,
+            non_coherent_atom_size: non_coherent_atom_size(self.context),
+        };
+
+        if let Some(data) = self.data {
+            if device_local {
+                upload_staged(self.context, &buffer, data, self.upload_batch)?;
+            } else {
+                buffer.upload(data)?;
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    fn allocate_memory(
+        &self,
+        device: &VulkanDevice,
+        buffer: vk::Buffer,
+        device_local: bool,
+    ) -> Result<vk::DeviceMemory, VulkanError> {
+        let mut requirements = unsafe { device.get().get_buffer_memory_requirements(buffer) };
+        if let Some(extra) = self.memory_requirements {
+            requirements.size = requirements.size.max(extra.size);
+            requirements.alignment = requirements.alignment.max(extra.alignment);
+            requirements.memory_type_bits &= extra.memory_type_bits;
+        }
+        let properties = if device_local {
+            vk::MemoryPropertyFlags::DEVICE_LOCAL
+        } else {
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT
+        };
+
+        let memory_type_index = find_memory_type(self.context, &requirements, properties)?;
+
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(memory_type_index)
+            .build();
+        let memory = unsafe { device.get().allocate_memory(&alloc_info, None) }
+            .map_err(|err| VulkanError::PipelineError(err.to_string()))?;
+
+        if let Err(err) = unsafe { device.get().bind_buffer_memory(buffer, memory, 0) } {
+            unsafe { device.get().free_memory(memory, None) };
+            return Err(VulkanError::PipelineError(err.to_string()));
+        }
+
+        Ok(memory)
+    }
+}
+
+fn upload_staged(
+    context: &VulkanContext,
+    buffer: &DataBuffer,
+    data: &[u8],
+    upload_batch: Option<&UploadBatch>,
+) -> Result<(), VulkanError> {
+    let _span = tracing::info_span!("upload_buffer", size = data.len()).entered();
+    let size = data.len() as vk::DeviceSize;
+    let staging_buffer = DataBufferBuilder::new(context)
+        .with_usage(vk::BufferUsageFlags::TRANSFER_SRC)
+        .with_location(MemoryLocation::Host)
+        .with_size(size)
+        .build()?;
+    staging_buffer.upload(data)?;
+
+    let command_buffer = match upload_batch {
+        Some(upload_batch) => upload_batch.get_command_buffer()?,
+        None => context.begin_single_time_commands()?,
+    };
+    let copy_region = vk::BufferCopy::builder().size(size).build();
+    context.get_device().cmd_copy_buffer(
+        command_buffer,
+        staging_buffer.get(),
+        buffer.get(),
+        &[copy_region],
+    );
+
+    match upload_batch {
+        Some(upload_batch) => {
+            upload_batch.keep_staging_buffer(staging_buffer);
+            Ok(())
+        }
+        None => context.end_single_time_commands(command_buffer),
+    }
+}
+
+pub(crate) fn find_memory_type(
+    context: &VulkanContext,
+    requirements: &vk::MemoryRequirements,
+    properties: vk::MemoryPropertyFlags,
+) -> Result<u32, VulkanError> {
+    let memory_properties = unsafe {
+        context
+            .get_instance()
+            .get()
+            .get_physical_device_memory_properties(context.get_physical_device().get())
+    };
+    (0..memory_properties.memory_type_count)
+        .find(|&index| {
+            requirements.memory_type_bits & (1 << index) != 0
+                && memory_properties.memory_types[index as usize]
+                    .property_flags
+                    .contains(properties)
+        })
+        .ok_or_else(|| {
+            VulkanError::PipelineError(String::from("Cannot find a suitable memory type"))
+        })
+}
+
+fn non_coherent_atom_size(context: &VulkanContext) -> vk::DeviceSize {
+    let properties = unsafe {
+        context
+            .get_instance()
+            .get()
+            .get_physical_device_properties(context.get_physical_device().get())
+    };
+    properties.limits.non_coherent_atom_size.max(1)
+}
+
+// Vulkan alignments are always powers of two
+pub fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    let alignment = alignment.max(1);
+    (value + alignment - 1) & !(alignment - 1)
+}
@@ -0,0 +1,207 @@
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::compute_pipeline::{ComputePipeline, ComputePipelineBuilder};
+use crate::descriptor_allocator::DescriptorAllocator;
+use crate::image::{cmd_transition_layout, ImageLayoutTransition};
+use crate::storage_image::{StorageImage, StorageImageBuilder};
+
+// Turning the cameras further than this in a single frame is taken for a cut
+const CUT_ANGLE: f32 = std::f32::consts::FRAC_PI_6;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CheckerboardPushConstants {
+    size: [u32; 2],
+    frame_index: u32,
+    history_valid: u32,
+}
+
+unsafe impl Zeroable for CheckerboardPushConstants {}
+unsafe impl Pod for CheckerboardPushConstants {}
+
+// Compute pass filling in the pixels the ray generation shader skipped this frame. They come
+// from the previous frame, reprojected with the motion vectors of their traced neighbours and
+// clamped to the colors of these neighbours. Without a history, on the first frame and after a
+// camera cut, the neighbours are averaged instead.
+pub(crate) struct Checkerboard {
+    pipeline: ComputePipeline,
+    // Reconstructed frames, one is read while the other is written
+    history_images: [StorageImage; 2],
+    history_valid: bool,
+    started: bool,
+}
+
+impl Checkerboard {
+    pub fn new(
+        context: &VulkanContext,
+        descriptor_allocator: &Rc<DescriptorAllocator>,
+    ) -> Result<Self, VulkanError> {
+        let pipeline = ComputePipelineBuilder::new(context, descriptor_allocator)
+            .with_shader(Path::new("assets/shaders/checkerboard.spv"))
+            .with_binding(0, vk::DescriptorType::STORAGE_IMAGE)
+            .with_binding(1, vk::DescriptorType::STORAGE_IMAGE)
+            .with_binding(2, vk::DescriptorType::STORAGE_IMAGE)
+            .with_binding(3, vk::DescriptorType::STORAGE_IMAGE)
+            .with_push_constant_size(mem::size_of::<CheckerboardPushConstants>() as u32)
+            .build()?;
+
+        let create_history = || {
+            StorageImageBuilder::new(context)
+                .with_format(vk::Format::R8G8B8A8_UNORM)
+                .with_extent(context.get_swapchain().get_extent())
+                .build()
+        };
+
+        Ok(Checkerboard {
+            pipeline,
+            history_images: [create_history()?, create_history()?],
+            history_valid: false,
+            started: false,
+        })
+    }
+
+    // Once per frame, the history of the previous frame is dropped on a cut
+    pub fn begin_frame(&mut self, camera_cut: bool) {
+        self.history_valid = self.started && !camera_cut;
+        self.started = true;
+    }
+
+    // Recorded outside of a render pass, the back buffer is in PRESENT_SRC_KHR and is left in it
+    pub fn cmd_resolve(
+        &self,
+        context: &VulkanContext,
+        command_buffer: vk::CommandBuffer,
+        back_buffer: vk::Image,
+        back_buffer_view: vk::ImageView,
+        motion_image: &StorageImage,
+        frame_index: u32,
+    ) -> Result<(), VulkanError> {
+        let previous = &self.history_images[(frame_index as usize + 1) % 2];
+        let next = &self.history_images[frame_index as usize % 2];
+
+        let descriptor_set = self.pipeline.allocate_transient_set()?;
+        let views = [
+            back_buffer_view,
+            previous.get_image_view(),
+            next.get_image_view(),
+            motion_image.get_image_view(),
+        ];
+        for (binding, &view) in views.iter().enumerate() {
+            self.pipeline.update_set_image(
+                descriptor_set,
+                binding as u32,
+                vk::DescriptorType::STORAGE_IMAGE,
+                view,
+                vk::Sampler::null(),
+                vk::ImageLayout::GENERAL,
+            );
+        }
+
+        let device = context.get_device();
+        cmd_transition_layout(
+            device,
+            command_buffer,
+            back_buffer,
+            1,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                new_layout: vk::ImageLayout::GENERAL,
+                src_access_mask: vk::AccessFlags::MEMORY_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                src_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+                dst_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+            },
+        );
+        // The motion vectors of this frame and the history written by the previous one
+        for image in [motion_image, previous, next].iter() {
+            cmd_transition_layout(
+                device,
+                command_buffer,
+                image.get(),
+                1,
+                ImageLayoutTransition {
+                    old_layout: vk::ImageLayout::GENERAL,
+                    new_layout: vk::ImageLayout::GENERAL,
+                    src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                    dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                    src_stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV
+                        | vk::PipelineStageFlags::COMPUTE_SHADER,
+                    dst_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+                },
+            );
+        }
+
+        let extent = next.get_extent();
+        let push_constants = CheckerboardPushConstants {
+            size: [extent.width, extent.height],
+            frame_index,
+            history_valid: self.history_valid as u32,
+        };
+        self.pipeline.cmd_dispatch_with_set(
+            command_buffer,
+            descriptor_set,
+            bytemuck::bytes_of(&push_constants),
+            [extent.width.div_ceil(8), extent.height.div_ceil(8), 1],
+        );
+
+        cmd_transition_layout(
+            device,
+            command_buffer,
+            back_buffer,
+            1,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::GENERAL,
+                new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                dst_access_mask: vk::AccessFlags::MEMORY_READ,
+                src_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+                dst_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+            },
+        );
+        Ok(())
+    }
+}
+
+// The cameras start with their view and projection matrices, like the CameraProperties of the
+// shaders. Another kind of projection or a view turned too far between two updates is a cut.
+pub(crate) fn is_camera_cut(previous: &[u8], current: &[u8], camera_size: usize) -> bool {
+    if previous.is_empty() || camera_size < 128 {
+        return false;
+    }
+    if previous.len() != current.len() {
+        return true;
+    }
+
+    previous
+        .chunks(camera_size)
+        .zip(current.chunks(camera_size))
+        .any(|(previous, current)| {
+            // Orthographic projections keep w, switching to or from one is a cut
+            let previous_w = read_matrix(previous, 64)[(3, 3)];
+            let w = read_matrix(current, 64)[(3, 3)];
+            let angle = glm::angle(&view_axis(previous), &view_axis(current));
+            w != previous_w || angle > CUT_ANGLE
+        })
+}
+
+// Z axis of the camera in world space, the third row of its view matrix
+fn view_axis(camera: &[u8]) -> glm::Vec3 {
+    let view = read_matrix(camera, 0);
+    glm::vec3(view[(2, 0)], view[(2, 1)], view[(2, 2)])
+}
+
+fn read_matrix(bytes: &[u8], offset: usize) -> glm::Mat4 {
+    let mut values = [0.0; 16];
+    for (value, chunk) in values.iter_mut().zip(bytes[offset..offset + 64].chunks(4)) {
+        *value = f32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    glm::make_mat4(&values)
+}
@@ -0,0 +1,279 @@
+use std::ffi::CStr;
+use std::path::Path;
+use std::rc::Rc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::shader_module::ShaderModuleBuilder;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::descriptor_allocator::{DescriptorAllocation, DescriptorAllocator};
+use crate::descriptor_commands::DescriptorCommands;
+
+// A compute shader with a descriptor set of its own, at set 0
+pub struct ComputePipeline {
+    device: Rc<VulkanDevice>,
+    descriptor_allocator: Rc<DescriptorAllocator>,
+    allocation: Option<DescriptorAllocation>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_set: vk::DescriptorSet,
+    bindings: Vec<vk::DescriptorSetLayoutBinding>,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl Drop for ComputePipeline {
+    fn drop(&mut self) {
+        self.device.destroy_pipeline(self.pipeline);
+        self.device.destroy_pipeline_layout(self.pipeline_layout);
+        self.device
+            .destroy_descriptor_set_layout(self.descriptor_set_layout);
+        if let Some(allocation) = self.allocation.take() {
+            self.descriptor_allocator.free(allocation);
+        }
+    }
+}
+
+impl ComputePipeline {
+    pub fn get(&self) -> vk::Pipeline {
+        self.pipeline
+    }
+
+    pub fn get_layout(&self) -> vk::PipelineLayout {
+        self.pipeline_layout
+    }
+
+    pub fn get_descriptor_set(&self) -> vk::DescriptorSet {
+        self.descriptor_set
+    }
+
+    // The whole buffer is bound, with the descriptor type given to the builder for this binding
+    pub fn update_buffer(
+        &self,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        buffer: vk::Buffer,
+    ) {
+        self.update_set_buffer(self.descriptor_set, binding, descriptor_type, buffer);
+    }
+
+    // A set with the layout of the pipeline for the current frame only, for the passes whose
+    // resources change every frame such as the back buffer
+    pub fn allocate_transient_set(&self) -> Result<vk::DescriptorSet, VulkanError> {
+        self.descriptor_allocator
+            .allocate_transient(self.descriptor_set_layout, &self.bindings)
+    }
+
+    pub fn update_set_buffer(
+        &self,
+        descriptor_set: vk::DescriptorSet,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        buffer: vk::Buffer,
+    ) {
+        let buffer_info = vk::DescriptorBufferInfo::builder()
+            .buffer(buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let buffer_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(descriptor_type)
+            .dst_binding(binding)
+            .buffer_info(&[buffer_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[buffer_wds]);
+    }
+
+    // The sampler is only used by combined image samplers, storage images pass a null one
+    pub fn update_set_image(
+        &self,
+        descriptor_set: vk::DescriptorSet,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        image_view: vk::ImageView,
+        sampler: vk::Sampler,
+        layout: vk::ImageLayout,
+    ) {
+        let image_info = vk::DescriptorImageInfo::builder()
+            .image_view(image_view)
+            .sampler(sampler)
+            .image_layout(layout)
+            .build();
+        let image_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(descriptor_type)
+            .dst_binding(binding)
+            .image_info(&[image_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[image_wds]);
+    }
+
+    // Binds the pipeline and its set, then dispatches with the given push constants
+    pub fn cmd_dispatch(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        push_constants: &[u8],
+        group_count: [u32; 3],
+    ) {
+        self.cmd_dispatch_with_set(
+            command_buffer,
+            self.descriptor_set,
+            push_constants,
+            group_count,
+        );
+    }
+
+    // Same as cmd_dispatch, with a set allocated by allocate_transient_set
+    pub fn cmd_dispatch_with_set(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        descriptor_set: vk::DescriptorSet,
+        push_constants: &[u8],
+        group_count: [u32; 3],
+    ) {
+        self.device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline,
+        );
+        self.device.cmd_bind_descriptor_sets(
+            command_buffer,
+            self.pipeline_layout,
+            vk::PipelineBindPoint::COMPUTE,
+            &[descriptor_set],
+        );
+        if !push_constants.is_empty() {
+            self.device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push_constants,
+            );
+        }
+
+        unsafe {
+            self.device.get().cmd_dispatch(
+                command_buffer,
+                group_count[0],
+                group_count[1],
+                group_count[2],
+            );
+        }
+    }
+}
+
+pub struct ComputePipelineBuilder<'a> {
+    context: &'a VulkanContext,
+    descriptor_allocator: &'a Rc<DescriptorAllocator>,
+    shader: Option<&'a Path>,
+    bindings: Vec<vk::DescriptorSetLayoutBinding>,
+    push_constant_size: u32,
+}
+
+impl<'a> ComputePipelineBuilder<'a> {
+    pub fn new(
+        context: &'a VulkanContext,
+        descriptor_allocator: &'a Rc<DescriptorAllocator>,
+    ) -> Self {
+        ComputePipelineBuilder {
+            context,
+            descriptor_allocator,
+            shader: None,
+            bindings: vec![],
+            push_constant_size: 0,
+        }
+    }
+
+    // Compiled SPIR-V, with a main entry point
+    pub fn with_shader(mut self, shader: &'a Path) -> Self {
+        self.shader = Some(shader);
+        self
+    }
+
+    pub fn with_binding(mut self, binding: u32, descriptor_type: vk::DescriptorType) -> Self {
+        self.bindings.push(
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_count(1)
+                .descriptor_type(descriptor_type)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+        );
+        self
+    }
+
+    pub fn with_push_constant_size(mut self, push_constant_size: u32) -> Self {
+        self.push_constant_size = push_constant_size;
+        self
+    }
+
+    pub fn build(self) -> Result<ComputePipeline, VulkanError> {
+        let shader = self.shader.ok_or_else(|| {
+            VulkanError::PipelineError(String::from("A compute pipeline needs a shader"))
+        })?;
+        let device = Rc::clone(self.context.get_device());
+
+        let layout_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&self.bindings)
+            .build();
+        let descriptor_set_layout = device.create_descriptor_set_layout(&layout_info)?;
+        let allocation = self
+            .descriptor_allocator
+            .allocate(descriptor_set_layout, &self.bindings)?;
+
+        let push_constant_ranges = [vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .offset(0)
+            .size(self.push_constant_size)
+            .build()];
+        let ranges: &[vk::PushConstantRange] = if self.push_constant_size > 0 {
+            &push_constant_ranges
+        } else {
+            &[]
+        };
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&[descriptor_set_layout])
+            .push_constant_ranges(ranges)
+            .build();
+        let pipeline_layout = device.create_pipeline_layout(&pipeline_layout_info)?;
+
+        let shader_module = ShaderModuleBuilder::new(Rc::clone(&device))
+            .with_path(shader)
+            .build()?;
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(shader_module.get())
+            .name(CStr::from_bytes_with_nul(b"main\0").unwrap())
+            .build();
+        let pipeline_info = vk::ComputePipelineCreateInfo::builder()
+            .stage(stage)
+            .layout(pipeline_layout)
+            .build();
+
+        let pipeline = unsafe {
+            device
+                .get()
+                .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+        }
+        .map_err(|(_, err)| VulkanError::PipelineError(err.to_string()))?[0];
+
+        Ok(ComputePipeline {
+            device,
+            descriptor_allocator: Rc::clone(self.descriptor_allocator),
+            descriptor_set: allocation.get(),
+            allocation: Some(allocation),
+            descriptor_set_layout,
+            bindings: self.bindings,
+            pipeline_layout,
+            pipeline,
+        })
+    }
+}
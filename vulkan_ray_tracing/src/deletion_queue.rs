@@ -0,0 +1,59 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use ash::version::DeviceV1_0;
+use vulkan_bootstrap::device::VulkanDevice;
+
+// Keeps resources alive until the frames that may still use them are done.
+// Every frame waits on the fence of the frame submitted frames_in_flight frames before,
+// so a resource released during frame N is unused once frame N + frames_in_flight begins.
+pub struct DeletionQueue {
+    device: Rc<VulkanDevice>,
+    frames_in_flight: u64,
+    frame: Cell<u64>,
+    pending: RefCell<VecDeque<(u64, Box<dyn Any>)>>,
+}
+
+impl Drop for DeletionQueue {
+    fn drop(&mut self) {
+        let _ = unsafe { self.device.get().device_wait_idle() };
+        self.pending.borrow_mut().clear();
+    }
+}
+
+impl DeletionQueue {
+    pub fn new(device: Rc<VulkanDevice>, frames_in_flight: u64) -> Self {
+        DeletionQueue {
+            device,
+            frames_in_flight,
+            frame: Cell::new(0),
+            pending: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    pub fn defer<T: 'static>(&self, resource: T) {
+        self.pending
+            .borrow_mut()
+            .push_back((self.frame.get(), Box::new(resource)));
+    }
+
+    // Has to be called once per frame, after waiting for the frame fence
+    pub fn next_frame(&self) {
+        let frame = self.frame.get() + 1;
+        self.frame.set(frame);
+
+        let mut pending = self.pending.borrow_mut();
+        while let Some((released, _)) = pending.front() {
+            if released + self.frames_in_flight > frame {
+                break;
+            }
+            pending.pop_front();
+        }
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.borrow().len()
+    }
+}
@@ -0,0 +1,278 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+
+use crate::descriptor_commands::DescriptorCommands;
+
+const SETS_PER_POOL: u32 = 16;
+const DESCRIPTORS_PER_TYPE: u32 = 64;
+// Every new pool has room for these, larger sets get pools sized for them
+const POOL_TYPES: [vk::DescriptorType; 5] = [
+    vk::DescriptorType::ACCELERATION_STRUCTURE_NV,
+    vk::DescriptorType::STORAGE_IMAGE,
+    vk::DescriptorType::UNIFORM_BUFFER,
+    vk::DescriptorType::STORAGE_BUFFER,
+    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+];
+
+struct PoolState {
+    pool: vk::DescriptorPool,
+    // Sets allocated and not freed yet, the pool is reset once it reaches zero
+    allocated: u32,
+}
+
+// A set allocated with DescriptorAllocator::allocate, given back with DescriptorAllocator::free
+pub struct DescriptorAllocation {
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+}
+
+impl DescriptorAllocation {
+    pub fn get(&self) -> vk::DescriptorSet {
+        self.set
+    }
+}
+
+// Allocates the descriptor sets from a growing list of shared pools. A set that does not fit in
+// the existing pools goes to a new one, and the pools are reused instead of destroyed.
+// Long lived sets are freed one by one, transient sets are only valid for the frame they were
+// allocated in and their pools are reset at once when the frame slot comes back.
+pub struct DescriptorAllocator {
+    device: Rc<VulkanDevice>,
+    pools: RefCell<Vec<PoolState>>,
+    // Pools of the transient sets, one list per frame in flight
+    frame_pools: RefCell<Vec<Vec<vk::DescriptorPool>>>,
+    // Reset pools, taken again before creating new ones
+    free_pools: RefCell<Vec<vk::DescriptorPool>>,
+    frame: Cell<usize>,
+}
+
+impl Drop for DescriptorAllocator {
+    fn drop(&mut self) {
+        for pool in self.pools.borrow_mut().drain(..) {
+            self.device.destroy_descriptor_pool(pool.pool);
+        }
+        for pools in self.frame_pools.borrow_mut().iter_mut() {
+            for pool in pools.drain(..) {
+                self.device.destroy_descriptor_pool(pool);
+            }
+        }
+        for pool in self.free_pools.borrow_mut().drain(..) {
+            self.device.destroy_descriptor_pool(pool);
+        }
+    }
+}
+
+impl DescriptorAllocator {
+    pub fn new(device: Rc<VulkanDevice>, frames_in_flight: u64) -> Self {
+        DescriptorAllocator {
+            device,
+            pools: RefCell::new(vec![]),
+            frame_pools: RefCell::new(vec![vec![]; frames_in_flight.max(1) as usize]),
+            free_pools: RefCell::new(vec![]),
+            frame: Cell::new(0),
+        }
+    }
+
+    // Stays valid until it is freed, the frames using the set have to be done by then
+    pub fn allocate(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+    ) -> Result<DescriptorAllocation, VulkanError> {
+        let mut pools = self.pools.borrow_mut();
+        for pool in pools.iter_mut().rev() {
+            if let Some(set) = self.try_allocate(pool.pool, layout)? {
+                pool.allocated += 1;
+                return Ok(DescriptorAllocation {
+                    pool: pool.pool,
+                    set,
+                });
+            }
+        }
+
+        // Sets freed one by one need pools created with the flag for it, unlike the free pools
+        let pool =
+            self.create_pool(bindings, vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)?;
+        let set = match self.allocate_in_new_pool(pool, layout) {
+            Ok(set) => set,
+            Err(err) => {
+                self.device.destroy_descriptor_pool(pool);
+                return Err(err);
+            }
+        };
+        pools.push(PoolState { pool, allocated: 1 });
+        Ok(DescriptorAllocation { pool, set })
+    }
+
+    pub fn free(&self, allocation: DescriptorAllocation) {
+        let mut pools = self.pools.borrow_mut();
+        let pool = match pools.iter_mut().find(|pool| pool.pool == allocation.pool) {
+            Some(pool) => pool,
+            None => return,
+        };
+
+        pool.allocated = pool.allocated.saturating_sub(1);
+        // Starting again from an empty pool avoids its fragmentation
+        if pool.allocated == 0 {
+            let _ = self.device.reset_descriptor_pool(pool.pool);
+        } else {
+            unsafe {
+                self.device
+                    .get()
+                    .free_descriptor_sets(allocation.pool, &[allocation.set]);
+            }
+        }
+    }
+
+    // Only valid during the current frame, the set is reclaimed once the frame slot comes back
+    pub fn allocate_transient(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+    ) -> Result<vk::DescriptorSet, VulkanError> {
+        let mut frame_pools = self.frame_pools.borrow_mut();
+        let pools = &mut frame_pools[self.frame.get()];
+        for &pool in pools.iter().rev() {
+            if let Some(set) = self.try_allocate(pool, layout)? {
+                return Ok(set);
+            }
+        }
+
+        // A reused pool may be too small for this set, it then stays with the frame anyway
+        if let Some(pool) = self.free_pools.borrow_mut().pop() {
+            pools.push(pool);
+            if let Some(set) = self.try_allocate(pool, layout)? {
+                return Ok(set);
+            }
+        }
+
+        let pool = self.create_pool(bindings, vk::DescriptorPoolCreateFlags::empty())?;
+        pools.push(pool);
+        self.allocate_in_new_pool(pool, layout)
+    }
+
+    // Has to be called once per frame, after waiting for the frame fence. The transient sets of
+    // the frame slot are not used anymore, their pools are reset for the new frame.
+    pub fn next_frame(&self) {
+        let mut frame_pools = self.frame_pools.borrow_mut();
+        let frame = (self.frame.get() + 1) % frame_pools.len();
+        self.frame.set(frame);
+
+        let mut free_pools = self.free_pools.borrow_mut();
+        for pool in frame_pools[frame].drain(..) {
+            match self.device.reset_descriptor_pool(pool) {
+                Ok(()) => free_pools.push(pool),
+                Err(_) => self.device.destroy_descriptor_pool(pool),
+            }
+        }
+    }
+
+    pub fn get_pool_count(&self) -> usize {
+        self.pools.borrow().len()
+            + self
+                .frame_pools
+                .borrow()
+                .iter()
+                .map(|pools| pools.len())
+                .sum::<usize>()
+            + self.free_pools.borrow().len()
+    }
+
+    // None when the pool is out of memory or too fragmented for the set
+    fn try_allocate(
+        &self,
+        pool: vk::DescriptorPool,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<Option<vk::DescriptorSet>, VulkanError> {
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&[layout])
+            .build();
+
+        match unsafe { self.device.get().allocate_descriptor_sets(&alloc_info) } {
+            Ok(sets) => Ok(Some(sets[0])),
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) => {
+                Ok(None)
+            }
+            Err(err) => Err(VulkanError::DeviceError(format!(
+                "Cannot allocate a descriptor set: {}",
+                err
+            ))),
+        }
+    }
+
+    fn allocate_in_new_pool(
+        &self,
+        pool: vk::DescriptorPool,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<vk::DescriptorSet, VulkanError> {
+        self.try_allocate(pool, layout)?.ok_or_else(|| {
+            VulkanError::DeviceError(String::from(
+                "A descriptor set does not fit in a pool sized for it",
+            ))
+        })
+    }
+
+    fn create_pool(
+        &self,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        flags: vk::DescriptorPoolCreateFlags,
+    ) -> Result<vk::DescriptorPool, VulkanError> {
+        let mut capacity: Vec<vk::DescriptorPoolSize> = POOL_TYPES
+            .iter()
+            .map(|&ty| vk::DescriptorPoolSize {
+                ty,
+                descriptor_count: DESCRIPTORS_PER_TYPE,
+            })
+            .collect();
+        // Room to grow, so a set rebuilt with a few more descriptors still fits
+        for size in pool_sizes(bindings).iter() {
+            let descriptor_count = size.descriptor_count.next_power_of_two();
+            match capacity.iter_mut().find(|capacity| capacity.ty == size.ty) {
+                Some(capacity) => {
+                    capacity.descriptor_count = capacity.descriptor_count.max(descriptor_count)
+                }
+                None => capacity.push(vk::DescriptorPoolSize {
+                    ty: size.ty,
+                    descriptor_count,
+                }),
+            }
+        }
+
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .flags(flags)
+            .pool_sizes(&capacity)
+            .max_sets(SETS_PER_POOL)
+            .build();
+        self.device.create_descriptor_pool(&pool_info)
+    }
+}
+
+// One entry per descriptor type. Vulkan rejects empty pool sizes, and bindings without
+// descriptors, such as the textures of a scene that has none, do not need any.
+pub(crate) fn pool_sizes(
+    bindings: &[vk::DescriptorSetLayoutBinding],
+) -> Vec<vk::DescriptorPoolSize> {
+    let mut pool_sizes: Vec<vk::DescriptorPoolSize> = vec![];
+    for binding in bindings
+        .iter()
+        .filter(|binding| binding.descriptor_count > 0)
+    {
+        match pool_sizes
+            .iter_mut()
+            .find(|size| size.ty == binding.descriptor_type)
+        {
+            Some(size) => size.descriptor_count += binding.descriptor_count,
+            None => pool_sizes.push(vk::DescriptorPoolSize {
+                ty: binding.descriptor_type,
+                descriptor_count: binding.descriptor_count,
+            }),
+        }
+    }
+    pool_sizes
+}
@@ -0,0 +1,75 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+
+// VulkanDevice::cmd_bind_descriptor_sets always binds from set 0 without dynamic offsets
+pub trait DescriptorCommands {
+    fn cmd_bind_descriptor_sets_at(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        first_set: u32,
+        descriptor_sets: &[vk::DescriptorSet],
+        dynamic_offsets: &[u32],
+    );
+
+    // Push constants are not exposed by VulkanDevice either
+    fn cmd_push_constants(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        layout: vk::PipelineLayout,
+        stage_flags: vk::ShaderStageFlags,
+        offset: u32,
+        constants: &[u8],
+    );
+
+    // Frees every set of the pool, so that it can be reused
+    fn reset_descriptor_pool(&self, pool: vk::DescriptorPool) -> Result<(), VulkanError>;
+}
+
+impl DescriptorCommands for VulkanDevice {
+    fn cmd_bind_descriptor_sets_at(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        first_set: u32,
+        descriptor_sets: &[vk::DescriptorSet],
+        dynamic_offsets: &[u32],
+    ) {
+        unsafe {
+            self.get().cmd_bind_descriptor_sets(
+                command_buffer,
+                bind_point,
+                layout,
+                first_set,
+                descriptor_sets,
+                dynamic_offsets,
+            );
+        }
+    }
+
+    fn cmd_push_constants(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        layout: vk::PipelineLayout,
+        stage_flags: vk::ShaderStageFlags,
+        offset: u32,
+        constants: &[u8],
+    ) {
+        unsafe {
+            self.get()
+                .cmd_push_constants(command_buffer, layout, stage_flags, offset, constants);
+        }
+    }
+
+    fn reset_descriptor_pool(&self, pool: vk::DescriptorPool) -> Result<(), VulkanError> {
+        unsafe {
+            self.get()
+                .reset_descriptor_pool(pool, vk::DescriptorPoolResetFlags::empty())
+        }
+        .map_err(|err| VulkanError::DeviceError(format!("Cannot reset a descriptor pool: {}", err)))
+    }
+}
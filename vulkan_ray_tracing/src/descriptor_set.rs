@@ -5,11 +5,17 @@ use vulkan_bootstrap::device::VulkanDevice;
 use vulkan_bootstrap::errors::VulkanError;
 use vulkan_bootstrap::vulkan_context::VulkanContext;
 
+use crate::barrier_commands::{BarrierCommands, BufferMemoryBarrier2, DependencyInfo};
+use crate::descriptor_allocator::{pool_sizes, DescriptorAllocation, DescriptorAllocator};
+use crate::device_limits::device_limits;
 use crate::geometry_instance::GeometryInstance;
+use crate::render_settings::MAX_MOTION_BLUR_STEPS;
+use crate::texture::Texture;
 
 pub struct DescriptorSet {
     device: Rc<VulkanDevice>,
-    descriptor_pool: vk::DescriptorPool,
+    descriptor_allocator: Rc<DescriptorAllocator>,
+    allocation: Option<DescriptorAllocation>,
     descriptor_set_layout: vk::DescriptorSetLayout,
     descriptor_set: vk::DescriptorSet,
 }
@@ -23,18 +29,20 @@ impl DescriptorSet {
         self.descriptor_set_layout
     }
 
+    // One acceleration structure per step of the motion blur, MAX_MOTION_BLUR_STEPS of them
     pub fn update_render_target(
         &mut self,
-        acceleration_structure: vk::AccelerationStructureNV,
+        acceleration_structures: &[vk::AccelerationStructureNV],
         target: vk::ImageView,
         camera_buffer: vk::Buffer,
-        geometry_instance: &GeometryInstance,
-        clear_buffer: vk::Buffer,
+        geometry_instances: &[GeometryInstance],
+        background_buffer: vk::Buffer,
+        instance_buffer: vk::Buffer,
     ) {
         let mut wds = vec![];
 
         let mut as_info = vk::WriteDescriptorSetAccelerationStructureNV::builder()
-            .acceleration_structures(&[acceleration_structure])
+            .acceleration_structures(acceleration_structures)
             .build();
         let mut as_wds = vk::WriteDescriptorSet::builder()
             .dst_set(self.descriptor_set)
@@ -43,7 +51,7 @@ impl DescriptorSet {
             .dst_binding(0)
             .push_next(&mut as_info)
             .build();
-        as_wds.descriptor_count = 1;
+        as_wds.descriptor_count = acceleration_structures.len() as u32;
         wds.push(as_wds);
 
         let output_image_info = vk::DescriptorImageInfo::builder()
@@ -74,50 +82,68 @@ impl DescriptorSet {
             .build();
         wds.push(cam_wds);
 
-        let vertex_info = vk::DescriptorBufferInfo::builder()
-            .buffer(geometry_instance.vertex_buffer.get())
-            .offset(0)
-            .range(vk::WHOLE_SIZE)
-            .build();
+        let vertex_infos: Vec<vk::DescriptorBufferInfo> = geometry_instances
+            .iter()
+            .map(|geometry_instance| {
+                vk::DescriptorBufferInfo::builder()
+                    .buffer(geometry_instance.vertex_buffer.get())
+                    .offset(0)
+                    .range(vk::WHOLE_SIZE)
+                    .build()
+            })
+            .collect();
         let vertex_wds = vk::WriteDescriptorSet::builder()
             .dst_set(self.descriptor_set)
             .dst_array_element(0)
             .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
             .dst_binding(3)
-            .buffer_info(&[vertex_info])
+            .buffer_info(&vertex_infos)
             .build();
         wds.push(vertex_wds);
 
-        let index_info = vk::DescriptorBufferInfo::builder()
-            .buffer(geometry_instance.index_buffer.get())
-            .offset(0)
-            .range(vk::WHOLE_SIZE)
-            .build();
+        let index_infos: Vec<vk::DescriptorBufferInfo> = geometry_instances
+            .iter()
+            .map(|geometry_instance| {
+                vk::DescriptorBufferInfo::builder()
+                    .buffer(geometry_instance.index_buffer.get())
+                    .offset(0)
+                    .range(vk::WHOLE_SIZE)
+                    .build()
+            })
+            .collect();
         let index_wds = vk::WriteDescriptorSet::builder()
             .dst_set(self.descriptor_set)
             .dst_array_element(0)
             .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
             .dst_binding(4)
-            .buffer_info(&[index_info])
+            .buffer_info(&index_infos)
             .build();
         wds.push(index_wds);
 
-        let mat_info = vk::DescriptorBufferInfo::builder()
-            .buffer(geometry_instance.material_buffer.get())
-            .offset(0)
-            .range(vk::WHOLE_SIZE)
-            .build();
+        let mat_infos: Vec<vk::DescriptorBufferInfo> = geometry_instances
+            .iter()
+            .map(|geometry_instance| {
+                vk::DescriptorBufferInfo::builder()
+                    .buffer(geometry_instance.material_buffer.get())
+                    .offset(0)
+                    .range(vk::WHOLE_SIZE)
+                    .build()
+            })
+            .collect();
         let mat_wds = vk::WriteDescriptorSet::builder()
             .dst_set(self.descriptor_set)
             .dst_array_element(0)
             .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
             .dst_binding(5)
-            .buffer_info(&[mat_info])
+            .buffer_info(&mat_infos)
             .build();
         wds.push(mat_wds);
 
         let mut image_infos = vec![];
-        for texture in geometry_instance.textures.iter() {
+        for texture in geometry_instances
+            .iter()
+            .flat_map(|geometry_instance| geometry_instance.textures.iter())
+        {
             let image_info = vk::DescriptorImageInfo::builder()
                 .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
                 .image_view(texture.get_image_view())
@@ -125,67 +151,348 @@ impl DescriptorSet {
                 .build();
             image_infos.push(image_info);
         }
-        let textures_wds = vk::WriteDescriptorSet::builder()
+        // Writes cannot be empty, scenes without textures leave the binding alone
+        if !image_infos.is_empty() {
+            let textures_wds = vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .dst_binding(6)
+                .image_info(&image_infos)
+                .build();
+            wds.push(textures_wds);
+        }
+
+        let background_info = vk::DescriptorBufferInfo::builder()
+            .buffer(background_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let background_wds = vk::WriteDescriptorSet::builder()
             .dst_set(self.descriptor_set)
             .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .dst_binding(6)
-            .image_info(&image_infos)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .dst_binding(7)
+            .buffer_info(&[background_info])
             .build();
-        wds.push(textures_wds);
+        wds.push(background_wds);
 
-        let clear_info = vk::DescriptorBufferInfo::builder()
-            .buffer(clear_buffer)
+        let instance_info = vk::DescriptorBufferInfo::builder()
+            .buffer(instance_buffer)
             .offset(0)
             .range(vk::WHOLE_SIZE)
             .build();
-        let clear_wds = vk::WriteDescriptorSet::builder()
+        let instance_wds = vk::WriteDescriptorSet::builder()
             .dst_set(self.descriptor_set)
             .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .dst_binding(7)
-            .buffer_info(&[clear_info])
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .dst_binding(8)
+            .buffer_info(&[instance_info])
             .build();
-        wds.push(clear_wds);
+        wds.push(instance_wds);
 
         self.device.update_descriptor_sets(&wds);
     }
+
+    pub fn update_depth_target(&mut self, depth_target: vk::ImageView) {
+        let depth_image_info = vk::DescriptorImageInfo::builder()
+            .sampler(vk::Sampler::null())
+            .image_layout(vk::ImageLayout::GENERAL)
+            .image_view(depth_target)
+            .build();
+        let depth_image_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .dst_binding(9)
+            .image_info(&[depth_image_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[depth_image_wds]);
+    }
+
+    pub fn update_id_target(&mut self, id_target: vk::ImageView) {
+        let id_image_info = vk::DescriptorImageInfo::builder()
+            .sampler(vk::Sampler::null())
+            .image_layout(vk::ImageLayout::GENERAL)
+            .image_view(id_target)
+            .build();
+        let id_image_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .dst_binding(16)
+            .image_info(&[id_image_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[id_image_wds]);
+    }
+
+    // Albedo, normal, motion and color images, the color comes after the previous cameras
+    pub fn update_aov_targets(&mut self, aov_targets: [vk::ImageView; 4]) {
+        let image_infos: Vec<vk::DescriptorImageInfo> = aov_targets
+            .iter()
+            .map(|&aov_target| {
+                vk::DescriptorImageInfo::builder()
+                    .sampler(vk::Sampler::null())
+                    .image_layout(vk::ImageLayout::GENERAL)
+                    .image_view(aov_target)
+                    .build()
+            })
+            .collect();
+        let aov_wds: Vec<vk::WriteDescriptorSet> = image_infos
+            .iter()
+            .zip([20, 21, 22, 24].iter())
+            .map(|(image_info, binding)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.descriptor_set)
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .dst_binding(*binding)
+                    .image_info(std::slice::from_ref(image_info))
+                    .build()
+            })
+            .collect();
+
+        self.device.update_descriptor_sets(&aov_wds);
+    }
+
+    pub fn update_previous_camera_buffer(&mut self, previous_camera_buffer: vk::Buffer) {
+        let camera_info = vk::DescriptorBufferInfo::builder()
+            .buffer(previous_camera_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let camera_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .dst_binding(23)
+            .buffer_info(&[camera_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[camera_wds]);
+    }
+
+    pub fn update_settings_buffer(&mut self, settings_buffer: vk::Buffer) {
+        let settings_info = vk::DescriptorBufferInfo::builder()
+            .buffer(settings_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let settings_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .dst_binding(10)
+            .buffer_info(&[settings_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[settings_wds]);
+    }
+
+    pub fn update_frame_resources(&mut self, frame_buffer: vk::Buffer, blue_noise: &Texture) {
+        let frame_info = vk::DescriptorBufferInfo::builder()
+            .buffer(frame_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let frame_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .dst_binding(13)
+            .buffer_info(&[frame_info])
+            .build();
+
+        let blue_noise_info = vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(blue_noise.get_image_view())
+            .sampler(blue_noise.get_sampler())
+            .build();
+        let blue_noise_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .dst_binding(14)
+            .image_info(&[blue_noise_info])
+            .build();
+
+        self.device
+            .update_descriptor_sets(&[frame_wds, blue_noise_wds]);
+    }
+
+    pub fn update_environment_map(&mut self, environment_map: &Texture) {
+        let environment_info = vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(environment_map.get_image_view())
+            .sampler(environment_map.get_sampler())
+            .build();
+        let environment_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .dst_binding(17)
+            .image_info(&[environment_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[environment_wds]);
+    }
+
+    pub fn update_light_buffers(&mut self, lights_buffer: vk::Buffer, alias_buffer: vk::Buffer) {
+        let lights_info = vk::DescriptorBufferInfo::builder()
+            .buffer(lights_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let lights_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .dst_binding(11)
+            .buffer_info(&[lights_info])
+            .build();
+
+        let alias_info = vk::DescriptorBufferInfo::builder()
+            .buffer(alias_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let alias_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .dst_binding(12)
+            .buffer_info(&[alias_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[lights_wds, alias_wds]);
+    }
+
+    pub fn update_user_data_buffer(&mut self, user_data_buffer: vk::Buffer) {
+        let user_data_info = vk::DescriptorBufferInfo::builder()
+            .buffer(user_data_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let user_data_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .dst_binding(15)
+            .buffer_info(&[user_data_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[user_data_wds]);
+    }
+
+    pub fn update_irradiance_buffers(
+        &mut self,
+        volume_buffer: vk::Buffer,
+        bake_buffer: vk::Buffer,
+    ) {
+        let volume_info = vk::DescriptorBufferInfo::builder()
+            .buffer(volume_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let volume_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .dst_binding(18)
+            .buffer_info(&[volume_info])
+            .build();
+
+        let bake_info = vk::DescriptorBufferInfo::builder()
+            .buffer(bake_buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+            .build();
+        let bake_wds = vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .dst_binding(19)
+            .buffer_info(&[bake_info])
+            .build();
+
+        self.device.update_descriptor_sets(&[volume_wds, bake_wds]);
+    }
 }
 
 impl Drop for DescriptorSet {
     fn drop(&mut self) {
         self.device
             .destroy_descriptor_set_layout(self.descriptor_set_layout);
-        self.device.destroy_descriptor_pool(self.descriptor_pool);
+        if let Some(allocation) = self.allocation.take() {
+            self.descriptor_allocator.free(allocation);
+        }
     }
 }
 
 pub struct DescriptorSetBuilder<'a> {
     context: &'a VulkanContext,
-    geometry_instance: &'a GeometryInstance,
+    descriptor_allocator: &'a Rc<DescriptorAllocator>,
+    geometry_instances: &'a [GeometryInstance],
 }
 
 impl<'a> DescriptorSetBuilder<'a> {
-    pub fn new(context: &'a VulkanContext, geometry_instance: &'a GeometryInstance) -> Self {
+    pub fn new(
+        context: &'a VulkanContext,
+        descriptor_allocator: &'a Rc<DescriptorAllocator>,
+        geometry_instances: &'a [GeometryInstance],
+    ) -> Self {
         DescriptorSetBuilder {
             context,
-            geometry_instance,
+            descriptor_allocator,
+            geometry_instances,
         }
     }
 
     pub fn build(self) -> Result<DescriptorSet, VulkanError> {
         let command_buffer = self.context.begin_single_time_commands()?;
 
-        self.cmd_pipeline_barrier(command_buffer, self.geometry_instance.vertex_buffer.get());
-        self.cmd_pipeline_barrier(command_buffer, self.geometry_instance.index_buffer.get());
+        // The vertex and index buffers were uploaded with staging copies, the hit shaders read them
+        let buffer_memory_barriers: Vec<BufferMemoryBarrier2> = self
+            .geometry_instances
+            .iter()
+            .flat_map(|geometry_instance| {
+                vec![
+                    geometry_instance.vertex_buffer.get(),
+                    geometry_instance.index_buffer.get(),
+                ]
+            })
+            .map(|buffer| BufferMemoryBarrier2 {
+                src_stage_mask: vk::PipelineStageFlags::TRANSFER,
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_stage_mask: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                buffer,
+            })
+            .collect();
+        self.context.get_device().cmd_pipeline_barrier2(
+            command_buffer,
+            &DependencyInfo {
+                buffer_memory_barriers: &buffer_memory_barriers,
+                ..Default::default()
+            },
+        );
 
         self.context.end_single_time_commands(command_buffer)?;
 
+        let geometry_count = self.geometry_instances.len() as u32;
+        let texture_count = self
+            .geometry_instances
+            .iter()
+            .map(|geometry_instance| geometry_instance.textures.len() as u32)
+            .sum();
+
         let mut bindings = vec![];
-        // Acceleration structure
+        // Acceleration structures, one per step of the motion blur
         bindings.push(self.add_binding(
             0,
-            1,
+            MAX_MOTION_BLUR_STEPS,
             vk::DescriptorType::ACCELERATION_STRUCTURE_NV,
             vk::ShaderStageFlags::RAYGEN_NV | vk::ShaderStageFlags::CLOSEST_HIT_NV,
         ));
@@ -206,71 +513,225 @@ impl<'a> DescriptorSetBuilder<'a> {
         // Vertex buffer
         bindings.push(self.add_binding(
             3,
-            1,
+            geometry_count,
             vk::DescriptorType::STORAGE_BUFFER,
             vk::ShaderStageFlags::CLOSEST_HIT_NV,
         ));
         // Index buffer
         bindings.push(self.add_binding(
             4,
-            1,
+            geometry_count,
             vk::DescriptorType::STORAGE_BUFFER,
             vk::ShaderStageFlags::CLOSEST_HIT_NV,
         ));
         // Material buffer
         bindings.push(self.add_binding(
             5,
-            1,
+            geometry_count,
             vk::DescriptorType::STORAGE_BUFFER,
             vk::ShaderStageFlags::CLOSEST_HIT_NV,
         ));
         // Textures
         bindings.push(self.add_binding(
             6,
-            self.geometry_instance.textures.len() as u32,
+            texture_count,
             vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
             vk::ShaderStageFlags::CLOSEST_HIT_NV,
         ));
-        // Clear color
+        // Background
         bindings.push(self.add_binding(
             7,
             1,
             vk::DescriptorType::UNIFORM_BUFFER,
-            vk::ShaderStageFlags::MISS_NV,
+            vk::ShaderStageFlags::RAYGEN_NV
+                | vk::ShaderStageFlags::MISS_NV
+                | vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Instance infos
+        bindings.push(self.add_binding(
+            8,
+            1,
+            vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Linear depth
+        bindings.push(self.add_binding(
+            9,
+            1,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::ShaderStageFlags::RAYGEN_NV,
+        ));
+        // Render settings
+        bindings.push(self.add_binding(
+            10,
+            1,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            vk::ShaderStageFlags::RAYGEN_NV | vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Lights, the ray generation shader lights the fog with them
+        bindings.push(self.add_binding(
+            11,
+            1,
+            vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::RAYGEN_NV | vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Light alias table
+        bindings.push(self.add_binding(
+            12,
+            1,
+            vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Frame constants
+        bindings.push(self.add_binding(
+            13,
+            1,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            vk::ShaderStageFlags::RAYGEN_NV
+                | vk::ShaderStageFlags::MISS_NV
+                | vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Blue noise
+        bindings.push(self.add_binding(
+            14,
+            1,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Instance user data
+        bindings.push(self.add_binding(
+            15,
+            1,
+            vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Object IDs
+        bindings.push(self.add_binding(
+            16,
+            1,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::ShaderStageFlags::RAYGEN_NV,
+        ));
+        // Environment map
+        bindings.push(self.add_binding(
+            17,
+            1,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::ShaderStageFlags::RAYGEN_NV
+                | vk::ShaderStageFlags::MISS_NV
+                | vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Irradiance volume
+        bindings.push(self.add_binding(
+            18,
+            1,
+            vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ));
+        // Probes written by the irradiance bake
+        bindings.push(self.add_binding(
+            19,
+            1,
+            vk::DescriptorType::STORAGE_BUFFER,
+            vk::ShaderStageFlags::RAYGEN_NV,
+        ));
+        // Albedo, normal and motion AOVs
+        for binding in 20..23 {
+            bindings.push(self.add_binding(
+                binding,
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::ShaderStageFlags::RAYGEN_NV,
+            ));
+        }
+        // Cameras of the previous update
+        bindings.push(self.add_binding(
+            23,
+            1,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            vk::ShaderStageFlags::RAYGEN_NV,
+        ));
+        // Color AOV
+        bindings.push(self.add_binding(
+            24,
+            1,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::ShaderStageFlags::RAYGEN_NV,
         ));
 
-        let descriptor_pool = self.generate_pool(&bindings)?;
+        self.check_limits(&bindings)?;
         let descriptor_set_layout = self.generate_layout(&bindings)?;
-        let descriptor_set = self.generate_set(descriptor_pool, descriptor_set_layout)?;
+        let allocation = match self
+            .descriptor_allocator
+            .allocate(descriptor_set_layout, &bindings)
+        {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                self.context
+                    .get_device()
+                    .destroy_descriptor_set_layout(descriptor_set_layout);
+                return Err(err);
+            }
+        };
 
         Ok(DescriptorSet {
-            device: Rc::clone(&self.context.get_device()),
-            descriptor_pool,
+            device: Rc::clone(self.context.get_device()),
+            descriptor_allocator: Rc::clone(self.descriptor_allocator),
+            descriptor_set: allocation.get(),
+            allocation: Some(allocation),
             descriptor_set_layout,
-            descriptor_set,
         })
     }
 
-    fn cmd_pipeline_barrier(&self, command_buffer: vk::CommandBuffer, buffer: vk::Buffer) {
-        let bmb = vk::BufferMemoryBarrier::builder()
-            .src_access_mask(vk::AccessFlags::empty())
-            .dst_access_mask(vk::AccessFlags::SHADER_READ)
-            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-            .offset(0)
-            .size(vk::WHOLE_SIZE)
-            .buffer(buffer)
-            .build();
+    // Big scenes run out of descriptors before anything else, a clear error beats a lost device
+    fn check_limits(&self, bindings: &[vk::DescriptorSetLayoutBinding]) -> Result<(), VulkanError> {
+        let limits = device_limits(self.context);
+
+        for size in pool_sizes(bindings).iter() {
+            if let Some((name, _, per_set)) = descriptor_limits(&limits, size.ty) {
+                if size.descriptor_count > per_set {
+                    return Err(VulkanError::PipelineError(format!(
+                        "The scene needs {} {} descriptors, the device supports {} per set",
+                        size.descriptor_count, name, per_set
+                    )));
+                }
+            }
+        }
 
-        self.context.get_device().cmd_pipeline_barrier(
-            command_buffer,
-            vk::PipelineStageFlags::ALL_COMMANDS,
-            vk::PipelineStageFlags::ALL_COMMANDS,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[bmb],
-            &[],
-        );
+        for &stage in [
+            vk::ShaderStageFlags::RAYGEN_NV,
+            vk::ShaderStageFlags::MISS_NV,
+            vk::ShaderStageFlags::CLOSEST_HIT_NV,
+        ]
+        .iter()
+        {
+            let stage_bindings: Vec<vk::DescriptorSetLayoutBinding> = bindings
+                .iter()
+                .filter(|binding| binding.stage_flags.contains(stage))
+                .copied()
+                .collect();
+            let stage_sizes = pool_sizes(&stage_bindings);
+            for size in stage_sizes.iter() {
+                if let Some((name, per_stage, _)) = descriptor_limits(&limits, size.ty) {
+                    if size.descriptor_count > per_stage {
+                        return Err(VulkanError::PipelineError(format!(
+                            "The {:?} stage needs {} {} descriptors, the device supports {}",
+                            stage, size.descriptor_count, name, per_stage
+                        )));
+                    }
+                }
+            }
+
+            let resources: u32 = stage_sizes.iter().map(|size| size.descriptor_count).sum();
+            if resources > limits.max_per_stage_resources {
+                return Err(VulkanError::PipelineError(format!(
+                    "The {:?} stage needs {} resources, the device supports {}",
+                    stage, resources, limits.max_per_stage_resources
+                )));
+            }
+        }
+
+        Ok(())
     }
 
     fn add_binding(
@@ -288,28 +749,6 @@ impl<'a> DescriptorSetBuilder<'a> {
             .build()
     }
 
-    fn generate_pool(
-        &self,
-        bindings: &[vk::DescriptorSetLayoutBinding],
-    ) -> Result<vk::DescriptorPool, VulkanError> {
-        let mut counters = vec![];
-        for binding in bindings {
-            counters.push(
-                vk::DescriptorPoolSize::builder()
-                    .ty(binding.descriptor_type)
-                    .descriptor_count(binding.descriptor_count)
-                    .build(),
-            );
-        }
-
-        let pool_info = vk::DescriptorPoolCreateInfo::builder()
-            .pool_sizes(&counters)
-            .max_sets(1)
-            .build();
-
-        self.context.get_device().create_descriptor_pool(&pool_info)
-    }
-
     fn generate_layout(
         &self,
         bindings: &[vk::DescriptorSetLayoutBinding],
@@ -321,20 +760,39 @@ impl<'a> DescriptorSetBuilder<'a> {
             .get_device()
             .create_descriptor_set_layout(&layout_info)
     }
+}
 
-    fn generate_set(
-        &self,
-        pool: vk::DescriptorPool,
-        layout: vk::DescriptorSetLayout,
-    ) -> Result<vk::DescriptorSet, VulkanError> {
-        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(pool)
-            .set_layouts(&[layout])
-            .build();
-
-        self.context
-            .get_device()
-            .allocate_descriptor_sets(&alloc_info)
-            .map(|set| set[0])
+// Name, per stage and per set limits of a descriptor type, None for the types without core limits
+fn descriptor_limits(
+    limits: &vk::PhysicalDeviceLimits,
+    ty: vk::DescriptorType,
+) -> Option<(&'static str, u32, u32)> {
+    match ty {
+        vk::DescriptorType::STORAGE_BUFFER => Some((
+            "storage buffer",
+            limits.max_per_stage_descriptor_storage_buffers,
+            limits.max_descriptor_set_storage_buffers,
+        )),
+        vk::DescriptorType::UNIFORM_BUFFER => Some((
+            "uniform buffer",
+            limits.max_per_stage_descriptor_uniform_buffers,
+            limits.max_descriptor_set_uniform_buffers,
+        )),
+        vk::DescriptorType::STORAGE_IMAGE => Some((
+            "storage image",
+            limits.max_per_stage_descriptor_storage_images,
+            limits.max_descriptor_set_storage_images,
+        )),
+        // Counted both as samplers and sampled images
+        vk::DescriptorType::COMBINED_IMAGE_SAMPLER => Some((
+            "texture",
+            limits
+                .max_per_stage_descriptor_samplers
+                .min(limits.max_per_stage_descriptor_sampled_images),
+            limits
+                .max_descriptor_set_samplers
+                .min(limits.max_descriptor_set_sampled_images),
+        )),
+        _ => None,
     }
 }
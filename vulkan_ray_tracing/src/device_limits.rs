@@ -0,0 +1,54 @@
+use ash::version::InstanceV1_0;
+use ash::vk;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+// Checks of the resources against the limits of the device before they are created. Release
+// builds run without the validation layers, going over a limit would otherwise show up as a
+// lost device or garbage in the image rather than as an error.
+
+pub(crate) fn device_limits(context: &VulkanContext) -> vk::PhysicalDeviceLimits {
+    unsafe {
+        context
+            .get_instance()
+            .get()
+            .get_physical_device_properties(context.get_physical_device().get())
+    }
+    .limits
+}
+
+// Storage buffers are bound whole, past maxStorageBufferRange the shaders cannot read the end
+pub(crate) fn check_storage_buffer_range(
+    limits: &vk::PhysicalDeviceLimits,
+    name: &str,
+    size: vk::DeviceSize,
+) -> Result<(), VulkanError> {
+    if size > vk::DeviceSize::from(limits.max_storage_buffer_range) {
+        return Err(VulkanError::PipelineError(format!(
+            "The {} buffer needs {} bytes, the device binds at most {} bytes per storage buffer",
+            name, size, limits.max_storage_buffer_range
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_image_extent(
+    limits: &vk::PhysicalDeviceLimits,
+    extent: vk::Extent2D,
+    array_layers: u32,
+) -> Result<(), VulkanError> {
+    let max_dimension = limits.max_image_dimension2_d;
+    if extent.width > max_dimension || extent.height > max_dimension {
+        return Err(VulkanError::PipelineError(format!(
+            "An image of {}x{} is requested, the device supports at most {}x{}",
+            extent.width, extent.height, max_dimension, max_dimension
+        )));
+    }
+    if array_layers > limits.max_image_array_layers {
+        return Err(VulkanError::PipelineError(format!(
+            "An image of {} layers is requested, the device supports at most {}",
+            array_layers, limits.max_image_array_layers
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+
+// Engine values of the frame that are set from the host, the renderer adds the sample index,
+// seed, frame index and resolution of its own. The debug flags mean nothing to the engine, they
+// are left to custom shaders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameParameters {
+    // Seconds of the application clock, for animated shaders
+    pub time: f32,
+    pub delta_time: f32,
+    // Returned by the rays that hit nothing while the background is solid
+    pub clear_color: glm::Vec4,
+    pub debug_flags: u32,
+}
+
+impl Default for FrameParameters {
+    fn default() -> Self {
+        FrameParameters {
+            time: 0.0,
+            delta_time: 0.0,
+            clear_color: glm::vec4(0.0, 0.0, 0.0, 1.0),
+            debug_flags: 0,
+        }
+    }
+}
+
+// std140 layout of the Frame uniform block of frame.glsl, written once per frame and bound to
+// the ray tracing and compute pipelines alike
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct FrameConstants {
+    sample_index: u32,
+    seed: u32,
+    frame_index: u32,
+    debug_flags: u32,
+    time: f32,
+    delta_time: f32,
+    resolution: [u32; 2],
+    clear_color: [f32; 4],
+}
+
+unsafe impl Zeroable for FrameConstants {}
+unsafe impl Pod for FrameConstants {}
+
+impl FrameConstants {
+    // The seed is derived from the sample index and the random seed, so every sample gets
+    // different random numbers
+    pub fn new(
+        parameters: &FrameParameters,
+        sample_index: u32,
+        random_seed: u32,
+        frame_index: u32,
+        resolution: [u32; 2],
+    ) -> Self {
+        // Wang hash, a random seed of 0 keeps the sequence of the frame index alone
+        let key = sample_index ^ random_seed.wrapping_mul(0x9e37_79b9);
+        let mut seed = (key ^ 61) ^ (key >> 16);
+        seed = seed.wrapping_mul(9);
+        seed ^= seed >> 4;
+        seed = seed.wrapping_mul(0x27d4_eb2d);
+        seed ^= seed >> 15;
+
+        FrameConstants {
+            sample_index,
+            seed,
+            frame_index,
+            debug_flags: parameters.debug_flags,
+            time: parameters.time,
+            delta_time: parameters.delta_time,
+            resolution,
+            clear_color: parameters.clear_color.into(),
+        }
+    }
+}
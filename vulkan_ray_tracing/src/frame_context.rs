@@ -0,0 +1,17 @@
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+
+// Given to the custom commands recorded into the frame. They are recorded into the command
+// buffer of the frame once its render pass ended, the back buffer is then in the
+// PRESENT_SRC_KHR layout and has to be left in it.
+pub struct FrameContext<'a> {
+    pub device: &'a VulkanDevice,
+    pub back_buffer: vk::Image,
+    pub back_buffer_view: vk::ImageView,
+    pub extent: vk::Extent2D,
+    // Linear depth of the camera rays, R32_SFLOAT in the GENERAL layout
+    pub depth_view: vk::ImageView,
+    pub frame_index: u32,
+    // Slot of the frame among the frames in flight, for resources written every frame
+    pub frame_slot: usize,
+}
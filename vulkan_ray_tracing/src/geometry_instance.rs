@@ -1,29 +1,57 @@
 use std::mem;
-use std::os::raw::c_void;
 
 use ash::vk;
+use bytemuck::{Pod, Zeroable};
 use nalgebra_glm as glm;
-use vulkan_bootstrap::buffer::{Buffer, BufferBuilder, BufferType};
 use vulkan_bootstrap::errors::VulkanError;
-use vulkan_bootstrap::texture::{Texture, TextureBuilder};
 use vulkan_bootstrap::vulkan_context::VulkanContext;
 
+use crate::buffer::{DataBuffer, DataBufferBuilder, MemoryLocation};
+use crate::device_limits::{check_storage_buffer_range, device_limits};
+use crate::renderer_stats::GeometryMemory;
+use crate::texture::{Texture, TextureBuilder, TextureCompression};
+use crate::texture_packing::{pack_textures, PackedTexture};
+use crate::transient_commands::{
+    TransientCommands, UploadBatch, UploadProgress, UploadProgressCallback,
+};
+
 pub struct ImageBuffer {
     pub pixels: Vec<u8>,
     pub tex_width: u32,
     pub tex_height: u32,
     pub tex_channels: u32,
+    // Color data, as opposed to normals or other values that must not be gamma decoded
+    pub srgb: bool,
+    // RGBA8 pixels when None, the blocks of the whole mip chain otherwise
+    pub compression: Option<TextureCompression>,
+}
+
+impl ImageBuffer {
+    pub fn get_format(&self) -> vk::Format {
+        match (self.compression, self.srgb) {
+            (Some(compression), srgb) => compression.format(srgb),
+            (None, true) => vk::Format::R8G8B8A8_SRGB,
+            (None, false) => vk::Format::R8G8B8A8_UNORM,
+        }
+    }
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Vertex {
     pub pos: glm::Vec3,
     pub nrm: glm::Vec3,
     pub color: glm::Vec3,
     pub tex_coord: glm::Vec2,
     pub mat_id: i32,
+    // Bitangent sign in w
+    pub tangent: glm::Vec4,
 }
 
+// Only made of f32 and i32 fields, without padding
+unsafe impl Zeroable for Vertex {}
+unsafe impl Pod for Vertex {}
+
 impl Vertex {
     pub fn get_binding_description() -> vk::VertexInputBindingDescription {
         vk::VertexInputBindingDescription::builder()
@@ -33,7 +61,7 @@ impl Vertex {
             .build()
     }
 
-    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
+    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 6] {
         [
             vk::VertexInputAttributeDescription::builder()
                 .binding(0)
@@ -65,11 +93,25 @@ impl Vertex {
                 .format(vk::Format::R32_SINT)
                 .offset(memoffset::offset_of!(Vertex, mat_id) as u32)
                 .build(),
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(5)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(memoffset::offset_of!(Vertex, tangent) as u32)
+                .build(),
         ]
     }
 }
 
+// Values of Material::brdf
+pub const BRDF_PHONG: i32 = 0;
+pub const BRDF_LAMBERT: i32 = 1;
+pub const BRDF_GGX_METAL_ROUGH: i32 = 2;
+// Refracts with the ior, tinted by the diffuse color
+pub const BRDF_GLASS: i32 = 3;
+
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Material {
     pub ambient: glm::Vec3,
     pub diffuse: glm::Vec3,
@@ -81,8 +123,24 @@ pub struct Material {
     pub dissolve: f32,
     pub illum: i32,
     pub texture_id: i32,
+    // Part of the texture to sample, UV offset in xy and scale in zw
+    pub texture_rect: glm::Vec4,
+    pub texture_layer: i32,
+    pub brdf: i32,
+    // Used by the GGX and glass models, the Phong one keeps the shininess
+    pub roughness: f32,
+    pub metallic: f32,
+    // Part of the dielectric lobe of the GGX model that goes through the surface
+    pub transmission: f32,
+    // Shades both sides and keeps the instance from culling its back faces
+    pub double_sided: i32,
+    // The shaders read materials as vec4s
+    pub padding: [i32; 2],
 }
 
+unsafe impl Zeroable for Material {}
+unsafe impl Pod for Material {}
+
 impl Default for Material {
     fn default() -> Self {
         Material {
@@ -96,20 +154,86 @@ impl Default for Material {
             dissolve: 1.0,
             illum: 0,
             texture_id: -1,
+            texture_rect: glm::vec4(0.0, 0.0, 1.0, 1.0),
+            texture_layer: 0,
+            brdf: BRDF_PHONG,
+            roughness: 0.5,
+            metallic: 0.0,
+            transmission: 0.0,
+            double_sided: 1,
+            padding: [0; 2],
         }
     }
 }
 
 pub struct GeometryInstance {
-    pub vertex_buffer: Buffer,
+    pub vertex_buffer: DataBuffer,
     pub vertex_count: u32,
     pub vertex_offset: u32,
-    pub index_buffer: Buffer,
+    pub index_buffer: DataBuffer,
     pub index_count: u32,
     pub index_offset: u32,
-    pub material_buffer: Buffer,
+    pub material_buffer: DataBuffer,
+    // Kept on the host to know which textures each material samples
+    pub materials: Vec<Material>,
     pub textures: Vec<Texture>,
     pub transform: glm::Mat4,
+    // What the instance was drawn with in the previous frame, for the motion vectors. None until
+    // it was drawn once, maintained by the pipeline.
+    pub previous_transform: Option<glm::Mat4>,
+    // Entry of the pipeline user data buffer, several instances can share one
+    pub user_data_index: u32,
+    // Center and radius in object space, for the instance culling
+    pub bounding_sphere: glm::Vec4,
+    // The acceleration structure only culls back faces per instance, one double sided material
+    // is enough to keep them all
+    pub double_sided: bool,
+}
+
+impl GeometryInstance {
+    // Between the transform, at 0, and the previous one, at 1. Interpolating the matrices is
+    // close enough for the motion of a single frame.
+    pub(crate) fn transform_at(&self, time: f32) -> glm::Mat4 {
+        match self.previous_transform {
+            Some(previous_transform) if time > 0.0 => {
+                self.transform + (previous_transform - self.transform) * time
+            }
+            _ => self.transform,
+        }
+    }
+
+    // Without the acceleration structure, which belongs to the pipeline
+    pub fn get_memory(&self) -> GeometryMemory {
+        let texture_memory: Vec<u64> = self
+            .textures
+            .iter()
+            .map(|texture| texture.get_memory_size())
+            .collect();
+        let material_texture_memory = self
+            .materials
+            .iter()
+            .map(|material| {
+                let index = material.texture_id as usize;
+                match self.textures.get(index) {
+                    Some(texture) if material.texture_id >= 0 => {
+                        let layer_memory =
+                            texture_memory[index] as f32 / texture.get_array_layers() as f32;
+                        (layer_memory * material.texture_rect.z * material.texture_rect.w) as u64
+                    }
+                    _ => 0,
+                }
+            })
+            .collect();
+
+        GeometryMemory {
+            vertex_memory: self.vertex_buffer.size(),
+            index_memory: self.index_buffer.size(),
+            material_memory: self.material_buffer.size(),
+            acceleration_structure_memory: 0,
+            texture_memory,
+            material_texture_memory,
+        }
+    }
 }
 
 pub struct GeometryInstanceBuilder<'a> {
@@ -118,6 +242,9 @@ pub struct GeometryInstanceBuilder<'a> {
     indices: Vec<u32>,
     materials: Vec<Material>,
     textures: Vec<ImageBuffer>,
+    texture_packing: bool,
+    transient_commands: Option<&'a TransientCommands>,
+    upload_progress: Option<UploadProgressCallback>,
 }
 
 impl<'a> GeometryInstanceBuilder<'a> {
@@ -128,6 +255,9 @@ impl<'a> GeometryInstanceBuilder<'a> {
             indices: vec![],
             materials: vec![],
             textures: vec![],
+            texture_packing: false,
+            transient_commands: None,
+            upload_progress: None,
         }
     }
 
@@ -151,13 +281,78 @@ impl<'a> GeometryInstanceBuilder<'a> {
         self
     }
 
-    pub fn build(self) -> Result<GeometryInstance, VulkanError> {
+    // Merges the textures into texture arrays and atlases, to use fewer descriptors
+    pub fn with_texture_packing(mut self, texture_packing: bool) -> Self {
+        self.texture_packing = texture_packing;
+        self
+    }
+
+    // The uploads are submitted without waiting, otherwise the queue is waited on once for all
+    pub fn with_transient_commands(mut self, transient_commands: &'a TransientCommands) -> Self {
+        self.transient_commands = Some(transient_commands);
+        self
+    }
+
+    // Reported as the buffers and textures are recorded, all of them are still submitted at once
+    pub fn with_upload_progress<F>(mut self, upload_progress: F) -> Self
+    where
+        F: FnMut(UploadProgress) + 'static,
+    {
+        self.upload_progress = Some(Box::new(upload_progress));
+        self
+    }
+
+    pub fn build(mut self) -> Result<GeometryInstance, VulkanError> {
+        let _span = tracing::info_span!(
+            "upload_geometry_instance",
+            vertices = self.vertices.len(),
+            textures = self.textures.len()
+        )
+        .entered();
         let transform = glm::identity();
 
-        let vertex_buffer = self.create_vertex_buffer(&self.vertices)?;
-        let index_buffer = self.create_index_buffer(&self.indices)?;
-        let material_buffer = self.create_material_buffer(&self.materials)?;
-        let textures = self.create_texture_images(&self.textures)?;
+        let images = std::mem::take(&mut self.textures);
+        let packed_textures = if self.texture_packing {
+            let (packed_textures, placements) = pack_textures(images);
+            for material in self
+                .materials
+                .iter_mut()
+                .filter(|material| material.texture_id >= 0)
+            {
+                let placement = placements[material.texture_id as usize];
+                material.texture_id = placement.texture as i32;
+                material.texture_layer = placement.layer as i32;
+                material.texture_rect = placement.rect;
+            }
+            packed_textures
+        } else {
+            images
+                .into_iter()
+                .map(|image| PackedTexture {
+                    image,
+                    array_layers: 1,
+                })
+                .collect()
+        };
+
+        self.check_limits()?;
+        let mut upload_batch = UploadBatch::new(self.context, self.transient_commands);
+        if let Some(upload_progress) = self.upload_progress.take() {
+            let total_bytes = self.upload_size(&packed_textures);
+            upload_batch = upload_batch.with_progress(total_bytes, upload_progress);
+        }
+        let vertex_buffer = self.create_vertex_buffer(&upload_batch, &self.vertices)?;
+        let index_buffer = self.create_index_buffer(&upload_batch, &self.indices)?;
+        let material_buffer = self.create_material_buffer(&upload_batch, &self.materials)?;
+        let textures = self.create_texture_images(&upload_batch, &packed_textures)?;
+        upload_batch.submit()?;
+        let bounding_sphere = bounding_sphere(&self.vertices);
+        // Rays leave glass through its back faces
+        let double_sided = self.materials.is_empty()
+            || self
+                .materials
+                .iter()
+                .any(|material| material.double_sided != 0 || material.brdf == BRDF_GLASS);
 
         Ok(GeometryInstance {
             vertex_buffer,
@@ -167,37 +362,96 @@ impl<'a> GeometryInstanceBuilder<'a> {
             index_count: self.indices.len() as u32,
             index_offset: 0,
             material_buffer,
+            materials: self.materials,
             textures,
             transform,
+            previous_transform: None,
+            user_data_index: 0,
+            bounding_sphere,
+            double_sided,
         })
     }
 
-    fn create_vertex_buffer(&self, vertices: &[Vertex]) -> Result<Buffer, VulkanError> {
-        let size = (mem::size_of::<Vertex>() * vertices.len()) as vk::DeviceSize;
-        let vertices = vertices.as_ptr() as *const c_void;
-        self.create_buffer(BufferType::Vertex, size, vertices)
+    // Before anything is uploaded, the hit shaders bind the buffers whole
+    fn check_limits(&self) -> Result<(), VulkanError> {
+        let limits = device_limits(self.context);
+        check_storage_buffer_range(
+            &limits,
+            "vertex",
+            mem::size_of_val(self.vertices.as_slice()) as vk::DeviceSize,
+        )?;
+        check_storage_buffer_range(
+            &limits,
+            "index",
+            mem::size_of_val(self.indices.as_slice()) as vk::DeviceSize,
+        )?;
+        check_storage_buffer_range(
+            &limits,
+            "material",
+            mem::size_of_val(self.materials.as_slice()) as vk::DeviceSize,
+        )
     }
 
-    fn create_index_buffer(&self, indices: &[u32]) -> Result<Buffer, VulkanError> {
-        let size = (mem::size_of::<u32>() * indices.len()) as vk::DeviceSize;
-        let indices = indices.as_ptr() as *const c_void;
-        self.create_buffer(BufferType::Index, size, indices)
+    // Bytes of the staging buffers, the placeholder texture included when there is no texture
+    fn upload_size(&self, images: &[PackedTexture]) -> u64 {
+        let texture_size = if images.is_empty() {
+            4
+        } else {
+            images
+                .iter()
+                .map(|packed_texture| packed_texture.image.pixels.len())
+                .sum()
+        };
+        (mem::size_of_val(self.vertices.as_slice())
+            + mem::size_of_val(self.indices.as_slice())
+            + mem::size_of_val(self.materials.as_slice())
+            + texture_size) as u64
     }
 
-    fn create_material_buffer(&self, materials: &[Material]) -> Result<Buffer, VulkanError> {
-        let size = (mem::size_of::<Material>() * materials.len()) as vk::DeviceSize;
-        let materials = materials.as_ptr() as *const c_void;
+    fn create_vertex_buffer(
+        &self,
+        upload_batch: &UploadBatch,
+        vertices: &[Vertex],
+    ) -> Result<DataBuffer, VulkanError> {
+        DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_upload_batch(upload_batch)
+            .with_data(vertices)
+            .build()
+    }
 
-        let mat_buffer = BufferBuilder::new(self.context)
-            .with_type(BufferType::Storage)
-            .with_size(size)
-            .build()?;
-        mat_buffer.copy_data(materials)?;
+    fn create_index_buffer(
+        &self,
+        upload_batch: &UploadBatch,
+        indices: &[u32],
+    ) -> Result<DataBuffer, VulkanError> {
+        DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_upload_batch(upload_batch)
+            .with_data(indices)
+            .build()
+    }
 
-        Ok(mat_buffer)
+    fn create_material_buffer(
+        &self,
+        upload_batch: &UploadBatch,
+        materials: &[Material],
+    ) -> Result<DataBuffer, VulkanError> {
+        DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_upload_batch(upload_batch)
+            .with_data(materials)
+            .build()
     }
 
-    fn create_texture_images(&self, images: &[ImageBuffer]) -> Result<Vec<Texture>, VulkanError> {
+    fn create_texture_images(
+        &self,
+        upload_batch: &UploadBatch,
+        images: &[PackedTexture],
+    ) -> Result<Vec<Texture>, VulkanError> {
         let mut textures = vec![];
 
         if images.is_empty() {
@@ -206,65 +460,54 @@ impl<'a> GeometryInstanceBuilder<'a> {
                 tex_width: 1,
                 tex_height: 1,
                 tex_channels: 4,
+                srgb: true,
+                compression: None,
             };
 
             let texture = TextureBuilder::new(self.context)
                 .with_width(image.tex_width)
                 .with_height(image.tex_height)
                 .with_pixels(&image.pixels)
+                .with_srgb(image.srgb)
+                .with_mipmaps(true)
+                .with_upload_batch(upload_batch)
                 .build()?;
             textures.push(texture);
         }
 
-        for image in images {
+        for packed_texture in images {
+            let image = &packed_texture.image;
             let texture = TextureBuilder::new(self.context)
                 .with_width(image.tex_width)
                 .with_height(image.tex_height)
                 .with_pixels(&image.pixels)
+                .with_format(image.get_format())
+                .with_array_layers(packed_texture.array_layers)
+                .with_mipmaps(true)
+                .with_upload_batch(upload_batch)
                 .build()?;
             textures.push(texture);
         }
 
         Ok(textures)
     }
+}
 
-    fn create_buffer(
-        &self,
-        ty: BufferType,
-        size: vk::DeviceSize,
-        data: *const c_void,
-    ) -> Result<Buffer, VulkanError> {
-        let staging_buffer = BufferBuilder::new(self.context)
-            .with_type(BufferType::Staging)
-            .with_size(size)
-            .build()?;
-
-        staging_buffer.copy_data(data)?;
-
-        let buffer = BufferBuilder::new(self.context)
-            .with_type(ty)
-            .with_size(size)
-            .build()?;
-
-        self.copy_buffer(staging_buffer.get(), buffer.get(), size)?;
-
-        Ok(buffer)
-    }
-
-    fn copy_buffer(
-        &self,
-        src_buffer: vk::Buffer,
-        dst_buffer: vk::Buffer,
-        size: vk::DeviceSize,
-    ) -> Result<(), VulkanError> {
-        let command_buffer = self.context.begin_single_time_commands()?;
-        let copy_region = vk::BufferCopy::builder().size(size).build();
-        self.context.get_device().cmd_copy_buffer(
-            command_buffer,
-            src_buffer,
-            dst_buffer,
-            &[copy_region],
-        );
-        self.context.end_single_time_commands(command_buffer)
-    }
+// Centered on the bounding box, not the smallest sphere but close enough for culling
+fn bounding_sphere(vertices: &[Vertex]) -> glm::Vec4 {
+    let first = match vertices.first() {
+        Some(vertex) => vertex.pos,
+        None => return glm::zero(),
+    };
+
+    let (min, max) = vertices.iter().fold((first, first), |(min, max), vertex| {
+        (glm::min2(&min, &vertex.pos), glm::max2(&max, &vertex.pos))
+    });
+    let center = (min + max) * 0.5;
+    let radius = vertices
+        .iter()
+        .map(|vertex| glm::distance(&center, &vertex.pos))
+        .fold(0.0, f32::max);
+
+    glm::vec4(center.x, center.y, center.z, radius)
 }
@@ -0,0 +1,182 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::barrier_commands::{BarrierCommands, DependencyInfo, ImageMemoryBarrier2};
+use crate::buffer::find_memory_type;
+use crate::device_limits::{check_image_extent, device_limits};
+
+pub(crate) struct ImageLayoutTransition {
+    pub old_layout: vk::ImageLayout,
+    pub new_layout: vk::ImageLayout,
+    pub src_access_mask: vk::AccessFlags,
+    pub dst_access_mask: vk::AccessFlags,
+    pub src_stage: vk::PipelineStageFlags,
+    pub dst_stage: vk::PipelineStageFlags,
+}
+
+// Creates a device local 2D image with its memory bound
+pub(crate) fn create_image(
+    context: &VulkanContext,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    mip_levels: u32,
+    array_layers: u32,
+    usage: vk::ImageUsageFlags,
+) -> Result<(vk::Image, vk::DeviceMemory), VulkanError> {
+    check_image_extent(&device_limits(context), extent, array_layers)?;
+    let device = context.get_device();
+
+    let image_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        })
+        .mip_levels(mip_levels)
+        .array_layers(array_layers)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .build();
+    let image = unsafe { device.get().create_image(&image_info, None) }
+        .map_err(|err| VulkanError::PipelineError(err.to_string()))?;
+
+    let requirements = unsafe { device.get().get_image_memory_requirements(image) };
+    let memory = find_memory_type(
+        context,
+        &requirements,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )
+    .and_then(|memory_type_index| {
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(memory_type_index)
+            .build();
+        unsafe { device.get().allocate_memory(&alloc_info, None) }
+            .map_err(|err| VulkanError::PipelineError(err.to_string()))
+    });
+    let memory = match memory {
+        Ok(memory) => memory,
+        Err(err) => {
+            unsafe { device.get().destroy_image(image, None) };
+            return Err(err);
+        }
+    };
+
+    if let Err(err) = unsafe { device.get().bind_image_memory(image, memory, 0) } {
+        unsafe {
+            device.get().destroy_image(image, None);
+            device.get().free_memory(memory, None);
+        }
+        return Err(VulkanError::PipelineError(err.to_string()));
+    }
+
+    Ok((image, memory))
+}
+
+pub(crate) fn create_image_view(
+    device: &VulkanDevice,
+    image: vk::Image,
+    format: vk::Format,
+    view_type: vk::ImageViewType,
+    mip_levels: u32,
+    array_layers: u32,
+) -> Result<vk::ImageView, VulkanError> {
+    create_swizzled_image_view(
+        device,
+        image,
+        format,
+        view_type,
+        mip_levels,
+        array_layers,
+        vk::ComponentMapping::default(),
+    )
+}
+
+// The channels of the view are read from other channels of the image
+pub(crate) fn create_swizzled_image_view(
+    device: &VulkanDevice,
+    image: vk::Image,
+    format: vk::Format,
+    view_type: vk::ImageViewType,
+    mip_levels: u32,
+    array_layers: u32,
+    components: vk::ComponentMapping,
+) -> Result<vk::ImageView, VulkanError> {
+    let view_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(view_type)
+        .format(format)
+        .components(components)
+        .subresource_range(mip_subresource_range(0, mip_levels, array_layers))
+        .build();
+    unsafe { device.get().create_image_view(&view_info, None) }
+        .map_err(|err| VulkanError::PipelineError(err.to_string()))
+}
+
+pub(crate) fn cmd_transition_layout(
+    device: &VulkanDevice,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    array_layers: u32,
+    transition: ImageLayoutTransition,
+) {
+    cmd_transition_subresource(
+        device,
+        command_buffer,
+        image,
+        color_subresource_range(array_layers),
+        transition,
+    );
+}
+
+pub(crate) fn cmd_transition_subresource(
+    device: &VulkanDevice,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    subresource_range: vk::ImageSubresourceRange,
+    transition: ImageLayoutTransition,
+) {
+    device.cmd_pipeline_barrier2(
+        command_buffer,
+        &DependencyInfo {
+            image_memory_barriers: &[ImageMemoryBarrier2 {
+                src_stage_mask: transition.src_stage,
+                src_access_mask: transition.src_access_mask,
+                dst_stage_mask: transition.dst_stage,
+                dst_access_mask: transition.dst_access_mask,
+                old_layout: transition.old_layout,
+                new_layout: transition.new_layout,
+                image,
+                subresource_range,
+            }],
+            ..Default::default()
+        },
+    );
+}
+
+// First mip level only
+pub(crate) fn color_subresource_range(array_layers: u32) -> vk::ImageSubresourceRange {
+    mip_subresource_range(0, 1, array_layers)
+}
+
+pub(crate) fn mip_subresource_range(
+    base_mip_level: u32,
+    level_count: u32,
+    array_layers: u32,
+) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(base_mip_level)
+        .level_count(level_count)
+        .base_array_layer(0)
+        .layer_count(array_layers)
+        .build()
+}
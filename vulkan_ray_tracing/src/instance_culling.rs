@@ -0,0 +1,231 @@
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::acceleration_structure::AccelerationStructure;
+use crate::barrier_commands::{BarrierCommands, DependencyInfo, MemoryBarrier2};
+use crate::buffer::{DataBuffer, DataBufferBuilder, MemoryLocation};
+use crate::compute_pipeline::{ComputePipeline, ComputePipelineBuilder};
+use crate::descriptor_allocator::DescriptorAllocator;
+use crate::geometry_instance::GeometryInstance;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CullingStats {
+    pub visible_instances: u32,
+    pub total_instances: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CullingPushConstants {
+    instance_count: u32,
+    camera_count: u32,
+    stats_index: u32,
+}
+
+unsafe impl Zeroable for CullingPushConstants {}
+unsafe impl Pod for CullingPushConstants {}
+
+// Buffers sized for the instances of one top level structure
+pub(crate) struct CullingBuffers {
+    _bounds_buffer: DataBuffer,
+    culled_buffer: DataBuffer,
+}
+
+// Compute pass run before the frame is traced, tests the bounding sphere of every instance
+// against the frustums of the cameras and builds the top level structure with the new masks.
+// Only the camera bit of the mask is cleared, shadow and bounce rays still see the whole scene.
+pub(crate) struct InstanceCulling {
+    pipeline: ComputePipeline,
+    buffers: Option<CullingBuffers>,
+    // Visible instances of each frame slot, read once the frame is done
+    stats_buffer: DataBuffer,
+    frame_count: u32,
+    instance_count: u32,
+}
+
+impl InstanceCulling {
+    pub fn new(
+        context: &VulkanContext,
+        descriptor_allocator: &Rc<DescriptorAllocator>,
+        camera_buffer: vk::Buffer,
+        frame_count: u32,
+    ) -> Result<Self, VulkanError> {
+        let pipeline = ComputePipelineBuilder::new(context, descriptor_allocator)
+            .with_shader(Path::new("assets/shaders/instance_culling.spv"))
+            .with_binding(0, vk::DescriptorType::STORAGE_BUFFER)
+            .with_binding(1, vk::DescriptorType::STORAGE_BUFFER)
+            .with_binding(2, vk::DescriptorType::STORAGE_BUFFER)
+            .with_binding(3, vk::DescriptorType::UNIFORM_BUFFER)
+            .with_binding(4, vk::DescriptorType::STORAGE_BUFFER)
+            .with_push_constant_size(mem::size_of::<CullingPushConstants>() as u32)
+            .build()?;
+
+        let stats_buffer = DataBufferBuilder::new(context)
+            .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Host)
+            .with_data(&vec![0u32; frame_count as usize])
+            .build()?;
+
+        pipeline.update_buffer(3, vk::DescriptorType::UNIFORM_BUFFER, camera_buffer);
+        pipeline.update_buffer(4, vk::DescriptorType::STORAGE_BUFFER, stats_buffer.get());
+
+        Ok(InstanceCulling {
+            pipeline,
+            buffers: None,
+            stats_buffer,
+            frame_count,
+            instance_count: 0,
+        })
+    }
+
+    // Called with every new top level structure, returns the buffers of the previous one
+    pub fn set_instances(
+        &mut self,
+        context: &VulkanContext,
+        top_level_as: &AccelerationStructure,
+        geometry_instances: &[GeometryInstance],
+    ) -> Result<Option<CullingBuffers>, VulkanError> {
+        let instances_buffer = top_level_as.get_instances_buffer().ok_or_else(|| {
+            VulkanError::PipelineError(String::from("Only top level structures can be culled"))
+        })?;
+
+        let bounds: Vec<[f32; 4]> = geometry_instances
+            .iter()
+            .map(|geometry_instance| {
+                let sphere = geometry_instance.bounding_sphere;
+                [sphere.x, sphere.y, sphere.z, sphere.w]
+            })
+            .collect();
+        let bounds_buffer = DataBufferBuilder::new(context)
+            .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Host)
+            .with_data(&bounds)
+            .build()?;
+
+        let culled_buffer = DataBufferBuilder::new(context)
+            .with_usage(vk::BufferUsageFlags::RAY_TRACING_NV | vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_size(instances_buffer.size())
+            .build()?;
+
+        self.pipeline.update_buffer(
+            0,
+            vk::DescriptorType::STORAGE_BUFFER,
+            instances_buffer.get(),
+        );
+        self.pipeline
+            .update_buffer(1, vk::DescriptorType::STORAGE_BUFFER, bounds_buffer.get());
+        self.pipeline
+            .update_buffer(2, vk::DescriptorType::STORAGE_BUFFER, culled_buffer.get());
+
+        self.instance_count = top_level_as.instance_count();
+        Ok(self.buffers.replace(CullingBuffers {
+            _bounds_buffer: bounds_buffer,
+            culled_buffer,
+        }))
+    }
+
+    // Has to be recorded outside of a render pass, before the frame is traced
+    pub fn cmd_cull(
+        &self,
+        context: &VulkanContext,
+        command_buffer: vk::CommandBuffer,
+        top_level_as: &AccelerationStructure,
+        camera_count: u32,
+        frame: u32,
+    ) {
+        let buffers = match self.buffers.as_ref() {
+            Some(buffers) => buffers,
+            None => return,
+        };
+
+        // The previous frames may still trace the structure that is about to be rebuilt
+        let device = context.get_device();
+        device.cmd_pipeline_barrier2(
+            command_buffer,
+            &DependencyInfo {
+                memory_barriers: &[MemoryBarrier2 {
+                    src_stage_mask: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV
+                        | vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV,
+                    src_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV
+                        | vk::AccessFlags::SHADER_READ,
+                    dst_stage_mask: vk::PipelineStageFlags::COMPUTE_SHADER,
+                    dst_access_mask: vk::AccessFlags::SHADER_WRITE,
+                }],
+                ..Default::default()
+            },
+        );
+
+        let push_constants = CullingPushConstants {
+            instance_count: self.instance_count,
+            camera_count,
+            stats_index: frame % self.frame_count,
+        };
+        let group_count = self.instance_count.div_ceil(WORKGROUP_SIZE);
+        self.pipeline.cmd_dispatch(
+            command_buffer,
+            bytemuck::bytes_of(&push_constants),
+            [group_count, 1, 1],
+        );
+
+        // The culled instances are read by the build, the stats by the host once the frame is done
+        device.cmd_pipeline_barrier2(
+            command_buffer,
+            &DependencyInfo {
+                memory_barriers: &[
+                    MemoryBarrier2 {
+                        src_stage_mask: vk::PipelineStageFlags::COMPUTE_SHADER,
+                        src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        dst_stage_mask: vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV,
+                        dst_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV
+                            | vk::AccessFlags::SHADER_READ,
+                    },
+                    MemoryBarrier2 {
+                        src_stage_mask: vk::PipelineStageFlags::COMPUTE_SHADER,
+                        src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        dst_stage_mask: vk::PipelineStageFlags::HOST,
+                        dst_access_mask: vk::AccessFlags::HOST_READ,
+                    },
+                ],
+                ..Default::default()
+            },
+        );
+
+        top_level_as.cmd_rebuild_top_level(command_buffer, buffers.culled_buffer.get());
+
+        device.cmd_pipeline_barrier2(
+            command_buffer,
+            &DependencyInfo {
+                memory_barriers: &[MemoryBarrier2 {
+                    src_stage_mask: vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV,
+                    src_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_NV,
+                    dst_stage_mask: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+                    dst_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV,
+                }],
+                ..Default::default()
+            },
+        );
+    }
+
+    // Reads the count of the frame slot and clears it for the next use of the slot, the frame
+    // that used it last has to be done
+    pub fn take_stats(&self, frame: u32) -> Result<CullingStats, VulkanError> {
+        let offset = ((frame % self.frame_count) as usize * mem::size_of::<u32>()) as u64;
+        let mut visible = [0u8; 4];
+        self.stats_buffer.read_data_at(offset, &mut visible)?;
+        self.stats_buffer.copy_data_at(offset, &[0u8; 4])?;
+
+        Ok(CullingStats {
+            visible_instances: u32::from_ne_bytes(visible),
+            total_instances: self.instance_count,
+        })
+    }
+}
@@ -0,0 +1,55 @@
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+
+// The instance custom index of the NV extension only has 24 bits
+pub const MAX_INSTANCE_CUSTOM_INDEX: u32 = (1 << 24) - 1;
+
+pub const INSTANCE_FLAG_HIGHLIGHTED: u32 = 1;
+// The surface shows the background with the shadows of the other instances on it, for grounds
+// under models that should not look like they float
+pub const INSTANCE_FLAG_SHADOW_CATCHER: u32 = 2;
+
+// Per instance data read by the hit shader. The custom index of a hit selects the geometry
+// instance, whose user data index selects the entry of this type.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceUserData {
+    pub object_id: u32,
+    pub flags: u32,
+    // Left to the application shaders
+    pub custom: [u32; 2],
+    // Multiplies the albedo
+    pub tint: glm::Vec4,
+}
+
+unsafe impl Zeroable for InstanceUserData {}
+unsafe impl Pod for InstanceUserData {}
+
+impl Default for InstanceUserData {
+    fn default() -> Self {
+        InstanceUserData {
+            object_id: 0,
+            flags: 0,
+            custom: [0; 2],
+            tint: glm::vec4(1.0, 1.0, 1.0, 1.0),
+        }
+    }
+}
+
+// Texel of the object ID buffer. The instance is the custom index of the hit plus one, 0 is
+// written where the camera ray missed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ObjectId {
+    pub instance: u32,
+    pub primitive: u32,
+}
+
+unsafe impl Zeroable for ObjectId {}
+unsafe impl Pod for ObjectId {}
+
+impl ObjectId {
+    pub fn custom_index(&self) -> Option<u32> {
+        self.instance.checked_sub(1)
+    }
+}
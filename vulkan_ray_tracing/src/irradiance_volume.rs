@@ -0,0 +1,106 @@
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+
+// L1 spherical harmonics, constant and linear bands
+pub const SH_COEFFICIENT_COUNT: usize = 4;
+// Directions traced by each thread of the bake, the sample count is rounded up to a multiple
+pub const BAKE_SAMPLES_PER_THREAD: u32 = 16;
+
+// Regular grid of light probes, the probe (x, y, z) sits at origin + spacing * (x, y, z)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeGrid {
+    pub origin: glm::Vec3,
+    pub spacing: glm::Vec3,
+    pub counts: [u32; 3],
+}
+
+impl ProbeGrid {
+    // Probes on the corners of the box and evenly across it, a single probe sits at the center
+    pub fn from_bounds(min: glm::Vec3, max: glm::Vec3, counts: [u32; 3]) -> Self {
+        let mut origin = min;
+        let mut spacing = glm::vec3(1.0, 1.0, 1.0);
+        let mut grid_counts = [1; 3];
+        for axis in 0..3 {
+            grid_counts[axis] = counts[axis].max(1);
+            if grid_counts[axis] == 1 {
+                origin[axis] = (min[axis] + max[axis]) * 0.5;
+            } else {
+                spacing[axis] =
+                    ((max[axis] - min[axis]) / (grid_counts[axis] - 1) as f32).max(1e-4);
+            }
+        }
+
+        ProbeGrid {
+            origin,
+            spacing,
+            counts: grid_counts,
+        }
+    }
+
+    pub fn probe_count(&self) -> usize {
+        self.counts.iter().map(|&count| count as usize).product()
+    }
+}
+
+// Diffuse lighting baked at the probes of a grid, sampled by the hit shader when path tracing
+// is off. Every probe has SH_COEFFICIENT_COUNT coefficients of the incoming radiance, in RGB with
+// an unused fourth component, in the order of the probes x first, then y, then z.
+#[derive(Clone, Debug)]
+pub struct IrradianceVolume {
+    pub grid: ProbeGrid,
+    pub coefficients: Vec<[f32; 4]>,
+}
+
+impl IrradianceVolume {
+    // Also what the buffer holds when no volume is set, the shaders then skip it
+    pub(crate) fn gpu_data(volume: Option<&IrradianceVolume>) -> Vec<u8> {
+        let header = match volume {
+            Some(volume) => IrradianceVolumeHeader {
+                origin: volume.grid.origin.into(),
+                enabled: 1,
+                spacing: volume.grid.spacing.into(),
+                padding: 0,
+                counts: volume.grid.counts,
+                padding2: 0,
+            },
+            None => IrradianceVolumeHeader::zeroed(),
+        };
+
+        let mut data = bytemuck::bytes_of(&header).to_vec();
+        match volume {
+            Some(volume) => data.extend_from_slice(bytemuck::cast_slice(&volume.coefficients)),
+            // Runtime sized arrays need at least one element
+            None => data.extend_from_slice(bytemuck::cast_slice(&[[0.0f32; 4]])),
+        }
+        data
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IrradianceVolumeHeader {
+    origin: [f32; 3],
+    enabled: u32,
+    spacing: [f32; 3],
+    padding: u32,
+    counts: [u32; 3],
+    padding2: u32,
+}
+
+unsafe impl Zeroable for IrradianceVolumeHeader {}
+unsafe impl Pod for IrradianceVolumeHeader {}
+
+// Read by the bake ray generation shader, a launch covers the probes from first_probe on
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct BakePushConstants {
+    pub origin: [f32; 3],
+    pub first_probe: u32,
+    pub spacing: [f32; 3],
+    pub sample_count: u32,
+    pub counts: [u32; 3],
+    pub seed: u32,
+}
+
+unsafe impl Zeroable for BakePushConstants {}
+unsafe impl Pod for BakePushConstants {}
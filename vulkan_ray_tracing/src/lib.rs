@@ -1,11 +1,53 @@
+pub use ash;
+pub use bytemuck;
 pub use nalgebra_glm as glm;
+// The context, errors and extensions the API of the renderer is built on, so the users depend on
+// a single version of it
+pub use vulkan_bootstrap;
 
+// The modules hidden from the documentation are the plumbing of the pipeline. They stay public
+// for the types of its API, but are not meant to be used on their own and can change freely.
+pub mod background;
+pub mod barrier_commands;
+#[doc(hidden)]
+pub mod blue_noise;
+pub mod buffer;
+pub mod compute_pipeline;
+#[doc(hidden)]
+pub mod deletion_queue;
+#[doc(hidden)]
+pub mod descriptor_allocator;
+pub mod descriptor_commands;
+pub mod draw_commands;
+pub mod frame_constants;
+pub mod frame_context;
 pub mod geometry_instance;
+pub mod instance_culling;
+pub mod instance_data;
+pub mod irradiance_volume;
+pub mod light;
+#[doc(hidden)]
+pub mod query_pool;
 pub mod ray_tracing_pipeline;
+pub mod render_settings;
+pub mod renderer_stats;
+pub mod shader_permutation;
+pub mod sky;
+pub mod storage_image;
+#[doc(hidden)]
+pub mod text_overlay;
+pub mod texture;
+#[doc(hidden)]
+pub mod transient_commands;
+pub mod viewport;
 
 mod acceleration_structure;
 mod bottom_level_acceleration_structure;
+mod checkerboard;
 mod descriptor_set;
+mod device_limits;
+mod image;
 mod pipeline;
 mod ray_tracing;
 mod shader_binding_table;
+mod texture_packing;
@@ -0,0 +1,211 @@
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightType {
+    Point,
+    // The position is the direction towards the light
+    Directional,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Light {
+    pub light_type: LightType,
+    pub position: glm::Vec3,
+    pub color: glm::Vec3,
+    pub intensity: f32,
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Light {
+            light_type: LightType::Point,
+            position: glm::vec3(0.0, 0.0, 0.0),
+            color: glm::vec3(1.0, 1.0, 1.0),
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Light {
+    // Luminance weighted intensity, point and directional lights are compared as is
+    pub fn power(&self) -> f32 {
+        glm::dot(&self.color, &glm::vec3(0.2126, 0.7152, 0.0722)) * self.intensity
+    }
+}
+
+// How the shaders pick the one light they sample per hit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightSamplingStrategy {
+    Uniform,
+    Power,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct LightsHeader {
+    pub light_count: u32,
+    pub padding: [u32; 3],
+}
+
+unsafe impl Zeroable for LightsHeader {}
+unsafe impl Pod for LightsHeader {}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct LightData {
+    position: glm::Vec3,
+    light_type: u32,
+    color: glm::Vec3,
+    intensity: f32,
+}
+
+unsafe impl Zeroable for LightData {}
+unsafe impl Pod for LightData {}
+
+impl From<&Light> for LightData {
+    fn from(light: &Light) -> Self {
+        LightData {
+            position: light.position,
+            light_type: match light.light_type {
+                LightType::Point => 0,
+                LightType::Directional => 1,
+            },
+            color: light.color,
+            intensity: light.intensity,
+        }
+    }
+}
+
+// An entry keeps itself with the given probability, otherwise the alias is picked.
+// The pdf is the probability of picking the entry through the whole table.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct AliasEntry {
+    pub probability: f32,
+    pub alias: u32,
+    pub pdf: f32,
+    pub padding: u32,
+}
+
+unsafe impl Zeroable for AliasEntry {}
+unsafe impl Pod for AliasEntry {}
+
+pub(crate) fn build_alias_table(
+    lights: &[Light],
+    strategy: LightSamplingStrategy,
+) -> Vec<AliasEntry> {
+    let mut weights: Vec<f32> = lights
+        .iter()
+        .map(|light| match strategy {
+            LightSamplingStrategy::Uniform => 1.0,
+            LightSamplingStrategy::Power => light.power().max(0.0),
+        })
+        .collect();
+    let mut total: f32 = weights.iter().sum();
+    if total <= 0.0 {
+        weights = vec![1.0; lights.len()];
+        total = lights.len() as f32;
+    }
+
+    let count = weights.len();
+    let mut table: Vec<AliasEntry> = weights
+        .iter()
+        .enumerate()
+        .map(|(index, weight)| AliasEntry {
+            probability: 1.0,
+            alias: index as u32,
+            pdf: weight / total,
+            padding: 0,
+        })
+        .collect();
+
+    // Vose's method
+    let mut scaled: Vec<f32> = weights
+        .iter()
+        .map(|weight| weight * count as f32 / total)
+        .collect();
+    let mut small: Vec<usize> = (0..count).filter(|&index| scaled[index] < 1.0).collect();
+    let mut large: Vec<usize> = (0..count).filter(|&index| scaled[index] >= 1.0).collect();
+    while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+        small.pop();
+        table[less].probability = scaled[less];
+        table[less].alias = more as u32;
+
+        scaled[more] -= 1.0 - scaled[less];
+        if scaled[more] < 1.0 {
+            large.pop();
+            small.push(more);
+        }
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light(intensity: f32) -> Light {
+        Light {
+            intensity,
+            ..Light::default()
+        }
+    }
+
+    // The probability of sampling each entry: pick a slot uniformly, then keep it or take its alias
+    fn sampled_probabilities(table: &[AliasEntry]) -> Vec<f32> {
+        let count = table.len() as f32;
+        let mut probabilities = vec![0.0; table.len()];
+        for (index, entry) in table.iter().enumerate() {
+            probabilities[index] += entry.probability / count;
+            probabilities[entry.alias as usize] += (1.0 - entry.probability) / count;
+        }
+        probabilities
+    }
+
+    #[test]
+    fn power_pdfs_sum_to_one() {
+        let lights = [light(1.0), light(3.0), light(0.5), light(10.0)];
+        let table = build_alias_table(&lights, LightSamplingStrategy::Power);
+
+        let sum: f32 = table.iter().map(|entry| entry.pdf).sum();
+        assert!((sum - 1.0).abs() < 1e-5, "{}", sum);
+    }
+
+    #[test]
+    fn table_samples_with_weight_over_total() {
+        let lights = [light(1.0), light(3.0), light(0.5), light(10.0), light(2.0)];
+        let table = build_alias_table(&lights, LightSamplingStrategy::Power);
+
+        let total: f32 = lights.iter().map(Light::power).sum();
+        let probabilities = sampled_probabilities(&table);
+        for ((light, entry), probability) in lights.iter().zip(&table).zip(probabilities) {
+            let expected = light.power() / total;
+            assert!((entry.pdf - expected).abs() < 1e-5);
+            assert!((probability - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn uniform_strategy_ignores_power() {
+        let lights = [light(1.0), light(30.0), light(0.1)];
+        let table = build_alias_table(&lights, LightSamplingStrategy::Uniform);
+
+        for probability in sampled_probabilities(&table) {
+            assert!((probability - 1.0 / 3.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn zero_power_falls_back_to_uniform() {
+        let lights = [light(0.0), light(0.0), light(0.0), light(0.0)];
+        let table = build_alias_table(&lights, LightSamplingStrategy::Power);
+
+        let probabilities = sampled_probabilities(&table);
+        for (entry, probability) in table.iter().zip(probabilities) {
+            assert!((entry.pdf - 0.25).abs() < 1e-5);
+            assert!((probability - 0.25).abs() < 1e-5);
+        }
+    }
+}
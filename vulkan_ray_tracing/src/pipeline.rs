@@ -1,5 +1,7 @@
 use std::ffi::CStr;
 use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
 
 use ash::vk;
 use vulkan_bootstrap::device::VulkanDevice;
@@ -10,6 +12,9 @@ use vulkan_bootstrap::vulkan_context::VulkanContext;
 use crate::descriptor_set::DescriptorSet;
 use crate::ray_tracing::RayTracing;
 
+// Largest hitAttributeNV variable of VK_NV_ray_tracing, the device does not report it
+pub const MAX_HIT_ATTRIBUTE_SIZE: u32 = 32;
+
 pub struct Pipeline {
     device: Rc<VulkanDevice>,
     pipeline_layout: vk::PipelineLayout,
@@ -19,6 +24,8 @@ pub struct Pipeline {
     pub shadow_miss_index: u32,
     pub hit_group_index: u32,
     pub shadow_hit_group_index: u32,
+    // In the order the callable shaders were added, after the hit groups
+    pub callable_indices: Vec<u32>,
 }
 
 impl Pipeline {
@@ -38,6 +45,90 @@ impl Drop for Pipeline {
     }
 }
 
+// Only holds Vulkan handles and pointers to the static entry point name, and the shader modules
+// they refer to are kept alive by the PendingPipeline until the worker is done
+struct PipelineCreateInfo {
+    stages: Vec<vk::PipelineShaderStageCreateInfo>,
+    groups: Vec<vk::RayTracingShaderGroupCreateInfoNV>,
+    max_recursion_depth: u32,
+    pipeline_layout: vk::PipelineLayout,
+}
+
+unsafe impl Send for PipelineCreateInfo {}
+
+// A pipeline being compiled on a worker thread
+pub struct PendingPipeline {
+    device: Rc<VulkanDevice>,
+    pipeline_layout: vk::PipelineLayout,
+    indices: [u32; 5],
+    callable_indices: Vec<u32>,
+    _shader_modules: Vec<ShaderModule>,
+    worker: Option<JoinHandle<()>>,
+    receiver: Receiver<Result<vk::Pipeline, VulkanError>>,
+}
+
+impl PendingPipeline {
+    // Returns the pipeline once the worker is done, without blocking
+    pub fn try_take(&mut self) -> Result<Option<Pipeline>, VulkanError> {
+        match self.receiver.try_recv() {
+            Ok(result) => self.finish(result).map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(VulkanError::PipelineError(String::from(
+                "The pipeline worker stopped",
+            ))),
+        }
+    }
+
+    pub fn wait(mut self) -> Result<Pipeline, VulkanError> {
+        let result = self
+            .receiver
+            .recv()
+            .map_err(|_| VulkanError::PipelineError(String::from("The pipeline worker stopped")))?;
+        self.finish(result)
+    }
+
+    fn finish(
+        &mut self,
+        result: Result<vk::Pipeline, VulkanError>,
+    ) -> Result<Pipeline, VulkanError> {
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        let pipeline = result?;
+
+        // The layout now belongs to the pipeline
+        let pipeline_layout =
+            std::mem::replace(&mut self.pipeline_layout, vk::PipelineLayout::null());
+        Ok(Pipeline {
+            device: Rc::clone(&self.device),
+            pipeline_layout,
+            pipeline,
+            ray_gen_index: self.indices[0],
+            miss_index: self.indices[1],
+            shadow_miss_index: self.indices[2],
+            hit_group_index: self.indices[3],
+            shadow_hit_group_index: self.indices[4],
+            callable_indices: std::mem::take(&mut self.callable_indices),
+        })
+    }
+}
+
+// A pipeline that is dropped before being taken is waited for, the shader modules and layout
+// cannot be released while the driver uses them
+impl Drop for PendingPipeline {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+            if let Ok(Ok(pipeline)) = self.receiver.try_recv() {
+                self.device.destroy_pipeline(pipeline);
+            }
+        }
+        if self.pipeline_layout != vk::PipelineLayout::null() {
+            self.device.destroy_pipeline_layout(self.pipeline_layout);
+        }
+    }
+}
+
 pub struct PipelineBuilder<'a> {
     context: &'a VulkanContext,
     ray_tracing: &'a RayTracing,
@@ -46,7 +137,11 @@ pub struct PipelineBuilder<'a> {
     miss_shader: Option<ShaderModule>,
     shadow_miss_shader: Option<ShaderModule>,
     hit_shader: Option<ShaderModule>,
+    callable_shaders: Vec<ShaderModule>,
     max_recursion_depth: u32,
+    max_hit_attribute_size: u32,
+    extra_set_layouts: &'a [vk::DescriptorSetLayout],
+    push_constant_ranges: &'a [vk::PushConstantRange],
 }
 
 impl<'a> PipelineBuilder<'a> {
@@ -63,7 +158,11 @@ impl<'a> PipelineBuilder<'a> {
             miss_shader: None,
             shadow_miss_shader: None,
             hit_shader: None,
+            callable_shaders: vec![],
             max_recursion_depth: 0,
+            max_hit_attribute_size: 0,
+            extra_set_layouts: &[],
+            push_constant_ranges: &[],
         }
     }
 
@@ -87,12 +186,41 @@ impl<'a> PipelineBuilder<'a> {
         self
     }
 
+    // Can be called several times, the shaders are called with the index they were added at
+    pub fn with_callable_shader(mut self, callable_shader: ShaderModule) -> Self {
+        self.callable_shaders.push(callable_shader);
+        self
+    }
+
     pub fn with_max_recursion_depth(mut self, max_recursion_depth: u32) -> Self {
         self.max_recursion_depth = max_recursion_depth;
         self
     }
 
-    pub fn build(self) -> Result<Pipeline, VulkanError> {
+    // Largest hit attribute of the shaders in bytes, at most MAX_HIT_ATTRIBUTE_SIZE
+    pub fn with_max_hit_attribute_size(mut self, max_hit_attribute_size: u32) -> Self {
+        self.max_hit_attribute_size = max_hit_attribute_size;
+        self
+    }
+
+    // Bound after the ray tracing descriptor set, starting at set 1
+    pub fn with_extra_set_layouts(mut self, set_layouts: &'a [vk::DescriptorSetLayout]) -> Self {
+        self.extra_set_layouts = set_layouts;
+        self
+    }
+
+    pub fn with_push_constant_ranges(
+        mut self,
+        push_constant_ranges: &'a [vk::PushConstantRange],
+    ) -> Self {
+        self.push_constant_ranges = push_constant_ranges;
+        self
+    }
+
+    // The shader compilation by the driver is what takes long, the rest is done right away
+    pub fn build_async(mut self) -> Result<PendingPipeline, VulkanError> {
+        self.validate_limits()?;
+
         let mut shader_stages = vec![];
         let mut shader_groups = vec![];
 
@@ -131,8 +259,25 @@ impl<'a> PipelineBuilder<'a> {
             &mut shader_groups,
         );
 
+        let callable_indices = self
+            .callable_shaders
+            .iter()
+            .map(|callable_shader| {
+                self.add_shader_stage(
+                    Some(callable_shader),
+                    vk::ShaderStageFlags::CALLABLE_NV,
+                    &mut shader_stages,
+                    &mut shader_groups,
+                )
+            })
+            .collect();
+
+        let mut set_layouts = vec![self.descriptor_set.get_layout()];
+        set_layouts.extend_from_slice(self.extra_set_layouts);
+
         let pipeline_layout_info = vk::PipelineLayoutCreateInfo::builder()
-            .set_layouts(&[self.descriptor_set.get_layout()])
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(self.push_constant_ranges)
             .build();
 
         let pipeline_layout = self
@@ -140,29 +285,81 @@ impl<'a> PipelineBuilder<'a> {
             .get_device()
             .create_pipeline_layout(&pipeline_layout_info)?;
 
-        let pipeline_info = vk::RayTracingPipelineCreateInfoNV::builder()
-            .stages(&shader_stages)
-            .groups(&shader_groups)
-            .max_recursion_depth(self.max_recursion_depth)
-            .layout(pipeline_layout)
-            .build();
+        let create_info = PipelineCreateInfo {
+            stages: shader_stages,
+            groups: shader_groups,
+            max_recursion_depth: self.max_recursion_depth,
+            pipeline_layout,
+        };
+        let loader = self.ray_tracing.get_loader();
+        let (sender, receiver) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            let create_info = create_info;
+            let pipeline_info = vk::RayTracingPipelineCreateInfoNV::builder()
+                .stages(&create_info.stages)
+                .groups(&create_info.groups)
+                .max_recursion_depth(create_info.max_recursion_depth)
+                .layout(create_info.pipeline_layout)
+                .build();
+
+            let result = unsafe {
+                loader.create_ray_tracing_pipelines(
+                    vk::PipelineCache::null(),
+                    &[pipeline_info],
+                    None,
+                )
+            }
+            .map(|pipelines| pipelines[0])
+            .map_err(|err| VulkanError::PipelineError(err.to_string()));
+            let _ = sender.send(result);
+        });
 
-        let pipeline = self
-            .ray_tracing
-            .create_ray_tracing_pipelines(&[pipeline_info])?[0];
+        let mut shader_modules: Vec<ShaderModule> = vec![
+            self.ray_gen_shader.take(),
+            self.miss_shader.take(),
+            self.shadow_miss_shader.take(),
+            self.hit_shader.take(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        shader_modules.append(&mut self.callable_shaders);
 
-        Ok(Pipeline {
+        Ok(PendingPipeline {
             device: Rc::clone(&self.context.get_device()),
             pipeline_layout,
-            pipeline,
-            ray_gen_index,
-            miss_index,
-            shadow_miss_index,
-            hit_group_index,
-            shadow_hit_group_index,
+            indices: [
+                ray_gen_index,
+                miss_index,
+                shadow_miss_index,
+                hit_group_index,
+                shadow_hit_group_index,
+            ],
+            callable_indices,
+            _shader_modules: shader_modules,
+            worker: Some(worker),
+            receiver,
         })
     }
 
+    // Drivers do not always reject these, the shaders would then read garbage
+    fn validate_limits(&self) -> Result<(), VulkanError> {
+        let max_recursion_depth = self.ray_tracing.get_properties().max_recursion_depth;
+        if self.max_recursion_depth > max_recursion_depth {
+            return Err(VulkanError::PipelineError(format!(
+                "A recursion depth of {} is requested, the device supports {}",
+                self.max_recursion_depth, max_recursion_depth
+            )));
+        }
+        if self.max_hit_attribute_size > MAX_HIT_ATTRIBUTE_SIZE {
+            return Err(VulkanError::PipelineError(format!(
+                "Hit attributes of {} bytes are requested, at most {} are supported",
+                self.max_hit_attribute_size, MAX_HIT_ATTRIBUTE_SIZE
+            )));
+        }
+        Ok(())
+    }
+
     fn add_shader_stage(
         &self,
         shader: Option<&ShaderModule>,
@@ -170,7 +367,9 @@ impl<'a> PipelineBuilder<'a> {
         shader_stages: &mut Vec<vk::PipelineShaderStageCreateInfo>,
         shader_groups: &mut Vec<vk::RayTracingShaderGroupCreateInfoNV>,
     ) -> u32 {
+        // The empty shadow hit group has no stage, the groups after it are one index behind
         let index = shader_stages.len() as u32;
+        let group_index = shader_groups.len() as u32;
 
         let mut group_info = vk::RayTracingShaderGroupCreateInfoNV::builder()
             .ty(vk::RayTracingShaderGroupTypeNV::TRIANGLES_HIT_GROUP)
@@ -208,6 +407,6 @@ impl<'a> PipelineBuilder<'a> {
         let group_info = group_info.build();
         shader_groups.push(group_info);
 
-        index
+        group_index
     }
 }
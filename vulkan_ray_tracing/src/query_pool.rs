@@ -0,0 +1,261 @@
+use std::rc::Rc;
+
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QueryType {
+    Occlusion,
+    PipelineStatistics(vk::QueryPipelineStatisticFlags),
+    Timestamp,
+}
+
+impl QueryType {
+    fn get(self) -> vk::QueryType {
+        match self {
+            QueryType::Occlusion => vk::QueryType::OCCLUSION,
+            QueryType::PipelineStatistics(_) => vk::QueryType::PIPELINE_STATISTICS,
+            QueryType::Timestamp => vk::QueryType::TIMESTAMP,
+        }
+    }
+
+    // Number of u64 values written per query
+    fn value_count(self) -> usize {
+        match self {
+            QueryType::PipelineStatistics(flags) => flags.as_raw().count_ones() as usize,
+            _ => 1,
+        }
+    }
+}
+
+// One range of queries per frame in flight, so results of a previous frame can be read
+// while the current one is recorded
+pub struct QueryPool {
+    device: Rc<VulkanDevice>,
+    query_pool: vk::QueryPool,
+    ty: QueryType,
+    queries_per_frame: u32,
+    frame_count: u32,
+    timestamp_period: f32,
+}
+
+impl Drop for QueryPool {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.get().destroy_query_pool(self.query_pool, None);
+        }
+    }
+}
+
+impl QueryPool {
+    pub fn get(&self) -> vk::QueryPool {
+        self.query_pool
+    }
+
+    pub fn get_type(&self) -> QueryType {
+        self.ty
+    }
+
+    pub fn queries_per_frame(&self) -> u32 {
+        self.queries_per_frame
+    }
+
+    // Nanoseconds per timestamp tick
+    pub fn timestamp_period(&self) -> f32 {
+        self.timestamp_period
+    }
+
+    // Has to be recorded outside of a render pass, before the frame's first query
+    pub fn reset(&self, command_buffer: vk::CommandBuffer, frame: u32) {
+        unsafe {
+            self.device.get().cmd_reset_query_pool(
+                command_buffer,
+                self.query_pool,
+                self.first_query(frame),
+                self.queries_per_frame,
+            );
+        }
+    }
+
+    pub fn begin(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame: u32,
+        query: u32,
+    ) -> Result<(), VulkanError> {
+        let index = self.query_index(frame, query)?;
+        let flags = match self.ty {
+            QueryType::Occlusion => vk::QueryControlFlags::PRECISE,
+            _ => vk::QueryControlFlags::empty(),
+        };
+        unsafe {
+            self.device
+                .get()
+                .cmd_begin_query(command_buffer, self.query_pool, index, flags);
+        }
+        Ok(())
+    }
+
+    pub fn end(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame: u32,
+        query: u32,
+    ) -> Result<(), VulkanError> {
+        let index = self.query_index(frame, query)?;
+        unsafe {
+            self.device
+                .get()
+                .cmd_end_query(command_buffer, self.query_pool, index);
+        }
+        Ok(())
+    }
+
+    pub fn write_timestamp(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        stage: vk::PipelineStageFlags,
+        frame: u32,
+        query: u32,
+    ) -> Result<(), VulkanError> {
+        if self.ty != QueryType::Timestamp {
+            return Err(VulkanError::PipelineError(String::from(
+                "Timestamps can only be written to a timestamp query pool",
+            )));
+        }
+        let index = self.query_index(frame, query)?;
+        unsafe {
+            self.device
+                .get()
+                .cmd_write_timestamp(command_buffer, stage, self.query_pool, index);
+        }
+        Ok(())
+    }
+
+    // Returns None while the frame is still in flight, otherwise one Vec of values per query
+    pub fn get_results(&self, frame: u32) -> Result<Option<Vec<Vec<u64>>>, VulkanError> {
+        let value_count = self.ty.value_count();
+        let mut data = vec![0u64; self.queries_per_frame as usize * value_count];
+        let stride = (value_count * std::mem::size_of::<u64>()) as vk::DeviceSize;
+
+        // ash only supports one value per query, pipeline statistics write several
+        let result = unsafe {
+            self.device.get().fp_v1_0().get_query_pool_results(
+                self.device.get().handle(),
+                self.query_pool,
+                self.first_query(frame),
+                self.queries_per_frame,
+                data.len() * std::mem::size_of::<u64>(),
+                data.as_mut_ptr() as *mut _,
+                stride,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+
+        match result {
+            vk::Result::SUCCESS => Ok(Some(
+                data.chunks(value_count)
+                    .map(|values| values.to_vec())
+                    .collect(),
+            )),
+            vk::Result::NOT_READY => Ok(None),
+            err => Err(VulkanError::PipelineError(err.to_string())),
+        }
+    }
+
+    fn first_query(&self, frame: u32) -> u32 {
+        (frame % self.frame_count) * self.queries_per_frame
+    }
+
+    fn query_index(&self, frame: u32, query: u32) -> Result<u32, VulkanError> {
+        if query >= self.queries_per_frame {
+            return Err(VulkanError::PipelineError(format!(
+                "Query {} is out of a pool of {} queries per frame",
+                query, self.queries_per_frame
+            )));
+        }
+        Ok(self.first_query(frame) + query)
+    }
+}
+
+pub struct QueryPoolBuilder<'a> {
+    context: &'a VulkanContext,
+    ty: QueryType,
+    queries_per_frame: u32,
+    frame_count: u32,
+}
+
+impl<'a> QueryPoolBuilder<'a> {
+    pub fn new(context: &'a VulkanContext) -> Self {
+        QueryPoolBuilder {
+            context,
+            ty: QueryType::Timestamp,
+            queries_per_frame: 1,
+            frame_count: 2,
+        }
+    }
+
+    pub fn with_type(mut self, ty: QueryType) -> Self {
+        self.ty = ty;
+        self
+    }
+
+    pub fn with_queries_per_frame(mut self, queries_per_frame: u32) -> Self {
+        self.queries_per_frame = queries_per_frame;
+        self
+    }
+
+    pub fn with_frame_count(mut self, frame_count: u32) -> Self {
+        self.frame_count = frame_count;
+        self
+    }
+
+    pub fn build(self) -> Result<QueryPool, VulkanError> {
+        if self.queries_per_frame == 0 || self.frame_count == 0 {
+            return Err(VulkanError::PipelineError(String::from(
+                "A query pool needs at least one query and one frame",
+            )));
+        }
+
+        let properties = unsafe {
+            self.context
+                .get_instance()
+                .get()
+                .get_physical_device_properties(self.context.get_physical_device().get())
+        };
+
+        if self.ty == QueryType::Timestamp && properties.limits.timestamp_compute_and_graphics == 0
+        {
+            return Err(VulkanError::PipelineError(String::from(
+                "Timestamp queries are not supported by this device",
+            )));
+        }
+
+        let statistics = match self.ty {
+            QueryType::PipelineStatistics(flags) => flags,
+            _ => vk::QueryPipelineStatisticFlags::empty(),
+        };
+
+        let query_pool_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(self.ty.get())
+            .query_count(self.queries_per_frame * self.frame_count)
+            .pipeline_statistics(statistics)
+            .build();
+
+        let device = Rc::clone(self.context.get_device());
+        let query_pool = unsafe { device.get().create_query_pool(&query_pool_info, None) }
+            .map_err(|err| VulkanError::PipelineError(err.to_string()))?;
+
+        Ok(QueryPool {
+            device,
+            query_pool,
+            ty: self.ty,
+            queries_per_frame: self.queries_per_frame,
+            frame_count: self.frame_count,
+            timestamp_period: properties.limits.timestamp_period,
+        })
+    }
+}
@@ -83,15 +83,9 @@ impl RayTracing {
         }
     }
 
-    pub fn create_ray_tracing_pipelines(
-        &self,
-        info: &[vk::RayTracingPipelineCreateInfoNV],
-    ) -> Result<Vec<vk::Pipeline>, VulkanError> {
-        unsafe {
-            self.ray_tracing
-                .create_ray_tracing_pipelines(vk::PipelineCache::null(), info, None)
-        }
-        .map_err(|err| VulkanError::PipelineError(err.to_string()))
+    // The extension functions can be called from other threads, unlike the context
+    pub fn get_loader(&self) -> ash::extensions::nv::RayTracing {
+        self.ray_tracing.clone()
     }
 
     pub fn get_ray_tracing_shader_group_handles(
@@ -123,6 +117,9 @@ impl RayTracing {
         hit_group_sbt: vk::Buffer,
         hit_group_offset: vk::DeviceSize,
         hit_group_stride: vk::DeviceSize,
+        callable_sbt: vk::Buffer,
+        callable_offset: vk::DeviceSize,
+        callable_stride: vk::DeviceSize,
         width: u32,
         height: u32,
         depth: u32,
@@ -138,9 +135,9 @@ impl RayTracing {
                 hit_group_sbt,
                 hit_group_offset,
                 hit_group_stride,
-                vk::Buffer::null(),
-                0,
-                0,
+                callable_sbt,
+                callable_offset,
+                callable_stride,
                 width,
                 height,
                 depth,
@@ -1,9 +1,11 @@
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
+use ash::version::DeviceV1_0;
 use ash::vk;
-use vulkan_bootstrap::buffer::{Buffer, BufferBuilder, BufferType};
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
 use vulkan_bootstrap::errors::VulkanError;
 use vulkan_bootstrap::shader_module::ShaderModuleBuilder;
 use vulkan_bootstrap::vulkan_context::VulkanContext;
@@ -11,320 +13,2206 @@ use vulkan_bootstrap::vulkan_context::VulkanContext;
 use crate::acceleration_structure::{
     AccelerationStructure, AccelerationStructureBuilder, Instance,
 };
-use crate::bottom_level_acceleration_structure::{
-    BottomLevelAccelerationStructure, BottomLevelAccelerationStructureBuilder,
-};
+use crate::background::{Background, BackgroundUniform, EnvironmentMap};
+use crate::barrier_commands::{BarrierCommands, DependencyInfo, MemoryBarrier2};
+use crate::blue_noise::generate_blue_noise;
+use crate::bottom_level_acceleration_structure::BottomLevelAccelerationStructureBuilder;
+use crate::buffer::{DataBuffer, DataBufferBuilder, MemoryLocation};
+use crate::checkerboard::{is_camera_cut, Checkerboard};
+use crate::deletion_queue::DeletionQueue;
+use crate::descriptor_allocator::DescriptorAllocator;
+use crate::descriptor_commands::DescriptorCommands;
 use crate::descriptor_set::{DescriptorSet, DescriptorSetBuilder};
+use crate::frame_constants::{FrameConstants, FrameParameters};
+use crate::frame_context::FrameContext;
 use crate::geometry_instance::{GeometryInstance, Vertex};
-use crate::pipeline::{Pipeline, PipelineBuilder};
+use crate::image::{cmd_transition_layout, ImageLayoutTransition};
+use crate::instance_culling::{CullingStats, InstanceCulling};
+use crate::instance_data::{InstanceUserData, ObjectId, MAX_INSTANCE_CUSTOM_INDEX};
+use crate::irradiance_volume::{
+    BakePushConstants, IrradianceVolume, ProbeGrid, BAKE_SAMPLES_PER_THREAD, SH_COEFFICIENT_COUNT,
+};
+use crate::light::{build_alias_table, Light, LightData, LightSamplingStrategy, LightsHeader};
+use crate::pipeline::{PendingPipeline, Pipeline, PipelineBuilder};
+use crate::query_pool::{QueryPool, QueryPoolBuilder, QueryType};
 use crate::ray_tracing::{RayTracing, RayTracingBuilder};
+use crate::render_settings::{
+    Aov, AovSettings, ClipPlane, FogSettings, LatencyMode, MotionBlurSettings, PathTracingSettings,
+    ReflectionSettings, RenderSettings, RenderSettingsUniform, SeedPolicy, WatchdogSettings,
+    MAX_CLIP_PLANES, MAX_MOTION_BLUR_STEPS,
+};
+use crate::renderer_stats::{GeometryMemory, RendererStats};
 use crate::shader_binding_table::{ShaderBindingTable, ShaderBindingTableBuilder};
+use crate::shader_permutation::{ShaderDefines, SHADER_DIRECTORY};
+use crate::storage_image::{StorageImage, StorageImageBuilder};
+use crate::text_overlay::{FontAtlas, TextLabel, TextOverlay};
+use crate::texture::{Texture, TextureBuilder};
+use crate::transient_commands::TransientCommands;
+use crate::viewport::{TraceDispatch, Viewport, ViewportPushConstants, MAX_VIEWPORTS};
 use std::cell::RefCell;
 
+const BLUE_NOISE_SIZE: usize = 64;
+const MAX_TIMED_LAUNCHES: u32 = 256;
+// Threads of one launch of the irradiance bake, each launch is submitted and waited for on its
+// own so that none runs long enough to trip the driver timeout
+const MAX_BAKE_THREADS_PER_LAUNCH: u32 = 1 << 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct InstanceInfo {
+    texture_offset: u32,
+    user_data_index: u32,
+    padding: [u32; 2],
+    // Rows of the object to world transform of the previous frame
+    previous_transform: [[f32; 4]; 3],
+}
+
+unsafe impl Zeroable for InstanceInfo {}
+unsafe impl Pod for InstanceInfo {}
+
+// Everything that depends on the list of geometry instances, the pipeline is still pending while
+// the first compile runs in the background
+struct InstanceResources {
+    top_level_as: AccelerationStructure,
+    instance_buffer: DataBuffer,
+    descriptor_set: DescriptorSet,
+    compiled: Option<(Pipeline, ShaderBindingTable)>,
+    pending_pipeline: Option<PendingPipeline>,
+}
+
 pub struct RayTracingPipeline {
+    // Dropped first, it waits for the device before releasing the resources it holds
+    deletion_queue: DeletionQueue,
     context: Rc<RefCell<VulkanContext>>,
-    sbt: ShaderBindingTable,
-    pipeline: Pipeline,
+    // None until the first pipeline is compiled, the frames are only cleared meanwhile
+    compiled: Option<(Pipeline, ShaderBindingTable)>,
+    pending_pipeline: Option<PendingPipeline>,
+    // Compiles started for descriptor sets that were replaced, dropped once done
+    retired_pipelines: Vec<PendingPipeline>,
+    async_compilation: bool,
     descriptor_set: DescriptorSet,
+    descriptor_allocator: Rc<DescriptorAllocator>,
+    // Per frame updates and geometry uploads, submitted without waiting for the queue
+    transient_commands: TransientCommands,
     top_level_as: AccelerationStructure,
-    _bottom_level_as: Vec<AccelerationStructure>,
-    geometry_instance: GeometryInstance,
-    camera_buffer: Buffer,
-    clear_buffer: Buffer,
+    // Built every frame some instances move with the motion blur enabled, the instances at the
+    // later steps of the shutter are traced in the first one
+    motion_top_level_as: Vec<AccelerationStructure>,
+    bottom_level_as: Vec<AccelerationStructure>,
+    geometry_instances: Vec<GeometryInstance>,
+    instance_buffer: DataBuffer,
+    // Set once the instances were drawn with new transforms, the next frame sees them moved
+    previous_transforms_changed: bool,
+    camera_buffer: DataBuffer,
+    previous_camera_buffer: DataBuffer,
+    // Empty to trace the whole back buffer with the first camera
+    viewports: Vec<Viewport>,
+    trace_dispatch: TraceDispatch,
+    // Timestamps around each launch, None when the device cannot write them
+    watchdog_queries: Option<QueryPool>,
+    // Frame slots whose timestamps were recorded and not read yet
+    timed_frames: Vec<bool>,
+    longest_dispatch_time: f32,
+    trace_time: f32,
+    // Clears the camera ray mask of the instances out of view, None when disabled
+    instance_culling: Option<InstanceCulling>,
+    culling_stats: CullingStats,
+    background_buffer: DataBuffer,
+    background: Background,
+    environment_map: Texture,
+    settings_buffer: DataBuffer,
+    render_settings: RenderSettings,
+    lights_buffer: DataBuffer,
+    light_alias_buffer: DataBuffer,
+    irradiance_buffer: DataBuffer,
+    // Stands in for the probes of a bake, the bake binds its own buffer
+    bake_buffer: DataBuffer,
+    frame_buffer: DataBuffer,
+    frame_parameters: FrameParameters,
+    frame_index: u32,
+    // Advanced according to the seed policy, restarts when the random seed changes
+    sample_index: u32,
+    // Compared every frame for SeedPolicy::FrozenWhileStatic
+    camera_data: Vec<u8>,
+    cameras_moved: bool,
+    // Set by a camera cut, the next frame does not reuse the previous ones
+    camera_cut: bool,
+    blue_noise: Texture,
+    user_data_buffer: DataBuffer,
+    depth_image: StorageImage,
+    // A single texel when the ID buffer is disabled, the ray generation shader skips it
+    id_image: StorageImage,
+    id_buffer: bool,
+    // Albedo, normal and motion, a single texel each unless enabled in the render settings
+    aov_images: [StorageImage; 4],
+    // Only created while the checkerboard rendering is enabled
+    checkerboard: Option<Checkerboard>,
+    // Only created once a font is set
+    text_overlay: Option<TextOverlay>,
+    text_labels: Vec<TextLabel>,
+    extra_set_layouts: Vec<vk::DescriptorSetLayout>,
+    callable_shaders: Vec<PathBuf>,
+    shader_defines: ShaderDefines,
+    extra_sets: Vec<Option<(vk::DescriptorSet, Vec<u32>)>>,
     ray_tracing: Rc<RayTracing>,
 }
 
 impl RayTracingPipeline {
-    pub fn update_camera_buffer(&self, camera_buffer: &[u8]) -> Result<(), VulkanError> {
-        let command_buffer = self.context.borrow().begin_single_time_commands()?;
-        self.camera_buffer
-            .update_buffer(command_buffer, camera_buffer);
-        self.context
-            .borrow()
-            .end_single_time_commands(command_buffer)
+    pub fn add_geometry_instance(
+        &mut self,
+        geometry_instance: GeometryInstance,
+    ) -> Result<(), VulkanError> {
+        self.replace_geometry_instances(&[], vec![geometry_instance])
+    }
+
+    pub fn geometry_instance_count(&self) -> usize {
+        self.geometry_instances.len()
+    }
+
+    // Removes the geometry instances at the given indices and appends the new ones,
+    // the remaining instances keep their order
+    pub fn replace_geometry_instances(
+        &mut self,
+        indices: &[usize],
+        geometry_instances: Vec<GeometryInstance>,
+    ) -> Result<(), VulkanError> {
+        let _span = tracing::info_span!(
+            "replace_geometry_instances",
+            removed = indices.len(),
+            added = geometry_instances.len()
+        )
+        .entered();
+        if let Some(index) = indices
+            .iter()
+            .find(|&&i| i >= self.geometry_instances.len())
+        {
+            return Err(VulkanError::PipelineError(format!(
+                "Geometry instance {} does not exist",
+                index
+            )));
+        }
+        if indices.len() >= self.geometry_instances.len() && geometry_instances.is_empty() {
+            return Err(VulkanError::PipelineError(String::from(
+                "The pipeline needs at least one geometry instance",
+            )));
+        }
+
+        let context = self.context.borrow();
+
+        // Built aside, a failure leaves the current structures as they are
+        let command_buffer = context.begin_single_time_commands()?;
+        let mut new_bottom_level_as = Vec::with_capacity(geometry_instances.len());
+        for geometry_instance in geometry_instances.iter() {
+            new_bottom_level_as.push(create_bottom_level_as(
+                &context,
+                Rc::clone(&self.ray_tracing),
+                command_buffer,
+                geometry_instance,
+            )?);
+        }
+        context.end_single_time_commands(command_buffer)?;
+
+        let bottom_level_as = std::mem::take(&mut self.bottom_level_as);
+        let old_geometry_instances = std::mem::take(&mut self.geometry_instances);
+        let mut removed = vec![];
+        for (index, (blas, geometry_instance)) in bottom_level_as
+            .into_iter()
+            .zip(old_geometry_instances)
+            .enumerate()
+        {
+            if indices.contains(&index) {
+                removed.push((index, blas, geometry_instance));
+            } else {
+                self.bottom_level_as.push(blas);
+                self.geometry_instances.push(geometry_instance);
+            }
+        }
+        let kept_count = self.geometry_instances.len();
+        self.bottom_level_as.extend(new_bottom_level_as);
+        self.geometry_instances.extend(geometry_instances);
+
+        let resources = match self.create_instance_resources(&context) {
+            Ok(resources) => resources,
+            Err(err) => {
+                // Back to the instances the current top level structure was built from
+                self.deletion_queue.defer((
+                    self.bottom_level_as.split_off(kept_count),
+                    self.geometry_instances.split_off(kept_count),
+                ));
+                for (index, blas, geometry_instance) in removed {
+                    self.bottom_level_as.insert(index, blas);
+                    self.geometry_instances.insert(index, geometry_instance);
+                }
+                return Err(err);
+            }
+        };
+
+        // A compile started by set_shader_defines uses the old layout as well
+        let retired = std::mem::replace(&mut self.pending_pipeline, resources.pending_pipeline);
+        if let Some(retired) = retired {
+            self.retired_pipelines.push(retired);
+        }
+
+        // Frames in flight may still use the removed resources
+        self.deletion_queue.defer(
+            removed
+                .into_iter()
+                .map(|(_, blas, geometry_instance)| (blas, geometry_instance))
+                .collect::<Vec<_>>(),
+        );
+        self.deletion_queue.defer((
+            std::mem::replace(&mut self.top_level_as, resources.top_level_as),
+            std::mem::replace(&mut self.instance_buffer, resources.instance_buffer),
+            std::mem::replace(&mut self.descriptor_set, resources.descriptor_set),
+            std::mem::replace(&mut self.compiled, resources.compiled),
+        ));
+        drop(context);
+
+        self.update_culling_instances()
+    }
+
+    fn create_instance_resources(
+        &self,
+        context: &VulkanContext,
+    ) -> Result<InstanceResources, VulkanError> {
+        let command_buffer = context.begin_single_time_commands()?;
+        let top_level_as = create_top_level_as(
+            context,
+            Rc::clone(&self.ray_tracing),
+            command_buffer,
+            &self.bottom_level_as,
+            &self.geometry_instances,
+            0.0,
+        )?;
+        context.end_single_time_commands(command_buffer)?;
+
+        let instance_buffer = create_instance_buffer(context, &self.geometry_instances)?;
+        let descriptor_set = DescriptorSetBuilder::new(
+            context,
+            &self.descriptor_allocator,
+            &self.geometry_instances,
+        )
+        .build()?;
+        let pending_pipeline = create_pipeline(
+            context,
+            &self.ray_tracing,
+            &descriptor_set,
+            &self.extra_set_layouts,
+            &self.callable_shaders,
+            &self.shader_defines,
+        )?;
+
+        // The old pipeline does not match the new descriptor set layout, so it cannot stand in
+        // for the new one. Only while nothing was compiled yet can the wait go on in the background.
+        let (compiled, pending_pipeline) = if self.async_compilation && self.compiled.is_none() {
+            (None, Some(pending_pipeline))
+        } else {
+            let pipeline = pending_pipeline.wait()?;
+            let sbt =
+                ShaderBindingTableBuilder::new(context, &self.ray_tracing, &pipeline).build()?;
+            (Some((pipeline, sbt)), None)
+        };
+
+        Ok(InstanceResources {
+            top_level_as,
+            instance_buffer,
+            descriptor_set,
+            compiled,
+            pending_pipeline,
+        })
+    }
+
+    // False while the first pipeline compiles in the background
+    pub fn is_ready(&self) -> bool {
+        self.compiled.is_some()
+    }
+
+    fn poll_pipeline(&mut self) -> Result<(), VulkanError> {
+        let retired_pipelines = std::mem::take(&mut self.retired_pipelines);
+        for mut retired in retired_pipelines {
+            if let Ok(None) = retired.try_take() {
+                self.retired_pipelines.push(retired);
+            }
+        }
+
+        let pipeline = match self.pending_pipeline.as_mut() {
+            Some(pending_pipeline) => match pending_pipeline.try_take()? {
+                Some(pipeline) => pipeline,
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        self.pending_pipeline = None;
+
+        let sbt =
+            ShaderBindingTableBuilder::new(&self.context.borrow(), &self.ray_tracing, &pipeline)
+                .build()?;
+        // Frames in flight may still trace with the previous permutation
+        if let Some(compiled) = self.compiled.replace((pipeline, sbt)) {
+            self.deletion_queue.defer(compiled);
+        }
+        Ok(())
+    }
+
+    pub fn get_shader_defines(&self) -> &ShaderDefines {
+        &self.shader_defines
+    }
+
+    // Compiles the pipeline with another permutation of the shaders in the background, the frames
+    // keep being traced with the current one until it is ready
+    pub fn set_shader_defines(&mut self, shader_defines: ShaderDefines) -> Result<(), VulkanError> {
+        if shader_defines == self.shader_defines {
+            return Ok(());
+        }
+
+        let pending_pipeline = create_pipeline(
+            &self.context.borrow(),
+            &self.ray_tracing,
+            &self.descriptor_set,
+            &self.extra_set_layouts,
+            &self.callable_shaders,
+            &shader_defines,
+        )?;
+        if let Some(retired) = self.pending_pipeline.replace(pending_pipeline) {
+            self.retired_pipelines.push(retired);
+        }
+        self.shader_defines = shader_defines;
+        Ok(())
+    }
+
+    // Only the top level acceleration structure is rebuilt, once for all the transforms
+    pub fn set_transforms(&mut self, transforms: &[(usize, glm::Mat4)]) -> Result<(), VulkanError> {
+        if let Some((index, _)) = transforms
+            .iter()
+            .find(|(index, _)| *index >= self.geometry_instances.len())
+        {
+            return Err(VulkanError::PipelineError(format!(
+                "Geometry instance {} does not exist",
+                index
+            )));
+        }
+        for &(index, transform) in transforms.iter() {
+            self.geometry_instances[index].transform = transform;
+        }
+
+        self.rebuild_top_level_as()
+    }
+
+    // Frames in flight keep tracing the old structure until they are done
+    fn rebuild_top_level_as(&mut self) -> Result<(), VulkanError> {
+        let context = self.context.borrow();
+        let command_buffer = context.begin_single_time_commands()?;
+        let top_level_as = create_top_level_as(
+            &context,
+            Rc::clone(&self.ray_tracing),
+            command_buffer,
+            &self.bottom_level_as,
+            &self.geometry_instances,
+            0.0,
+        )?;
+        context.end_single_time_commands(command_buffer)?;
+
+        self.deletion_queue
+            .defer(std::mem::replace(&mut self.top_level_as, top_level_as));
+        drop(context);
+
+        self.update_culling_instances()
+    }
+
+    pub fn get_geometry_instance(&self, index: usize) -> Option<&GeometryInstance> {
+        self.geometry_instances.get(index)
+    }
+
+    // Linear view space depth of the primary rays, the far distance where nothing was hit
+    pub fn get_depth_image(&self) -> &StorageImage {
+        &self.depth_image
+    }
+
+    // Instance and primitive hit by the camera ray of every pixel, for selection and tools
+    pub fn read_id_buffer(&self, rect: Viewport) -> Result<Vec<ObjectId>, VulkanError> {
+        if !self.id_buffer {
+            return Err(VulkanError::PipelineError(String::from(
+                "The ID buffer is disabled",
+            )));
+        }
+
+        let region = vk::Rect2D {
+            offset: vk::Offset2D {
+                x: rect.x as i32,
+                y: rect.y as i32,
+            },
+            extent: vk::Extent2D {
+                width: rect.width,
+                height: rect.height,
+            },
+        };
+        self.id_image.read_region(&self.context.borrow(), region)
+    }
+
+    // Readback of an AOV for compositing, the size of T has to match the texel size of the AOV
+    pub fn read_aov<T: Pod>(&self, aov: Aov, rect: Viewport) -> Result<Vec<T>, VulkanError> {
+        if !self.render_settings.aovs.is_enabled(aov) {
+            return Err(VulkanError::PipelineError(format!(
+                "The {:?} AOV is disabled",
+                aov
+            )));
+        }
+        if std::mem::size_of::<T>() != aov.texel_size() {
+            return Err(VulkanError::PipelineError(format!(
+                "The {:?} AOV has {} bytes per texel, not {}",
+                aov,
+                aov.texel_size(),
+                std::mem::size_of::<T>()
+            )));
+        }
+
+        let region = vk::Rect2D {
+            offset: vk::Offset2D {
+                x: rect.x as i32,
+                y: rect.y as i32,
+            },
+            extent: vk::Extent2D {
+                width: rect.width,
+                height: rect.height,
+            },
+        };
+        self.get_aov_image(aov)
+            .read_region(&self.context.borrow(), region)
+    }
+
+    // For a denoiser or an export recorded in the frame, the images stay in the GENERAL layout
+    pub fn get_aov_image(&self, aov: Aov) -> &StorageImage {
+        match aov {
+            Aov::Albedo => &self.aov_images[0],
+            Aov::Normal => &self.aov_images[1],
+            Aov::Motion => &self.aov_images[2],
+            Aov::Color => &self.aov_images[3],
+            Aov::Depth => &self.depth_image,
+        }
+    }
+
+    // Set 0 belongs to the pipeline, extra sets start at 1 in the order of their layouts
+    pub fn set_descriptor_set(
+        &mut self,
+        set_index: u32,
+        descriptor_set: vk::DescriptorSet,
+        dynamic_offsets: &[u32],
+    ) -> Result<(), VulkanError> {
+        let slot = (set_index as usize)
+            .checked_sub(1)
+            .and_then(|index| self.extra_sets.get_mut(index))
+            .ok_or_else(|| {
+                VulkanError::PipelineError(format!(
+                    "Descriptor set {} has no layout in the pipeline",
+                    set_index
+                ))
+            })?;
+        *slot = Some((descriptor_set, dynamic_offsets.to_vec()));
+        Ok(())
+    }
+
+    // One camera per viewport, in the same order
+    pub fn update_camera_buffers<T: Pod>(&mut self, cameras: &[T]) -> Result<(), VulkanError> {
+        let _span = tracing::info_span!("update_camera_buffers").entered();
+        if cameras.len() > MAX_VIEWPORTS {
+            return Err(VulkanError::PipelineError(format!(
+                "{} cameras given, at most {} are supported",
+                cameras.len(),
+                MAX_VIEWPORTS
+            )));
+        }
+
+        let camera_data: &[u8] = bytemuck::cast_slice(cameras);
+        if is_camera_cut(&self.camera_data, camera_data, mem::size_of::<T>()) {
+            self.camera_cut = true;
+        }
+        let command_buffer = self.transient_commands.begin()?;
+        self.camera_buffer.update(command_buffer, cameras)?;
+        // The motion vectors of the first update are 0
+        let previous_camera_data = if self.camera_data.is_empty() {
+            camera_data
+        } else {
+            &self.camera_data
+        };
+        self.previous_camera_buffer
+            .update(command_buffer, previous_camera_data)?;
+        self.transient_commands.submit(command_buffer)?;
+
+        if self.camera_data != camera_data {
+            self.camera_data = camera_data.to_vec();
+            self.cameras_moved = true;
+        }
+        Ok(())
+    }
+
+    pub fn get_viewports(&self) -> &[Viewport] {
+        &self.viewports
+    }
+
+    // Viewports may overlap, the later ones are traced on top
+    pub fn set_viewports(&mut self, viewports: &[Viewport]) -> Result<(), VulkanError> {
+        if viewports.len() > MAX_VIEWPORTS {
+            return Err(VulkanError::PipelineError(format!(
+                "{} viewports given, at most {} are supported",
+                viewports.len(),
+                MAX_VIEWPORTS
+            )));
+        }
+
+        self.viewports = viewports.to_vec();
+        Ok(())
+    }
+
+    pub fn get_trace_dispatch(&self) -> TraceDispatch {
+        self.trace_dispatch
+    }
+
+    pub fn set_trace_dispatch(&mut self, trace_dispatch: TraceDispatch) {
+        self.trace_dispatch = trace_dispatch;
+    }
+
+    pub fn get_background(&self) -> Background {
+        self.background
+    }
+
+    // Solid colors are read from the frame constants, going from one to another only changes
+    // the clear color of the next frame
+    pub fn set_background(&mut self, background: Background) -> Result<(), VulkanError> {
+        if let Background::Solid(color) = background {
+            self.frame_parameters.clear_color = color;
+            if let Background::Solid(_) = self.background {
+                self.background = background;
+                return Ok(());
+            }
+        }
+
+        let context = self.context.borrow();
+        let command_buffer = context.begin_single_time_commands()?;
+        self.background_buffer
+            .update(command_buffer, &[BackgroundUniform::from(&background)])?;
+        context.end_single_time_commands(command_buffer)?;
+
+        self.background = background;
+        Ok(())
+    }
+
+    // Used by the environment background, the previous map is released once unused
+    pub fn set_environment_map(
+        &mut self,
+        environment_map: &EnvironmentMap,
+    ) -> Result<(), VulkanError> {
+        let texture = create_environment_texture(&self.context.borrow(), environment_map)?;
+        self.deletion_queue
+            .defer(std::mem::replace(&mut self.environment_map, texture));
+        Ok(())
+    }
+
+    pub fn get_frame_parameters(&self) -> &FrameParameters {
+        &self.frame_parameters
+    }
+
+    // Written to the frame constants of the next frame
+    pub fn set_frame_parameters(&mut self, frame_parameters: FrameParameters) {
+        if let Background::Solid(_) = self.background {
+            self.background = Background::Solid(frame_parameters.clear_color);
+        }
+        self.frame_parameters = frame_parameters;
+    }
+
+    pub fn get_render_settings(&self) -> &RenderSettings {
+        &self.render_settings
+    }
+
+    pub fn set_render_settings(
+        &mut self,
+        render_settings: RenderSettings,
+    ) -> Result<(), VulkanError> {
+        let context = self.context.borrow();
+        let command_buffer = context.begin_single_time_commands()?;
+        self.settings_buffer.update(
+            command_buffer,
+            &[RenderSettingsUniform::from(&render_settings)],
+        )?;
+        context.end_single_time_commands(command_buffer)?;
+
+        if render_settings.random_seed != self.render_settings.random_seed {
+            self.sample_index = 0;
+        }
+        // The checkerboard pass reprojects with the motion vectors
+        if render_settings.aovs != self.render_settings.aovs
+            || render_settings.checkerboard != self.render_settings.checkerboard
+        {
+            let aov_images = create_aov_images(&context, &render_settings)?;
+            self.deletion_queue
+                .defer(std::mem::replace(&mut self.aov_images, aov_images));
+        }
+        if render_settings.checkerboard && self.checkerboard.is_none() {
+            self.checkerboard = Some(Checkerboard::new(&context, &self.descriptor_allocator)?);
+        } else if !render_settings.checkerboard {
+            if let Some(checkerboard) = self.checkerboard.take() {
+                self.deletion_queue.defer(checkerboard);
+            }
+        }
+        self.render_settings = render_settings;
+        Ok(())
+    }
+
+    pub fn set_random_seed(
+        &mut self,
+        random_seed: u32,
+        seed_policy: SeedPolicy,
+    ) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.random_seed = random_seed;
+        render_settings.seed_policy = seed_policy;
+        self.set_render_settings(render_settings)
+    }
+
+    pub fn set_reflection_settings(
+        &mut self,
+        reflections: ReflectionSettings,
+    ) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.reflections = reflections;
+        self.set_render_settings(render_settings)
+    }
+
+    pub fn set_path_tracing_settings(
+        &mut self,
+        path_tracing: PathTracingSettings,
+    ) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.path_tracing = path_tracing;
+        self.set_render_settings(render_settings)
+    }
+
+    pub fn set_fog_settings(&mut self, fog: FogSettings) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.fog = fog;
+        self.set_render_settings(render_settings)
+    }
+
+    pub fn set_watchdog_settings(&mut self, watchdog: WatchdogSettings) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.watchdog = watchdog;
+        self.set_render_settings(render_settings)
+    }
+
+    pub fn set_latency_mode(&mut self, latency_mode: LatencyMode) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.latency_mode = latency_mode;
+        self.set_render_settings(render_settings)
+    }
+
+    // The AOV images are created again, what they held is lost
+    pub fn set_aovs(&mut self, aovs: AovSettings) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.aovs = aovs;
+        self.set_render_settings(render_settings)
+    }
+
+    // The instances that moved since the previous frame are blurred along their motion
+    pub fn set_motion_blur(&mut self, motion_blur: MotionBlurSettings) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.motion_blur = motion_blur;
+        self.set_render_settings(render_settings)
+    }
+
+    pub fn set_checkerboard(&mut self, checkerboard: bool) -> Result<(), VulkanError> {
+        let mut render_settings = self.render_settings;
+        render_settings.checkerboard = checkerboard;
+        self.set_render_settings(render_settings)
+    }
+
+    // For the cuts the cameras do not show, such as a new scene seen from the same place. The
+    // next frame is drawn without the previous ones.
+    pub fn reset_history(&mut self) {
+        self.camera_cut = true;
+    }
+
+    // Camera rays skip the instances out of view of every camera, the top level structure is
+    // built again on the GPU every frame with the new masks
+    pub fn set_instance_culling(&mut self, enabled: bool) -> Result<(), VulkanError> {
+        if enabled == self.instance_culling.is_some() {
+            return Ok(());
+        }
+
+        if enabled {
+            let instance_culling = InstanceCulling::new(
+                &self.context.borrow(),
+                &self.descriptor_allocator,
+                self.camera_buffer.get(),
+                self.timed_frames.len() as u32,
+            )?;
+            self.instance_culling = Some(instance_culling);
+            return self.update_culling_instances();
+        }
+
+        if let Some(instance_culling) = self.instance_culling.take() {
+            self.deletion_queue.defer(instance_culling);
+        }
+        self.culling_stats = CullingStats::default();
+
+        // The structure was last built with the culled masks
+        self.rebuild_top_level_as()
+    }
+
+    // Of the last frame that was read back, None when the culling is disabled
+    pub fn get_culling_stats(&self) -> Option<CullingStats> {
+        self.instance_culling.as_ref().map(|_| self.culling_stats)
+    }
+
+    fn update_culling_instances(&mut self) -> Result<(), VulkanError> {
+        if let Some(instance_culling) = self.instance_culling.as_mut() {
+            let previous = instance_culling.set_instances(
+                &self.context.borrow(),
+                &self.top_level_as,
+                &self.geometry_instances,
+            )?;
+            if let Some(previous) = previous {
+                self.deletion_queue.defer(previous);
+            }
+        }
+        Ok(())
+    }
+
+    // In milliseconds, of the last frame whose timestamps were read
+    pub fn get_longest_dispatch_time(&self) -> f32 {
+        self.longest_dispatch_time
+    }
+
+    pub fn get_stats(&self) -> RendererStats {
+        let triangle_count = self
+            .geometry_instances
+            .iter()
+            .map(|geometry_instance| u64::from(geometry_instance.index_count / 3))
+            .sum();
+        let acceleration_structure_memory = self
+            .bottom_level_as
+            .iter()
+            .chain(std::iter::once(&self.top_level_as))
+            .chain(self.motion_top_level_as.iter())
+            .map(|acceleration_structure| acceleration_structure.memory_size())
+            .sum();
+        let texture_memory = self
+            .geometry_instances
+            .iter()
+            .flat_map(|geometry_instance| geometry_instance.textures.iter())
+            .chain(vec![&self.environment_map, &self.blue_noise])
+            .map(|texture| texture.get_memory_size())
+            .sum();
+
+        RendererStats {
+            triangle_count,
+            instance_count: self.geometry_instances.len() as u32,
+            acceleration_structure_memory,
+            texture_memory,
+            trace_time: self.trace_time,
+            longest_dispatch_time: self.longest_dispatch_time,
+            culling: self.get_culling_stats(),
+        }
+    }
+
+    // One entry per geometry instance, in the order they were added
+    pub fn get_memory_breakdown(&self) -> Vec<GeometryMemory> {
+        self.geometry_instances
+            .iter()
+            .zip(self.bottom_level_as.iter())
+            .map(|(geometry_instance, blas)| GeometryMemory {
+                acceleration_structure_memory: blas.memory_size(),
+                ..geometry_instance.get_memory()
+            })
+            .collect()
+    }
+
+    // Replaces all the clip planes, an empty slice removes them
+    pub fn set_clip_planes(&mut self, clip_planes: &[ClipPlane]) -> Result<(), VulkanError> {
+        if clip_planes.len() > MAX_CLIP_PLANES {
+            return Err(VulkanError::PipelineError(format!(
+                "{} clip planes given, at most {} are supported",
+                clip_planes.len(),
+                MAX_CLIP_PLANES
+            )));
+        }
+
+        let mut render_settings = self.render_settings;
+        render_settings.set_clip_planes(clip_planes);
+        self.set_render_settings(render_settings)
+    }
+
+    // The buffers are recreated, the old ones are released once the frames using them are done
+    pub fn set_lights(
+        &mut self,
+        lights: &[Light],
+        strategy: LightSamplingStrategy,
+    ) -> Result<(), VulkanError> {
+        let (lights_buffer, light_alias_buffer) =
+            create_light_buffers(&self.context.borrow(), lights, strategy)?;
+        self.deletion_queue.defer((
+            std::mem::replace(&mut self.lights_buffer, lights_buffer),
+            std::mem::replace(&mut self.light_alias_buffer, light_alias_buffer),
+        ));
+        Ok(())
+    }
+
+    // Replaces every entry, the geometry instances point to them with their user data index
+    pub fn set_user_data(&mut self, user_data: &[InstanceUserData]) -> Result<(), VulkanError> {
+        let user_data_buffer = create_user_data_buffer(&self.context.borrow(), user_data)?;
+        self.deletion_queue.defer(std::mem::replace(
+            &mut self.user_data_buffer,
+            user_data_buffer,
+        ));
+        Ok(())
+    }
+
+    // None goes back to the constant ambient light of the frames that are not path traced
+    pub fn set_irradiance_volume(
+        &mut self,
+        irradiance_volume: Option<&IrradianceVolume>,
+    ) -> Result<(), VulkanError> {
+        let irradiance_buffer =
+            create_irradiance_buffer(&self.context.borrow(), irradiance_volume)?;
+        self.deletion_queue.defer(std::mem::replace(
+            &mut self.irradiance_buffer,
+            irradiance_buffer,
+        ));
+        Ok(())
+    }
+
+    // Path traces the light arriving at every probe of the grid, from sample_count directions
+    // rounded up to a multiple of BAKE_SAMPLES_PER_THREAD. Blocks until the bake is done and does
+    // not show the volume, set_irradiance_volume does.
+    pub fn bake_irradiance_volume(
+        &mut self,
+        grid: &ProbeGrid,
+        sample_count: u32,
+    ) -> Result<IrradianceVolume, VulkanError> {
+        let _span =
+            tracing::info_span!("bake_irradiance_volume", probes = grid.probe_count()).entered();
+        if grid.probe_count() == 0 {
+            return Err(VulkanError::PipelineError(String::from(
+                "The probe grid has no probes",
+            )));
+        }
+
+        // The bake rewrites the descriptor set of the frames
+        unsafe { self.context.borrow().get_device().get().device_wait_idle() }
+            .map_err(|err| VulkanError::DeviceError(err.to_string()))?;
+
+        // The hit shader only gathers the bounced light while path tracing
+        let render_settings = self.render_settings;
+        let mut bake_settings = render_settings;
+        bake_settings.path_tracing.enabled = true;
+        self.set_render_settings(bake_settings)?;
+        let result = self.trace_irradiance_volume(grid, sample_count);
+        self.set_render_settings(render_settings)?;
+        result
+    }
+
+    fn trace_irradiance_volume(
+        &mut self,
+        grid: &ProbeGrid,
+        sample_count: u32,
+    ) -> Result<IrradianceVolume, VulkanError> {
+        let probe_count = grid.probe_count();
+        let thread_count = sample_count.max(1).div_ceil(BAKE_SAMPLES_PER_THREAD);
+        // Every thread writes its share of the coefficients of its probe, summed up below
+        let mut partial_sums =
+            vec![[0.0f32; 4]; probe_count * thread_count as usize * SH_COEFFICIENT_COUNT];
+
+        self.update_descriptor_set();
+        let context = self.context.borrow();
+        let bake_buffer = DataBufferBuilder::new(&context)
+            .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Host)
+            .with_size(mem::size_of_val(partial_sums.as_slice()) as vk::DeviceSize)
+            .build()?;
+        self.descriptor_set
+            .update_irradiance_buffers(self.irradiance_buffer.get(), bake_buffer.get());
+
+        let pipeline = create_bake_pipeline(
+            &context,
+            &self.ray_tracing,
+            &self.descriptor_set,
+            &self.shader_defines,
+        )?
+        .wait()?;
+        let sbt = ShaderBindingTableBuilder::new(&context, &self.ray_tracing, &pipeline).build()?;
+
+        let device = context.get_device();
+        let probes_per_launch = (MAX_BAKE_THREADS_PER_LAUNCH / thread_count).max(1) as usize;
+        for first_probe in (0..probe_count).step_by(probes_per_launch) {
+            let command_buffer = context.begin_single_time_commands()?;
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_NV,
+                pipeline.get(),
+            );
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                pipeline.get_layout(),
+                vk::PipelineBindPoint::RAY_TRACING_NV,
+                &[self.descriptor_set.get()],
+            );
+            let push_constants = BakePushConstants {
+                origin: grid.origin.into(),
+                first_probe: first_probe as u32,
+                spacing: grid.spacing.into(),
+                sample_count: thread_count * BAKE_SAMPLES_PER_THREAD,
+                counts: grid.counts,
+                seed: self.render_settings.random_seed,
+            };
+            device.cmd_push_constants(
+                command_buffer,
+                pipeline.get_layout(),
+                vk::ShaderStageFlags::RAYGEN_NV,
+                0,
+                bytemuck::bytes_of(&push_constants),
+            );
+
+            self.ray_tracing.cmd_trace_rays(
+                command_buffer,
+                sbt.get(),
+                sbt.ray_gen_offset,
+                sbt.get(),
+                sbt.miss_offset,
+                sbt.miss_entry_size,
+                sbt.get(),
+                sbt.hit_group_offset,
+                sbt.hit_group_entry_size,
+                sbt.get_callable(),
+                sbt.callable_offset,
+                sbt.callable_entry_size,
+                probes_per_launch.min(probe_count - first_probe) as u32,
+                thread_count,
+                1,
+            );
+            device.cmd_pipeline_barrier2(
+                command_buffer,
+                &DependencyInfo {
+                    memory_barriers: &[MemoryBarrier2 {
+                        src_stage_mask: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+                        src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                        dst_stage_mask: vk::PipelineStageFlags::HOST,
+                        dst_access_mask: vk::AccessFlags::HOST_READ,
+                    }],
+                    ..Default::default()
+                },
+            );
+            context.end_single_time_commands(command_buffer)?;
+        }
+
+        bake_buffer.read_data_at(0, bytemuck::cast_slice_mut(&mut partial_sums))?;
+        let mut coefficients = vec![[0.0f32; 4]; probe_count * SH_COEFFICIENT_COUNT];
+        for (index, partial_sum) in partial_sums.iter().enumerate() {
+            let probe = index / (thread_count as usize * SH_COEFFICIENT_COUNT);
+            let coefficient =
+                &mut coefficients[probe * SH_COEFFICIENT_COUNT + index % SH_COEFFICIENT_COUNT];
+            for (sum, value) in coefficient.iter_mut().zip(partial_sum.iter()).take(3) {
+                *sum += value;
+            }
+        }
+
+        Ok(IrradianceVolume {
+            grid: *grid,
+            coefficients,
+        })
+    }
+
+    pub fn set_user_data_index(
+        &mut self,
+        index: usize,
+        user_data_index: u32,
+    ) -> Result<(), VulkanError> {
+        match self.geometry_instances.get_mut(index) {
+            Some(geometry_instance) => geometry_instance.user_data_index = user_data_index,
+            None => {
+                return Err(VulkanError::PipelineError(format!(
+                    "Geometry instance {} does not exist",
+                    index
+                )))
+            }
+        }
+
+        let instance_buffer =
+            create_instance_buffer(&self.context.borrow(), &self.geometry_instances)?;
+        self.deletion_queue.defer(std::mem::replace(
+            &mut self.instance_buffer,
+            instance_buffer,
+        ));
+        Ok(())
+    }
+
+    // Replaces the font of the text labels
+    pub fn set_font_atlas(&mut self, font: &FontAtlas) -> Result<(), VulkanError> {
+        let text_overlay = TextOverlay::new(
+            &self.context.borrow(),
+            &self.descriptor_allocator,
+            font,
+            self.timed_frames.len(),
+        )?;
+        if let Some(previous) = self.text_overlay.replace(text_overlay) {
+            self.deletion_queue.defer(previous);
+        }
+        Ok(())
+    }
+
+    // Drawn over every frame from the next one on, until other labels are set. Nothing is drawn
+    // without a font.
+    pub fn set_text_labels(&mut self, text_labels: &[TextLabel]) {
+        self.text_labels = text_labels.to_vec();
+    }
+
+    pub fn get_frame_index(&self) -> u32 {
+        self.frame_index
+    }
+
+    // For geometry built while the pipeline runs, see GeometryInstanceBuilder::with_transient_commands
+    pub fn get_transient_commands(&self) -> &TransientCommands {
+        &self.transient_commands
+    }
+
+    // Shared by the descriptor sets of the renderer, the sets bound with set_descriptor_set can
+    // come from it too. Transient sets stay valid until the frame slot comes back.
+    pub fn get_descriptor_allocator(&self) -> &Rc<DescriptorAllocator> {
+        &self.descriptor_allocator
+    }
+
+    // RGBA8 tileable blue noise, sampled by the shaders with the frame seed
+    pub fn get_blue_noise(&self) -> &Texture {
+        &self.blue_noise
     }
 
     pub fn begin_draw(&mut self) -> Result<(), VulkanError> {
+        let _span = tracing::info_span!("begin_draw", frame = self.frame_index).entered();
         self.context.borrow_mut().frame_begin()?;
+        self.deletion_queue.next_frame();
+        self.descriptor_allocator.next_frame();
+        self.transient_commands.next_frame()?;
+        self.poll_pipeline()?;
 
-        self.create_image_barrier(
-            vk::AccessFlags::MEMORY_READ,
-            vk::AccessFlags::TRANSFER_WRITE,
-            vk::ImageLayout::UNDEFINED,
-            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        self.frame_index = self.frame_index.wrapping_add(1);
+        // The fence of the frame slot was waited on, its previous timestamps are done
+        self.check_watchdog()?;
+        if let Some(instance_culling) = self.instance_culling.as_ref() {
+            self.culling_stats = instance_culling.take_stats(self.frame_index)?;
+        }
+
+        let advance = match self.render_settings.seed_policy {
+            SeedPolicy::PerFrame => true,
+            SeedPolicy::Fixed => false,
+            SeedPolicy::FrozenWhileStatic => self.cameras_moved,
+        };
+        if advance {
+            self.sample_index = self.sample_index.wrapping_add(1);
+        }
+        self.cameras_moved = false;
+        if let Some(checkerboard) = self.checkerboard.as_mut() {
+            checkerboard.begin_frame(self.camera_cut);
+        }
+        self.camera_cut = false;
+        self.update_previous_transforms()?;
+
+        let extent = self.context.borrow().get_swapchain().get_extent();
+        let command_buffer = self.transient_commands.begin()?;
+        self.frame_buffer.update(
+            command_buffer,
+            &[FrameConstants::new(
+                &self.frame_parameters,
+                self.sample_index,
+                self.render_settings.random_seed,
+                self.frame_index,
+                [extent.width, extent.height],
+            )],
         )?;
+        if let Some(queries) = self.watchdog_queries.as_ref() {
+            queries.reset(command_buffer, self.frame_index);
+            let slot = self.frame_index as usize % self.timed_frames.len();
+            self.timed_frames[slot] = self.compiled.is_some();
+        }
+        self.transient_commands.submit(command_buffer)?;
 
+        if let Some(text_overlay) = self.text_overlay.as_mut() {
+            let slot = self.frame_index as usize % self.timed_frames.len();
+            text_overlay.upload(&self.context.borrow(), slot, &self.text_labels)?;
+        }
+
+        self.transition_back_buffer(ImageLayoutTransition {
+            old_layout: vk::ImageLayout::UNDEFINED,
+            new_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            src_access_mask: vk::AccessFlags::MEMORY_READ,
+            dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+            src_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+            dst_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+        })?;
+
+        self.update_descriptor_set();
+        Ok(())
+    }
+
+    // The instance buffer holds the transforms of the previous frame. It is only created again
+    // when one of them changed, that is the frame after an instance moved.
+    fn update_previous_transforms(&mut self) -> Result<(), VulkanError> {
+        self.update_motion_top_level_as()?;
+        if self.previous_transforms_changed {
+            let instance_buffer =
+                create_instance_buffer(&self.context.borrow(), &self.geometry_instances)?;
+            self.deletion_queue
+                .defer(mem::replace(&mut self.instance_buffer, instance_buffer));
+        }
+
+        self.previous_transforms_changed = false;
+        for geometry_instance in self.geometry_instances.iter_mut() {
+            if geometry_instance.previous_transform != Some(geometry_instance.transform) {
+                geometry_instance.previous_transform = Some(geometry_instance.transform);
+                self.previous_transforms_changed = true;
+            }
+        }
+        Ok(())
+    }
+
+    // Step k of the shutter moves the instances back towards their previous transform, the steps
+    // past the built structures trace the current one
+    fn update_motion_top_level_as(&mut self) -> Result<(), VulkanError> {
+        for top_level_as in self.motion_top_level_as.drain(..) {
+            self.deletion_queue.defer(top_level_as);
+        }
+
+        let motion_blur = self.render_settings.motion_blur;
+        let step_count = motion_blur.step_count();
+        let moved = self.geometry_instances.iter().any(|geometry_instance| {
+            geometry_instance.previous_transform != Some(geometry_instance.transform)
+        });
+        if step_count < 2 || !moved {
+            return Ok(());
+        }
+
+        let context = self.context.borrow();
+        for step in 1..step_count {
+            let time = motion_blur.shutter * step as f32 / (step_count - 1) as f32;
+            let command_buffer = context.begin_single_time_commands()?;
+            let top_level_as = create_top_level_as(
+                &context,
+                Rc::clone(&self.ray_tracing),
+                command_buffer,
+                &self.bottom_level_as,
+                &self.geometry_instances,
+                time,
+            )?;
+            context.end_single_time_commands(command_buffer)?;
+            self.motion_top_level_as.push(top_level_as);
+        }
+        Ok(())
+    }
+
+    fn update_descriptor_set(&mut self) {
+        let mut acceleration_structures = [self.top_level_as.get(); MAX_MOTION_BLUR_STEPS as usize];
+        for (slot, top_level_as) in acceleration_structures[1..]
+            .iter_mut()
+            .zip(self.motion_top_level_as.iter())
+        {
+            *slot = top_level_as.get();
+        }
         self.descriptor_set.update_render_target(
-            self.top_level_as.get(),
+            &acceleration_structures,
             self.context.borrow().get_current_back_buffer_view(),
             self.camera_buffer.get(),
-            &self.geometry_instance,
-            self.clear_buffer.get(),
+            &self.geometry_instances,
+            self.background_buffer.get(),
+            self.instance_buffer.get(),
         );
-
-        Ok(())
+        self.descriptor_set
+            .update_depth_target(self.depth_image.get_image_view());
+        self.descriptor_set
+            .update_id_target(self.id_image.get_image_view());
+        self.descriptor_set.update_aov_targets([
+            self.aov_images[0].get_image_view(),
+            self.aov_images[1].get_image_view(),
+            self.aov_images[2].get_image_view(),
+            self.aov_images[3].get_image_view(),
+        ]);
+        self.descriptor_set
+            .update_previous_camera_buffer(self.previous_camera_buffer.get());
+        self.descriptor_set
+            .update_settings_buffer(self.settings_buffer.get());
+        self.descriptor_set
+            .update_light_buffers(self.lights_buffer.get(), self.light_alias_buffer.get());
+        self.descriptor_set
+            .update_frame_resources(self.frame_buffer.get(), &self.blue_noise);
+        self.descriptor_set
+            .update_environment_map(&self.environment_map);
+        self.descriptor_set
+            .update_user_data_buffer(self.user_data_buffer.get());
+        self.descriptor_set
+            .update_irradiance_buffers(self.irradiance_buffer.get(), self.bake_buffer.get());
     }
 
     pub fn draw(&self) -> Result<(), VulkanError> {
+        let _span = tracing::info_span!("draw").entered();
         let command_buffer = self.context.borrow().get_current_command_buffer();
+        if let Some(instance_culling) = self.instance_culling.as_ref() {
+            instance_culling.cmd_cull(
+                &self.context.borrow(),
+                command_buffer,
+                &self.top_level_as,
+                self.viewports.len().max(1) as u32,
+                self.frame_index,
+            );
+        }
         self.context.borrow().begin_render_pass();
+        if let Some((pipeline, sbt)) = self.compiled.as_ref() {
+            self.trace_rays(command_buffer, pipeline, sbt);
+        }
+
+        self.transition_back_buffer(ImageLayoutTransition {
+            old_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+            src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+            dst_access_mask: vk::AccessFlags::MEMORY_READ,
+            src_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+            dst_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+        })?;
+
+        self.context
+            .borrow()
+            .get_device()
+            .cmd_next_subpass(command_buffer);
+
+        Ok(())
+    }
+
+    // Splits the next frames into smaller tiles when a launch took too long
+    fn check_watchdog(&mut self) -> Result<(), VulkanError> {
+        let slot = self.frame_index as usize % self.timed_frames.len();
+        let queries = match self.watchdog_queries.as_ref() {
+            Some(queries) if self.timed_frames[slot] => queries,
+            _ => return Ok(()),
+        };
+        self.timed_frames[slot] = false;
+
+        let timestamps = match queries.get_results(self.frame_index)? {
+            Some(timestamps) => timestamps,
+            None => return Ok(()),
+        };
+        let longest = timestamps
+            .windows(2)
+            .map(|pair| pair[1][0].saturating_sub(pair[0][0]))
+            .max()
+            .unwrap_or(0);
+        let to_milliseconds =
+            |ticks: u64| (ticks as f64 * queries.timestamp_period() as f64 / 1_000_000.0) as f32;
+        self.longest_dispatch_time = to_milliseconds(longest);
+        // The unused queries are written right after the last launch
+        self.trace_time = match (timestamps.first(), timestamps.last()) {
+            (Some(first), Some(last)) => to_milliseconds(last[0].saturating_sub(first[0])),
+            _ => 0.0,
+        };
+
+        let watchdog = self.render_settings.watchdog;
+        if !watchdog.enabled || self.longest_dispatch_time <= watchdog.max_dispatch_time {
+            return Ok(());
+        }
+
+        let extent = self.context.borrow().get_swapchain().get_extent();
+        let tile_size = self
+            .trace_dispatch
+            .tile_size
+            .unwrap_or_else(|| extent.width.max(extent.height));
+        let split_size = (tile_size / 2).max(watchdog.min_tile_size);
+        if split_size < tile_size {
+            self.trace_dispatch.tile_size = Some(split_size);
+        }
+        Ok(())
+    }
+
+    // Every query of the frame is written, its results are only available once they all are
+    fn write_watchdog_timestamp(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        stage: vk::PipelineStageFlags,
+        query: u32,
+    ) {
+        if let Some(queries) = self.watchdog_queries.as_ref() {
+            if query < queries.queries_per_frame() {
+                let _ = queries.write_timestamp(command_buffer, stage, self.frame_index, query);
+            }
+        }
+    }
+
+    fn trace_rays(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &Pipeline,
+        sbt: &ShaderBindingTable,
+    ) {
         self.context.borrow().get_device().cmd_bind_pipeline(
             command_buffer,
             vk::PipelineBindPoint::RAY_TRACING_NV,
-            self.pipeline.get(),
+            pipeline.get(),
         );
 
         self.context.borrow().get_device().cmd_bind_descriptor_sets(
             command_buffer,
-            self.pipeline.get_layout(),
+            pipeline.get_layout(),
             vk::PipelineBindPoint::RAY_TRACING_NV,
             &[self.descriptor_set.get()],
         );
 
-        self.ray_tracing.cmd_trace_rays(
-            command_buffer,
-            self.sbt.get(),
-            self.sbt.ray_gen_offset,
-            self.sbt.get(),
-            self.sbt.miss_offset,
-            self.sbt.miss_entry_size,
-            self.sbt.get(),
-            self.sbt.hit_group_offset,
-            self.sbt.hit_group_entry_size,
-            self.context.borrow().get_swapchain().get_extent().width,
-            self.context.borrow().get_swapchain().get_extent().height,
-            1,
-        );
+        for (index, extra_set) in self.extra_sets.iter().enumerate() {
+            if let Some((descriptor_set, dynamic_offsets)) = extra_set {
+                self.context
+                    .borrow()
+                    .get_device()
+                    .cmd_bind_descriptor_sets_at(
+                        command_buffer,
+                        vk::PipelineBindPoint::RAY_TRACING_NV,
+                        pipeline.get_layout(),
+                        index as u32 + 1,
+                        &[*descriptor_set],
+                        dynamic_offsets,
+                    );
+            }
+        }
 
-        self.create_image_barrier(
-            vk::AccessFlags::TRANSFER_WRITE,
-            vk::AccessFlags::MEMORY_READ,
-            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-            vk::ImageLayout::PRESENT_SRC_KHR,
-        )?;
+        let extent = self.context.borrow().get_swapchain().get_extent();
+        let viewports = if self.viewports.is_empty() {
+            vec![Viewport::full(extent)]
+        } else {
+            self.viewports.clone()
+        };
 
-        self.context
-            .borrow()
-            .get_device()
-            .cmd_next_subpass(command_buffer);
+        self.write_watchdog_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, 0);
+        let mut launch_count = 0;
 
-        Ok(())
+        // The ray generation shader offsets its pixels, so every viewport lands in its own part
+        // of the back buffer
+        for (camera_index, viewport) in viewports.iter().enumerate() {
+            let viewport = match viewport.clip(extent) {
+                Some(viewport) => viewport,
+                None => continue,
+            };
+
+            for launch in self.trace_dispatch.launches(&viewport) {
+                let push_constants = ViewportPushConstants {
+                    offset: [viewport.x, viewport.y],
+                    camera_index: camera_index as u32,
+                    padding: 0,
+                    tile_offset: [launch.x - viewport.x, launch.y - viewport.y],
+                    size: [viewport.width, viewport.height],
+                };
+                self.context.borrow().get_device().cmd_push_constants(
+                    command_buffer,
+                    pipeline.get_layout(),
+                    vk::ShaderStageFlags::RAYGEN_NV,
+                    0,
+                    bytemuck::bytes_of(&push_constants),
+                );
+
+                self.ray_tracing.cmd_trace_rays(
+                    command_buffer,
+                    sbt.get(),
+                    sbt.ray_gen_offset,
+                    sbt.get(),
+                    sbt.miss_offset,
+                    sbt.miss_entry_size,
+                    sbt.get(),
+                    sbt.hit_group_offset,
+                    sbt.hit_group_entry_size,
+                    sbt.get_callable(),
+                    sbt.callable_offset,
+                    sbt.callable_entry_size,
+                    launch.width,
+                    launch.height,
+                    1,
+                );
+
+                // Only the first MAX_TIMED_LAUNCHES launches of a frame are timed
+                launch_count += 1;
+                self.write_watchdog_timestamp(
+                    command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    launch_count,
+                );
+            }
+        }
+
+        for query in launch_count + 1..=MAX_TIMED_LAUNCHES {
+            self.write_watchdog_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                query,
+            );
+        }
     }
 
     pub fn end_draw(&self) -> Result<(), VulkanError> {
+        self.end_draw_with(|_, _| {})
+    }
+
+    // Records the application commands into the frame, after the traced image was written to
+    // the back buffer and before the frame is submitted
+    pub fn end_draw_with<F>(&self, commands: F) -> Result<(), VulkanError>
+    where
+        F: FnOnce(vk::CommandBuffer, &FrameContext),
+    {
+        let _span = tracing::info_span!("end_draw").entered();
         self.context.borrow().end_render_pass();
+
+        let context = self.context.borrow();
+        let frame_context = FrameContext {
+            device: context.get_device(),
+            back_buffer: context.get_current_back_buffer(),
+            back_buffer_view: context.get_current_back_buffer_view(),
+            extent: context.get_swapchain().get_extent(),
+            depth_view: self.depth_image.get_image_view(),
+            frame_index: self.frame_index,
+            frame_slot: self.frame_index as usize % self.timed_frames.len(),
+        };
+        if let Some(checkerboard) = self.checkerboard.as_ref() {
+            checkerboard.cmd_resolve(
+                &context,
+                context.get_current_command_buffer(),
+                frame_context.back_buffer,
+                frame_context.back_buffer_view,
+                &self.aov_images[2],
+                self.frame_index,
+            )?;
+        }
+        if let Some(text_overlay) = self.text_overlay.as_ref() {
+            text_overlay.cmd_draw(
+                &context,
+                context.get_current_command_buffer(),
+                frame_context.back_buffer,
+                frame_context.back_buffer_view,
+                self.frame_buffer.get(),
+                frame_context.frame_slot,
+            )?;
+        }
+        commands(context.get_current_command_buffer(), &frame_context);
+        drop(context);
+
         self.context.borrow().frame_end()?;
-        self.context.borrow_mut().frame_present()
+        self.context.borrow_mut().frame_present()?;
+
+        // A single frame in flight, the next one starts from an idle GPU
+        if self.render_settings.latency_mode == LatencyMode::LowLatency {
+            unsafe { self.context.borrow().get_device().get().device_wait_idle() }
+                .map_err(|err| VulkanError::DeviceError(err.to_string()))?;
+        }
+        Ok(())
     }
 
-    fn create_image_barrier(
-        &self,
-        src_access_mask: vk::AccessFlags,
-        dst_access_mask: vk::AccessFlags,
-        old_layout: vk::ImageLayout,
-        new_layout: vk::ImageLayout,
-    ) -> Result<(), VulkanError> {
-        let command_buffer = self.context.borrow().begin_single_time_commands()?;
-        let subresource_range = vk::ImageSubresourceRange::builder()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .base_mip_level(0)
-            .level_count(1)
-            .base_array_layer(0)
-            .layer_count(1)
-            .build();
-
-        let image_memory_barrier = vk::ImageMemoryBarrier::builder()
-            .src_access_mask(src_access_mask)
-            .dst_access_mask(dst_access_mask)
-            .old_layout(old_layout)
-            .new_layout(new_layout)
-            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-            .image(self.context.borrow().get_current_back_buffer())
-            .subresource_range(subresource_range)
-            .build();
-
-        self.context.borrow().get_device().cmd_pipeline_barrier(
+    fn transition_back_buffer(&self, transition: ImageLayoutTransition) -> Result<(), VulkanError> {
+        let context = self.context.borrow();
+        let command_buffer = context.begin_single_time_commands()?;
+        cmd_transition_layout(
+            context.get_device(),
             command_buffer,
-            vk::PipelineStageFlags::ALL_COMMANDS,
-            vk::PipelineStageFlags::ALL_COMMANDS,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            &[image_memory_barrier],
+            context.get_current_back_buffer(),
+            1,
+            transition,
         );
-        self.context
-            .borrow()
-            .end_single_time_commands(command_buffer)
+        context.end_single_time_commands(command_buffer)
     }
 }
 
 pub struct RayTracingPipelineBuilder {
     context: Rc<RefCell<VulkanContext>>,
-    geometry_instance: Option<GeometryInstance>,
+    geometry_instances: Vec<GeometryInstance>,
     camera_buffer_size: vk::DeviceSize,
+    extra_set_layouts: Vec<vk::DescriptorSetLayout>,
+    callable_shaders: Vec<PathBuf>,
+    shader_defines: ShaderDefines,
+    frames_in_flight: u64,
+    render_settings: RenderSettings,
+    lights: Vec<Light>,
+    light_sampling_strategy: LightSamplingStrategy,
+    user_data: Vec<InstanceUserData>,
+    async_compilation: bool,
+    id_buffer: bool,
+    background: Option<Background>,
+    environment_map: Option<EnvironmentMap>,
+    irradiance_volume: Option<IrradianceVolume>,
+    font_atlas: Option<FontAtlas>,
 }
 
 impl RayTracingPipelineBuilder {
     pub fn new(context: Rc<RefCell<VulkanContext>>) -> Self {
         RayTracingPipelineBuilder {
             context,
-            geometry_instance: None,
+            geometry_instances: vec![],
             camera_buffer_size: 0,
+            extra_set_layouts: vec![],
+            callable_shaders: vec![],
+            shader_defines: ShaderDefines::default(),
+            frames_in_flight: 2,
+            render_settings: RenderSettings::default(),
+            lights: vec![],
+            light_sampling_strategy: LightSamplingStrategy::Power,
+            user_data: vec![],
+            async_compilation: false,
+            id_buffer: false,
+            background: None,
+            environment_map: None,
+            irradiance_volume: None,
+            font_atlas: None,
         }
     }
 
+    // Has to match the frames count of the context
+    pub fn with_frames_in_flight(mut self, frames_in_flight: u64) -> Self {
+        self.frames_in_flight = frames_in_flight;
+        self
+    }
+
     pub fn with_geometry_instance(mut self, geometry_instance: GeometryInstance) -> Self {
-        self.geometry_instance = Some(geometry_instance);
+        self.geometry_instances.push(geometry_instance);
         self
     }
 
+    // Size of one camera, the buffer holds one per viewport
     pub fn with_camera_buffer_size(mut self, camera_buffer_size: vk::DeviceSize) -> Self {
         self.camera_buffer_size = camera_buffer_size;
         self
     }
 
+    pub fn with_render_settings(mut self, render_settings: RenderSettings) -> Self {
+        self.render_settings = render_settings;
+        self
+    }
+
+    pub fn with_lights(mut self, lights: &[Light], strategy: LightSamplingStrategy) -> Self {
+        self.lights = lights.to_vec();
+        self.light_sampling_strategy = strategy;
+        self
+    }
+
+    pub fn with_user_data(mut self, user_data: &[InstanceUserData]) -> Self {
+        self.user_data = user_data.to_vec();
+        self
+    }
+
+    // The pipeline compiles on a worker thread and the frames are cleared until it is ready,
+    // instead of blocking the build for the seconds the driver takes
+    pub fn with_async_compilation(mut self, async_compilation: bool) -> Self {
+        self.async_compilation = async_compilation;
+        self
+    }
+
+    pub fn with_background(mut self, background: Background) -> Self {
+        self.background = Some(background);
+        self
+    }
+
+    pub fn with_environment_map(mut self, environment_map: EnvironmentMap) -> Self {
+        self.environment_map = Some(environment_map);
+        self
+    }
+
+    // Costs an RG32 image the size of the swapchain
+    pub fn with_id_buffer(mut self, id_buffer: bool) -> Self {
+        self.id_buffer = id_buffer;
+        self
+    }
+
+    // The layouts stay owned by the caller and must outlive the pipeline
+    pub fn with_descriptor_set_layout(mut self, set_layout: vk::DescriptorSetLayout) -> Self {
+        self.extra_set_layouts.push(set_layout);
+        self
+    }
+
+    // SPIR-V of a callable shader, called by the other shaders with the index of its call, in the
+    // order they were added. Material shading can be moved out of the closest hit shader this way.
+    pub fn with_callable_shader(mut self, path: &Path) -> Self {
+        self.callable_shaders.push(path.to_path_buf());
+        self
+    }
+
+    // Selects the permutation of the ray tracing shaders, it has to be listed in
+    // assets/shaders/permutations.txt to be compiled
+    pub fn with_shader_defines(mut self, shader_defines: ShaderDefines) -> Self {
+        self.shader_defines = shader_defines;
+        self
+    }
+
+    pub fn with_irradiance_volume(mut self, irradiance_volume: IrradianceVolume) -> Self {
+        self.irradiance_volume = Some(irradiance_volume);
+        self
+    }
+
+    pub fn with_font_atlas(mut self, font_atlas: FontAtlas) -> Self {
+        self.font_atlas = Some(font_atlas);
+        self
+    }
+
     pub fn build(self) -> Result<RayTracingPipeline, VulkanError> {
-        let ray_tracing = Rc::new(RayTracingBuilder::new(&self.context.borrow()).build()?);
+        let context = self.context.borrow();
+        let ray_tracing = Rc::new(RayTracingBuilder::new(&context).build()?);
 
-        let camera_buffer = BufferBuilder::new(&self.context.borrow())
-            .with_type(BufferType::Uniform)
-            .with_size(self.camera_buffer_size)
+        let camera_buffer = DataBufferBuilder::new(&context)
+            .with_usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_size(self.camera_buffer_size * MAX_VIEWPORTS as vk::DeviceSize)
+            .build()?;
+        let previous_camera_buffer = DataBufferBuilder::new(&context)
+            .with_usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_size(self.camera_buffer_size * MAX_VIEWPORTS as vk::DeviceSize)
             .build()?;
 
-        let clear_buffer = BufferBuilder::new(&self.context.borrow())
-            .with_type(BufferType::Uniform)
-            .with_size((mem::size_of::<f32>() * 4) as u64)
+        // The clear color of the context unless a background is given
+        let background = self
+            .background
+            .unwrap_or_else(|| Background::Solid(glm::make_vec4(context.get_clear_value())));
+        let background_buffer = DataBufferBuilder::new(&context)
+            .with_usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_data(&[BackgroundUniform::from(&background)])
             .build()?;
 
-        let clear_color = self.context.borrow().get_clear_value().as_ptr() as *const u8;
-        let clear_color =
-            unsafe { std::slice::from_raw_parts(clear_color, std::mem::size_of::<f32>() * 4) };
-        let command_buffer = self.context.borrow().begin_single_time_commands()?;
-        clear_buffer.update_buffer(command_buffer, clear_color);
-        self.context
-            .borrow()
-            .end_single_time_commands(command_buffer)?;
+        let environment_map = create_environment_texture(
+            &context,
+            self.environment_map
+                .as_ref()
+                .unwrap_or(&EnvironmentMap::black()),
+        )?;
+
+        let settings_buffer = DataBufferBuilder::new(&context)
+            .with_usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_data(&[RenderSettingsUniform::from(&self.render_settings)])
+            .build()?;
+
+        let (lights_buffer, light_alias_buffer) =
+            create_light_buffers(&context, &self.lights, self.light_sampling_strategy)?;
+
+        let irradiance_buffer =
+            create_irradiance_buffer(&context, self.irradiance_volume.as_ref())?;
+        let bake_buffer = DataBufferBuilder::new(&context)
+            .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_size(mem::size_of::<[f32; 4]>() as vk::DeviceSize)
+            .build()?;
+
+        let frame_parameters = FrameParameters {
+            clear_color: match background {
+                Background::Solid(color) => color,
+                _ => glm::make_vec4(context.get_clear_value()),
+            },
+            ..FrameParameters::default()
+        };
+        let extent = context.get_swapchain().get_extent();
+        let frame_buffer = DataBufferBuilder::new(&context)
+            .with_usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .with_location(MemoryLocation::Device)
+            .with_data(&[FrameConstants::new(
+                &frame_parameters,
+                0,
+                self.render_settings.random_seed,
+                0,
+                [extent.width, extent.height],
+            )])
+            .build()?;
+
+        let blue_noise = TextureBuilder::new(&context)
+            .with_width(BLUE_NOISE_SIZE as u32)
+            .with_height(BLUE_NOISE_SIZE as u32)
+            .with_pixels(&generate_blue_noise(BLUE_NOISE_SIZE, 1))
+            .with_srgb(false)
+            .build()?;
+
+        let user_data_buffer = create_user_data_buffer(&context, &self.user_data)?;
+
+        let depth_image = StorageImageBuilder::new(&context)
+            .with_format(vk::Format::R32_SFLOAT)
+            .with_extent(context.get_swapchain().get_extent())
+            .with_usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC)
+            .build()?;
+
+        let id_extent = if self.id_buffer {
+            context.get_swapchain().get_extent()
+        } else {
+            vk::Extent2D {
+                width: 1,
+                height: 1,
+            }
+        };
+        let id_image = StorageImageBuilder::new(&context)
+            .with_format(vk::Format::R32G32_UINT)
+            .with_extent(id_extent)
+            .with_usage(vk::ImageUsageFlags::TRANSFER_SRC)
+            .build()?;
+        let aov_images = create_aov_images(&context, &self.render_settings)?;
 
-        let geometry_instance = self.geometry_instance.as_ref().unwrap();
+        let command_buffer = context.begin_single_time_commands()?;
+        let mut bottom_level_as = vec![];
+        for geometry_instance in self.geometry_instances.iter() {
+            bottom_level_as.push(create_bottom_level_as(
+                &context,
+                Rc::clone(&ray_tracing),
+                command_buffer,
+                geometry_instance,
+            )?);
+        }
+        let top_level_as = create_top_level_as(
+            &context,
+            Rc::clone(&ray_tracing),
+            command_buffer,
+            &bottom_level_as,
+            &self.geometry_instances,
+            0.0,
+        )?;
+        context.end_single_time_commands(command_buffer)?;
+
+        let instance_buffer = create_instance_buffer(&context, &self.geometry_instances)?;
+
+        let descriptor_allocator = Rc::new(DescriptorAllocator::new(
+            Rc::clone(context.get_device()),
+            self.frames_in_flight,
+        ));
+        let descriptor_set =
+            DescriptorSetBuilder::new(&context, &descriptor_allocator, &self.geometry_instances)
+                .build()?;
 
-        let (bottom_level_as, top_level_as) =
-            self.create_acceleration_structures(Rc::clone(&ray_tracing), &geometry_instance)?;
+        let pending_pipeline = create_pipeline(
+            &context,
+            &ray_tracing,
+            &descriptor_set,
+            &self.extra_set_layouts,
+            &self.callable_shaders,
+            &self.shader_defines,
+        )?;
 
-        let descriptor_set = self.create_descriptor_set(&geometry_instance)?;
+        let (compiled, pending_pipeline) = if self.async_compilation {
+            (None, Some(pending_pipeline))
+        } else {
+            let pipeline = pending_pipeline.wait()?;
+            let sbt = ShaderBindingTableBuilder::new(&context, &ray_tracing, &pipeline).build()?;
+            (Some((pipeline, sbt)), None)
+        };
 
-        let pipeline = self.create_pipeline(&ray_tracing, &descriptor_set)?;
+        // The watchdog is disabled on devices without timestamps
+        let watchdog_queries = QueryPoolBuilder::new(&context)
+            .with_type(QueryType::Timestamp)
+            .with_queries_per_frame(MAX_TIMED_LAUNCHES + 1)
+            .with_frame_count(self.frames_in_flight as u32)
+            .build()
+            .ok();
+        let transient_commands = TransientCommands::new(&context, self.frames_in_flight)?;
+        let checkerboard = if self.render_settings.checkerboard {
+            Some(Checkerboard::new(&context, &descriptor_allocator)?)
+        } else {
+            None
+        };
+        let text_overlay = match self.font_atlas.as_ref() {
+            Some(font_atlas) => Some(TextOverlay::new(
+                &context,
+                &descriptor_allocator,
+                font_atlas,
+                self.frames_in_flight as usize,
+            )?),
+            None => None,
+        };
 
-        let sbt = self.create_shader_binding_table(&ray_tracing, &pipeline)?;
+        let context_device = Rc::clone(context.get_device());
+        drop(context);
 
         Ok(RayTracingPipeline {
+            deletion_queue: DeletionQueue::new(Rc::clone(&context_device), self.frames_in_flight),
             context: self.context,
             ray_tracing,
             camera_buffer,
-            clear_buffer,
-            geometry_instance: self.geometry_instance.unwrap(),
-            _bottom_level_as: bottom_level_as,
+            previous_camera_buffer,
+            viewports: vec![],
+            trace_dispatch: TraceDispatch::default(),
+            watchdog_queries,
+            timed_frames: vec![false; self.frames_in_flight as usize],
+            longest_dispatch_time: 0.0,
+            trace_time: 0.0,
+            instance_culling: None,
+            culling_stats: CullingStats::default(),
+            background_buffer,
+            background,
+            environment_map,
+            settings_buffer,
+            render_settings: self.render_settings,
+            lights_buffer,
+            light_alias_buffer,
+            irradiance_buffer,
+            bake_buffer,
+            frame_buffer,
+            frame_parameters,
+            frame_index: 0,
+            sample_index: 0,
+            camera_data: vec![],
+            cameras_moved: false,
+            camera_cut: false,
+            blue_noise,
+            user_data_buffer,
+            depth_image,
+            id_image,
+            id_buffer: self.id_buffer,
+            aov_images,
+            checkerboard,
+            text_overlay,
+            text_labels: vec![],
+            extra_sets: vec![None; self.extra_set_layouts.len()],
+            extra_set_layouts: self.extra_set_layouts,
+            callable_shaders: self.callable_shaders,
+            shader_defines: self.shader_defines,
+            instance_buffer,
+            previous_transforms_changed: false,
+            geometry_instances: self.geometry_instances,
+            bottom_level_as,
             top_level_as,
+            motion_top_level_as: vec![],
             descriptor_set,
-            pipeline,
-            sbt,
+            descriptor_allocator,
+            transient_commands,
+            compiled,
+            pending_pipeline,
+            retired_pipelines: vec![],
+            async_compilation: self.async_compilation,
         })
     }
+}
 
-    fn create_acceleration_structures(
-        &self,
-        ray_tracing: Rc<RayTracing>,
-        geometry_instance: &GeometryInstance,
-    ) -> Result<(Vec<AccelerationStructure>, AccelerationStructure), VulkanError> {
-        let command_buffer = self.context.borrow().begin_single_time_commands().unwrap();
-
-        let blas = self.create_bottom_level_as(geometry_instance);
-        let structure =
-            AccelerationStructureBuilder::new(&self.context.borrow(), Rc::clone(&ray_tracing))
-                .with_bottom_level_as(&[blas])
-                .with_command_buffer(command_buffer)
-                .build()?;
-        let bottom_level_as = vec![structure];
+fn create_environment_texture(
+    context: &VulkanContext,
+    environment_map: &EnvironmentMap,
+) -> Result<Texture, VulkanError> {
+    TextureBuilder::new(context)
+        .with_width(environment_map.width)
+        .with_height(environment_map.height)
+        .with_pixels(bytemuck::cast_slice(&environment_map.half_pixels()))
+        .with_format(vk::Format::R16G16B16A16_SFLOAT)
+        .build()
+}
 
-        let instances: Vec<Instance> = bottom_level_as
-            .iter()
-            .enumerate()
-            .map(|(index, blas)| Instance {
-                bottom_level_as: blas.get(),
-                transform: geometry_instance.transform,
-                instance_id: index as u32,
-                hit_group_index: (index * 2) as u32,
-            })
-            .collect();
+fn create_bottom_level_as(
+    context: &VulkanContext,
+    ray_tracing: Rc<RayTracing>,
+    command_buffer: vk::CommandBuffer,
+    geom: &GeometryInstance,
+) -> Result<AccelerationStructure, VulkanError> {
+    let blas = BottomLevelAccelerationStructureBuilder::new()
+        .with_vertex_buffer(geom.vertex_buffer.get())
+        .with_vertex_offset(geom.vertex_offset)
+        .with_vertex_count(geom.vertex_count)
+        .with_vertex_size(mem::size_of::<Vertex>() as u32)
+        .with_index_buffer(geom.index_buffer.get())
+        .with_index_offset(geom.index_offset)
+        .with_index_count(geom.index_count)
+        .with_opaque(true)
+        .build();
 
-        let top_level_as =
-            AccelerationStructureBuilder::new(&self.context.borrow(), Rc::clone(&ray_tracing))
-                .with_top_level_as(&instances)
-                .with_command_buffer(command_buffer)
-                .build()?;
+    AccelerationStructureBuilder::new(context, ray_tracing)
+        .with_bottom_level_as(&[blas])
+        .with_command_buffer(command_buffer)
+        .build()
+}
 
-        self.context
-            .borrow()
-            .end_single_time_commands(command_buffer)?;
+fn create_top_level_as(
+    context: &VulkanContext,
+    ray_tracing: Rc<RayTracing>,
+    command_buffer: vk::CommandBuffer,
+    bottom_level_as: &[AccelerationStructure],
+    geometry_instances: &[GeometryInstance],
+    time: f32,
+) -> Result<AccelerationStructure, VulkanError> {
+    if geometry_instances.len() as u64 > MAX_INSTANCE_CUSTOM_INDEX as u64 + 1 {
+        return Err(VulkanError::PipelineError(format!(
+            "{} geometry instances do not fit in the instance custom index",
+            geometry_instances.len()
+        )));
+    }
+
+    // Every instance shares the same hit groups, the instance id selects the geometry buffers
+    let instances: Vec<Instance> = bottom_level_as
+        .iter()
+        .zip(geometry_instances.iter())
+        .enumerate()
+        .map(|(index, (blas, geometry_instance))| Instance {
+            bottom_level_as: blas.get(),
+            transform: geometry_instance.transform_at(time),
+            instance_id: index as u32,
+            hit_group_index: 0,
+            // The models wind their front faces counter clockwise
+            flags: if geometry_instance.double_sided {
+                vk::GeometryInstanceFlagsNV::TRIANGLE_CULL_DISABLE
+            } else {
+                vk::GeometryInstanceFlagsNV::TRIANGLE_FRONT_COUNTERCLOCKWISE
+            },
+        })
+        .collect();
 
-        Ok((bottom_level_as, top_level_as))
+    AccelerationStructureBuilder::new(context, ray_tracing)
+        .with_top_level_as(&instances)
+        .with_command_buffer(command_buffer)
+        .build()
+}
+
+fn create_instance_buffer(
+    context: &VulkanContext,
+    geometry_instances: &[GeometryInstance],
+) -> Result<DataBuffer, VulkanError> {
+    let mut texture_offset = 0;
+    let mut instance_infos = Vec::with_capacity(geometry_instances.len());
+    for geometry_instance in geometry_instances.iter() {
+        let previous_transform = geometry_instance
+            .previous_transform
+            .unwrap_or(geometry_instance.transform);
+        let mut rows = [[0.0; 4]; 3];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = previous_transform[(i, j)];
+            }
+        }
+        instance_infos.push(InstanceInfo {
+            texture_offset,
+            user_data_index: geometry_instance.user_data_index,
+            padding: [0; 2],
+            previous_transform: rows,
+        });
+        texture_offset += geometry_instance.textures.len() as u32;
     }
 
-    fn create_bottom_level_as(&self, geom: &GeometryInstance) -> BottomLevelAccelerationStructure {
-        BottomLevelAccelerationStructureBuilder::new()
-            .with_vertex_buffer(geom.vertex_buffer.get())
-            .with_vertex_offset(geom.vertex_offset)
-            .with_vertex_count(geom.vertex_count)
-            .with_vertex_size(mem::size_of::<Vertex>() as u32)
-            .with_index_buffer(geom.index_buffer.get())
-            .with_index_offset(geom.index_offset)
-            .with_index_count(geom.index_count)
-            .with_opaque(true)
+    DataBufferBuilder::new(context)
+        .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .with_location(MemoryLocation::Host)
+        .with_data(&instance_infos)
+        .build()
+}
+
+// Albedo, normal and motion images, a single texel for the disabled ones. The checkerboard pass
+// needs the motion vectors.
+fn create_aov_images(
+    context: &VulkanContext,
+    render_settings: &RenderSettings,
+) -> Result<[StorageImage; 4], VulkanError> {
+    let create = |aov: Aov, format: vk::Format| {
+        let enabled = render_settings.aovs.is_enabled(aov)
+            || (aov == Aov::Motion && render_settings.checkerboard);
+        let extent = if enabled {
+            context.get_swapchain().get_extent()
+        } else {
+            vk::Extent2D {
+                width: 1,
+                height: 1,
+            }
+        };
+        StorageImageBuilder::new(context)
+            .with_format(format)
+            .with_extent(extent)
+            .with_usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC)
             .build()
+    };
+
+    Ok([
+        create(Aov::Albedo, vk::Format::R8G8B8A8_UNORM)?,
+        create(Aov::Normal, vk::Format::R16G16B16A16_SFLOAT)?,
+        create(Aov::Motion, vk::Format::R16G16_SFLOAT)?,
+        create(Aov::Color, vk::Format::R16G16B16A16_SFLOAT)?,
+    ])
+}
+
+// Always holds an entry, the instances that were given none read the default one
+fn create_user_data_buffer(
+    context: &VulkanContext,
+    user_data: &[InstanceUserData],
+) -> Result<DataBuffer, VulkanError> {
+    let default_user_data = [InstanceUserData::default()];
+    let user_data = if user_data.is_empty() {
+        &default_user_data[..]
+    } else {
+        user_data
+    };
+
+    DataBufferBuilder::new(context)
+        .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .with_location(MemoryLocation::Host)
+        .with_data(user_data)
+        .build()
+}
+
+// The lights are preceded by their count, the alias table always has an entry
+fn create_light_buffers(
+    context: &VulkanContext,
+    lights: &[Light],
+    strategy: LightSamplingStrategy,
+) -> Result<(DataBuffer, DataBuffer), VulkanError> {
+    let header = LightsHeader {
+        light_count: lights.len() as u32,
+        padding: [0; 3],
+    };
+    let light_datas: Vec<LightData> = lights.iter().map(LightData::from).collect();
+    let mut lights_data = bytemuck::bytes_of(&header).to_vec();
+    lights_data.extend_from_slice(bytemuck::cast_slice(&light_datas));
+
+    let lights_buffer = DataBufferBuilder::new(context)
+        .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .with_location(MemoryLocation::Host)
+        .with_data(&lights_data)
+        .build()?;
+
+    let mut alias_table = build_alias_table(lights, strategy);
+    if alias_table.is_empty() {
+        alias_table.push(Default::default());
     }
+    let light_alias_buffer = DataBufferBuilder::new(context)
+        .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .with_location(MemoryLocation::Host)
+        .with_data(&alias_table)
+        .build()?;
 
-    fn create_descriptor_set(
-        &self,
-        geometry_instance: &GeometryInstance,
-    ) -> Result<DescriptorSet, VulkanError> {
-        DescriptorSetBuilder::new(&self.context.borrow(), geometry_instance).build()
+    Ok((lights_buffer, light_alias_buffer))
+}
+
+fn create_irradiance_buffer(
+    context: &VulkanContext,
+    irradiance_volume: Option<&IrradianceVolume>,
+) -> Result<DataBuffer, VulkanError> {
+    if let Some(irradiance_volume) = irradiance_volume {
+        let expected = irradiance_volume.grid.probe_count() * SH_COEFFICIENT_COUNT;
+        if irradiance_volume.coefficients.len() != expected {
+            return Err(VulkanError::PipelineError(format!(
+                "The irradiance volume has {} coefficients, its grid needs {}",
+                irradiance_volume.coefficients.len(),
+                expected
+            )));
+        }
     }
 
-    fn create_pipeline(
-        &self,
-        ray_tracing: &RayTracing,
-        descriptor_set: &DescriptorSet,
-    ) -> Result<Pipeline, VulkanError> {
-        let ray_gen_module =
-            ShaderModuleBuilder::new(Rc::clone(&self.context.borrow().get_device()))
-                .with_path(Path::new("assets/shaders/raygen.spv"))
-                .build()?;
-        let miss_module = ShaderModuleBuilder::new(Rc::clone(&self.context.borrow().get_device()))
-            .with_path(Path::new("assets/shaders/miss.spv"))
-            .build()?;
-        let shadow_miss_module =
-            ShaderModuleBuilder::new(Rc::clone(&self.context.borrow().get_device()))
-                .with_path(Path::new("assets/shaders/shadow_miss.spv"))
-                .build()?;
-        let closest_hit_module =
-            ShaderModuleBuilder::new(Rc::clone(&self.context.borrow().get_device()))
-                .with_path(Path::new("assets/shaders/closesthit.spv"))
-                .build()?;
+    DataBufferBuilder::new(context)
+        .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .with_location(MemoryLocation::Host)
+        .with_data(&IrradianceVolume::gpu_data(irradiance_volume))
+        .build()
+}
 
-        PipelineBuilder::new(&self.context.borrow(), ray_tracing, descriptor_set)
-            .with_ray_gen_shader(ray_gen_module)
-            .with_miss_shader(miss_module)
-            .with_shadow_miss_shader(shadow_miss_module)
-            .with_hit_shader(closest_hit_module)
-            .with_max_recursion_depth(2)
-            .build()
+// Barycentrics of the triangle hit, declared as a vec3 by the closest hit shader
+const HIT_ATTRIBUTE_SIZE: u32 = 12;
+
+fn create_pipeline(
+    context: &VulkanContext,
+    ray_tracing: &RayTracing,
+    descriptor_set: &DescriptorSet,
+    extra_set_layouts: &[vk::DescriptorSetLayout],
+    callable_shaders: &[PathBuf],
+    shader_defines: &ShaderDefines,
+) -> Result<PendingPipeline, VulkanError> {
+    let paths = permutation_paths(
+        &["raygen", "miss", "shadow_miss", "closesthit"],
+        shader_defines,
+    )?;
+    let ray_gen_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[0])
+        .build()?;
+    let miss_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[1])
+        .build()?;
+    let shadow_miss_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[2])
+        .build()?;
+    let closest_hit_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[3])
+        .build()?;
+
+    let push_constant_ranges = [vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::RAYGEN_NV)
+        .offset(0)
+        .size(mem::size_of::<ViewportPushConstants>() as u32)
+        .build()];
+
+    let mut pipeline_builder = PipelineBuilder::new(context, ray_tracing, descriptor_set);
+    for path in callable_shaders.iter() {
+        let callable_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+            .with_path(path)
+            .build()?;
+        pipeline_builder = pipeline_builder.with_callable_shader(callable_module);
     }
 
-    fn create_shader_binding_table(
-        &self,
-        ray_tracing: &RayTracing,
-        pipeline: &Pipeline,
-    ) -> Result<ShaderBindingTable, VulkanError> {
-        ShaderBindingTableBuilder::new(&self.context.borrow(), ray_tracing, pipeline).build()
+    pipeline_builder
+        .with_ray_gen_shader(ray_gen_module)
+        .with_miss_shader(miss_module)
+        .with_shadow_miss_shader(shadow_miss_module)
+        .with_hit_shader(closest_hit_module)
+        .with_max_recursion_depth(2)
+        .with_max_hit_attribute_size(HIT_ATTRIBUTE_SIZE)
+        .with_extra_set_layouts(extra_set_layouts)
+        .with_push_constant_ranges(&push_constant_ranges)
+        .build_async()
+}
+
+// The stages of the frames with the ray generation shader of the bake
+fn create_bake_pipeline(
+    context: &VulkanContext,
+    ray_tracing: &RayTracing,
+    descriptor_set: &DescriptorSet,
+    shader_defines: &ShaderDefines,
+) -> Result<PendingPipeline, VulkanError> {
+    let paths = permutation_paths(
+        &["bake_irradiance", "miss", "shadow_miss", "closesthit"],
+        shader_defines,
+    )?;
+    let ray_gen_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[0])
+        .build()?;
+    let miss_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[1])
+        .build()?;
+    let shadow_miss_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[2])
+        .build()?;
+    let closest_hit_module = ShaderModuleBuilder::new(Rc::clone(context.get_device()))
+        .with_path(&paths[3])
+        .build()?;
+
+    let push_constant_ranges = [vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::RAYGEN_NV)
+        .offset(0)
+        .size(mem::size_of::<BakePushConstants>() as u32)
+        .build()];
+
+    PipelineBuilder::new(context, ray_tracing, descriptor_set)
+        .with_ray_gen_shader(ray_gen_module)
+        .with_miss_shader(miss_module)
+        .with_shadow_miss_shader(shadow_miss_module)
+        .with_hit_shader(closest_hit_module)
+        .with_max_recursion_depth(2)
+        .with_max_hit_attribute_size(HIT_ATTRIBUTE_SIZE)
+        .with_push_constant_ranges(&push_constant_ranges)
+        .build_async()
+}
+
+// Shaders that do not use the defines are only compiled without them. At least one of the
+// shaders has to be compiled with the defines, otherwise they are most likely misspelled.
+fn permutation_paths(
+    shaders: &[&str],
+    shader_defines: &ShaderDefines,
+) -> Result<Vec<PathBuf>, VulkanError> {
+    let default_defines = ShaderDefines::default();
+    let paths: Vec<PathBuf> = shaders
+        .iter()
+        .map(|shader| shader_defines.get_path(shader))
+        .collect();
+    if shader_defines.is_empty() || paths.iter().any(|path| path.exists()) {
+        return Ok(shaders
+            .iter()
+            .zip(paths)
+            .map(|(shader, path)| {
+                if path.exists() {
+                    path
+                } else {
+                    default_defines.get_path(shader)
+                }
+            })
+            .collect());
     }
+
+    Err(VulkanError::PipelineError(format!(
+        "No shader is compiled with the defines {}, add the permutation to {}/permutations.txt",
+        shader_defines.get_key().trim_start_matches('.'),
+        SHADER_DIRECTORY
+    )))
 }
@@ -0,0 +1,391 @@
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+
+// Size of the clip plane array of the shaders
+pub const MAX_CLIP_PLANES: usize = 4;
+// Size of the top level acceleration structure array of the shaders
+pub const MAX_MOTION_BLUR_STEPS: u32 = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReflectionSettings {
+    pub enabled: bool,
+    // Rougher materials are not traced, roughness is derived from the material shininess
+    pub max_roughness: f32,
+    pub max_distance: f32,
+    // Reflection rays that hit nothing within max_distance return the background instead of black
+    pub environment_fallback: bool,
+}
+
+impl Default for ReflectionSettings {
+    fn default() -> Self {
+        ReflectionSettings {
+            enabled: true,
+            max_roughness: 0.3,
+            max_distance: 100.0,
+            environment_fallback: true,
+        }
+    }
+}
+
+// Diffuse and specular bounces are traced instead of the mirror reflections alone
+#[derive(Clone, Copy, Debug)]
+pub struct PathTracingSettings {
+    pub enabled: bool,
+    // Bounces always traced before Russian roulette can end a path
+    pub min_bounces: u32,
+    pub max_bounces: u32,
+    // Clamp of the light sampled at each hit, 0 disables it
+    pub max_direct_radiance: f32,
+    // Clamp of what each bounce adds to the pixel, 0 disables it
+    pub max_indirect_radiance: f32,
+}
+
+impl Default for PathTracingSettings {
+    fn default() -> Self {
+        PathTracingSettings {
+            enabled: false,
+            min_bounces: 3,
+            max_bounces: 8,
+            max_direct_radiance: 0.0,
+            max_indirect_radiance: 10.0,
+        }
+    }
+}
+
+// Guards against the driver resetting the device when a single launch runs for too long
+#[derive(Clone, Copy, Debug)]
+pub struct WatchdogSettings {
+    pub enabled: bool,
+    // Longest launch allowed, in milliseconds, before the next frames are traced in smaller tiles
+    pub max_dispatch_time: f32,
+    // Tiles are not split below this size
+    pub min_tile_size: u32,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        WatchdogSettings {
+            enabled: true,
+            max_dispatch_time: 100.0,
+            min_tile_size: 64,
+        }
+    }
+}
+
+// Exponential height fog between the camera and the first hit, with a single scattering of the
+// directional lights
+#[derive(Clone, Copy, Debug)]
+pub struct FogSettings {
+    pub enabled: bool,
+    // Extinction per unit of distance at the base height
+    pub density: f32,
+    pub base_height: f32,
+    // How fast the density decreases with the height above the base, 0 for a uniform fog
+    pub height_falloff: f32,
+    // Light the fog scatters toward the camera, the directional lights add to it
+    pub color: glm::Vec3,
+    // Henyey-Greenstein asymmetry, from -1 for back scattering to 1 for forward scattering around
+    // the lights, 0 scatters the same in every direction
+    pub anisotropy: f32,
+    // Fog crossed by the rays that hit nothing, 0 leaves the background clear
+    pub max_distance: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        FogSettings {
+            enabled: false,
+            density: 0.02,
+            base_height: 0.0,
+            height_falloff: 0.2,
+            color: glm::vec3(0.5, 0.6, 0.7),
+            anisotropy: 0.3,
+            max_distance: 1000.0,
+        }
+    }
+}
+
+// The moving instances are blurred along their motion since the previous frame. The shutter time
+// is split into steps, each with its own top level acceleration structure built with the
+// transforms of its time, and every pixel traces one of them at random.
+#[derive(Clone, Copy, Debug)]
+pub struct MotionBlurSettings {
+    pub enabled: bool,
+    // Part of the frame time the shutter stays open, 1 blurs the whole motion since the previous
+    // frame
+    pub shutter: f32,
+    // From 2 to MAX_MOTION_BLUR_STEPS, more steps blur more smoothly and rebuild more structures
+    pub steps: u32,
+}
+
+impl Default for MotionBlurSettings {
+    fn default() -> Self {
+        MotionBlurSettings {
+            enabled: false,
+            shutter: 0.5,
+            steps: MAX_MOTION_BLUR_STEPS,
+        }
+    }
+}
+
+impl MotionBlurSettings {
+    // 1 when disabled, the shaders then only trace the first structure
+    pub fn step_count(&self) -> u32 {
+        if self.enabled {
+            self.steps.clamp(2, MAX_MOTION_BLUR_STEPS)
+        } else {
+            1
+        }
+    }
+}
+
+// How the random numbers of the shaders are seeded from one frame to the next
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SeedPolicy {
+    // A new seed every frame, the sequence only depends on the random seed and the frame count
+    #[default]
+    PerFrame,
+    // Every frame uses the same samples, the image stays identical
+    Fixed,
+    // A new seed every frame while the cameras move, the samples stop changing once they stand
+    // still so the image does not flicker
+    FrozenWhileStatic,
+}
+
+// How many frames the CPU runs ahead of the GPU
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LatencyMode {
+    // Every frame in flight is used, the CPU records a frame while the GPU traces the previous
+    #[default]
+    Throughput,
+    // The CPU waits for each frame to be done before reading the inputs of the next, for
+    // interactive editing where the response to the mouse matters more than the framerate
+    LowLatency,
+}
+
+// Arbitrary output variables, extra images the camera rays write next to the color for denoisers
+// and compositing. The linear depth is always written. The disabled ones are a single texel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AovSettings {
+    // Color of the first surface hit, the background on a miss
+    pub albedo: bool,
+    // World space shading normal of the first hit, 0 on a miss
+    pub normal: bool,
+    // Offset in pixels from each pixel to where its first hit was in the previous frame, as the
+    // cameras and the instances moved
+    pub motion: bool,
+    // Radiance of the camera rays before it is written to the back buffer, for offline renders
+    // that accumulate several frames
+    pub color: bool,
+}
+
+impl AovSettings {
+    pub fn is_enabled(&self, aov: Aov) -> bool {
+        match aov {
+            Aov::Albedo => self.albedo,
+            Aov::Normal => self.normal,
+            Aov::Motion => self.motion,
+            Aov::Color => self.color,
+            Aov::Depth => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aov {
+    // RGBA8, the alpha is the coverage
+    Albedo,
+    // RGBA16 half floats
+    Normal,
+    // R32 float, linear view space depth
+    Depth,
+    // RG16 half floats
+    Motion,
+    // RGBA16 half floats, the alpha is the coverage
+    Color,
+}
+
+impl Aov {
+    // Bytes per texel of its image
+    pub fn texel_size(self) -> usize {
+        match self {
+            Aov::Normal | Aov::Color => 8,
+            _ => 4,
+        }
+    }
+}
+
+// Geometry on the side the normal points to, where dot(normal, p) + d > 0, is cut away.
+// Rays are shortened to what is left, so the cut also lets light and shadows through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClipPlane {
+    pub normal: glm::Vec3,
+    pub d: f32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderSettings {
+    pub reflections: ReflectionSettings,
+    pub path_tracing: PathTracingSettings,
+    pub clip_planes: [Option<ClipPlane>; MAX_CLIP_PLANES],
+    pub fog: FogSettings,
+    // Only used on the CPU, it is not part of the uniform
+    pub watchdog: WatchdogSettings,
+    // Renders with the same seed, policy and frame count get the same samples, for golden images
+    // and debugging. The frame uniform is derived from them on the CPU.
+    pub random_seed: u32,
+    pub seed_policy: SeedPolicy,
+    // Only used on the CPU
+    pub latency_mode: LatencyMode,
+    // Only used on the CPU, the shaders skip the images of a single texel
+    pub aovs: AovSettings,
+    // Traces every other pixel, alternating each frame, and fills in the others from the
+    // previous frame. About half the cost of a frame, with some blur in motion.
+    pub checkerboard: bool,
+    pub motion_blur: MotionBlurSettings,
+}
+
+impl RenderSettings {
+    // Replaces all the clip planes, the ones past MAX_CLIP_PLANES are ignored
+    pub fn set_clip_planes(&mut self, clip_planes: &[ClipPlane]) {
+        self.clip_planes = [None; MAX_CLIP_PLANES];
+        for (slot, plane) in self.clip_planes.iter_mut().zip(clip_planes) {
+            *slot = Some(*plane);
+        }
+    }
+}
+
+// Named quality level of the renderer, the part of the render settings that does not depend on
+// the content of the scene
+#[derive(Clone, Debug)]
+pub struct RenderPreset {
+    pub name: String,
+    pub reflections: ReflectionSettings,
+    pub path_tracing: PathTracingSettings,
+    pub seed_policy: SeedPolicy,
+}
+
+impl RenderPreset {
+    // With the default settings
+    pub fn new(name: &str) -> Self {
+        RenderPreset {
+            name: String::from(name),
+            reflections: ReflectionSettings::default(),
+            path_tracing: PathTracingSettings::default(),
+            seed_policy: SeedPolicy::default(),
+        }
+    }
+
+    // Mirror reflections of the smoothest materials only, for fast navigation
+    pub fn preview() -> Self {
+        RenderPreset {
+            reflections: ReflectionSettings {
+                max_roughness: 0.1,
+                ..ReflectionSettings::default()
+            },
+            ..Self::new("Preview")
+        }
+    }
+
+    pub fn quality() -> Self {
+        RenderPreset {
+            path_tracing: PathTracingSettings {
+                enabled: true,
+                max_bounces: 12,
+                ..PathTracingSettings::default()
+            },
+            ..Self::new("Quality")
+        }
+    }
+
+    // The same bounces and samples every frame, so the frame times can be compared
+    pub fn benchmark() -> Self {
+        RenderPreset {
+            path_tracing: PathTracingSettings {
+                enabled: true,
+                min_bounces: 4,
+                max_bounces: 4,
+                ..PathTracingSettings::default()
+            },
+            seed_policy: SeedPolicy::Fixed,
+            ..Self::new("Benchmark")
+        }
+    }
+
+    pub fn apply(&self, settings: &mut RenderSettings) {
+        settings.reflections = self.reflections;
+        settings.path_tracing = self.path_tracing;
+        settings.seed_policy = self.seed_policy;
+    }
+}
+
+// std140 layout of the RenderSettings uniform block of the shaders
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct RenderSettingsUniform {
+    reflections_enabled: u32,
+    reflection_max_roughness: f32,
+    reflection_max_distance: f32,
+    reflection_environment_fallback: u32,
+    path_tracing_enabled: u32,
+    path_min_bounces: u32,
+    path_max_bounces: u32,
+    path_max_direct_radiance: f32,
+    path_max_indirect_radiance: f32,
+    clip_plane_count: u32,
+    padding: [u32; 2],
+    clip_planes: [[f32; 4]; MAX_CLIP_PLANES],
+    fog_enabled: u32,
+    fog_density: f32,
+    fog_base_height: f32,
+    fog_height_falloff: f32,
+    fog_color: [f32; 3],
+    fog_anisotropy: f32,
+    fog_max_distance: f32,
+    checkerboard: u32,
+    motion_blur_steps: u32,
+    padding2: u32,
+}
+
+unsafe impl Zeroable for RenderSettingsUniform {}
+unsafe impl Pod for RenderSettingsUniform {}
+
+impl From<&RenderSettings> for RenderSettingsUniform {
+    fn from(settings: &RenderSettings) -> Self {
+        // The shaders only loop over the planes in use
+        let mut clip_planes = [[0.0; 4]; MAX_CLIP_PLANES];
+        let mut clip_plane_count = 0;
+        for plane in settings.clip_planes.iter().flatten() {
+            let normal = plane.normal.normalize();
+            let d = plane.d / plane.normal.norm();
+            clip_planes[clip_plane_count] = [normal.x, normal.y, normal.z, d];
+            clip_plane_count += 1;
+        }
+
+        RenderSettingsUniform {
+            reflections_enabled: settings.reflections.enabled as u32,
+            reflection_max_roughness: settings.reflections.max_roughness,
+            reflection_max_distance: settings.reflections.max_distance,
+            reflection_environment_fallback: settings.reflections.environment_fallback as u32,
+            path_tracing_enabled: settings.path_tracing.enabled as u32,
+            path_min_bounces: settings.path_tracing.min_bounces,
+            path_max_bounces: settings.path_tracing.max_bounces,
+            path_max_direct_radiance: settings.path_tracing.max_direct_radiance,
+            path_max_indirect_radiance: settings.path_tracing.max_indirect_radiance,
+            clip_plane_count: clip_plane_count as u32,
+            padding: [0; 2],
+            clip_planes,
+            fog_enabled: settings.fog.enabled as u32,
+            fog_density: settings.fog.density,
+            fog_base_height: settings.fog.base_height,
+            fog_height_falloff: settings.fog.height_falloff,
+            fog_color: settings.fog.color.into(),
+            // Exactly 1 or -1 puts all the light in a single direction
+            fog_anisotropy: settings.fog.anisotropy.clamp(-0.99, 0.99),
+            fog_max_distance: settings.fog.max_distance,
+            checkerboard: settings.checkerboard as u32,
+            motion_blur_steps: settings.motion_blur.step_count(),
+            padding2: 0,
+        }
+    }
+}
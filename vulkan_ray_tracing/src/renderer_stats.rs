@@ -0,0 +1,71 @@
+use std::fmt;
+
+use crate::instance_culling::CullingStats;
+
+// What the renderer holds and how long the GPU took, for the stats HUD or profiling
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RendererStats {
+    pub triangle_count: u64,
+    pub instance_count: u32,
+    // Bottom and top level structures, with their scratch and instance buffers
+    pub acceleration_structure_memory: u64,
+    pub texture_memory: u64,
+    // In milliseconds, from the first to the last launch of the last frame whose timestamps were
+    // read, 0 when the device cannot write timestamps
+    pub trace_time: f32,
+    pub longest_dispatch_time: f32,
+    // None while the culling is disabled
+    pub culling: Option<CullingStats>,
+}
+
+// GPU memory of one geometry instance, from the sizes of its buffers and the memory requirements
+// of its images
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeometryMemory {
+    pub vertex_memory: u64,
+    pub index_memory: u64,
+    pub material_memory: u64,
+    // Bottom level structure with its scratch buffer
+    pub acceleration_structure_memory: u64,
+    // One entry per texture of the instance, after the packing
+    pub texture_memory: Vec<u64>,
+    // Per material, the part of the texture memory it samples. Atlas entries only count their
+    // area, materials that share a texture each count it.
+    pub material_texture_memory: Vec<u64>,
+}
+
+impl GeometryMemory {
+    pub fn total(&self) -> u64 {
+        self.vertex_memory
+            + self.index_memory
+            + self.material_memory
+            + self.acceleration_structure_memory
+            + self.texture_memory.iter().sum::<u64>()
+    }
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+impl fmt::Display for RendererStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} triangles, {} instances, AS {:.1} MB, textures {:.1} MB, trace {:.2} ms",
+            self.triangle_count,
+            self.instance_count,
+            megabytes(self.acceleration_structure_memory),
+            megabytes(self.texture_memory),
+            self.trace_time
+        )?;
+        if let Some(culling) = self.culling {
+            write!(
+                f,
+                ", {}/{} visible",
+                culling.visible_instances, culling.total_instances
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,27 +1,36 @@
-use std::os::raw::c_void;
-
 use ash::vk;
-use vulkan_bootstrap::buffer::{Buffer, BufferBuilder, BufferType};
 use vulkan_bootstrap::errors::VulkanError;
 use vulkan_bootstrap::vulkan_context::VulkanContext;
 
+use crate::buffer::{align_up, DataBuffer, DataBufferBuilder, MemoryLocation};
 use crate::pipeline::Pipeline;
 use crate::ray_tracing::RayTracing;
 
 pub struct ShaderBindingTable {
-    sbt_buffer: Buffer,
+    sbt_buffer: DataBuffer,
     pub ray_gen_entry_size: vk::DeviceSize,
     pub ray_gen_offset: vk::DeviceSize,
     pub miss_entry_size: vk::DeviceSize,
     pub miss_offset: vk::DeviceSize,
     pub hit_group_entry_size: vk::DeviceSize,
     pub hit_group_offset: vk::DeviceSize,
+    pub callable_entry_size: vk::DeviceSize,
+    pub callable_offset: vk::DeviceSize,
 }
 
 impl ShaderBindingTable {
     pub fn get(&self) -> vk::Buffer {
         self.sbt_buffer.get()
     }
+
+    // Null when the pipeline has no callable shaders, the region would be past the buffer
+    pub fn get_callable(&self) -> vk::Buffer {
+        if self.callable_entry_size > 0 {
+            self.sbt_buffer.get()
+        } else {
+            vk::Buffer::null()
+        }
+    }
 }
 
 pub struct ShaderBindingTableBuilder<'a> {
@@ -50,25 +59,36 @@ impl<'a> ShaderBindingTableBuilder<'a> {
             self.pipeline.hit_group_index,
             self.pipeline.shadow_hit_group_index,
         ];
+        let callable = &self.pipeline.callable_indices;
 
-        let prog_id_size = self.ray_tracing.get_properties().shader_group_handle_size;
-        let entry_size = (prog_id_size + (prog_id_size % 16)) as vk::DeviceSize;
+        let properties = self.ray_tracing.get_properties();
+        let prog_id_size = properties.shader_group_handle_size as vk::DeviceSize;
+        let base_alignment = properties.shader_group_base_alignment as vk::DeviceSize;
+        let entry_size = align_up(prog_id_size, 16);
 
         let ray_gen_entry_size = entry_size;
         let miss_entry_size = entry_size;
         let hit_group_entry_size = entry_size;
+        let callable_entry_size = if callable.is_empty() { 0 } else { entry_size };
 
-        let sbt_size = ray_gen_entry_size * ray_gen.len() as u64
-            + miss_entry_size * miss.len() as u64
-            + hit_group_entry_size * hit_group.len() as u64;
-
-        let sbt_buffer = BufferBuilder::new(self.context)
-            .with_type(BufferType::ShaderBindingTable)
-            .with_size(sbt_size)
-            .build()?;
-
-        let group_count = (ray_gen.len() + miss.len() + hit_group.len()) as u32;
-        let mut shader_handle_storage = vec![0u8; (group_count * prog_id_size) as usize];
+        // Each region has to start on the shader group base alignment
+        let ray_gen_offset = 0;
+        let miss_offset = align_up(
+            ray_gen_offset + ray_gen_entry_size * ray_gen.len() as vk::DeviceSize,
+            base_alignment,
+        );
+        let hit_group_offset = align_up(
+            miss_offset + miss_entry_size * miss.len() as vk::DeviceSize,
+            base_alignment,
+        );
+        let callable_offset = align_up(
+            hit_group_offset + hit_group_entry_size * hit_group.len() as vk::DeviceSize,
+            base_alignment,
+        );
+        let sbt_size = callable_offset + callable_entry_size * callable.len() as vk::DeviceSize;
+
+        let group_count = (ray_gen.len() + miss.len() + hit_group.len() + callable.len()) as u32;
+        let mut shader_handle_storage = vec![0u8; group_count as usize * prog_id_size as usize];
 
         self.ray_tracing.get_ray_tracing_shader_group_handles(
             self.pipeline.get(),
@@ -77,26 +97,27 @@ impl<'a> ShaderBindingTableBuilder<'a> {
             &mut shader_handle_storage,
         )?;
 
-        let data = self
-            .context
-            .get_device()
-            .map_memory(sbt_buffer.get_memory(), sbt_size)?;
-
-        unsafe {
-            std::ptr::copy(
-                shader_handle_storage.as_ptr() as *const c_void,
-                data,
-                sbt_size as usize,
-            );
+        let mut sbt_data = vec![0u8; sbt_size as usize];
+        let regions = [
+            (&ray_gen, ray_gen_offset, ray_gen_entry_size),
+            (&miss, miss_offset, miss_entry_size),
+            (&hit_group, hit_group_offset, hit_group_entry_size),
+            (callable, callable_offset, callable_entry_size),
+        ];
+        for (groups, offset, entry_size) in regions.iter() {
+            for (entry, &group) in groups.iter().enumerate() {
+                let src = group as usize * prog_id_size as usize;
+                let dst = (offset + entry_size * entry as vk::DeviceSize) as usize;
+                sbt_data[dst..dst + prog_id_size as usize]
+                    .copy_from_slice(&shader_handle_storage[src..src + prog_id_size as usize]);
+            }
         }
 
-        self.context
-            .get_device()
-            .unmap_memory(sbt_buffer.get_memory());
-
-        let ray_gen_offset = 0;
-        let miss_offset = ray_gen_entry_size * ray_gen.len() as vk::DeviceSize;
-        let hit_group_offset = miss_offset + miss_entry_size * miss.len() as vk::DeviceSize;
+        let sbt_buffer = DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::RAY_TRACING_NV | vk::BufferUsageFlags::TRANSFER_SRC)
+            .with_location(MemoryLocation::Host)
+            .with_data(&sbt_data)
+            .build()?;
 
         Ok(ShaderBindingTable {
             sbt_buffer,
@@ -106,6 +127,8 @@ impl<'a> ShaderBindingTableBuilder<'a> {
             miss_offset,
             hit_group_entry_size,
             hit_group_offset,
+            callable_entry_size,
+            callable_offset,
         })
     }
 }
@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const SHADER_DIRECTORY: &str = "assets/shaders";
+
+// Preprocessor defines a shader is compiled with. The build script compiles the permutations
+// listed in assets/shaders/permutations.txt next to the default one without defines, each to its
+// own SPIR-V file named after the define set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ShaderDefines {
+    // Sorted, so the same defines given in any order select the same file
+    defines: BTreeMap<String, Option<String>>,
+}
+
+impl ShaderDefines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_define(mut self, name: &str) -> Self {
+        self.defines.insert(name.to_string(), None);
+        self
+    }
+
+    pub fn with_value(mut self, name: &str, value: &str) -> Self {
+        self.defines
+            .insert(name.to_string(), Some(value.to_string()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defines.is_empty()
+    }
+
+    // Empty for the default permutation, otherwise the defines separated by dots, e.g.
+    // ".DEBUG_VIEW=2.NO_SHADOWS". build.rs names the files the same way.
+    pub fn get_key(&self) -> String {
+        self.defines
+            .iter()
+            .map(|(name, value)| match value {
+                Some(value) => format!(".{}={}", name, value),
+                None => format!(".{}", name),
+            })
+            .collect()
+    }
+
+    // SPIR-V of the permutation of a shader, the shader is named without its extension
+    pub fn get_path(&self, shader: &str) -> PathBuf {
+        Path::new(SHADER_DIRECTORY).join(format!("{}{}.spv", shader, self.get_key()))
+    }
+}
@@ -0,0 +1,75 @@
+use nalgebra_glm as glm;
+
+use crate::light::{Light, LightType};
+
+// Tilt of the path of the sun toward -Z, so that it is not straight overhead at noon
+const SUN_PATH_TILT: f32 = 0.6;
+// Wavelengths of the RGB channels, in micrometers
+const CHANNEL_WAVELENGTHS: [f32; 3] = [0.68, 0.55, 0.44];
+
+// Analytic daylight of Preetham et al. 1999, evaluated by the shaders for the rays that hit
+// nothing. The turbidity goes from about 2 for a clear sky to 10 for a hazy one, the intensity
+// scales the sky and the sun light alike.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sky {
+    // Toward the sun, the sky goes dark once it is below the horizon
+    pub sun_direction: glm::Vec3,
+    pub turbidity: f32,
+    pub intensity: f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky {
+            sun_direction: glm::normalize(&glm::vec3(5.0, 4.0, 3.0)),
+            turbidity: 3.0,
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Sky {
+    // For day-night cycles, the sun rises in +X at 6, is highest at 12 and sets in -X at 18
+    pub fn with_time_of_day(mut self, hours: f32) -> Self {
+        let angle = (hours - 6.0) / 24.0 * std::f32::consts::PI * 2.0;
+        self.sun_direction = glm::vec3(
+            angle.cos(),
+            angle.sin() * SUN_PATH_TILT.cos(),
+            -angle.sin() * SUN_PATH_TILT.sin(),
+        );
+        self
+    }
+
+    // Sunlight left after crossing the atmosphere, black once the sun has set
+    pub fn sun_color(&self) -> glm::Vec3 {
+        let direction = glm::normalize(&self.sun_direction);
+        if direction.y <= 0.0 {
+            return glm::vec3(0.0, 0.0, 0.0);
+        }
+
+        // Relative optical mass of Kasten and Young, the atmosphere is thicker toward the horizon
+        let zenith_angle = direction.y.acos().to_degrees();
+        let optical_mass =
+            1.0 / (direction.y + 0.15 * (93.885 - zenith_angle).max(0.001).powf(-1.253));
+        // Angstrom exponent and turbidity coefficient of the aerosols
+        let beta = 0.046_083_66 * self.turbidity - 0.045_860_26;
+
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        for (channel, &wavelength) in CHANNEL_WAVELENGTHS.iter().enumerate() {
+            let rayleigh = 0.008_735 * wavelength.powf(-4.08);
+            let aerosol = beta * wavelength.powf(-1.3);
+            color[channel] = (-optical_mass * (rayleigh + aerosol)).exp();
+        }
+        color
+    }
+
+    // The directional light standing for the sun
+    pub fn sun_light(&self) -> Light {
+        Light {
+            light_type: LightType::Directional,
+            position: glm::normalize(&self.sun_direction),
+            color: self.sun_color(),
+            intensity: self.intensity,
+        }
+    }
+}
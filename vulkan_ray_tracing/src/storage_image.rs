@@ -0,0 +1,223 @@
+use std::rc::Rc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use bytemuck::Pod;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::buffer::{DataBufferBuilder, MemoryLocation};
+use crate::image::{cmd_transition_layout, create_image, create_image_view, ImageLayoutTransition};
+
+// Image written by the ray tracing shaders, kept in the GENERAL layout
+pub struct StorageImage {
+    device: Rc<VulkanDevice>,
+    image: vk::Image,
+    image_view: vk::ImageView,
+    memory: vk::DeviceMemory,
+    format: vk::Format,
+    extent: vk::Extent2D,
+}
+
+impl Drop for StorageImage {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.get().destroy_image_view(self.image_view, None);
+            self.device.get().destroy_image(self.image, None);
+            self.device.get().free_memory(self.memory, None);
+        }
+    }
+}
+
+impl StorageImage {
+    pub fn get(&self) -> vk::Image {
+        self.image
+    }
+
+    pub fn get_image_view(&self) -> vk::ImageView {
+        self.image_view
+    }
+
+    pub fn get_format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn get_extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    // Copies a region back to the host, T has to match the texel format. Waits for the copy, so
+    // the last submitted frame is what gets read.
+    pub fn read_region<T: Pod>(
+        &self,
+        context: &VulkanContext,
+        region: vk::Rect2D,
+    ) -> Result<Vec<T>, VulkanError> {
+        let inside = region.offset.x >= 0
+            && region.offset.y >= 0
+            && region.offset.x as u32 + region.extent.width <= self.extent.width
+            && region.offset.y as u32 + region.extent.height <= self.extent.height;
+        if !inside {
+            return Err(VulkanError::PipelineError(format!(
+                "Region {:?} is out of an image of {:?}",
+                region, self.extent
+            )));
+        }
+
+        let mut texels = vec![T::zeroed(); (region.extent.width * region.extent.height) as usize];
+        if texels.is_empty() {
+            return Ok(texels);
+        }
+
+        let readback_buffer = DataBufferBuilder::new(context)
+            .with_usage(vk::BufferUsageFlags::TRANSFER_DST)
+            .with_location(MemoryLocation::Host)
+            .with_size(std::mem::size_of_val(texels.as_slice()) as vk::DeviceSize)
+            .build()?;
+
+        let command_buffer = context.begin_single_time_commands()?;
+        cmd_transition_layout(
+            &self.device,
+            command_buffer,
+            self.image,
+            1,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::GENERAL,
+                new_layout: vk::ImageLayout::GENERAL,
+                src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+                src_stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+                dst_stage: vk::PipelineStageFlags::TRANSFER,
+            },
+        );
+
+        let copy = vk::BufferImageCopy::builder()
+            .image_subresource(
+                vk::ImageSubresourceLayers::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(0)
+                    .base_array_layer(0)
+                    .layer_count(1)
+                    .build(),
+            )
+            .image_offset(vk::Offset3D {
+                x: region.offset.x,
+                y: region.offset.y,
+                z: 0,
+            })
+            .image_extent(vk::Extent3D {
+                width: region.extent.width,
+                height: region.extent.height,
+                depth: 1,
+            })
+            .build();
+        unsafe {
+            self.device.get().cmd_copy_image_to_buffer(
+                command_buffer,
+                self.image,
+                vk::ImageLayout::GENERAL,
+                readback_buffer.get(),
+                &[copy],
+            );
+        }
+
+        // The next frame writes the image again
+        cmd_transition_layout(
+            &self.device,
+            command_buffer,
+            self.image,
+            1,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::GENERAL,
+                new_layout: vk::ImageLayout::GENERAL,
+                src_access_mask: vk::AccessFlags::TRANSFER_READ,
+                dst_access_mask: vk::AccessFlags::SHADER_WRITE,
+                src_stage: vk::PipelineStageFlags::TRANSFER,
+                dst_stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+            },
+        );
+        context.end_single_time_commands(command_buffer)?;
+
+        readback_buffer.read_data_at(0, bytemuck::cast_slice_mut(&mut texels))?;
+        Ok(texels)
+    }
+}
+
+pub struct StorageImageBuilder<'a> {
+    context: &'a VulkanContext,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    usage: vk::ImageUsageFlags,
+}
+
+impl<'a> StorageImageBuilder<'a> {
+    pub fn new(context: &'a VulkanContext) -> Self {
+        StorageImageBuilder {
+            context,
+            format: vk::Format::R8G8B8A8_UNORM,
+            extent: vk::Extent2D::default(),
+            usage: vk::ImageUsageFlags::STORAGE,
+        }
+    }
+
+    pub fn with_format(mut self, format: vk::Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_extent(mut self, extent: vk::Extent2D) -> Self {
+        self.extent = extent;
+        self
+    }
+
+    pub fn with_usage(mut self, usage: vk::ImageUsageFlags) -> Self {
+        self.usage |= usage;
+        self
+    }
+
+    pub fn build(self) -> Result<StorageImage, VulkanError> {
+        let device = Rc::clone(self.context.get_device());
+
+        let (image, memory) =
+            create_image(self.context, self.format, self.extent, 1, 1, self.usage)?;
+
+        // Owns the image and memory from here, so errors below clean up through Drop
+        let mut storage_image = StorageImage {
+            device: Rc::clone(&device),
+            image,
+            image_view: vk::ImageView::null(),
+            memory,
+            format: self.format,
+            extent: self.extent,
+        };
+
+        storage_image.image_view = create_image_view(
+            &device,
+            image,
+            self.format,
+            vk::ImageViewType::TYPE_2D,
+            1,
+            1,
+        )?;
+
+        let command_buffer = self.context.begin_single_time_commands()?;
+        cmd_transition_layout(
+            &device,
+            command_buffer,
+            image,
+            1,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::UNDEFINED,
+                new_layout: vk::ImageLayout::GENERAL,
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_access_mask: vk::AccessFlags::SHADER_WRITE,
+                src_stage: vk::PipelineStageFlags::TOP_OF_PIPE,
+                dst_stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+            },
+        );
+        self.context.end_single_time_commands(command_buffer)?;
+
+        Ok(storage_image)
+    }
+}
@@ -0,0 +1,309 @@
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use nalgebra_glm as glm;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::buffer::{DataBuffer, DataBufferBuilder, MemoryLocation};
+use crate::compute_pipeline::{ComputePipeline, ComputePipelineBuilder};
+use crate::descriptor_allocator::DescriptorAllocator;
+use crate::image::{cmd_transition_layout, ImageLayoutTransition};
+use crate::texture::{Texture, TextureBuilder};
+
+// Glyphs the buffers of the frame slots hold at first, they grow with the text
+const INITIAL_GLYPH_CAPACITY: usize = 256;
+
+// Bitmap font of fixed size cells, laid out in a grid of columns by rows. The cells hold the
+// consecutive characters from first_char on, e.g. the printable ASCII characters from ' '.
+#[derive(Clone, Debug)]
+pub struct FontAtlas {
+    pub width: u32,
+    pub height: u32,
+    // Coverage of the glyphs, one byte per pixel
+    pub pixels: Vec<u8>,
+    pub columns: u32,
+    pub rows: u32,
+    pub first_char: char,
+}
+
+impl FontAtlas {
+    pub fn cell_size(&self) -> (u32, u32) {
+        (
+            self.width / self.columns.max(1),
+            self.height / self.rows.max(1),
+        )
+    }
+
+    // Characters outside of the atlas are skipped
+    fn cell(&self, character: char) -> Option<u32> {
+        let index = (character as u32).checked_sub(self.first_char as u32)?;
+        if index < self.columns * self.rows {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+// Text drawn over the traced image, position is the top left corner in pixels of the back
+// buffer. A new line starts at every '\n', scale multiplies the size of the cells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextLabel {
+    pub text: String,
+    pub position: (i32, i32),
+    pub color: glm::Vec4,
+    pub scale: u32,
+}
+
+impl TextLabel {
+    pub fn new(text: &str, position: (i32, i32)) -> Self {
+        TextLabel {
+            text: text.to_string(),
+            position,
+            color: glm::vec4(1.0, 1.0, 1.0, 1.0),
+            scale: 1,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Glyph {
+    position: [i32; 2],
+    cell: u32,
+    scale: u32,
+    color: [f32; 4],
+}
+
+unsafe impl Zeroable for Glyph {}
+unsafe impl Pod for Glyph {}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TextPushConstants {
+    cell_size: [u32; 2],
+    columns: u32,
+    glyph_count: u32,
+}
+
+unsafe impl Zeroable for TextPushConstants {}
+unsafe impl Pod for TextPushConstants {}
+
+// Compute pass drawn into the back buffer once the frame is traced, one workgroup per glyph
+// blends the coverage of its cell over the image. Does not need any raster pipeline, so the
+// stats of the engine can be shown on screen without a UI layer.
+pub(crate) struct TextOverlay {
+    pipeline: ComputePipeline,
+    atlas: Texture,
+    cell_size: (u32, u32),
+    font: FontAtlas,
+    // Glyphs of each frame slot, written once the fence of the slot was waited on
+    glyph_buffers: Vec<DataBuffer>,
+    glyph_counts: Vec<u32>,
+}
+
+impl TextOverlay {
+    pub fn new(
+        context: &VulkanContext,
+        descriptor_allocator: &Rc<DescriptorAllocator>,
+        font: &FontAtlas,
+        frame_count: usize,
+    ) -> Result<Self, VulkanError> {
+        let (cell_width, cell_height) = font.cell_size();
+        if cell_width == 0 || cell_height == 0 {
+            return Err(VulkanError::PipelineError(format!(
+                "A font atlas of {}x{} cannot hold {}x{} glyphs",
+                font.width, font.height, font.columns, font.rows
+            )));
+        }
+
+        // White texels with the coverage in alpha, the color comes from the labels
+        let pixels: Vec<u8> = font
+            .pixels
+            .iter()
+            .flat_map(|&coverage| vec![255, 255, 255, coverage])
+            .collect();
+        let atlas = TextureBuilder::new(context)
+            .with_width(font.width)
+            .with_height(font.height)
+            .with_srgb(false)
+            .with_pixels(&pixels)
+            .build()?;
+
+        let pipeline = ComputePipelineBuilder::new(context, descriptor_allocator)
+            .with_shader(Path::new("assets/shaders/text_overlay.spv"))
+            .with_binding(0, vk::DescriptorType::STORAGE_IMAGE)
+            .with_binding(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .with_binding(2, vk::DescriptorType::STORAGE_BUFFER)
+            .with_binding(3, vk::DescriptorType::UNIFORM_BUFFER)
+            .with_push_constant_size(mem::size_of::<TextPushConstants>() as u32)
+            .build()?;
+
+        let glyph_buffers = (0..frame_count)
+            .map(|_| create_glyph_buffer(context, INITIAL_GLYPH_CAPACITY))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(TextOverlay {
+            pipeline,
+            atlas,
+            cell_size: (cell_width, cell_height),
+            font: font.clone(),
+            glyph_buffers,
+            glyph_counts: vec![0; frame_count],
+        })
+    }
+
+    // The frame that used the slot last has to be done
+    pub fn upload(
+        &mut self,
+        context: &VulkanContext,
+        frame_slot: usize,
+        labels: &[TextLabel],
+    ) -> Result<(), VulkanError> {
+        let glyphs = self.layout(labels);
+        let capacity = (self.glyph_buffers[frame_slot].size() as usize) / mem::size_of::<Glyph>();
+        if glyphs.len() > capacity {
+            self.glyph_buffers[frame_slot] =
+                create_glyph_buffer(context, glyphs.len().next_power_of_two())?;
+        }
+
+        if !glyphs.is_empty() {
+            self.glyph_buffers[frame_slot].copy_data_at(0, bytemuck::cast_slice(&glyphs))?;
+        }
+        self.glyph_counts[frame_slot] = glyphs.len() as u32;
+        Ok(())
+    }
+
+    fn layout(&self, labels: &[TextLabel]) -> Vec<Glyph> {
+        let (cell_width, cell_height) = self.cell_size;
+        let mut glyphs = vec![];
+        for label in labels.iter() {
+            let scale = label.scale.max(1);
+            let (mut x, mut y) = label.position;
+            for character in label.text.chars() {
+                if character == '\n' {
+                    x = label.position.0;
+                    y += (cell_height * scale) as i32;
+                    continue;
+                }
+                // Spaces and missing characters still move the pen
+                if let Some(cell) = self.font.cell(character).filter(|_| character != ' ') {
+                    glyphs.push(Glyph {
+                        position: [x, y],
+                        cell,
+                        scale,
+                        color: label.color.into(),
+                    });
+                }
+                x += (cell_width * scale) as i32;
+            }
+        }
+        glyphs
+    }
+
+    // Recorded outside of a render pass, the back buffer is in PRESENT_SRC_KHR and is left in it
+    pub fn cmd_draw(
+        &self,
+        context: &VulkanContext,
+        command_buffer: vk::CommandBuffer,
+        back_buffer: vk::Image,
+        back_buffer_view: vk::ImageView,
+        // The frame constants, for the resolution
+        frame_buffer: vk::Buffer,
+        frame_slot: usize,
+    ) -> Result<(), VulkanError> {
+        let glyph_count = self.glyph_counts[frame_slot];
+        if glyph_count == 0 {
+            return Ok(());
+        }
+
+        let descriptor_set = self.pipeline.allocate_transient_set()?;
+        self.pipeline.update_set_image(
+            descriptor_set,
+            0,
+            vk::DescriptorType::STORAGE_IMAGE,
+            back_buffer_view,
+            vk::Sampler::null(),
+            vk::ImageLayout::GENERAL,
+        );
+        self.pipeline.update_set_image(
+            descriptor_set,
+            1,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            self.atlas.get_image_view(),
+            self.atlas.get_sampler(),
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        self.pipeline.update_set_buffer(
+            descriptor_set,
+            2,
+            vk::DescriptorType::STORAGE_BUFFER,
+            self.glyph_buffers[frame_slot].get(),
+        );
+        self.pipeline.update_set_buffer(
+            descriptor_set,
+            3,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            frame_buffer,
+        );
+
+        let device = context.get_device();
+        cmd_transition_layout(
+            device,
+            command_buffer,
+            back_buffer,
+            1,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                new_layout: vk::ImageLayout::GENERAL,
+                src_access_mask: vk::AccessFlags::MEMORY_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                src_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+                dst_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+            },
+        );
+
+        let push_constants = TextPushConstants {
+            cell_size: [self.cell_size.0, self.cell_size.1],
+            columns: self.font.columns,
+            glyph_count,
+        };
+        self.pipeline.cmd_dispatch_with_set(
+            command_buffer,
+            descriptor_set,
+            bytemuck::bytes_of(&push_constants),
+            [glyph_count, 1, 1],
+        );
+
+        cmd_transition_layout(
+            device,
+            command_buffer,
+            back_buffer,
+            1,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::GENERAL,
+                new_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                dst_access_mask: vk::AccessFlags::MEMORY_READ,
+                src_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+                dst_stage: vk::PipelineStageFlags::ALL_COMMANDS,
+            },
+        );
+        Ok(())
+    }
+}
+
+fn create_glyph_buffer(
+    context: &VulkanContext,
+    capacity: usize,
+) -> Result<DataBuffer, VulkanError> {
+    DataBufferBuilder::new(context)
+        .with_usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .with_location(MemoryLocation::Host)
+        .with_size((capacity * mem::size_of::<Glyph>()) as vk::DeviceSize)
+        .build()
+}
@@ -0,0 +1,599 @@
+use std::rc::Rc;
+
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::buffer::{DataBufferBuilder, MemoryLocation};
+use crate::image::{
+    cmd_transition_subresource, create_image, create_swizzled_image_view, mip_subresource_range,
+    ImageLayoutTransition,
+};
+use crate::transient_commands::UploadBatch;
+
+// Block compressed formats, for textures compressed before the upload. The pixels hold the whole
+// mip chain, every level rounded up to 4x4 blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureCompression {
+    // RGB in 8 bytes per block
+    Bc1,
+    // A single channel in 8 bytes per block, sampled as gray. There is no sRGB variant.
+    Bc4,
+    // RGBA in 16 bytes per block
+    Bc7,
+}
+
+impl TextureCompression {
+    pub fn block_size(self) -> usize {
+        match self {
+            TextureCompression::Bc1 | TextureCompression::Bc4 => 8,
+            TextureCompression::Bc7 => 16,
+        }
+    }
+
+    pub fn format(self, srgb: bool) -> vk::Format {
+        match (self, srgb) {
+            (TextureCompression::Bc1, true) => vk::Format::BC1_RGB_SRGB_BLOCK,
+            (TextureCompression::Bc1, false) => vk::Format::BC1_RGB_UNORM_BLOCK,
+            (TextureCompression::Bc4, _) => vk::Format::BC4_UNORM_BLOCK,
+            (TextureCompression::Bc7, true) => vk::Format::BC7_SRGB_BLOCK,
+            (TextureCompression::Bc7, false) => vk::Format::BC7_UNORM_BLOCK,
+        }
+    }
+
+    fn from_format(format: vk::Format) -> Option<Self> {
+        match format {
+            vk::Format::BC1_RGB_SRGB_BLOCK | vk::Format::BC1_RGB_UNORM_BLOCK => {
+                Some(TextureCompression::Bc1)
+            }
+            vk::Format::BC4_UNORM_BLOCK => Some(TextureCompression::Bc4),
+            vk::Format::BC7_SRGB_BLOCK | vk::Format::BC7_UNORM_BLOCK => {
+                Some(TextureCompression::Bc7)
+            }
+            _ => None,
+        }
+    }
+
+    // Whether the device can sample and filter every format of the compression
+    pub fn is_supported(self, context: &VulkanContext) -> bool {
+        [self.format(true), self.format(false)]
+            .iter()
+            .all(|&format| {
+                let properties = unsafe {
+                    context
+                        .get_instance()
+                        .get()
+                        .get_physical_device_format_properties(
+                            context.get_physical_device().get(),
+                            format,
+                        )
+                };
+                properties.optimal_tiling_features.contains(
+                    vk::FormatFeatureFlags::SAMPLED_IMAGE
+                        | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+                )
+            })
+    }
+}
+
+// Levels down to 1x1
+pub fn full_mip_levels(width: u32, height: u32) -> u32 {
+    32 - width.max(height).leading_zeros()
+}
+
+// Bytes of one level of one layer of a block compressed image
+pub fn compressed_level_size(
+    compression: TextureCompression,
+    width: u32,
+    height: u32,
+    mip_level: u32,
+) -> usize {
+    let blocks_x = ((width >> mip_level).max(1) as usize).div_ceil(4);
+    let blocks_y = ((height >> mip_level).max(1) as usize).div_ceil(4);
+    blocks_x * blocks_y * compression.block_size()
+}
+
+// Sampled RGBA texture, color textures are sRGB so the sampler returns linear values
+pub struct Texture {
+    device: Rc<VulkanDevice>,
+    image: vk::Image,
+    image_view: vk::ImageView,
+    sampler: vk::Sampler,
+    memory: vk::DeviceMemory,
+    format: vk::Format,
+    mip_levels: u32,
+    array_layers: u32,
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.get().destroy_sampler(self.sampler, None);
+            self.device.get().destroy_image_view(self.image_view, None);
+            self.device.get().destroy_image(self.image, None);
+            self.device.get().free_memory(self.memory, None);
+        }
+    }
+}
+
+impl Texture {
+    pub fn get(&self) -> vk::Image {
+        self.image
+    }
+
+    pub fn get_image_view(&self) -> vk::ImageView {
+        self.image_view
+    }
+
+    pub fn get_sampler(&self) -> vk::Sampler {
+        self.sampler
+    }
+
+    pub fn get_format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn get_array_layers(&self) -> u32 {
+        self.array_layers
+    }
+
+    pub fn get_mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    // Size of the image allocation, with the padding the driver added
+    pub fn get_memory_size(&self) -> vk::DeviceSize {
+        unsafe { self.device.get().get_image_memory_requirements(self.image) }.size
+    }
+}
+
+pub struct TextureBuilder<'a> {
+    context: &'a VulkanContext,
+    width: u32,
+    height: u32,
+    pixels: &'a [u8],
+    format: vk::Format,
+    array_layers: u32,
+    mipmaps: bool,
+    upload_batch: Option<&'a UploadBatch<'a>>,
+}
+
+impl<'a> TextureBuilder<'a> {
+    pub fn new(context: &'a VulkanContext) -> Self {
+        TextureBuilder {
+            context,
+            width: 0,
+            height: 0,
+            pixels: &[],
+            format: vk::Format::R8G8B8A8_SRGB,
+            array_layers: 1,
+            mipmaps: false,
+            upload_batch: None,
+        }
+    }
+
+    pub fn with_width(mut self, width: u32) -> Self {
+        self.width = width;
+        self
+    }
+
+    pub fn with_height(mut self, height: u32) -> Self {
+        self.height = height;
+        self
+    }
+
+    // Tightly packed pixels of the format, one layer after the other. Block compressed formats
+    // hold every level of the mip chain, the layers of a level before the next level.
+    pub fn with_pixels(mut self, pixels: &'a [u8]) -> Self {
+        self.pixels = pixels;
+        self
+    }
+
+    // Albedo and emission maps are sRGB, data maps like normals or roughness are not
+    pub fn with_srgb(mut self, srgb: bool) -> Self {
+        self.format = if srgb {
+            vk::Format::R8G8B8A8_SRGB
+        } else {
+            vk::Format::R8G8B8A8_UNORM
+        };
+        self
+    }
+
+    pub fn with_format(mut self, format: vk::Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_array_layers(mut self, array_layers: u32) -> Self {
+        self.array_layers = array_layers;
+        self
+    }
+
+    // The full mip chain is filtered down from the pixels with blits, for the hit shaders to pick
+    // a level from the ray cone. Formats without linear blits keep a single level.
+    pub fn with_mipmaps(mut self, mipmaps: bool) -> Self {
+        self.mipmaps = mipmaps;
+        self
+    }
+
+    // The copy and layout transitions are recorded into the batch instead of waiting on their own
+    pub fn with_upload_batch(mut self, upload_batch: &'a UploadBatch<'a>) -> Self {
+        self.upload_batch = Some(upload_batch);
+        self
+    }
+
+    pub fn build(self) -> Result<Texture, VulkanError> {
+        let _span = tracing::info_span!("upload_texture", width = self.width, height = self.height)
+            .entered();
+        let compression = TextureCompression::from_format(self.format);
+        let expected_size = match compression {
+            Some(compression) => {
+                (0..full_mip_levels(self.width, self.height))
+                    .map(|level| compressed_level_size(compression, self.width, self.height, level))
+                    .sum::<usize>()
+                    * self.array_layers as usize
+            }
+            None => {
+                self.width as usize
+                    * self.height as usize
+                    * self.array_layers as usize
+                    * texel_size(self.format)
+            }
+        };
+        if self.pixels.len() != expected_size || expected_size == 0 {
+            return Err(VulkanError::PipelineError(format!(
+                "Texture of {}x{}x{} needs {} bytes of pixels, got {}",
+                self.width,
+                self.height,
+                self.array_layers,
+                expected_size,
+                self.pixels.len()
+            )));
+        }
+
+        let device = Rc::clone(self.context.get_device());
+        let extent = vk::Extent2D {
+            width: self.width,
+            height: self.height,
+        };
+
+        // Compressed images cannot be blitted to, their levels come with the pixels
+        let mip_levels = if compression.is_some()
+            || (self.mipmaps && supports_linear_blit(self.context, self.format))
+        {
+            full_mip_levels(self.width, self.height)
+        } else {
+            1
+        };
+        let mut usage = vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED;
+        if mip_levels > 1 && compression.is_none() {
+            usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        }
+
+        let (image, memory) = create_image(
+            self.context,
+            self.format,
+            extent,
+            mip_levels,
+            self.array_layers,
+            usage,
+        )?;
+
+        // Owns the image and memory from here, so errors below clean up through Drop
+        let mut texture = Texture {
+            device: Rc::clone(&device),
+            image,
+            image_view: vk::ImageView::null(),
+            sampler: vk::Sampler::null(),
+            memory,
+            format: self.format,
+            mip_levels,
+            array_layers: self.array_layers,
+        };
+
+        match compression {
+            Some(compression) => {
+                self.upload_compressed(&device, image, extent, mip_levels, compression)?
+            }
+            None => self.upload(&device, image, extent, mip_levels)?,
+        }
+
+        // Single channel images are read as gray by the shaders
+        let components = if compression == Some(TextureCompression::Bc4) {
+            vk::ComponentMapping {
+                r: vk::ComponentSwizzle::R,
+                g: vk::ComponentSwizzle::R,
+                b: vk::ComponentSwizzle::R,
+                a: vk::ComponentSwizzle::ONE,
+            }
+        } else {
+            vk::ComponentMapping::default()
+        };
+        // Always an array view, so single images and texture arrays share the sampler2DArray binding
+        texture.image_view = create_swizzled_image_view(
+            &device,
+            image,
+            self.format,
+            vk::ImageViewType::TYPE_2D_ARRAY,
+            mip_levels,
+            self.array_layers,
+            components,
+        )?;
+        texture.sampler = create_sampler(&device, mip_levels)?;
+
+        Ok(texture)
+    }
+
+    fn upload(
+        &self,
+        device: &VulkanDevice,
+        image: vk::Image,
+        extent: vk::Extent2D,
+        mip_levels: u32,
+    ) -> Result<(), VulkanError> {
+        let staging_buffer = DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .with_location(MemoryLocation::Host)
+            .with_data(self.pixels)
+            .build()?;
+
+        let command_buffer = match self.upload_batch {
+            Some(upload_batch) => upload_batch.get_command_buffer()?,
+            None => self.context.begin_single_time_commands()?,
+        };
+        cmd_transition_subresource(
+            device,
+            command_buffer,
+            image,
+            mip_subresource_range(0, mip_levels, self.array_layers),
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::UNDEFINED,
+                new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                src_stage: vk::PipelineStageFlags::TOP_OF_PIPE,
+                dst_stage: vk::PipelineStageFlags::TRANSFER,
+            },
+        );
+
+        let region = vk::BufferImageCopy::builder()
+            .image_subresource(self.mip_layers(0))
+            .image_extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .build();
+        unsafe {
+            device.get().cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.get(),
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+            );
+        }
+
+        // Every level is blitted from the previous one, which is then done
+        for level in 1..mip_levels {
+            cmd_transition_subresource(
+                device,
+                command_buffer,
+                image,
+                mip_subresource_range(level - 1, 1, self.array_layers),
+                ImageLayoutTransition {
+                    old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    new_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    dst_access_mask: vk::AccessFlags::TRANSFER_READ,
+                    src_stage: vk::PipelineStageFlags::TRANSFER,
+                    dst_stage: vk::PipelineStageFlags::TRANSFER,
+                },
+            );
+
+            let blit = vk::ImageBlit::builder()
+                .src_subresource(self.mip_layers(level - 1))
+                .src_offsets([vk::Offset3D::default(), mip_offset(extent, level - 1)])
+                .dst_subresource(self.mip_layers(level))
+                .dst_offsets([vk::Offset3D::default(), mip_offset(extent, level)])
+                .build();
+            unsafe {
+                device.get().cmd_blit_image(
+                    command_buffer,
+                    image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[blit],
+                    vk::Filter::LINEAR,
+                );
+            }
+
+            cmd_transition_subresource(
+                device,
+                command_buffer,
+                image,
+                mip_subresource_range(level - 1, 1, self.array_layers),
+                ImageLayoutTransition {
+                    old_layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    src_access_mask: vk::AccessFlags::TRANSFER_READ,
+                    dst_access_mask: vk::AccessFlags::SHADER_READ,
+                    src_stage: vk::PipelineStageFlags::TRANSFER,
+                    dst_stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+                },
+            );
+        }
+
+        cmd_transition_subresource(
+            device,
+            command_buffer,
+            image,
+            mip_subresource_range(mip_levels - 1, 1, self.array_layers),
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                src_stage: vk::PipelineStageFlags::TRANSFER,
+                dst_stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+            },
+        );
+
+        match self.upload_batch {
+            Some(upload_batch) => {
+                upload_batch.keep_staging_buffer(staging_buffer);
+                Ok(())
+            }
+            None => self.context.end_single_time_commands(command_buffer),
+        }
+    }
+
+    // One copy region per level, all of them in a single copy command
+    fn upload_compressed(
+        &self,
+        device: &VulkanDevice,
+        image: vk::Image,
+        extent: vk::Extent2D,
+        mip_levels: u32,
+        compression: TextureCompression,
+    ) -> Result<(), VulkanError> {
+        let staging_buffer = DataBufferBuilder::new(self.context)
+            .with_usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .with_location(MemoryLocation::Host)
+            .with_data(self.pixels)
+            .build()?;
+
+        let command_buffer = match self.upload_batch {
+            Some(upload_batch) => upload_batch.get_command_buffer()?,
+            None => self.context.begin_single_time_commands()?,
+        };
+        let subresource_range = mip_subresource_range(0, mip_levels, self.array_layers);
+        cmd_transition_subresource(
+            device,
+            command_buffer,
+            image,
+            subresource_range,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::UNDEFINED,
+                new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                src_stage: vk::PipelineStageFlags::TOP_OF_PIPE,
+                dst_stage: vk::PipelineStageFlags::TRANSFER,
+            },
+        );
+
+        let mut regions = vec![];
+        let mut offset = 0;
+        for level in 0..mip_levels {
+            let level_extent = mip_offset(extent, level);
+            regions.push(
+                vk::BufferImageCopy::builder()
+                    .buffer_offset(offset as vk::DeviceSize)
+                    .image_subresource(self.mip_layers(level))
+                    .image_extent(vk::Extent3D {
+                        width: level_extent.x as u32,
+                        height: level_extent.y as u32,
+                        depth: 1,
+                    })
+                    .build(),
+            );
+            offset += compressed_level_size(compression, extent.width, extent.height, level)
+                * self.array_layers as usize;
+        }
+        unsafe {
+            device.get().cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.get(),
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            );
+        }
+
+        cmd_transition_subresource(
+            device,
+            command_buffer,
+            image,
+            subresource_range,
+            ImageLayoutTransition {
+                old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                src_stage: vk::PipelineStageFlags::TRANSFER,
+                dst_stage: vk::PipelineStageFlags::RAY_TRACING_SHADER_NV,
+            },
+        );
+
+        match self.upload_batch {
+            Some(upload_batch) => {
+                upload_batch.keep_staging_buffer(staging_buffer);
+                Ok(())
+            }
+            None => self.context.end_single_time_commands(command_buffer),
+        }
+    }
+
+    fn mip_layers(&self, mip_level: u32) -> vk::ImageSubresourceLayers {
+        vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(mip_level)
+            .base_array_layer(0)
+            .layer_count(self.array_layers)
+            .build()
+    }
+}
+
+// Far corner of a mip level
+fn mip_offset(extent: vk::Extent2D, mip_level: u32) -> vk::Offset3D {
+    vk::Offset3D {
+        x: (extent.width >> mip_level).max(1) as i32,
+        y: (extent.height >> mip_level).max(1) as i32,
+        z: 1,
+    }
+}
+
+fn supports_linear_blit(context: &VulkanContext, format: vk::Format) -> bool {
+    let properties = unsafe {
+        context
+            .get_instance()
+            .get()
+            .get_physical_device_format_properties(context.get_physical_device().get(), format)
+    };
+    properties.optimal_tiling_features.contains(
+        vk::FormatFeatureFlags::BLIT_SRC
+            | vk::FormatFeatureFlags::BLIT_DST
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+    )
+}
+
+// Bytes per texel of the RGBA formats the textures are created with
+fn texel_size(format: vk::Format) -> usize {
+    match format {
+        vk::Format::R16G16B16A16_SFLOAT => 8,
+        vk::Format::R32G32B32A32_SFLOAT => 16,
+        _ => 4,
+    }
+}
+
+pub(crate) fn create_sampler(
+    device: &VulkanDevice,
+    mip_levels: u32,
+) -> Result<vk::Sampler, VulkanError> {
+    let sampler_info = vk::SamplerCreateInfo::builder()
+        .mag_filter(vk::Filter::LINEAR)
+        .min_filter(vk::Filter::LINEAR)
+        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+        .address_mode_u(vk::SamplerAddressMode::REPEAT)
+        .address_mode_v(vk::SamplerAddressMode::REPEAT)
+        .address_mode_w(vk::SamplerAddressMode::REPEAT)
+        .max_lod((mip_levels - 1) as f32)
+        .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
+        .build();
+    unsafe { device.get().create_sampler(&sampler_info, None) }
+        .map_err(|err| VulkanError::PipelineError(err.to_string()))
+}
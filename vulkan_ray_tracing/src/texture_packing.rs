@@ -0,0 +1,194 @@
+use nalgebra_glm as glm;
+
+use crate::geometry_instance::ImageBuffer;
+
+// Minimum maxImageArrayLayers guaranteed by the spec
+const MAX_ARRAY_LAYERS: usize = 256;
+// Atlases bigger than this are not worth it, the images are kept as separate textures
+const MAX_ATLAS_SIZE: u32 = 4096;
+// Empty texels between atlas entries, to limit bleeding from linear filtering
+const ATLAS_PADDING: u32 = 1;
+
+// Where an input image ended up
+#[derive(Clone, Copy)]
+pub(crate) struct TexturePlacement {
+    pub texture: usize,
+    pub layer: u32,
+    // UV offset in xy and scale in zw
+    pub rect: glm::Vec4,
+}
+
+pub(crate) struct PackedTexture {
+    pub image: ImageBuffer,
+    pub array_layers: u32,
+}
+
+// Same sized images become layers of a texture array, the others share an atlas.
+// sRGB and linear images are never packed together as they need different formats, compressed
+// images are not packed at all.
+pub(crate) fn pack_textures(
+    images: Vec<ImageBuffer>,
+) -> (Vec<PackedTexture>, Vec<TexturePlacement>) {
+    let mut textures = vec![];
+    let mut placements = vec![None; images.len()];
+
+    let mut images: Vec<Option<ImageBuffer>> = images.into_iter().map(Some).collect();
+    for srgb in &[true, false] {
+        let group: Vec<usize> = (0..images.len())
+            .filter(|&index| {
+                images[index]
+                    .as_ref()
+                    .map(|image| (image.srgb, image.compression))
+                    == Some((*srgb, None))
+            })
+            .collect();
+        if group.is_empty() {
+            continue;
+        }
+
+        let first = images[group[0]].as_ref().unwrap();
+        let (width, height) = (first.tex_width, first.tex_height);
+        let same_size = group.iter().all(|&index| {
+            let image = images[index].as_ref().unwrap();
+            image.tex_width == width && image.tex_height == height
+        });
+
+        if same_size {
+            for layers in group.chunks(MAX_ARRAY_LAYERS) {
+                let mut pixels = vec![];
+                for (layer, &index) in layers.iter().enumerate() {
+                    pixels.extend_from_slice(&images[index].take().unwrap().pixels);
+                    placements[index] = Some(TexturePlacement {
+                        texture: textures.len(),
+                        layer: layer as u32,
+                        rect: glm::vec4(0.0, 0.0, 1.0, 1.0),
+                    });
+                }
+                textures.push(PackedTexture {
+                    image: ImageBuffer {
+                        pixels,
+                        tex_width: width,
+                        tex_height: height,
+                        tex_channels: 4,
+                        srgb: *srgb,
+                        compression: None,
+                    },
+                    array_layers: layers.len() as u32,
+                });
+            }
+        } else if let Some(atlas) = pack_atlas(&images, &group, *srgb) {
+            for (&index, rect) in group.iter().zip(atlas.rects) {
+                images[index] = None;
+                placements[index] = Some(TexturePlacement {
+                    texture: textures.len(),
+                    layer: 0,
+                    rect,
+                });
+            }
+            textures.push(PackedTexture {
+                image: atlas.image,
+                array_layers: 1,
+            });
+        }
+    }
+
+    // Whatever could not be packed keeps its own texture
+    for (index, image) in images.into_iter().enumerate() {
+        if let Some(image) = image {
+            placements[index] = Some(TexturePlacement {
+                texture: textures.len(),
+                layer: 0,
+                rect: glm::vec4(0.0, 0.0, 1.0, 1.0),
+            });
+            textures.push(PackedTexture {
+                image,
+                array_layers: 1,
+            });
+        }
+    }
+
+    let placements = placements.into_iter().map(Option::unwrap).collect();
+    (textures, placements)
+}
+
+struct Atlas {
+    image: ImageBuffer,
+    // In the order of the group
+    rects: Vec<glm::Vec4>,
+}
+
+// Shelf packing, tallest images first
+fn pack_atlas(images: &[Option<ImageBuffer>], group: &[usize], srgb: bool) -> Option<Atlas> {
+    let size = |index: usize| {
+        let image = images[index].as_ref().unwrap();
+        (
+            image.tex_width + ATLAS_PADDING,
+            image.tex_height + ATLAS_PADDING,
+        )
+    };
+
+    let area: u32 = group
+        .iter()
+        .map(|&index| size(index).0 * size(index).1)
+        .sum();
+    let widest = group.iter().map(|&index| size(index).0).max()?;
+    let atlas_width = ((area as f32).sqrt().ceil() as u32)
+        .next_power_of_two()
+        .max(widest);
+
+    let mut order = group.to_vec();
+    order.sort_by_key(|&index| std::cmp::Reverse(size(index).1));
+
+    let mut origins = vec![(0, 0); images.len()];
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+    for &index in &order {
+        let (width, height) = size(index);
+        if x + width > atlas_width {
+            x = 0;
+            y += shelf_height;
+            shelf_height = 0;
+        }
+        origins[index] = (x, y);
+        x += width;
+        shelf_height = shelf_height.max(height);
+    }
+    let atlas_height = y + shelf_height;
+
+    if atlas_width > MAX_ATLAS_SIZE || atlas_height > MAX_ATLAS_SIZE {
+        return None;
+    }
+
+    let row_size = atlas_width as usize * 4;
+    let mut pixels = vec![0; row_size * atlas_height as usize];
+    let mut rects = vec![];
+    for &index in group {
+        let image = images[index].as_ref().unwrap();
+        let (x, y) = origins[index];
+        let image_row_size = image.tex_width as usize * 4;
+        for row in 0..image.tex_height as usize {
+            let dst = (y as usize + row) * row_size + x as usize * 4;
+            let src = row * image_row_size;
+            pixels[dst..dst + image_row_size]
+                .copy_from_slice(&image.pixels[src..src + image_row_size]);
+        }
+
+        rects.push(glm::vec4(
+            x as f32 / atlas_width as f32,
+            y as f32 / atlas_height as f32,
+            image.tex_width as f32 / atlas_width as f32,
+            image.tex_height as f32 / atlas_height as f32,
+        ));
+    }
+
+    Some(Atlas {
+        image: ImageBuffer {
+            pixels,
+            tex_width: atlas_width,
+            tex_height: atlas_height,
+            tex_channels: 4,
+            srgb,
+            compression: None,
+        },
+        rects,
+    })
+}
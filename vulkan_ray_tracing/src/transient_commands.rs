@@ -0,0 +1,332 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+use vulkan_bootstrap::device::VulkanDevice;
+use vulkan_bootstrap::errors::VulkanError;
+use vulkan_bootstrap::vulkan_context::VulkanContext;
+
+use crate::barrier_commands::{BarrierCommands, DependencyInfo, MemoryBarrier2};
+use crate::buffer::DataBuffer;
+
+struct TransientFrame {
+    pool: vk::CommandPool,
+    // Allocated from the pool once, reused in order after every reset
+    command_buffers: Vec<vk::CommandBuffer>,
+    used_command_buffers: usize,
+    fences: Vec<vk::Fence>,
+    used_fences: usize,
+    // Kept until the commands that read them are done
+    resources: Vec<Box<dyn Any>>,
+}
+
+// Short command buffers submitted without waiting for the queue to be idle. Every frame in
+// flight has its own command pool, reset at once when the frame slot comes back.
+pub struct TransientCommands {
+    device: Rc<VulkanDevice>,
+    queue: vk::Queue,
+    frames: RefCell<Vec<TransientFrame>>,
+    frame: Cell<usize>,
+}
+
+impl Drop for TransientCommands {
+    fn drop(&mut self) {
+        let _ = unsafe { self.device.get().device_wait_idle() };
+        for frame in self.frames.borrow_mut().drain(..) {
+            unsafe {
+                for &fence in frame.fences.iter() {
+                    self.device.get().destroy_fence(fence, None);
+                }
+                self.device.get().destroy_command_pool(frame.pool, None);
+            }
+        }
+    }
+}
+
+impl TransientCommands {
+    pub fn new(context: &VulkanContext, frames_in_flight: u64) -> Result<Self, VulkanError> {
+        let device = Rc::clone(context.get_device());
+        let queue_family_index = graphics_queue_family(context)?;
+        let queue = unsafe { device.get().get_device_queue(queue_family_index, 0) };
+
+        let transient_commands = TransientCommands {
+            device,
+            queue,
+            frames: RefCell::new(vec![]),
+            frame: Cell::new(0),
+        };
+
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+            .queue_family_index(queue_family_index)
+            .build();
+        for _ in 0..frames_in_flight.max(1) {
+            let pool = unsafe {
+                transient_commands
+                    .device
+                    .get()
+                    .create_command_pool(&pool_info, None)
+            }
+            .map_err(|err| device_error("Cannot create a command pool", err))?;
+            transient_commands.frames.borrow_mut().push(TransientFrame {
+                pool,
+                command_buffers: vec![],
+                used_command_buffers: 0,
+                fences: vec![],
+                used_fences: 0,
+                resources: vec![],
+            });
+        }
+
+        Ok(transient_commands)
+    }
+
+    // The commands are ordered with everything submitted to the queue before and after them
+    pub fn begin(&self) -> Result<vk::CommandBuffer, VulkanError> {
+        let mut frames = self.frames.borrow_mut();
+        let frame = &mut frames[self.frame.get()];
+
+        if frame.used_command_buffers == frame.command_buffers.len() {
+            let alloc_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(frame.pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1)
+                .build();
+            let command_buffers =
+                unsafe { self.device.get().allocate_command_buffers(&alloc_info) }
+                    .map_err(|err| device_error("Cannot allocate a command buffer", err))?;
+            frame.command_buffers.extend(command_buffers);
+        }
+        let command_buffer = frame.command_buffers[frame.used_command_buffers];
+        frame.used_command_buffers += 1;
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+            .build();
+        unsafe {
+            self.device
+                .get()
+                .begin_command_buffer(command_buffer, &begin_info)
+        }
+        .map_err(|err| device_error("Cannot begin a command buffer", err))?;
+
+        self.device.cmd_pipeline_barrier2(
+            command_buffer,
+            &DependencyInfo {
+                memory_barriers: &[queue_barrier()],
+                ..Default::default()
+            },
+        );
+        Ok(command_buffer)
+    }
+
+    // Returns once submitted, the commands are done when the frame slot comes back
+    pub fn submit(&self, command_buffer: vk::CommandBuffer) -> Result<(), VulkanError> {
+        self.device.cmd_pipeline_barrier2(
+            command_buffer,
+            &DependencyInfo {
+                memory_barriers: &[queue_barrier()],
+                ..Default::default()
+            },
+        );
+        unsafe { self.device.get().end_command_buffer(command_buffer) }
+            .map_err(|err| device_error("Cannot end a command buffer", err))?;
+
+        let mut frames = self.frames.borrow_mut();
+        let frame = &mut frames[self.frame.get()];
+        if frame.used_fences == frame.fences.len() {
+            let fence_info = vk::FenceCreateInfo::builder().build();
+            let fence = unsafe { self.device.get().create_fence(&fence_info, None) }
+                .map_err(|err| device_error("Cannot create a fence", err))?;
+            frame.fences.push(fence);
+        }
+        let fence = frame.fences[frame.used_fences];
+
+        let submit_info = vk::SubmitInfo::builder()
+            .command_buffers(&[command_buffer])
+            .build();
+        unsafe {
+            self.device
+                .get()
+                .queue_submit(self.queue, &[submit_info], fence)
+        }
+        .map_err(|err| device_error("Cannot submit a command buffer", err))?;
+        frame.used_fences += 1;
+        Ok(())
+    }
+
+    // Released with the commands submitted during the current frame
+    pub fn keep_alive<T: 'static>(&self, resource: T) {
+        let mut frames = self.frames.borrow_mut();
+        frames[self.frame.get()].resources.push(Box::new(resource));
+    }
+
+    // Has to be called once per frame. The commands of the frame slot were submitted
+    // frames_in_flight frames ago and are normally done, so waiting for them rarely blocks.
+    pub fn next_frame(&self) -> Result<(), VulkanError> {
+        let mut frames = self.frames.borrow_mut();
+        let index = (self.frame.get() + 1) % frames.len();
+        self.frame.set(index);
+
+        let frame = &mut frames[index];
+        let fences = &frame.fences[..frame.used_fences];
+        if !fences.is_empty() {
+            unsafe {
+                self.device
+                    .get()
+                    .wait_for_fences(fences, true, u64::MAX)
+                    .and_then(|_| self.device.get().reset_fences(fences))
+            }
+            .map_err(|err| device_error("Cannot wait for the transient commands", err))?;
+        }
+        frame.used_fences = 0;
+        frame.resources.clear();
+
+        if frame.used_command_buffers > 0 {
+            unsafe {
+                self.device
+                    .get()
+                    .reset_command_pool(frame.pool, vk::CommandPoolResetFlags::empty())
+            }
+            .map_err(|err| device_error("Cannot reset a command pool", err))?;
+            frame.used_command_buffers = 0;
+        }
+        Ok(())
+    }
+}
+
+// Bytes of the batch copied to staging buffers and recorded so far
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UploadProgress {
+    pub uploaded_bytes: u64,
+    pub total_bytes: u64,
+}
+
+pub type UploadProgressCallback = Box<dyn FnMut(UploadProgress)>;
+
+// Records the copies of several uploads into one command buffer, submitted at once. Without
+// transient commands the batch goes through the single time commands of the context, and waits
+// for the queue once for the whole batch.
+pub struct UploadBatch<'a> {
+    context: &'a VulkanContext,
+    transient_commands: Option<&'a TransientCommands>,
+    command_buffer: Cell<Option<vk::CommandBuffer>>,
+    // Read by the recorded copies, released once they are done
+    staging_buffers: RefCell<Vec<DataBuffer>>,
+    progress: RefCell<Option<UploadProgressCallback>>,
+    total_bytes: u64,
+    uploaded_bytes: Cell<u64>,
+}
+
+// Anything recorded and not submitted yet is submitted when the batch is dropped
+impl<'a> Drop for UploadBatch<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl<'a> UploadBatch<'a> {
+    pub fn new(
+        context: &'a VulkanContext,
+        transient_commands: Option<&'a TransientCommands>,
+    ) -> Self {
+        UploadBatch {
+            context,
+            transient_commands,
+            command_buffer: Cell::new(None),
+            staging_buffers: RefCell::new(vec![]),
+            progress: RefCell::new(None),
+            total_bytes: 0,
+            uploaded_bytes: Cell::new(0),
+        }
+    }
+
+    // Called after each recorded copy. The total is what the caller expects to upload, the
+    // staging buffers are only created along the way.
+    pub fn with_progress<F>(mut self, total_bytes: u64, progress: F) -> Self
+    where
+        F: FnMut(UploadProgress) + 'static,
+    {
+        self.total_bytes = total_bytes;
+        self.progress = RefCell::new(Some(Box::new(progress)));
+        self
+    }
+
+    // Begun with the first upload of the batch
+    pub fn get_command_buffer(&self) -> Result<vk::CommandBuffer, VulkanError> {
+        if let Some(command_buffer) = self.command_buffer.get() {
+            return Ok(command_buffer);
+        }
+
+        let command_buffer = match self.transient_commands {
+            Some(transient_commands) => transient_commands.begin()?,
+            None => self.context.begin_single_time_commands()?,
+        };
+        self.command_buffer.set(Some(command_buffer));
+        Ok(command_buffer)
+    }
+
+    pub fn keep_staging_buffer(&self, staging_buffer: DataBuffer) {
+        let uploaded_bytes = self.uploaded_bytes.get() + staging_buffer.size();
+        self.uploaded_bytes.set(uploaded_bytes);
+        self.staging_buffers.borrow_mut().push(staging_buffer);
+
+        if let Some(progress) = self.progress.borrow_mut().as_mut() {
+            progress(UploadProgress {
+                uploaded_bytes: uploaded_bytes.min(self.total_bytes),
+                total_bytes: self.total_bytes,
+            });
+        }
+    }
+
+    pub fn submit(self) -> Result<(), VulkanError> {
+        self.flush()
+    }
+
+    fn flush(&self) -> Result<(), VulkanError> {
+        let command_buffer = match self.command_buffer.take() {
+            Some(command_buffer) => command_buffer,
+            None => return Ok(()),
+        };
+        let staging_buffers = std::mem::take(&mut *self.staging_buffers.borrow_mut());
+
+        match self.transient_commands {
+            Some(transient_commands) => {
+                transient_commands.keep_alive(staging_buffers);
+                transient_commands.submit(command_buffer)
+            }
+            None => self.context.end_single_time_commands(command_buffer),
+        }
+    }
+}
+
+// vulkan_bootstrap does not expose its queue, it takes the first queue of the first graphics
+// family. Submitting to the same queue keeps the transient commands in order with its own.
+fn graphics_queue_family(context: &VulkanContext) -> Result<u32, VulkanError> {
+    let families = unsafe {
+        context
+            .get_instance()
+            .get()
+            .get_physical_device_queue_family_properties(context.get_physical_device().get())
+    };
+    families
+        .iter()
+        .position(|family| family.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+        .map(|index| index as u32)
+        .ok_or_else(|| VulkanError::DeviceError(String::from("No graphics queue family")))
+}
+
+fn queue_barrier() -> MemoryBarrier2 {
+    MemoryBarrier2 {
+        src_stage_mask: vk::PipelineStageFlags::ALL_COMMANDS,
+        src_access_mask: vk::AccessFlags::MEMORY_WRITE,
+        dst_stage_mask: vk::PipelineStageFlags::ALL_COMMANDS,
+        dst_access_mask: vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+    }
+}
+
+fn device_error(message: &str, err: vk::Result) -> VulkanError {
+    VulkanError::DeviceError(format!("{}: {}", message, err))
+}
@@ -0,0 +1,126 @@
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+
+// Size of the camera array in the ray generation shader
+pub const MAX_VIEWPORTS: usize = 4;
+// Tile sides are rounded up to this, so the launches keep whole blocks of threads
+pub const TILE_ALIGNMENT: u32 = 8;
+
+// Region of the back buffer, in pixels, traced with the camera at the same index as the viewport
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    pub fn full(extent: vk::Extent2D) -> Self {
+        Viewport {
+            x: 0,
+            y: 0,
+            width: extent.width,
+            height: extent.height,
+        }
+    }
+
+    // Part of the viewport inside the extent, None if nothing is left
+    pub fn clip(&self, extent: vk::Extent2D) -> Option<Viewport> {
+        if self.x >= extent.width || self.y >= extent.height {
+            return None;
+        }
+
+        let width = self.width.min(extent.width - self.x);
+        let height = self.height.min(extent.height - self.y);
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        Some(Viewport {
+            x: self.x,
+            y: self.y,
+            width,
+            height,
+        })
+    }
+
+    // Overlap of the two viewports, None if they do not overlap
+    pub fn intersect(&self, other: &Viewport) -> Option<Viewport> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+
+        Some(Viewport {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+
+    // Row by row from the top left, the tiles on the right and bottom edges are cut to fit
+    pub fn tiles(&self, tile_size: u32) -> Vec<Viewport> {
+        let tile_size = tile_size.max(1);
+        let mut tiles = vec![];
+        for y in (self.y..self.y + self.height).step_by(tile_size as usize) {
+            for x in (self.x..self.x + self.width).step_by(tile_size as usize) {
+                tiles.push(Viewport {
+                    x,
+                    y,
+                    width: tile_size.min(self.x + self.width - x),
+                    height: tile_size.min(self.y + self.height - y),
+                });
+            }
+        }
+        tiles
+    }
+}
+
+// How the viewports are launched. Tiles are traced one after the other, the driver can preempt
+// the GPU between them so a heavy frame on a slow GPU does not trip the timeout detection.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraceDispatch {
+    // None traces every viewport in a single launch
+    pub tile_size: Option<u32>,
+    // Debug mode, only the pixels inside are traced and the others keep what they had
+    pub region: Option<Viewport>,
+}
+
+impl TraceDispatch {
+    // Parts of the viewport to launch, in the order they are traced
+    pub(crate) fn launches(&self, viewport: &Viewport) -> Vec<Viewport> {
+        let traced = match self.region {
+            Some(region) => match viewport.intersect(&region) {
+                Some(traced) => traced,
+                None => return vec![],
+            },
+            None => *viewport,
+        };
+
+        match self.tile_size {
+            Some(tile_size) => {
+                traced.tiles(tile_size.max(1).div_ceil(TILE_ALIGNMENT) * TILE_ALIGNMENT)
+            }
+            None => vec![traced],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct ViewportPushConstants {
+    pub offset: [u32; 2],
+    pub camera_index: u32,
+    pub padding: u32,
+    // Of the launched tile inside the viewport
+    pub tile_offset: [u32; 2],
+    pub size: [u32; 2],
+}
+
+unsafe impl Zeroable for ViewportPushConstants {}
+unsafe impl Pod for ViewportPushConstants {}